@@ -0,0 +1,643 @@
+//! Async session storage for use inside async runtimes
+//!
+//! [`AsyncSessionStorage`] mirrors [`SessionStorage`] with boxed futures so it
+//! stays object-safe: a `Box<dyn AsyncSessionStorage>` can be held across
+//! `.await` points and moved between tasks, because every future it returns is
+//! `Send`. [`BlockingStorage`] adapts any synchronous backend by running its
+//! calls on tokio's blocking thread pool.
+//!
+//! [`AsyncSessionStorage::search`] streams full-text search hits session by
+//! session and can be cancelled between sessions.
+//!
+//! [`AsyncSessionManager::with_background_save`] moves saves off the caller's
+//! path entirely: they run in spawned tasks, at most one in flight per
+//! session, and [`AsyncSessionManager::flush_pending`] waits for them.
+
+use crate::compaction::{CompactionStrategy, ContextCompactor};
+use crate::error::{ContextError, Result};
+use crate::search::SearchHit;
+use crate::clock::Clock;
+use crate::naming::SessionNamer;
+use crate::session::{compact, Message, SaveFailurePolicy, SaveSchedule, Session, SessionFactory, SizeLimits};
+use crate::storage::{SessionInfo, SessionStorage};
+use crate::tokens::TokenModel;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+use tokio::task::JoinHandle;
+use tracing::warn;
+use uuid::Uuid;
+
+/// Boxed `Send` future returned by [`AsyncSessionStorage`] methods
+pub type StorageFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+/// Async counterpart of [`SessionStorage`]
+pub trait AsyncSessionStorage: Send + Sync {
+    /// Save a session to storage
+    fn save_session<'a>(&'a self, session: &'a Session) -> StorageFuture<'a, ()>;
+
+    /// Load a session by ID
+    fn load_session<'a>(&'a self, session_id: &'a Uuid) -> StorageFuture<'a, Session>;
+
+    /// Load the most recent session
+    fn load_latest_session(&self) -> StorageFuture<'_, Option<Session>>;
+
+    /// List all available sessions
+    fn list_sessions(&self) -> StorageFuture<'_, Vec<SessionInfo>>;
+
+    /// Delete a session
+    fn delete_session<'a>(&'a self, session_id: &'a Uuid) -> StorageFuture<'a, ()>;
+
+    /// Clean up old sessions (keep last N sessions)
+    fn cleanup_old_sessions(&self, keep_count: usize) -> StorageFuture<'_, usize>;
+
+    /// Stream hits for `query` across every session, as [`Session::search`]
+    ///
+    /// Sessions are listed once, then loaded and searched one at a time as
+    /// the stream is polled, so hits from early sessions arrive while later
+    /// ones are still unread. Setting `cancel` (say, when the user types
+    /// another character) ends the stream at the next session boundary;
+    /// dropping the stream stops it too. Sessions that fail to load are
+    /// skipped with a warning; only a failed listing yields an error.
+    fn search<'a>(&'a self, query: &'a str, cancel: &'a AtomicBool) -> SearchStream<'a> {
+        SearchStream {
+            query,
+            cancel,
+            load: Box::new(move |session_id| Box::pin(async move { self.load_session(&session_id).await })),
+            listing: Some(self.list_sessions()),
+            pending: VecDeque::new(),
+            loading: None,
+            hits: VecDeque::new(),
+        }
+    }
+}
+
+/// Search hits from [`AsyncSessionStorage::search`], in `list_sessions` order
+///
+/// Implements [`futures_core::Stream`]; [`SearchStream::next`] reads it
+/// without a stream combinator crate.
+pub struct SearchStream<'a> {
+    query: &'a str,
+    cancel: &'a AtomicBool,
+    load: Box<dyn Fn(Uuid) -> StorageFuture<'a, Session> + Send + Sync + 'a>,
+    listing: Option<StorageFuture<'a, Vec<SessionInfo>>>,
+    /// Listed sessions not yet loaded
+    pending: VecDeque<Uuid>,
+    /// The session being loaded and its id
+    loading: Option<(Uuid, StorageFuture<'a, Session>)>,
+    /// Hits from the last loaded session not yet yielded
+    hits: VecDeque<SearchHit>,
+}
+
+impl SearchStream<'_> {
+    /// The next hit, or `None` once every session is searched or `cancel` is set
+    pub async fn next(&mut self) -> Option<Result<SearchHit>> {
+        std::future::poll_fn(|cx| self.poll_hit(cx)).await
+    }
+
+    fn poll_hit(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<SearchHit>>> {
+        loop {
+            if self.cancel.load(Ordering::Relaxed) {
+                return Poll::Ready(None);
+            }
+            if let Some(hit) = self.hits.pop_front() {
+                return Poll::Ready(Some(Ok(hit)));
+            }
+
+            if let Some(listing) = &mut self.listing {
+                let sessions = match listing.as_mut().poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(sessions) => sessions,
+                };
+                self.listing = None;
+                match sessions {
+                    Ok(sessions) => self.pending = sessions.into_iter().map(|info| info.id).collect(),
+                    Err(e) => return Poll::Ready(Some(Err(e))),
+                }
+                continue;
+            }
+
+            if let Some((session_id, loading)) = &mut self.loading {
+                let session = match loading.as_mut().poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(session) => session,
+                };
+                match session {
+                    Ok(session) => self.hits.extend(session.search(self.query)),
+                    Err(e) => warn!("Skipping session {} in search: {}", session_id, e),
+                }
+                self.loading = None;
+                continue;
+            }
+
+            match self.pending.pop_front() {
+                Some(session_id) => self.loading = Some((session_id, (self.load)(session_id))),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl futures_core::Stream for SearchStream<'_> {
+    type Item = Result<SearchHit>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_hit(cx)
+    }
+}
+
+/// Runs a synchronous [`SessionStorage`] on tokio's blocking pool
+///
+/// Must be used from within a tokio runtime.
+pub struct BlockingStorage<S> {
+    inner: Arc<S>,
+}
+
+impl<S: SessionStorage + 'static> BlockingStorage<S> {
+    pub fn new(storage: S) -> Self {
+        Self {
+            inner: Arc::new(storage),
+        }
+    }
+
+    /// Run `f` against the wrapped storage without blocking the async runtime
+    fn run<T, F>(&self, f: F) -> StorageFuture<'static, T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&S) -> Result<T> + Send + 'static,
+    {
+        let inner = Arc::clone(&self.inner);
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || f(&inner))
+                .await
+                .map_err(|e| ContextError::Storage(format!("Storage task failed: {}", e)))?
+        })
+    }
+}
+
+impl<S: SessionStorage + 'static> AsyncSessionStorage for BlockingStorage<S> {
+    fn save_session<'a>(&'a self, session: &'a Session) -> StorageFuture<'a, ()> {
+        let session = session.clone();
+        self.run(move |storage| storage.save_session(&session))
+    }
+
+    fn load_session<'a>(&'a self, session_id: &'a Uuid) -> StorageFuture<'a, Session> {
+        let session_id = *session_id;
+        self.run(move |storage| storage.load_session(&session_id))
+    }
+
+    fn load_latest_session(&self) -> StorageFuture<'_, Option<Session>> {
+        self.run(|storage| storage.load_latest_session())
+    }
+
+    fn list_sessions(&self) -> StorageFuture<'_, Vec<SessionInfo>> {
+        self.run(|storage| storage.list_sessions())
+    }
+
+    fn delete_session<'a>(&'a self, session_id: &'a Uuid) -> StorageFuture<'a, ()> {
+        let session_id = *session_id;
+        self.run(move |storage| storage.delete_session(&session_id))
+    }
+
+    fn cleanup_old_sessions(&self, keep_count: usize) -> StorageFuture<'_, usize> {
+        self.run(move |storage| storage.cleanup_old_sessions(keep_count))
+    }
+}
+
+/// Saves handed off to background tasks, shared with those tasks
+#[derive(Default)]
+struct BackgroundSaves {
+    /// Sessions with a save task running, each with the newest snapshot
+    /// waiting to be written after the current one (if any)
+    queued: HashMap<Uuid, Option<Session>>,
+    /// Failures not yet reported by `flush_pending`
+    errors: Vec<ContextError>,
+}
+
+fn lock(saves: &Mutex<BackgroundSaves>) -> MutexGuard<'_, BackgroundSaves> {
+    saves.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Session manager over async storage
+///
+/// Covers the core load/save/append cycle of
+/// [`SessionManager`](crate::SessionManager) with the same compaction,
+/// auto-save, durability, and versioning behavior. It is `Send + Sync`, so it can be
+/// shared behind an async mutex or moved into a spawned task.
+pub struct AsyncSessionManager {
+    storage: Arc<dyn AsyncSessionStorage + Send + Sync>,
+    compaction_strategy: CompactionStrategy,
+    compactor: Option<Box<dyn ContextCompactor>>,
+    max_tokens: usize,
+    compaction_target: usize,
+    token_model: Option<TokenModel>,
+    auto_save: bool,
+    persist_empty_sessions: bool,
+    skip_empty_sessions: bool,
+    save_schedule: SaveSchedule,
+    limits: SizeLimits,
+    save_failure_policy: SaveFailurePolicy,
+    factory: SessionFactory,
+    background_save: bool,
+    background: Arc<Mutex<BackgroundSaves>>,
+    save_tasks: Vec<JoinHandle<()>>,
+}
+
+impl AsyncSessionManager {
+    /// Create a manager over async storage; `config.storage_dir` is ignored
+    ///
+    /// Fails with `ContextError::Config` if the config is invalid; see
+    /// [`Config::validate`](crate::Config::validate).
+    pub fn new(storage: Box<dyn AsyncSessionStorage + Send + Sync>, config: crate::Config) -> Result<Self> {
+        config.validate()?;
+        Ok(Self {
+            storage: Arc::from(storage),
+            compaction_target: config.compaction_target(),
+            limits: SizeLimits::of(&config),
+            factory: SessionFactory::of(&config),
+            save_failure_policy: config.save_failure_policy,
+            compaction_strategy: config.compaction_strategy,
+            compactor: None,
+            max_tokens: config.max_tokens,
+            token_model: config.token_model,
+            auto_save: config.auto_save,
+            persist_empty_sessions: config.persist_empty_sessions,
+            skip_empty_sessions: config.skip_empty_sessions,
+            save_schedule: SaveSchedule::new(config.durability),
+            background_save: false,
+            background: Arc::default(),
+            save_tasks: Vec::new(),
+        })
+    }
+
+    /// Compact with a custom compactor instead of `config.compaction_strategy`
+    pub fn with_compactor(mut self, compactor: Box<dyn ContextCompactor>) -> Self {
+        self.compactor = Some(compactor);
+        self
+    }
+
+    /// Stamp sessions this manager creates or loads with `clock`, as
+    /// [`SessionManager::with_clock`](crate::SessionManager::with_clock)
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.factory.clock = Some(clock);
+        self
+    }
+
+    /// Name sessions this manager creates with `namer`, as
+    /// [`SessionManager::with_namer`](crate::SessionManager::with_namer)
+    pub fn with_namer(mut self, namer: Box<dyn SessionNamer>) -> Self {
+        self.factory.namer = Some(namer);
+        self
+    }
+
+    /// Save in spawned tasks instead of awaiting each save
+    ///
+    /// Every save (explicit or auto-save) bumps the version and returns as
+    /// soon as a snapshot is queued. Each session has at most one save in
+    /// flight; snapshots queued while it runs are coalesced so only the
+    /// newest is written next. Failures don't undo the version bump and are
+    /// reported by the next [`flush_pending`](Self::flush_pending).
+    ///
+    /// Until a queued save lands, loads may return the older copy, and a
+    /// crash loses whatever was still queued. Call `flush_pending` before
+    /// shutting down; dropping the manager doesn't cancel running saves, but
+    /// nothing waits for them either.
+    ///
+    /// Must be used from within a tokio runtime.
+    pub fn with_background_save(mut self, enabled: bool) -> Self {
+        self.background_save = enabled;
+        self
+    }
+
+    /// Wait for every background save queued so far
+    ///
+    /// Returns the first failure since the last call, if any. A no-op
+    /// without [`with_background_save`](Self::with_background_save).
+    pub async fn flush_pending(&mut self) -> Result<()> {
+        for task in std::mem::take(&mut self.save_tasks) {
+            task.await
+                .map_err(|e| ContextError::Storage(format!("Save task failed: {}", e)))?;
+        }
+        let mut errors = std::mem::take(&mut lock(&self.background).errors).into_iter();
+        match errors.next() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Queue `session` for the background task, starting one if none is
+    /// running for it
+    fn queue_save(&mut self, session: Session) {
+        let session_id = session.id;
+        {
+            let mut saves = lock(&self.background);
+            if let Some(queued) = saves.queued.get_mut(&session_id) {
+                *queued = Some(session);
+                return;
+            }
+            saves.queued.insert(session_id, Some(session));
+        }
+
+        let storage = Arc::clone(&self.storage);
+        let background = Arc::clone(&self.background);
+        self.save_tasks.retain(|task| !task.is_finished());
+        self.save_tasks.push(tokio::spawn(async move {
+            loop {
+                let next = {
+                    let mut saves = lock(&background);
+                    match saves.queued.get_mut(&session_id).and_then(Option::take) {
+                        Some(session) => session,
+                        None => {
+                            saves.queued.remove(&session_id);
+                            break;
+                        }
+                    }
+                };
+                if let Err(e) = storage.save_session(&next).await {
+                    lock(&background).errors.push(e);
+                }
+            }
+        }));
+    }
+
+    /// Bump the session version and save it, undoing the bump on failure
+    async fn persist(&mut self, session: &mut Session) -> Result<()> {
+        session.version += 1;
+        if self.background_save {
+            self.queue_save(session.clone());
+            self.save_schedule.saved(&session.id);
+            return Ok(());
+        }
+        if let Err(e) = self.storage.save_session(session).await {
+            session.version -= 1;
+            return Err(e);
+        }
+        self.save_schedule.saved(&session.id);
+        Ok(())
+    }
+
+    /// Load the most recent session, creating one if none exists
+    ///
+    /// With `skip_empty_sessions`, an empty latest session is passed over for
+    /// the most recently modified one with messages.
+    pub async fn load_latest(&mut self) -> Result<Session> {
+        let latest = match self.storage.load_latest_session().await? {
+            Some(session) if self.skip_empty_sessions && session.messages().is_empty() => {
+                let newest = self.list_sessions().await?.into_iter().max_by_key(|info| info.modified_at);
+                match newest {
+                    Some(info) => Some(self.storage.load_session(&info.id).await?),
+                    None => None,
+                }
+            }
+            latest => latest,
+        };
+        match latest {
+            Some(mut session) => {
+                self.factory.resumed(&mut session);
+                self.factory.loaded(&mut session);
+                Ok(session)
+            }
+            None => self.new_session().await,
+        }
+    }
+
+    /// Load a specific session by ID
+    pub async fn load_session(&mut self, session_id: &Uuid) -> Result<Session> {
+        let mut session = self.storage.load_session(session_id).await?;
+        self.factory.loaded(&mut session);
+        Ok(session)
+    }
+
+    /// Save a session
+    pub async fn save_session(&mut self, session: &mut Session) -> Result<()> {
+        self.persist(session).await
+    }
+
+    /// Save a session now, including any messages buffered by `Durability`
+    ///
+    /// With background saves this also waits for them, like
+    /// [`flush_pending`](Self::flush_pending).
+    pub async fn flush(&mut self, session: &mut Session) -> Result<()> {
+        self.persist(session).await?;
+        self.flush_pending().await
+    }
+
+    /// Create a new session
+    pub async fn new_session(&mut self) -> Result<Session> {
+        let mut session = self.factory.fresh_session();
+        if self.auto_save && self.persist_empty_sessions {
+            self.persist(&mut session).await?;
+        }
+        Ok(session)
+    }
+
+    /// List all available sessions, leaving out empty ones when
+    /// `skip_empty_sessions` is on
+    pub async fn list_sessions(&self) -> Result<Vec<SessionInfo>> {
+        let mut sessions = self.storage.list_sessions().await?;
+        if self.skip_empty_sessions {
+            sessions.retain(|info| info.message_count > 0);
+        }
+        Ok(sessions)
+    }
+
+    /// Add a message to a session with automatic compaction and saving
+    ///
+    /// Applies the same `max_metadata_bytes` and `content_policy` checks as
+    /// [`SessionManager::add_message`](crate::SessionManager::add_message),
+    /// and fails with `ContextError::SessionTooLarge`, without saving or
+    /// changing `session`, if it's over `max_messages` or `max_bytes` after
+    /// compaction. A failed auto-save is handled per `save_failure_policy`.
+    pub async fn add_message(&mut self, session: &mut Session, mut message: Message) -> Result<()> {
+        self.limits.check_message(&mut message)?;
+        let snapshot = self.save_failure_policy.snapshot(session);
+        let model = self.token_model.as_ref();
+        session.add_message_within(message, model, self.max_tokens, |session| {
+            if session.track_tokens_with(model) > self.max_tokens {
+                compact(session, self.compactor.as_deref(), &self.compaction_strategy, self.compaction_target, model)?;
+            }
+            self.limits.check_session(session)
+        })?;
+
+        if self.auto_save
+            && self.save_schedule.message_added(&session.id)
+            && let Err(e) = self.persist(session).await
+        {
+            self.save_failure_policy.recover(e, session, snapshot, &mut self.save_schedule)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::FileStorage;
+    use crate::test_support::CountingStorage;
+    use std::sync::atomic::Ordering;
+    use tempfile::TempDir;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_async_manager_runs_in_spawned_task() {
+        assert_send_sync::<Box<dyn AsyncSessionStorage + Send + Sync>>();
+        assert_send_sync::<AsyncSessionManager>();
+
+        let temp_dir = TempDir::new().unwrap();
+        let storage = BlockingStorage::new(FileStorage::with_directory(temp_dir.path()).unwrap());
+        let mut manager = AsyncSessionManager::new(Box::new(storage), crate::Config::default()).unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let (session, count) = runtime.block_on(async move {
+            tokio::spawn(async move {
+                let mut session = manager.load_latest().await.unwrap();
+                manager.add_message(&mut session, Message::user("Hello".to_string())).await.unwrap();
+                let reloaded = manager.load_session(&session.id).await.unwrap();
+                let count = manager.list_sessions().await.unwrap().len();
+                (reloaded, count)
+            })
+            .await
+            .unwrap()
+        });
+
+        assert_eq!(session.messages().len(), 1);
+        assert_eq!(session.version, 2);
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_async_manager_enforces_config_limits() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = BlockingStorage::new(FileStorage::with_directory(temp_dir.path()).unwrap());
+        let config = crate::Config {
+            max_messages: Some(2),
+            content_policy: crate::ContentPolicy::Reject,
+            ..Default::default()
+        };
+        let mut manager = AsyncSessionManager::new(Box::new(storage), config).unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let mut session = manager.new_session().await.unwrap();
+            let err = manager.add_message(&mut session, Message::user("bell\u{7}".to_string())).await.unwrap_err();
+            assert!(matches!(err, ContextError::InvalidContent(_)));
+            assert!(session.messages().is_empty());
+
+            for i in 0..2 {
+                manager.add_message(&mut session, Message::user(format!("Message {}", i))).await.unwrap();
+            }
+            let err = manager.add_message(&mut session, Message::user("One too many".to_string())).await.unwrap_err();
+            assert!(matches!(err, ContextError::SessionTooLarge(_)));
+            assert_eq!(manager.load_session(&session.id).await.unwrap().messages().len(), 2);
+            assert_eq!(session.messages().len(), 2);
+        });
+
+        let storage = BlockingStorage::new(FileStorage::with_directory(temp_dir.path()).unwrap());
+        let config = crate::Config { compaction_target_ratio: f32::NAN, ..Default::default() };
+        assert!(matches!(AsyncSessionManager::new(Box::new(storage), config), Err(ContextError::Config(_))));
+    }
+
+    #[test]
+    fn test_async_manager_creates_sessions_like_sync_manager() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = BlockingStorage::new(FileStorage::with_directory(temp_dir.path()).unwrap());
+        let config = crate::Config {
+            session_id_version: crate::SessionIdVersion::V7,
+            trim_incomplete_on_load: true,
+            save_failure_policy: SaveFailurePolicy::PropagateAndRollback,
+            ..Default::default()
+        };
+        let mut manager = AsyncSessionManager::new(Box::new(storage), config)
+            .unwrap()
+            .with_namer(Box::new(|| "TICKET-1".to_string()));
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let mut session = manager.new_session().await.unwrap();
+            assert_eq!(session.id.get_version_num(), 7);
+            assert_eq!(session.name, "TICKET-1");
+
+            manager.add_message(&mut session, Message::user("Hello".to_string())).await.unwrap();
+            manager.add_message(&mut session, Message::assistant(String::new())).await.unwrap();
+            assert_eq!(manager.load_latest().await.unwrap().messages().len(), 1);
+
+            // A failed auto-save rolls the session back
+            std::fs::remove_dir_all(temp_dir.path()).unwrap();
+            std::fs::write(temp_dir.path(), "not a directory").unwrap();
+            assert!(manager.add_message(&mut session, Message::user("Lost".to_string())).await.is_err());
+            assert_eq!(session.messages().len(), 2);
+            assert_eq!(session.version, 3);
+        });
+    }
+
+    #[test]
+    fn test_background_saves_coalesce_until_flushed() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = CountingStorage::with_directory(temp_dir.path());
+        let saves = Arc::clone(&storage.saves);
+        let mut manager = AsyncSessionManager::new(Box::new(BlockingStorage::new(storage)), crate::Config::default())
+            .unwrap()
+            .with_background_save(true);
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            // Nothing has yielded yet, so the spawned task hasn't started and
+            // every snapshot replaces the one queued before it
+            let mut session = manager.new_session().await.unwrap();
+            for i in 0..3 {
+                manager.add_message(&mut session, Message::user(format!("Message {}", i))).await.unwrap();
+            }
+            assert_eq!(session.version, 4);
+            assert_eq!(saves.load(Ordering::SeqCst), 0);
+
+            manager.flush_pending().await.unwrap();
+            assert_eq!(saves.load(Ordering::SeqCst), 1);
+            let saved = manager.load_session(&session.id).await.unwrap();
+            assert_eq!((saved.messages().len(), saved.version), (3, 4));
+
+            // With an unwritable directory the failure shows up on flush
+            std::fs::remove_dir_all(temp_dir.path()).unwrap();
+            std::fs::write(temp_dir.path(), "not a directory").unwrap();
+            manager.add_message(&mut session, Message::user("Lost".to_string())).await.unwrap();
+            assert!(manager.flush_pending().await.is_err());
+            assert!(manager.flush_pending().await.is_ok());
+        });
+    }
+
+    #[test]
+    fn test_search_streams_hits_and_stops_when_cancelled() {
+        let temp_dir = TempDir::new().unwrap();
+        let files = FileStorage::with_directory(temp_dir.path()).unwrap();
+        for i in 0..3 {
+            let mut session = Session::new();
+            session.add_message(Message::user(format!("deploy {} and deploy again", i)));
+            files.save_session(&session).unwrap();
+        }
+        let storage = BlockingStorage::new(files);
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let not_cancelled = AtomicBool::new(false);
+            let mut all = storage.search("DEPLOY", &not_cancelled);
+            let mut hits = Vec::new();
+            while let Some(hit) = all.next().await {
+                hits.push(hit.unwrap());
+            }
+            assert_eq!(hits.len(), 6);
+            assert!(hits.chunks(2).all(|pair| pair[0].session_id == pair[1].session_id));
+
+            // Cancelling ends the stream even with a hit from this session buffered
+            let cancel = AtomicBool::new(false);
+            let mut refined = storage.search("deploy", &cancel);
+            let first = refined.next().await.unwrap().unwrap();
+            cancel.store(true, Ordering::Relaxed);
+            assert!(refined.next().await.is_none());
+            assert_eq!(first.char_offset, 0);
+        });
+    }
+}
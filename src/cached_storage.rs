@@ -0,0 +1,216 @@
+//! Read-through session cache over any storage backend
+//!
+//! [`CachedStorage`] keeps recently loaded sessions in memory, so repeated
+//! loads of the same session (a UI re-reading the latest one on every
+//! keystroke) skip reading and parsing the file. Writes go straight to the
+//! wrapped backend and drop the affected entries.
+
+use crate::error::Result;
+use crate::session::{Message, Session};
+use crate::storage::{SessionInfo, SessionStorage};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, MutexGuard};
+use uuid::Uuid;
+
+/// Sessions held in memory, least recently used first in `order`
+#[derive(Debug, Default)]
+struct Cache {
+    sessions: HashMap<Uuid, Session>,
+    order: VecDeque<Uuid>,
+    /// The id `load_latest_session` last resolved to
+    latest: Option<Uuid>,
+    /// Bumped on every invalidation, so a load that raced a write doesn't
+    /// cache what it read from before the write
+    generation: u64,
+}
+
+impl Cache {
+    fn get(&mut self, session_id: &Uuid) -> Option<Session> {
+        let session = self.sessions.get(session_id)?.clone();
+        self.touch(session_id);
+        Some(session)
+    }
+
+    fn insert(&mut self, session: Session, capacity: usize) {
+        if capacity == 0 {
+            return;
+        }
+        let id = session.id;
+        if self.sessions.insert(id, session).is_some() {
+            self.touch(&id);
+        } else {
+            self.order.push_back(id);
+        }
+        while self.order.len() > capacity {
+            if let Some(evicted) = self.order.pop_front() {
+                self.sessions.remove(&evicted);
+            }
+        }
+    }
+
+    /// Drop a session and the cached latest id after a write
+    fn invalidate(&mut self, session_id: &Uuid) {
+        if self.sessions.remove(session_id).is_some() {
+            self.order.retain(|id| id != session_id);
+        }
+        self.latest = None;
+        self.generation += 1;
+    }
+
+    fn touch(&mut self, session_id: &Uuid) {
+        if let Some(position) = self.order.iter().position(|id| id == session_id) {
+            self.order.remove(position);
+            self.order.push_back(*session_id);
+        }
+    }
+}
+
+/// Memoizes loaded sessions in front of another [`SessionStorage`]
+///
+/// Up to `capacity` sessions are kept, evicting the least recently used.
+/// `save_session` and `delete_session` drop the session they touch and the
+/// cached latest id; cleanup clears everything. Listing always goes to the
+/// backend. Changes made to the backend by anything other than this wrapper
+/// (another process, say) are not seen until the entry is evicted, so share
+/// one `CachedStorage` rather than wrapping the same backend twice.
+pub struct CachedStorage<S> {
+    inner: S,
+    capacity: usize,
+    cache: Mutex<Cache>,
+}
+
+impl<S: SessionStorage> CachedStorage<S> {
+    pub fn new(inner: S, capacity: usize) -> Self {
+        Self {
+            inner,
+            capacity,
+            cache: Mutex::new(Cache::default()),
+        }
+    }
+
+    /// The wrapped backend
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Drop every cached session
+    pub fn clear(&self) {
+        let mut cache = self.lock();
+        let generation = cache.generation + 1;
+        *cache = Cache { generation, ..Cache::default() };
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Cache> {
+        self.cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<S: SessionStorage> SessionStorage for CachedStorage<S> {
+    fn save_session(&self, session: &Session) -> Result<()> {
+        let result = self.inner.save_session(session);
+        self.lock().invalidate(&session.id);
+        result
+    }
+
+    fn load_session(&self, session_id: &Uuid) -> Result<Session> {
+        let generation = {
+            let mut cache = self.lock();
+            if let Some(session) = cache.get(session_id) {
+                return Ok(session);
+            }
+            cache.generation
+        };
+
+        let session = self.inner.load_session(session_id)?;
+        let mut cache = self.lock();
+        if cache.generation == generation {
+            cache.insert(session.clone(), self.capacity);
+        }
+        Ok(session)
+    }
+
+    fn load_latest_session(&self) -> Result<Option<Session>> {
+        let generation = {
+            let mut cache = self.lock();
+            if let Some(session) = cache.latest.and_then(|id| cache.get(&id)) {
+                return Ok(Some(session));
+            }
+            cache.generation
+        };
+
+        let latest = self.inner.load_latest_session()?;
+        let mut cache = self.lock();
+        if cache.generation == generation {
+            cache.latest = latest.as_ref().map(|session| session.id);
+            if let Some(session) = &latest {
+                cache.insert(session.clone(), self.capacity);
+            }
+        }
+        Ok(latest)
+    }
+
+    fn list_sessions(&self) -> Result<Vec<SessionInfo>> {
+        self.inner.list_sessions()
+    }
+
+    fn delete_session(&self, session_id: &Uuid) -> Result<()> {
+        let result = self.inner.delete_session(session_id);
+        self.lock().invalidate(session_id);
+        result
+    }
+
+    fn append_message(&self, session_id: &Uuid, message: &Message) -> Result<()> {
+        let result = self.inner.append_message(session_id, message);
+        self.lock().invalidate(session_id);
+        result
+    }
+
+    fn cleanup_old_sessions(&self, keep_count: usize) -> Result<usize> {
+        let result = self.inner.cleanup_old_sessions(keep_count);
+        self.clear();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::Message;
+    use crate::test_support::CountingStorage;
+    use std::sync::atomic::Ordering;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_cached_storage_hits_and_invalidates() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = CachedStorage::new(CountingStorage::with_directory(temp_dir.path()), 2);
+        let loads = |storage: &CachedStorage<CountingStorage>| storage.inner().loads.load(Ordering::SeqCst);
+
+        let mut sessions: Vec<Session> = (0..3).map(|_| Session::new()).collect();
+        for session in &sessions {
+            storage.save_session(session).unwrap();
+        }
+
+        for _ in 0..3 {
+            assert_eq!(storage.load_latest_session().unwrap().unwrap().id, sessions[2].id);
+        }
+        assert_eq!(loads(&storage), 1);
+
+        // A save drops the stale copy, so the next load sees the new message
+        sessions[2].add_message(Message::user("Hello".to_string()));
+        storage.save_session(&sessions[2]).unwrap();
+        assert_eq!(storage.load_latest_session().unwrap().unwrap().messages().len(), 1);
+        assert_eq!(loads(&storage), 2);
+
+        // Capacity 2: loading two more sessions evicts the latest one
+        storage.load_session(&sessions[0].id).unwrap();
+        storage.load_session(&sessions[1].id).unwrap();
+        storage.load_session(&sessions[1].id).unwrap();
+        assert_eq!(loads(&storage), 4);
+        storage.load_session(&sessions[2].id).unwrap();
+        assert_eq!(loads(&storage), 5);
+
+        storage.delete_session(&sessions[2].id).unwrap();
+        assert!(storage.load_session(&sessions[2].id).is_err());
+    }
+}
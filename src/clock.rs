@@ -0,0 +1,124 @@
+//! Injectable time source for session and message timestamps
+
+use chrono::{DateTime, Duration, Utc};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Source of the current time
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The wall clock, `Utc::now()`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that only moves when told to, for tests and replays
+///
+/// With a step, every reading advances the clock afterwards, so successive
+/// timestamps are strictly increasing and fully deterministic.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<DateTime<Utc>>,
+    step: Duration,
+}
+
+impl ManualClock {
+    pub fn new(start: DateTime<Utc>) -> Self {
+        Self {
+            now: Mutex::new(start),
+            step: Duration::zero(),
+        }
+    }
+
+    /// Advance by `step` after every reading
+    pub fn with_step(mut self, step: Duration) -> Self {
+        self.step = step;
+        self
+    }
+
+    /// Jump to `time`
+    pub fn set(&self, time: DateTime<Utc>) {
+        *self.lock() = time;
+    }
+
+    /// Move forward by `by`
+    pub fn advance(&self, by: Duration) {
+        *self.lock() += by;
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, DateTime<Utc>> {
+        self.now.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        let mut now = self.lock();
+        let reading = *now;
+        *now += self.step;
+        reading
+    }
+}
+
+/// Clock carried by a session; the wall clock unless one was injected
+#[derive(Clone, Default)]
+pub(crate) struct SessionClock(Option<Arc<dyn Clock>>);
+
+impl SessionClock {
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> Self {
+        Self(Some(clock))
+    }
+
+    pub(crate) fn now(&self) -> DateTime<Utc> {
+        self.0.as_ref().map_or_else(Utc::now, |clock| clock.now())
+    }
+
+    pub(crate) fn is_injected(&self) -> bool {
+        self.0.is_some()
+    }
+}
+
+impl fmt::Debug for SessionClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.is_injected() { "SessionClock(injected)" } else { "SessionClock(system)" })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::{Message, Session};
+
+    #[test]
+    fn test_manual_clock_makes_sessions_deterministic() {
+        let start = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        let clock = Arc::new(ManualClock::new(start).with_step(Duration::seconds(1)));
+
+        let mut session = Session::with_clock(clock.clone());
+        assert_eq!(session.created_at, start);
+        for i in 0..3 {
+            session.add_message(Message::user(format!("Message {}", i)));
+        }
+
+        let stamps: Vec<i64> = session.messages().iter().map(|m| (m.timestamp - start).num_seconds()).collect();
+        assert_eq!(stamps, [1, 2, 3]);
+        assert_eq!(session.updated_at, session.messages()[2].timestamp);
+        assert_eq!(session.messages_since(start + Duration::seconds(1)).len(), 2);
+
+        clock.set(start + Duration::days(1));
+        assert_eq!(session.duplicate().created_at, start + Duration::days(1));
+
+        // Without an injected clock, messages keep their own timestamps
+        let fixed = Message::user("imported".to_string()).with_timestamp(start);
+        let mut plain = Session::new();
+        plain.add_message(fixed);
+        assert_eq!(plain.messages()[0].timestamp, start);
+    }
+}
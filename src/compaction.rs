@@ -1,22 +1,115 @@
 //! Context compaction strategies
 
-use crate::session::{Session, Message};
-use crate::error::Result;
+use crate::session::{removed_messages, Session, Message, MessageRole};
+use crate::error::{ContextError, Result};
+use crate::tokens::TokenModel;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use uuid::Uuid;
+
+/// Which messages [`CompactionStrategy::Sliding`] and
+/// [`CompactionStrategy::SystemAndRecent`] protect, and whether they work in
+/// whole exchanges
+///
+/// Only `keep_trailing_exchange` is on by default, so `..Default::default()`
+/// sets just the ones wanted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetentionOptions {
+    /// Never remove the earliest user message (typically the task prompt)
+    pub preserve_first_user: bool,
+    /// Remove or keep whole exchanges (a user message plus the assistant and
+    /// tool replies after it), so no reply is left without its question
+    pub exchange_aware: bool,
+    /// Keep the last user message and everything after it intact, so a
+    /// reload never resumes mid-exchange
+    ///
+    /// The exchange is kept even if it alone is over the strategy's budget,
+    /// leaving the session over the target. An agent loop driven by a single
+    /// prompt grows one exchange without bound, so turn this off there.
+    pub keep_trailing_exchange: bool,
+}
+
+impl Default for RetentionOptions {
+    fn default() -> Self {
+        Self {
+            preserve_first_user: false,
+            exchange_aware: false,
+            keep_trailing_exchange: true,
+        }
+    }
+}
 
 /// Strategies for compacting conversation context when approaching token limits
 #[derive(Debug, Clone)]
 pub enum CompactionStrategy {
     /// Remove oldest messages beyond token limit
-    Sliding { max_tokens: usize },
+    ///
+    /// With `preserve_first_user`, the earliest user message is never
+    /// removed. With `exchange_aware`, whole exchanges are removed at once.
+    /// With `keep_trailing_exchange`, nothing from the last user message
+    /// onward is removed, even if that leaves more than `max_tokens`.
+    Sliding {
+        max_tokens: usize,
+        options: RetentionOptions,
+    },
     
     /// Keep system messages + recent conversation
+    ///
+    /// With `preserve_first_user`, the earliest user message is kept as well.
+    /// Its tokens come out of `recent_tokens`, but it is kept even if it alone
+    /// exceeds that budget. With `exchange_aware`, recent messages are kept as
+    /// whole exchanges, and the pinned user message keeps its replies. With
+    /// `keep_trailing_exchange`, the last user message and everything after
+    /// it are kept like the pinned message.
     SystemAndRecent { 
         system_tokens: usize, 
-        recent_tokens: usize 
+        recent_tokens: usize,
+        options: RetentionOptions,
     },
     
     /// Smart compaction preserving important messages
     Intelligent { target_tokens: usize },
+
+    /// Keep system messages and the last `count` exchanges
+    ///
+    /// An exchange is a user message plus the assistant and tool replies after
+    /// it. Everything else older than the oldest kept exchange is removed.
+    /// Tokens are not bounded: the target is ignored, and a single huge
+    /// exchange is kept whole even if it alone exceeds the context window.
+    RecentExchanges { count: usize },
+
+    /// Shrink only messages of `role`, leaving every other message untouched
+    ///
+    /// Oldest first, messages of `role` estimated above
+    /// `max_tokens_per_message` are cut down to that size and end with
+    /// `[truncated]`, until the session fits the target. A limit too small
+    /// for the marker cuts without it, and one the role's token model
+    /// overhead alone uses up (0, without overhead) drops them instead.
+    /// Suited to verbose tool output dominating the budget; the session may
+    /// stay over the target once every such message is shrunk.
+    CompressRole {
+        role: MessageRole,
+        max_tokens_per_message: usize,
+    },
+
+    /// Guarantee each listed role a minimum share of the target
+    ///
+    /// For each `(role, floor)`, the newest messages of that role that fit in
+    /// `floor` tokens are kept first. Whatever the target leaves after them,
+    /// including floor a role didn't use, goes to the most recent remaining
+    /// messages of any role. Compaction fails with
+    /// `ContextError::CompactionFailed` if the floors add up to more than the
+    /// target.
+    RoleFloors { floors: Vec<(MessageRole, usize)> },
+
+    /// Keep the recent tail plus an evenly spread sample of what's older
+    ///
+    /// The newest messages that fit in `recent_tokens` are kept whole. The
+    /// older messages are split into `sample_count` equal stretches and the
+    /// middle message of each is kept, if it fits in what the target leaves,
+    /// so the model keeps a trace of the whole conversation. The sample only
+    /// depends on the messages and their token counts, so the same session
+    /// always compacts the same way. System messages get no special treatment.
+    ReservoirSample { recent_tokens: usize, sample_count: usize },
 }
 
 impl Default for CompactionStrategy {
@@ -24,25 +117,136 @@ impl Default for CompactionStrategy {
         Self::SystemAndRecent {
             system_tokens: 1000,
             recent_tokens: 6000,
+            options: RetentionOptions::default(),
+        }
+    }
+}
+
+impl CompactionStrategy {
+    /// Check that the strategy's token budgets fit within the compaction
+    /// target, [`Config::compaction_target`](crate::Config::compaction_target)
+    /// for the managers
+    ///
+    /// Budgets over the target usually mean the configuration doesn't say
+    /// what was meant: a `recent_tokens` that can never be reached, say.
+    /// Fails with `ContextError::Config` naming the budgets. Count-based and
+    /// per-message strategies always pass.
+    pub fn validate(&self, target_tokens: usize) -> Result<()> {
+        let (budget, description) = match self {
+            Self::Sliding { max_tokens: budget, .. } => (*budget, format!("Sliding max_tokens {}", budget)),
+            Self::SystemAndRecent { system_tokens, recent_tokens, .. } => (
+                system_tokens.saturating_add(*recent_tokens),
+                format!("SystemAndRecent system_tokens {} + recent_tokens {}", system_tokens, recent_tokens),
+            ),
+            Self::Intelligent { target_tokens } => (*target_tokens, format!("Intelligent target_tokens {}", target_tokens)),
+            Self::ReservoirSample { recent_tokens, .. } => (*recent_tokens, format!("ReservoirSample recent_tokens {}", recent_tokens)),
+            Self::RoleFloors { floors } => {
+                let total = floor_total(floors);
+                (total, format!("RoleFloors floors totalling {}", total))
+            }
+            Self::RecentExchanges { .. } | Self::CompressRole { .. } => return Ok(()),
+        };
+
+        if budget > target_tokens {
+            return Err(ContextError::Config(format!(
+                "{} exceeds the compaction target of {} tokens",
+                description, target_tokens
+            )));
         }
+        Ok(())
     }
 }
 
+/// A contiguous run of messages compaction must leave exactly as it is
+///
+/// Both ends are inclusive and may be given in either order. See
+/// [`Session::compact_pinned`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PinnedRange {
+    pub start_id: Uuid,
+    pub end_id: Uuid,
+}
+
+/// What compaction would do to a session, from [`Session::compact_preview`]
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CompactionPreview {
+    /// Ids of messages that would stay, in order
+    pub kept: Vec<Uuid>,
+    /// Ids of messages that would be removed, in order
+    pub removed: Vec<Uuid>,
+    /// Kept messages whose content would be shortened, e.g. by
+    /// [`CompactionStrategy::CompressRole`]
+    pub truncated: Vec<Uuid>,
+    /// Estimated total tokens after compaction
+    pub projected_tokens: usize,
+}
+
 /// Trait for implementing custom compaction strategies
 pub trait ContextCompactor: Send + Sync {
     /// Compact a session to fit within the target token count
     fn compact(&self, session: &mut Session, target_tokens: usize) -> Result<()>;
+
+    /// Compact like [`ContextCompactor::compact`], returning the removed messages
+    /// in their original order
+    fn compact_returning(&self, session: &mut Session, target_tokens: usize) -> Result<Vec<Message>> {
+        let before = session.messages().to_vec();
+        self.compact(session, target_tokens)?;
+        Ok(removed_messages(before, session.messages()))
+    }
     
     /// Estimate the priority of a message (higher = more important to keep)
+    ///
+    /// Implementations should honor [`Message::importance`] when it is set.
     fn message_priority(&self, message: &Message, context: &Session) -> f64;
 }
 
+/// Shape of the recency score in [`IntelligentCompactor::message_priority`]
+///
+/// Scores are in `0.0..=1.0`. Age is measured as the fraction of the
+/// conversation that came after a message, so the newest message has age 0
+/// and the oldest has an age just under 1.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RecencyCurve {
+    /// Score rises evenly with position (`position / total`)
+    #[default]
+    Linear,
+    /// Score halves every `half_life` of age, so old messages fall off sharply
+    /// while recent ones stay close to 1.0
+    Exponential { half_life: f64 },
+    /// Messages younger than `cliff` score 1.0, everything older scores 0.0
+    Step { cliff: f64 },
+}
+
+impl RecencyCurve {
+    /// Recency score for the message at `position` out of `total`
+    pub fn score(&self, position: usize, total: usize) -> f64 {
+        if total == 0 {
+            return 0.0;
+        }
+        let total = total as f64;
+        let position = position as f64;
+        let age = (total - 1.0 - position).max(0.0) / total;
+
+        match *self {
+            RecencyCurve::Linear => position / total,
+            RecencyCurve::Exponential { half_life } if half_life > 0.0 => 0.5f64.powf(age / half_life),
+            RecencyCurve::Exponential { .. } => if age == 0.0 { 1.0 } else { 0.0 },
+            RecencyCurve::Step { cliff } => if age < cliff { 1.0 } else { 0.0 },
+        }
+    }
+}
+
 /// Smart compactor that preserves high-priority messages
+///
+/// Like built-in compaction, it keeps messages referenced by kept messages;
+/// see [`Session::compact`].
 pub struct IntelligentCompactor {
     /// Minimum number of recent messages to always keep
     pub min_recent_messages: usize,
     /// Weight for recency in priority calculation
     pub recency_weight: f64,
+    /// How recency is scored before `recency_weight` is applied
+    pub recency_curve: RecencyCurve,
     /// Weight for role in priority calculation
     pub role_weight: f64,
     /// Weight for length/content in priority calculation
@@ -54,6 +258,7 @@ impl Default for IntelligentCompactor {
         Self {
             min_recent_messages: 5,
             recency_weight: 1.0,
+            recency_curve: RecencyCurve::default(),
             role_weight: 0.5,
             content_weight: 0.3,
         }
@@ -65,16 +270,17 @@ impl ContextCompactor for IntelligentCompactor {
         if session.total_tokens() <= target_tokens {
             return Ok(());
         }
+        let before = reference_snapshot(session.messages());
 
         // Always keep the most recent messages
-        let keep_recent = std::cmp::min(self.min_recent_messages, session.messages.len());
+        let keep_recent = std::cmp::min(self.min_recent_messages, session.messages().len());
         let mut kept_messages = Vec::new();
         
         // Calculate priorities for all messages except the most recent ones
         let mut message_priorities: Vec<(usize, f64)> = Vec::new();
-        let messages_to_consider = session.messages.len().saturating_sub(keep_recent);
+        let messages_to_consider = session.messages().len().saturating_sub(keep_recent);
         
-        for (i, message) in session.messages.iter().take(messages_to_consider).enumerate() {
+        for (i, message) in session.messages().iter().take(messages_to_consider).enumerate() {
             let priority = self.message_priority(message, session);
             message_priorities.push((i, priority));
         }
@@ -86,14 +292,14 @@ impl ContextCompactor for IntelligentCompactor {
         let mut token_count = 0;
         
         // First, add the recent messages (always kept)
-        for message in session.messages.iter().skip(messages_to_consider) {
+        for message in session.messages().iter().skip(messages_to_consider) {
             token_count += message.estimate_tokens();
         }
         
         // Then add high-priority older messages
         let mut indices_to_keep = Vec::new();
         for (original_index, _priority) in message_priorities {
-            let message = &session.messages[original_index];
+            let message = &session.messages()[original_index];
             let message_tokens = message.estimate_tokens();
             
             if token_count + message_tokens <= target_tokens {
@@ -107,15 +313,19 @@ impl ContextCompactor for IntelligentCompactor {
         
         // Build the final message list
         for &index in &indices_to_keep {
-            kept_messages.push(session.messages[index].clone());
+            kept_messages.push(session.messages()[index].clone());
         }
         
         // Add the recent messages at the end
-        for message in session.messages.iter().skip(messages_to_consider) {
+        for message in session.messages().iter().skip(messages_to_consider) {
             kept_messages.push(message.clone());
         }
         
-        session.messages = kept_messages;
+        *session.messages_mut() = kept_messages;
+        if let Some(before) = before {
+            restore_references(session.messages_mut(), &before);
+        }
+        session.recount_tokens();
         Ok(())
     }
     
@@ -123,11 +333,10 @@ impl ContextCompactor for IntelligentCompactor {
         let mut priority = 0.0;
         
         // Recency: more recent messages have higher priority
-        let total_messages = session.messages.len() as f64;
-        let message_position = session.messages.iter()
+        let message_position = session.messages().iter()
             .position(|m| m.id == message.id)
-            .unwrap_or(0) as f64;
-        let recency_score = message_position / total_messages;
+            .unwrap_or(0);
+        let recency_score = self.recency_curve.score(message_position, session.messages().len());
         priority += recency_score * self.recency_weight;
         
         // Role: system messages are important, tool results are valuable
@@ -135,7 +344,7 @@ impl ContextCompactor for IntelligentCompactor {
             crate::session::MessageRole::System => 1.0,
             crate::session::MessageRole::Tool => 0.8,
             crate::session::MessageRole::Assistant => 0.6,
-            crate::session::MessageRole::User => 0.4,
+            crate::session::MessageRole::User | crate::session::MessageRole::Unknown(_) => 0.4,
         };
         priority += role_score * self.role_weight;
         
@@ -143,11 +352,510 @@ impl ContextCompactor for IntelligentCompactor {
         let content_length = message.content.len() as f64;
         let content_score = (content_length / 1000.0).min(1.0); // Cap at 1.0
         priority += content_score * self.content_weight;
+
+        // External importance scales everything above; 0.0 makes a message the first to go
+        if let Some(importance) = message.importance {
+            priority *= importance.max(0.0);
+        }
         
         priority
     }
 }
 
+/// Compact a bare message list the way [`Session::compact`] would
+///
+/// For messages not (yet) in a session, e.g. assembled from several sources.
+/// Returns the removed messages in their original order; messages shortened
+/// by [`CompactionStrategy::CompressRole`] stay in `messages`.
+pub fn compact_messages(messages: &mut Vec<Message>, strategy: &CompactionStrategy, target_tokens: usize) -> Result<Vec<Message>> {
+    compact_messages_with(messages, strategy, target_tokens, None)
+}
+
+/// Like [`compact_messages`], measuring budgets with `model` when given
+pub fn compact_messages_with(
+    messages: &mut Vec<Message>,
+    strategy: &CompactionStrategy,
+    target_tokens: usize,
+    model: Option<&TokenModel>,
+) -> Result<Vec<Message>> {
+    let before = messages.clone();
+    apply(messages, strategy, target_tokens, model)?;
+    Ok(removed_messages(before, messages))
+}
+
+/// Run `strategy` over `messages`; see [`Session::compact`] for the rules
+pub(crate) fn apply(
+    messages: &mut Vec<Message>,
+    strategy: &CompactionStrategy,
+    target_tokens: usize,
+    model: Option<&TokenModel>,
+) -> Result<()> {
+    let count_based = matches!(strategy, CompactionStrategy::RecentExchanges { .. });
+    if !count_based && total_tokens(messages, model) <= target_tokens {
+        return Ok(());
+    }
+
+    let before = reference_snapshot(messages);
+    match strategy {
+        CompactionStrategy::Sliding { max_tokens, options } => {
+            compact_sliding(messages, (*max_tokens).min(target_tokens), options, model);
+        }
+        CompactionStrategy::SystemAndRecent {
+            system_tokens,
+            recent_tokens,
+            options,
+        } => {
+            let (system_tokens, recent_tokens) = clamp_split(*system_tokens, *recent_tokens, target_tokens);
+            compact_system_and_recent(
+                messages,
+                system_tokens,
+                recent_tokens,
+                options,
+                model,
+            );
+        }
+        CompactionStrategy::Intelligent { target_tokens: strategy_target } => {
+            compact_intelligent(messages, (*strategy_target).min(target_tokens), model);
+        }
+        CompactionStrategy::RecentExchanges { count } => {
+            compact_recent_exchanges(messages, *count);
+        }
+        CompactionStrategy::CompressRole { role, max_tokens_per_message } => {
+            compress_role(messages, role, *max_tokens_per_message, target_tokens, model);
+        }
+        CompactionStrategy::RoleFloors { floors } => {
+            let guaranteed = floor_total(floors);
+            if guaranteed > target_tokens {
+                return Err(ContextError::CompactionFailed(format!(
+                    "role floors total {} tokens, over the target of {}",
+                    guaranteed, target_tokens
+                )));
+            }
+            compact_role_floors(messages, floors, target_tokens, model);
+        }
+        CompactionStrategy::ReservoirSample { recent_tokens, sample_count } => {
+            compact_reservoir_sample(messages, (*recent_tokens).min(target_tokens), *sample_count, target_tokens, model);
+        }
+    }
+    if let Some(before) = before {
+        restore_references(messages, &before);
+    }
+    Ok(())
+}
+
+/// Like [`apply`], never removing or altering messages in `pinned`
+///
+/// The range is taken out, the rest of the conversation is compacted to what
+/// the target leaves after the range's own tokens, then the range is put back
+/// in its original position and form.
+pub(crate) fn apply_pinned(
+    messages: &mut Vec<Message>,
+    strategy: &CompactionStrategy,
+    target_tokens: usize,
+    pinned: &PinnedRange,
+    model: Option<&TokenModel>,
+) -> Result<()> {
+    let find = |id: &Uuid| {
+        messages.iter().position(|m| m.id == *id).ok_or_else(|| {
+            ContextError::CompactionFailed(format!("pinned range message {} is not in the session", id))
+        })
+    };
+    let (first, last) = (find(&pinned.start_id)?, find(&pinned.end_id)?);
+    let range = first.min(last)..=first.max(last);
+
+    let count_based = matches!(strategy, CompactionStrategy::RecentExchanges { .. });
+    if !count_based && total_tokens(messages, model) <= target_tokens {
+        return Ok(());
+    }
+    let pinned_tokens = total_tokens(&messages[range.clone()], model);
+    if pinned_tokens > target_tokens {
+        return Err(ContextError::CompactionFailed(format!(
+            "pinned range alone is {} tokens, over the target of {}",
+            pinned_tokens, target_tokens
+        )));
+    }
+
+    let mut rest = messages.clone();
+    rest.drain(range.clone());
+    apply(&mut rest, strategy, target_tokens - pinned_tokens, model)?;
+
+    let original_index: HashMap<Uuid, usize> = messages.iter().enumerate().map(|(i, m)| (m.id, i)).collect();
+    reinsert(&mut rest, messages, &original_index, range);
+    // `apply` only saw the references of unpinned messages
+    if reference_snapshot(messages).is_some() {
+        restore_references(&mut rest, messages);
+    }
+    *messages = rest;
+    Ok(())
+}
+
+fn total_tokens(messages: &[Message], model: Option<&TokenModel>) -> usize {
+    messages.iter().map(|m| m.estimate_tokens_opt(model)).sum()
+}
+
+/// The messages as they are now, if any carry references compaction must honor
+fn reference_snapshot(messages: &[Message]) -> Option<Vec<Message>> {
+    messages.iter()
+        .any(|m| m.metadata.contains_key("references"))
+        .then(|| messages.to_vec())
+}
+
+/// Put back messages from `before` that kept messages reference, transitively
+///
+/// Restored messages go back at their original position relative to the
+/// kept ones; kept messages are left as compaction made them.
+fn restore_references(messages: &mut Vec<Message>, before: &[Message]) {
+    let original_index: HashMap<Uuid, usize> = before.iter().enumerate().map(|(i, m)| (m.id, i)).collect();
+    let kept: HashSet<Uuid> = messages.iter().map(|m| m.id).collect();
+
+    let mut restored = BTreeSet::new();
+    let mut pending: Vec<Uuid> = messages.iter().flat_map(Message::references).collect();
+    while let Some(id) = pending.pop() {
+        let Some(&index) = original_index.get(&id) else {
+            continue;
+        };
+        if !kept.contains(&id) && restored.insert(index) {
+            pending.extend(before[index].references());
+        }
+    }
+
+    reinsert(messages, before, &original_index, restored);
+}
+
+/// Insert `before[index]` for each of `indices` (ascending) back where it was
+/// relative to the messages still present
+fn reinsert(
+    messages: &mut Vec<Message>,
+    before: &[Message],
+    original_index: &HashMap<Uuid, usize>,
+    indices: impl IntoIterator<Item = usize>,
+) {
+    for index in indices {
+        let position = messages.iter()
+            .position(|m| original_index.get(&m.id).is_some_and(|&i| i > index))
+            .unwrap_or(messages.len());
+        messages.insert(position, before[index].clone());
+    }
+}
+
+/// Id of the earliest user message, if it should be pinned through compaction
+fn pinned_first_user(messages: &[Message], preserve_first_user: bool) -> Option<Uuid> {
+    if !preserve_first_user {
+        return None;
+    }
+    messages.iter().find(|m| m.role == MessageRole::User).map(|m| m.id)
+}
+
+/// Message indices grouped into the units compaction keeps or drops whole
+///
+/// Without `exchange_aware` every message is its own unit. With it, each
+/// user message starts an exchange that also owns the assistant and tool
+/// messages after it; messages before the first user message form one
+/// leading unit, and each system message stands alone. Units are ordered
+/// by their first message.
+fn compaction_units(messages: &[Message], exchange_aware: bool) -> Vec<Vec<usize>> {
+    if !exchange_aware {
+        return (0..messages.len()).map(|i| vec![i]).collect();
+    }
+
+    let mut units: Vec<Vec<usize>> = Vec::new();
+    let mut current_exchange = None;
+    for (index, message) in messages.iter().enumerate() {
+        match (&message.role, current_exchange) {
+            (MessageRole::System, _) => units.push(vec![index]),
+            (MessageRole::User, _) | (_, None) => {
+                current_exchange = Some(units.len());
+                units.push(vec![index]);
+            }
+            (_, Some(exchange)) => units[exchange].push(index),
+        }
+    }
+    units
+}
+
+/// Compaction units, and the indices `keep_trailing_exchange` protects
+///
+/// The trailing exchange runs from the last user message to the end and is
+/// protected whole, whatever it costs. Nothing is protected when the option
+/// is off or there is no user message.
+fn retention_units(messages: &[Message], options: &RetentionOptions) -> (Vec<Vec<usize>>, Vec<usize>) {
+    match messages.iter().rposition(|m| m.role == MessageRole::User) {
+        Some(start) if options.keep_trailing_exchange => {
+            (compaction_units(&messages[..start], options.exchange_aware), (start..messages.len()).collect())
+        }
+        _ => (compaction_units(messages, options.exchange_aware), Vec::new()),
+    }
+}
+
+fn unit_tokens(messages: &[Message], unit: &[usize], model: Option<&TokenModel>) -> usize {
+    unit.iter().map(|&i| messages[i].estimate_tokens_opt(model)).sum()
+}
+
+/// Keep the messages whose index isn't marked in `removed`
+fn retain_unmarked(messages: &mut Vec<Message>, removed: &[bool]) {
+    let mut index = 0;
+    messages.retain(|_| {
+        index += 1;
+        !removed[index - 1]
+    });
+}
+
+fn compact_sliding(
+    messages: &mut Vec<Message>,
+    max_tokens: usize,
+    options: &RetentionOptions,
+    model: Option<&TokenModel>,
+) {
+    let pinned = pinned_first_user(messages, options.preserve_first_user);
+    let (units, _) = retention_units(messages, options);
+    let mut total = total_tokens(messages, model);
+    let mut removed = vec![false; messages.len()];
+
+    for unit in units {
+        if total <= max_tokens {
+            break;
+        }
+        if unit.iter().any(|&i| Some(messages[i].id) == pinned) {
+            continue;
+        }
+        total -= unit_tokens(messages, &unit, model);
+        for i in unit {
+            removed[i] = true;
+        }
+    }
+
+    retain_unmarked(messages, &removed);
+}
+
+fn compact_recent_exchanges(messages: &mut Vec<Message>, count: usize) {
+    let exchange_starts: Vec<usize> = compaction_units(messages, true)
+        .into_iter()
+        .map(|unit| unit[0])
+        .filter(|&i| messages[i].role == MessageRole::User)
+        .collect();
+    let cutoff = match exchange_starts.len().checked_sub(count) {
+        _ if count == 0 => messages.len(),
+        Some(skip) if skip > 0 => exchange_starts[skip],
+        _ => return,
+    };
+
+    let mut index = 0;
+    messages.retain(|message| {
+        index += 1;
+        index > cutoff || message.role == MessageRole::System
+    });
+}
+
+fn compress_role(
+    messages: &mut Vec<Message>,
+    role: &MessageRole,
+    max_tokens_per_message: usize,
+    target_tokens: usize,
+    model: Option<&TokenModel>,
+) {
+    const MARKER: &str = "\n[truncated]";
+    let mut total = total_tokens(messages, model);
+    let mut dropped = vec![false; messages.len()];
+    // The role overhead is charged whatever is kept, so only the rest is content
+    let content_tokens = max_tokens_per_message.saturating_sub(model.map_or(0, |model| model.overhead_for(role)));
+
+    for (index, message) in messages.iter_mut().enumerate() {
+        if total <= target_tokens {
+            break;
+        }
+        let tokens = message.estimate_tokens_opt(model);
+        if &message.role != role || tokens <= max_tokens_per_message {
+            continue;
+        }
+
+        if content_tokens == 0 {
+            dropped[index] = true;
+            total -= tokens;
+            continue;
+        }
+
+        // An exact count says how densely this content really tokenizes
+        let original_len = message.content.len().max(1);
+        let budget_bytes = match message.token_count {
+            Some(count) if count > 0 => content_tokens * original_len / count,
+            _ => content_tokens * model.map_or(4, |model| model.divisor_for(&message.content)),
+        };
+        // Too small a budget for the marker: cut the content without it
+        let marker = if budget_bytes >= MARKER.len() { MARKER } else { "" };
+        let mut cut = (budget_bytes - marker.len()).min(message.content.len());
+        while !message.content.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        message.content.truncate(cut);
+        message.content.push_str(marker);
+        // Scale an exact count to the kept content rather than fall back to
+        // an estimate at a different density
+        message.token_count = message.token_count
+            .map(|count| (count * message.content.len()).div_ceil(original_len));
+        total = total - tokens + message.estimate_tokens_opt(model);
+    }
+
+    retain_unmarked(messages, &dropped);
+}
+
+/// Shrink a system/recent split that exceeds `target_tokens` to fit it,
+/// keeping the two budgets in proportion
+fn clamp_split(system_tokens: usize, recent_tokens: usize, target_tokens: usize) -> (usize, usize) {
+    let budget = system_tokens as u128 + recent_tokens as u128;
+    if budget <= target_tokens as u128 {
+        return (system_tokens, recent_tokens);
+    }
+    let system_tokens = (system_tokens as u128 * target_tokens as u128 / budget) as usize;
+    (system_tokens, target_tokens - system_tokens)
+}
+
+fn compact_system_and_recent(
+    messages: &mut Vec<Message>,
+    system_tokens: usize,
+    recent_tokens: usize,
+    options: &RetentionOptions,
+    model: Option<&TokenModel>,
+) {
+    let pinned = pinned_first_user(messages, options.preserve_first_user);
+    let (units, trailing_unit) = retention_units(messages, options);
+    let mut units: Vec<Vec<usize>> = units.into_iter()
+        .filter(|unit| messages[unit[0]].role != MessageRole::System)
+        .collect();
+    let pinned_unit = units.iter()
+        .position(|unit| unit.iter().any(|&i| Some(messages[i].id) == pinned))
+        .map(|position| units.remove(position))
+        .unwrap_or_default();
+    let recent_tokens = recent_tokens
+        .saturating_sub(unit_tokens(messages, &pinned_unit, model))
+        .saturating_sub(unit_tokens(messages, &trailing_unit, model));
+
+    // Keep system messages that fit in system_tokens budget
+    let mut system_messages = Vec::new();
+    let mut system_token_count = 0;
+
+    for (index, message) in messages.iter().enumerate() {
+        if message.role == MessageRole::System && !trailing_unit.contains(&index) {
+            let tokens = message.estimate_tokens_opt(model);
+            if system_token_count + tokens <= system_tokens {
+                system_messages.push(message.clone());
+                system_token_count += tokens;
+            }
+        }
+    }
+
+    // Keep recent units that fit in recent_tokens budget
+    let mut recent_units = Vec::new();
+    let mut recent_token_count = 0;
+
+    for unit in units.iter().rev() {
+        let tokens = unit_tokens(messages, unit, model);
+        if recent_token_count + tokens <= recent_tokens {
+            recent_units.insert(0, unit);
+            recent_token_count += tokens;
+        } else {
+            break;
+        }
+    }
+
+    // Combine system, pinned, recent, and trailing messages, in session order
+    let mut kept: Vec<usize> = pinned_unit.iter()
+        .chain(recent_units.into_iter().flatten())
+        .chain(&trailing_unit)
+        .copied()
+        .collect();
+    kept.sort_unstable();
+    let kept: Vec<Message> = kept.into_iter().map(|i| messages[i].clone()).collect();
+    *messages = system_messages;
+    messages.extend(kept);
+}
+
+fn floor_total(floors: &[(MessageRole, usize)]) -> usize {
+    floors.iter().fold(0, |total, (_, floor)| total.saturating_add(*floor))
+}
+
+fn compact_role_floors(
+    messages: &mut Vec<Message>,
+    floors: &[(MessageRole, usize)],
+    target_tokens: usize,
+    model: Option<&TokenModel>,
+) {
+    let mut kept = vec![false; messages.len()];
+    let mut used = 0;
+
+    for (role, floor) in floors {
+        let mut role_tokens = 0;
+        for (index, message) in messages.iter().enumerate().rev() {
+            if &message.role != role || kept[index] {
+                continue;
+            }
+            let tokens = message.estimate_tokens_opt(model);
+            if role_tokens + tokens <= *floor {
+                kept[index] = true;
+                role_tokens += tokens;
+            }
+        }
+        used += role_tokens;
+    }
+
+    // The rest of the budget goes to recent messages, newest first
+    for (index, message) in messages.iter().enumerate().rev() {
+        if kept[index] {
+            continue;
+        }
+        let tokens = message.estimate_tokens_opt(model);
+        if used + tokens > target_tokens {
+            break;
+        }
+        kept[index] = true;
+        used += tokens;
+    }
+
+    let removed: Vec<bool> = kept.iter().map(|kept| !kept).collect();
+    retain_unmarked(messages, &removed);
+}
+
+fn compact_reservoir_sample(
+    messages: &mut Vec<Message>,
+    recent_tokens: usize,
+    sample_count: usize,
+    target_tokens: usize,
+    model: Option<&TokenModel>,
+) {
+    let mut used = 0;
+    let mut tail_start = messages.len();
+    while tail_start > 0 {
+        let tokens = messages[tail_start - 1].estimate_tokens_opt(model);
+        if used + tokens > recent_tokens {
+            break;
+        }
+        used += tokens;
+        tail_start -= 1;
+    }
+
+    // The middle of each of `sample_count` equal stretches of the older messages
+    let mut removed = vec![true; tail_start];
+    let samples = sample_count.min(tail_start);
+    for stretch in 0..samples {
+        let index = (2 * stretch + 1) * tail_start / (2 * samples);
+        let tokens = messages[index].estimate_tokens_opt(model);
+        if used + tokens <= target_tokens {
+            removed[index] = false;
+            used += tokens;
+        }
+    }
+
+    removed.resize(messages.len(), false);
+    retain_unmarked(messages, &removed);
+}
+
+fn compact_intelligent(messages: &mut Vec<Message>, target_tokens: usize, model: Option<&TokenModel>) {
+    // For now, use system_and_recent strategy
+    // TODO: Implement more sophisticated compaction
+    let system_tokens = target_tokens / 4;
+    let recent_tokens = (target_tokens * 3) / 4;
+    compact_system_and_recent(messages, system_tokens, recent_tokens, &RetentionOptions::default(), model)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -172,7 +880,327 @@ mod tests {
         compactor.compact(&mut session, target_tokens).unwrap();
         
         // Should keep some messages, prioritizing recent and important ones
-        assert!(!session.messages.is_empty());
+        assert!(!session.messages().is_empty());
         assert!(session.total_tokens() <= target_tokens);
     }
-}
\ No newline at end of file
+
+    fn numbered_session() -> Session {
+        let mut session = Session::with_name("test".to_string());
+        for i in 0..10 {
+            session.add_message(Message::user(format!("Message number {} {}", i, "x".repeat(20))));
+        }
+        session
+    }
+
+    #[test]
+    fn test_importance_overrides_computed_priority() {
+        let mut session = numbered_session();
+        let flagged = session.messages()[1].id;
+        session.messages_mut()[1].importance = Some(10.0);
+        session.messages_mut()[7].importance = Some(0.0);
+        let tokens_per_message = session.messages()[0].estimate_tokens();
+
+        // Room for the two recent messages plus one more
+        let compactor = IntelligentCompactor { min_recent_messages: 2, ..Default::default() };
+        compactor.compact(&mut session, tokens_per_message * 3).unwrap();
+        let kept: Vec<Uuid> = session.messages().iter().map(|m| m.id).collect();
+        assert_eq!(kept.len(), 3);
+        assert_eq!(kept[0], flagged);
+
+        // The score survives a round trip; unscored messages don't gain the field
+        let json = serde_json::to_value(session.messages()).unwrap();
+        assert_eq!(json[0]["importance"], 10.0);
+        assert!(json[1].get("importance").is_none());
+        let restored: Vec<Message> = serde_json::from_value(json).unwrap();
+        assert_eq!(restored[0].importance, Some(10.0));
+    }
+
+    #[test]
+    fn test_compact_returning_reports_removed() {
+        let mut session = numbered_session();
+        let original_len = session.messages().len();
+
+        let compactor = IntelligentCompactor::default();
+        let removed = compactor.compact_returning(&mut session, 40).unwrap();
+        assert!(!removed.is_empty());
+        assert_eq!(removed.len() + session.messages().len(), original_len);
+        assert!(removed.iter().all(|m| !session.messages().iter().any(|kept| kept.id == m.id)));
+
+        let mut sliding = numbered_session();
+        let strategy = CompactionStrategy::Sliding { max_tokens: 40, options: RetentionOptions::default() };
+        let removed = sliding.compact_returning(&strategy, 40).unwrap();
+        assert!(removed[0].content.starts_with("Message number 0 "));
+        assert_eq!(removed.len() + sliding.messages().len(), original_len);
+        assert!(sliding.compact_returning(&strategy, 40).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_recency_curves() {
+        let linear = RecencyCurve::Linear;
+        assert_eq!(linear.score(0, 10), 0.0);
+        assert_eq!(linear.score(5, 10), 0.5);
+
+        let exponential = RecencyCurve::Exponential { half_life: 0.2 };
+        assert_eq!(exponential.score(9, 10), 1.0);
+        assert!(exponential.score(8, 10) > 0.7);
+        assert!(exponential.score(0, 10) < 0.05);
+
+        let step = RecencyCurve::Step { cliff: 0.3 };
+        assert_eq!(step.score(9, 10), 1.0);
+        assert_eq!(step.score(7, 10), 1.0);
+        assert_eq!(step.score(6, 10), 0.0);
+    }
+
+    #[test]
+    fn test_compact_messages_matches_session_compaction() {
+        let mut messages = vec![Message::system("You are helpful".to_string())];
+        for i in 0..6 {
+            messages.push(Message::user(format!("Question {} {}", i, "x".repeat(30))));
+            messages.push(Message::assistant(format!("Answer {} {}", i, "y".repeat(30))));
+        }
+        let strategy = CompactionStrategy::default();
+        let budget = CompactionStrategy::SystemAndRecent {
+            system_tokens: 10,
+            recent_tokens: 40,
+            options: RetentionOptions { preserve_first_user: true, exchange_aware: true, keep_trailing_exchange: true },
+        };
+
+        let mut session = Session::new();
+        for message in &messages {
+            session.add_message(message.clone());
+        }
+        let mut loose = messages.clone();
+
+        // Under the target nothing happens
+        assert!(compact_messages(&mut loose, &strategy, 10_000).unwrap().is_empty());
+        assert_eq!(loose.len(), messages.len());
+
+        let removed = compact_messages(&mut loose, &budget, 50).unwrap();
+        session.compact(&budget, 50).unwrap();
+        let ids = |messages: &[Message]| messages.iter().map(|m| m.id).collect::<Vec<_>>();
+        assert_eq!(ids(&loose), ids(session.messages()));
+        assert_eq!(removed.len() + loose.len(), messages.len());
+        assert_eq!(loose[1].content, messages[1].content);
+        assert_eq!(loose.last().unwrap().id, messages.last().unwrap().id);
+    }
+
+    #[test]
+    fn test_default_keeps_trailing_exchange_over_budget() {
+        let mut session = Session::new();
+        session.add_system_message("You are a coding agent".to_string());
+        session.add_user_message("Fix the failing build".to_string());
+        for i in 0..60 {
+            session.add_assistant_message(format!("Step {} {}", i, "x".repeat(400)));
+            session.add_tool_message(format!("Output {} {}", i, "y".repeat(400)));
+        }
+        let config = crate::Config::default();
+        assert!(session.total_tokens() > config.max_tokens);
+
+        // No step of the exchange is dropped, tool replies included
+        let mut kept = session.clone();
+        kept.compact(&config.compaction_strategy, config.max_tokens).unwrap();
+        assert_eq!(kept.messages().len(), session.messages().len());
+        let sliding = CompactionStrategy::Sliding { max_tokens: 500, options: RetentionOptions::default() };
+        kept.compact(&sliding, 500).unwrap();
+        assert_eq!(kept.messages().len(), session.messages().len() - 1);
+        assert_eq!(kept.messages().iter().filter(|m| m.role == MessageRole::Tool).count(), 60);
+
+        // An agent loop opts out to stay within budget
+        let options = RetentionOptions { keep_trailing_exchange: false, ..Default::default() };
+        let mut looped = session.clone();
+        looped.compact(&CompactionStrategy::Sliding { max_tokens: 500, options }, 500).unwrap();
+        assert!(looped.total_tokens() <= 500);
+        assert_eq!(looped.messages().last().unwrap().id, session.messages().last().unwrap().id);
+    }
+
+    #[test]
+    fn test_validate_budget_split() {
+        let split = |system_tokens, recent_tokens| CompactionStrategy::SystemAndRecent {
+            system_tokens,
+            recent_tokens,
+            options: RetentionOptions { keep_trailing_exchange: true, ..Default::default() },
+        };
+        assert!(split(1000, 3000).validate(4000).is_ok());
+        let err = split(1000, 6000).validate(4000).unwrap_err();
+        assert!(matches!(err, ContextError::Config(ref reason) if reason.contains("1000 + recent_tokens 6000")));
+        assert!(split(usize::MAX, 1).validate(usize::MAX - 1).is_err());
+        assert!(CompactionStrategy::Intelligent { target_tokens: 50 }.validate(40).is_err());
+        assert!(CompactionStrategy::RecentExchanges { count: 100 }.validate(1).is_ok());
+        assert!(CompactionStrategy::default().validate(crate::Config::default().max_tokens).is_ok());
+
+        // Misconfiguration fails when the manager is built, not at the first compaction
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let storage = crate::storage::FileStorage::with_directory(temp_dir.path()).unwrap();
+        let config = crate::Config { max_tokens: 4000, ..Default::default() };
+        let err = crate::SessionManager::with_storage(Box::new(storage), config).err().unwrap();
+        assert!(matches!(err, ContextError::Config(_)));
+
+        // The budgets are checked against the target, not max_tokens
+        let config = || crate::Config { compaction_target_ratio: 0.5, ..Default::default() };
+        let storage = crate::storage::FileStorage::with_directory(temp_dir.path()).unwrap();
+        assert!(crate::SessionManager::with_storage(Box::new(storage), config()).is_err());
+        let storage = crate::async_storage::BlockingStorage::new(crate::storage::FileStorage::with_directory(temp_dir.path()).unwrap());
+        assert!(crate::AsyncSessionManager::new(Box::new(storage), config()).is_err());
+
+        // Direct compaction shrinks the split to the target, in proportion
+        assert_eq!(clamp_split(60, 60, 100), (50, 50));
+        assert_eq!(clamp_split(usize::MAX, 1, 10), (9, 1));
+        let mut session = Session::new();
+        session.add_system_message("s".repeat(160));
+        for i in 0..10 {
+            session.add_message(Message::user(format!("Question {} {}", i, "x".repeat(40))));
+        }
+        let strategy = CompactionStrategy::SystemAndRecent { system_tokens: 60, recent_tokens: 60, options: RetentionOptions::default() };
+        session.compact(&strategy, 100).unwrap();
+        assert!(session.total_tokens() <= 100);
+        assert_eq!(session.messages()[0].role, MessageRole::System);
+
+        let mut session = Session::new();
+        for i in 0..20 {
+            session.add_message(Message::user(format!("Question {} {}", i, "x".repeat(400))));
+        }
+        session.compact(&CompactionStrategy::default(), 1000).unwrap();
+        assert!(session.total_tokens() <= 1000);
+    }
+
+    #[test]
+    fn test_compact_pinned_keeps_middle_range() {
+        let mut session = Session::new();
+        for i in 0..12 {
+            session.add_message(Message::user(format!("Message {} {}", i, "x".repeat(30))));
+        }
+        session.add_message(Message::tool("y".repeat(400)));
+        let ids: Vec<Uuid> = session.messages().iter().map(|m| m.id).collect();
+        let pinned = PinnedRange { start_id: ids[6], end_id: ids[4] };
+
+        let mut sliding = session.clone();
+        let options = RetentionOptions { keep_trailing_exchange: false, ..Default::default() };
+        let strategy = CompactionStrategy::Sliding { max_tokens: 60, options };
+        sliding.compact_pinned(&strategy, 60, &pinned).unwrap();
+        let kept: Vec<Uuid> = sliding.messages().iter().map(|m| m.id).collect();
+        assert_eq!(&kept[..3], &ids[4..=6]);
+        assert!(!kept.contains(&ids[3]));
+        assert!(sliding.total_tokens() <= 60);
+
+        // Truncation skips the range too
+        let mut compressed = session.clone();
+        let strategy = CompactionStrategy::CompressRole { role: MessageRole::User, max_tokens_per_message: 3 };
+        compressed.compact_pinned(&strategy, 60, &PinnedRange { start_id: ids[0], end_id: ids[1] }).unwrap();
+        assert_eq!(compressed.messages()[1].content, session.messages()[1].content);
+        assert!(compressed.messages()[2].content.ends_with("[truncated]"));
+
+        let too_big = PinnedRange { start_id: ids[0], end_id: ids[12] };
+        let err = session.clone().compact_pinned(&strategy, 60, &too_big).unwrap_err();
+        assert!(matches!(err, ContextError::CompactionFailed(_)));
+        let missing = PinnedRange { start_id: ids[0], end_id: Uuid::new_v4() };
+        assert!(session.compact_pinned(&strategy, 60, &missing).is_err());
+        assert_eq!(session.messages().len(), 13);
+    }
+
+    #[test]
+    fn test_compact_pinned_tail_charges_range_once() {
+        let mut session = Session::new();
+        for i in 0..10 {
+            session.add_message(Message::user(format!("Message {}", i)).with_token_count(10));
+        }
+        let ids: Vec<Uuid> = session.messages().iter().map(|m| m.id).collect();
+        let strategy = CompactionStrategy::Sliding { max_tokens: 60, options: RetentionOptions::default() };
+
+        let mut pinned = session.clone();
+        pinned.compact_pinned(&strategy, 60, &PinnedRange { start_id: ids[8], end_id: ids[9] }).unwrap();
+        let mut plain = session.clone();
+        plain.compact(&strategy, 60).unwrap();
+
+        assert_eq!(pinned.total_tokens(), 60);
+        assert_eq!(pinned.messages().iter().map(|m| m.id).collect::<Vec<_>>(), &ids[4..]);
+        assert_eq!(pinned.total_tokens(), plain.total_tokens());
+    }
+
+    #[test]
+    fn test_compact_pinned_keeps_what_the_range_references() {
+        let mut session = Session::new();
+        for i in 0..10 {
+            session.add_message(Message::user(format!("Message {}", i)).with_token_count(10));
+        }
+        let ids: Vec<Uuid> = session.messages().iter().map(|m| m.id).collect();
+        session.messages_mut()[8]
+            .metadata
+            .insert("references".to_string(), serde_json::json!([ids[1].to_string()]));
+        let strategy = CompactionStrategy::Sliding { max_tokens: 60, options: RetentionOptions::default() };
+
+        session.compact_pinned(&strategy, 60, &PinnedRange { start_id: ids[7], end_id: ids[8] }).unwrap();
+        let kept: Vec<Uuid> = session.messages().iter().map(|m| m.id).collect();
+        assert_eq!(kept[0], ids[1]);
+        assert_eq!(&kept[1..], &ids[4..]);
+    }
+
+    #[test]
+    fn test_role_floors_guarantee_each_role() {
+        let mut session = Session::new();
+        session.add_message(Message::system(format!("Rules {}", "r".repeat(80))));
+        for i in 0..10 {
+            session.add_message(Message::user(format!("Question {} {}", i, "x".repeat(30))));
+            session.add_message(Message::assistant(format!("Answer {} {}", i, "y".repeat(30))));
+        }
+        let system_tokens = session.messages()[0].estimate_tokens();
+        let turn_tokens = session.messages()[1].estimate_tokens();
+        let target = system_tokens + 4 * turn_tokens;
+
+        // SystemAndRecent drops system messages over its system budget...
+        let mut squeezed = session.clone();
+        let split = CompactionStrategy::SystemAndRecent {
+            system_tokens: system_tokens - 1,
+            recent_tokens: target - system_tokens + 1,
+            options: RetentionOptions::default(),
+        };
+        squeezed.compact(&split, target).unwrap();
+        assert!(squeezed.messages().iter().all(|m| m.role != MessageRole::System));
+
+        // ...while a floor keeps them, and unused floor goes to recent messages
+        let floors = CompactionStrategy::RoleFloors {
+            floors: vec![(MessageRole::System, system_tokens), (MessageRole::User, 2 * turn_tokens)],
+        };
+        let mut floored = session.clone();
+        floored.compact(&floors, target).unwrap();
+        let contents: Vec<&str> = floored.messages().iter().map(|m| &m.content[..8]).collect();
+        assert_eq!(contents, ["Rules rr", "Question", "Answer 8", "Question", "Answer 9"]);
+        assert!(floored.total_tokens() <= target);
+
+        let greedy = CompactionStrategy::RoleFloors { floors: vec![(MessageRole::User, target + 1)] };
+        let err = session.clone().compact(&greedy, target).unwrap_err();
+        assert!(matches!(err, ContextError::CompactionFailed(_)));
+        assert!(greedy.validate(target).is_err());
+        assert!(floors.validate(target).is_ok());
+    }
+
+    #[test]
+    fn test_reservoir_sample_spreads_over_older_messages() {
+        let mut session = Session::new();
+        for i in 0..20 {
+            session.add_message(Message::user(format!("Message {:02} {}", i, "x".repeat(30))));
+        }
+        let per_message = session.messages()[0].estimate_tokens();
+        let kept_numbers = |session: &Session| -> Vec<usize> {
+            session.messages().iter().map(|m| m.content[8..10].parse().unwrap()).collect()
+        };
+        let strategy = CompactionStrategy::ReservoirSample { recent_tokens: 4 * per_message, sample_count: 4 };
+
+        let mut sampled = session.clone();
+        sampled.compact(&strategy, 8 * per_message).unwrap();
+        assert_eq!(kept_numbers(&sampled), [2, 6, 10, 14, 16, 17, 18, 19]);
+        let mut again = session.clone();
+        again.compact(&strategy, 8 * per_message).unwrap();
+        assert_eq!(kept_numbers(&again), kept_numbers(&sampled));
+
+        // Samples that don't fit the target are skipped
+        let mut tight = session.clone();
+        tight.compact(&strategy, 5 * per_message).unwrap();
+        assert_eq!(kept_numbers(&tight), [2, 16, 17, 18, 19]);
+
+        let mut tail_only = session.clone();
+        let no_sample = CompactionStrategy::ReservoirSample { recent_tokens: 2 * per_message, sample_count: 0 };
+        tail_only.compact(&no_sample, 8 * per_message).unwrap();
+        assert_eq!(kept_numbers(&tail_only), [18, 19]);
+        assert!(strategy.validate(3 * per_message).is_err());
+    }
+}
@@ -0,0 +1,93 @@
+//! Comparing sessions by what was said, for golden-session tests
+
+use crate::session::{Message, Session};
+use std::fmt;
+
+/// The first message where two sessions' contents differ, from
+/// [`Session::content_diff`]
+#[derive(Debug, Clone, Copy)]
+pub struct ContentDiff<'a> {
+    /// Zero-based position of the differing message
+    pub index: usize,
+    /// The message in `self`, or `None` if that session ends first
+    pub left: Option<&'a Message>,
+    /// The message in `other`, or `None` if that session ends first
+    pub right: Option<&'a Message>,
+}
+
+impl fmt::Display for ContentDiff<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let side = |message: Option<&Message>| match message {
+            Some(message) => format!("{}: {:?}", message.role.as_str(), message.content),
+            None => "(no message)".to_string(),
+        };
+        write!(f, "message {} differs\n  left:  {}\n  right: {}", self.index, side(self.left), side(self.right))
+    }
+}
+
+impl Session {
+    /// Whether both sessions hold the same messages by role and content
+    ///
+    /// Ids, timestamps, token counts, metadata, and session names are ignored.
+    pub fn content_equals(&self, other: &Session) -> bool {
+        self.first_difference(other, false).is_none()
+    }
+
+    /// Like [`Session::content_equals`], also requiring equal message metadata
+    pub fn content_equals_with_metadata(&self, other: &Session) -> bool {
+        self.first_difference(other, true).is_none()
+    }
+
+    /// The first message that differs by role or content, for test failure
+    /// messages; `None` when [`Session::content_equals`] holds
+    pub fn content_diff<'a>(&'a self, other: &'a Session) -> Option<ContentDiff<'a>> {
+        self.first_difference(other, false)
+    }
+
+    fn first_difference<'a>(&'a self, other: &'a Session, compare_metadata: bool) -> Option<ContentDiff<'a>> {
+        let same = |left: &Message, right: &Message| {
+            left.role == right.role
+                && left.content == right.content
+                && (!compare_metadata || left.metadata == right.metadata)
+        };
+        (0..self.messages().len().max(other.messages().len()))
+            .map(|index| ContentDiff { index, left: self.messages().get(index), right: other.messages().get(index) })
+            .find(|diff| !matches!((diff.left, diff.right), (Some(left), Some(right)) if same(left, right)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_comparison_ignores_ids_and_times() {
+        let mut golden = Session::with_name("golden".to_string());
+        golden.add_message(Message::user("List files".to_string()));
+        golden.add_message(
+            Message::assistant("Calling ls".to_string())
+                .with_metadata("tool_calls".to_string(), serde_json::json!([{ "id": "call_1" }])),
+        );
+
+        let mut run = Session::with_name("run 2".to_string());
+        run.add_message(Message::user("List files".to_string()).with_timestamp(chrono::Utc::now() + chrono::Duration::hours(1)));
+        run.add_message(Message::assistant("Calling ls".to_string()));
+        assert!(golden.content_equals(&run));
+        assert!(golden.content_diff(&run).is_none());
+        assert!(!golden.content_equals_with_metadata(&run));
+
+        run.messages_mut()[1].content = "Calling find".to_string();
+        let diff = golden.content_diff(&run).unwrap();
+        assert_eq!((diff.index, diff.left.unwrap().id), (1, golden.messages()[1].id));
+        assert_eq!(
+            diff.to_string(),
+            "message 1 differs\n  left:  assistant: \"Calling ls\"\n  right: assistant: \"Calling find\""
+        );
+
+        run.messages_mut().truncate(1);
+        let diff = run.content_diff(&golden).unwrap();
+        assert_eq!(diff.index, 1);
+        assert!(diff.left.is_none());
+        assert!(diff.to_string().ends_with("left:  (no message)\n  right: assistant: \"Calling ls\""));
+    }
+}
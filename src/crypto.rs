@@ -0,0 +1,87 @@
+//! Authenticated encryption for session files
+//!
+//! ChaCha20-Poly1305 (RFC 8439) from the `chacha20poly1305` crate, with a
+//! fresh nonce from the OS RNG for every write. Sealed data is
+//! `MAGIC || nonce || ciphertext || tag`, and the header is bound in as
+//! associated data.
+
+use crate::error::{ContextError, Result};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+
+/// Leading bytes that mark sealed data
+pub(crate) const MAGIC: &[u8; 8] = b"GCENC\x00\x02\n";
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// A 32-byte session encryption key
+#[derive(Clone)]
+pub(crate) struct Key(ChaCha20Poly1305);
+
+impl Key {
+    pub(crate) fn new(secret: &[u8; 32]) -> Self {
+        Self(ChaCha20Poly1305::new(secret.into()))
+    }
+}
+
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+/// Whether `data` was produced by [`seal`]
+pub(crate) fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Encrypt and authenticate `plaintext` under a fresh random nonce
+pub(crate) fn seal(key: &Key, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = key.0
+        .encrypt(&nonce, Payload { msg: plaintext, aad: MAGIC })
+        .map_err(|_| ContextError::Storage("Failed to encrypt session".to_string()))?;
+
+    let mut sealed = Vec::with_capacity(MAGIC.len() + NONCE_LEN + ciphertext.len());
+    sealed.extend_from_slice(MAGIC);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+/// Verify and decrypt data produced by [`seal`]
+pub(crate) fn open(key: &Key, sealed: &[u8]) -> Result<Vec<u8>> {
+    if !is_sealed(sealed) || sealed.len() < MAGIC.len() + NONCE_LEN + TAG_LEN {
+        return Err(ContextError::InvalidSession("Truncated encrypted session".to_string()));
+    }
+
+    let (nonce, ciphertext) = sealed[MAGIC.len()..].split_at(NONCE_LEN);
+    key.0
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: MAGIC })
+        .map_err(|_| {
+            ContextError::InvalidSession(
+                "Encrypted session failed authentication (wrong key or tampered file)".to_string(),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_round_trip() {
+        let key = Key::new(&[7; 32]);
+        let sealed = seal(&key, b"secret plans").unwrap();
+        assert!(is_sealed(&sealed));
+        assert_ne!(seal(&key, b"secret plans").unwrap(), sealed);
+        assert_eq!(open(&key, &sealed).unwrap(), b"secret plans");
+
+        let mut tampered = sealed.clone();
+        tampered[MAGIC.len() + NONCE_LEN] ^= 1;
+        assert!(open(&key, &tampered).is_err());
+        assert!(open(&Key::new(&[8; 32]), &sealed).is_err());
+        assert!(open(&key, &sealed[..20]).is_err());
+    }
+}
@@ -31,4 +31,25 @@ pub enum ContextError {
 
     #[error("Configuration error: {0}")]
     Config(String),
+
+    #[error("Session too large: {0}")]
+    SessionTooLarge(String),
+
+    #[error("Metadata too large: {0}")]
+    MetadataTooLarge(String),
+
+    #[error("Save conflict: {0}")]
+    Conflict(String),
+
+    #[error("Invalid content: {0}")]
+    InvalidContent(String),
+
+    #[error("Protocol violation at message {index}: {reason}")]
+    ProtocolViolation { index: usize, reason: String },
+
+    #[error("Message {index} has role {role:?}, which this format can't represent")]
+    UnsupportedRole { index: usize, role: String },
+
+    #[error("Message {index} can't be represented in this format: {reason}")]
+    UnsupportedContent { index: usize, reason: String },
 }
\ No newline at end of file
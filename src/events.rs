@@ -0,0 +1,45 @@
+//! Session lifecycle events for logging and audit pipelines
+//!
+//! [`SessionManager::event_stream`](crate::SessionManager::event_stream)
+//! hands out a channel per subscriber, so an audit log, a dashboard, and an
+//! analytics job can all watch the same manager without sharing a callback.
+
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// Something a `SessionManager` did to a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEvent {
+    /// The session was written to storage at `version`
+    Saved { session_id: Uuid, version: u64, message_count: usize },
+    /// The session was read from storage
+    Loaded { session_id: Uuid },
+    /// Compaction removed `messages_removed` messages worth `removed_tokens`
+    /// estimated tokens
+    Compacted { session_id: Uuid, removed_tokens: usize, messages_removed: usize },
+    /// The session was deleted from storage
+    Deleted { session_id: Uuid },
+}
+
+/// Fans events out to every live subscriber
+#[derive(Debug, Default)]
+pub(crate) struct EventBus {
+    subscribers: Mutex<Vec<Sender<SessionEvent>>>,
+}
+
+impl EventBus {
+    pub(crate) fn subscribe(&self) -> Receiver<SessionEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap_or_else(|e| e.into_inner()).push(sender);
+        receiver
+    }
+
+    /// Send `event` to every subscriber, forgetting those whose receiver was dropped
+    pub(crate) fn emit(&self, event: SessionEvent) {
+        self.subscribers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|subscriber| subscriber.send(event).is_ok());
+    }
+}
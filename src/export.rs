@@ -0,0 +1,279 @@
+//! Session exports for sharing transcripts and building training data
+
+use crate::error::{ContextError, Result};
+use crate::format::{openai_role, LossyConversion, OpenAIToolRole};
+use crate::session::{MessageRole, Session};
+use std::collections::HashMap;
+use tracing::warn;
+
+/// Layout for [`Session::to_transcript`]
+#[derive(Debug, Clone)]
+pub struct TranscriptOptions {
+    /// Prefix for each role's messages, e.g. `"Human:"`. Roles without an
+    /// entry use their heading name followed by a colon.
+    pub role_labels: HashMap<MessageRole, String>,
+    /// Text placed between messages
+    pub separator: String,
+    /// Whether to prefix each message with `[YYYY-MM-DD HH:MM:SS]`
+    pub include_timestamps: bool,
+}
+
+impl Default for TranscriptOptions {
+    fn default() -> Self {
+        Self {
+            role_labels: HashMap::new(),
+            separator: "\n\n".to_string(),
+            include_timestamps: false,
+        }
+    }
+}
+
+impl TranscriptOptions {
+    /// Use `label` as the prefix for `role`
+    pub fn with_label(mut self, role: MessageRole, label: impl Into<String>) -> Self {
+        self.role_labels.insert(role, label.into());
+        self
+    }
+
+    /// Set the text placed between messages
+    pub fn with_separator(mut self, separator: impl Into<String>) -> Self {
+        self.separator = separator.into();
+        self
+    }
+
+    /// Prefix each message with its timestamp
+    pub fn with_timestamps(mut self, include: bool) -> Self {
+        self.include_timestamps = include;
+        self
+    }
+
+    fn label(&self, role: &MessageRole) -> String {
+        self.role_labels.get(role)
+            .cloned()
+            .unwrap_or_else(|| format!("{}:", role_heading(role)))
+    }
+}
+
+/// Heading text for a role
+fn role_heading(role: &MessageRole) -> &str {
+    match role {
+        MessageRole::System => "System",
+        MessageRole::User => "User",
+        MessageRole::Assistant => "Assistant",
+        MessageRole::Tool => "Tool",
+        MessageRole::Unknown(role) => role,
+    }
+}
+
+impl Session {
+    /// Render the session as Markdown for sharing transcripts
+    ///
+    /// Each message becomes a `### Role` heading with its timestamp in italics
+    /// underneath. Content is copied verbatim, so code fences survive; system
+    /// messages are set off as blockquotes.
+    pub fn to_markdown(&self) -> String {
+        let mut out = format!("# {}\n", self.name);
+
+        for message in self.messages() {
+            out.push_str(&format!(
+                "\n### {}\n*{}*\n\n",
+                role_heading(&message.role),
+                message.timestamp.format("%Y-%m-%d %H:%M:%S UTC")
+            ));
+
+            if message.role == MessageRole::System {
+                for line in message.content.lines() {
+                    if line.is_empty() {
+                        out.push_str(">\n");
+                    } else {
+                        out.push_str(&format!("> {}\n", line));
+                    }
+                }
+            } else {
+                out.push_str(&message.content);
+                if !message.content.ends_with('\n') {
+                    out.push('\n');
+                }
+            }
+        }
+
+        out
+    }
+
+    /// Render the session as a plain-text transcript
+    ///
+    /// Each message is `<label> <content>`, optionally led by its timestamp,
+    /// with `opts.separator` between messages and nothing after the last.
+    pub fn to_transcript(&self, opts: &TranscriptOptions) -> String {
+        self.messages().iter()
+            .map(|message| {
+                let label = opts.label(&message.role);
+                if opts.include_timestamps {
+                    format!("[{}] {} {}", message.timestamp.format("%Y-%m-%d %H:%M:%S"), label, message.content)
+                } else {
+                    format!("{} {}", label, message.content)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(&opts.separator)
+    }
+
+    /// Render the session as one line of OpenAI fine-tuning JSONL
+    ///
+    /// The line is `{"messages": [{"role": ..., "content": ...}, ...]}` with the
+    /// same role names as [`OpenAIFormat`](crate::format::OpenAIFormat), ending
+    /// in a newline so exports of several sessions can be concatenated into one
+    /// training file. Tool messages are skipped, since the chat fine-tuning
+    /// format has no plain tool-result role.
+    ///
+    /// Messages the format can't hold (unknown roles, and assistant turns
+    /// that call tools) are skipped with a warning; see
+    /// [`Session::to_openai_jsonl_with`].
+    pub fn to_openai_jsonl(&self) -> Result<String> {
+        self.to_openai_jsonl_with(LossyConversion::default())
+    }
+
+    /// [`Session::to_openai_jsonl`], failing on unrepresentable messages
+    /// under `LossyConversion::Reject`
+    ///
+    /// Unknown roles fail with `ContextError::UnsupportedRole` and assistant
+    /// messages with `tool_calls` with `ContextError::UnsupportedContent`;
+    /// under `LossyConversion::Coerce` each is left out with a warning rather
+    /// than relabelled or emptied.
+    pub fn to_openai_jsonl_with(&self, lossy: LossyConversion) -> Result<String> {
+        let mut messages = Vec::new();
+        for (index, message) in self.messages().iter().enumerate() {
+            let error = match &message.role {
+                MessageRole::Tool => continue,
+                MessageRole::Unknown(role) => ContextError::UnsupportedRole { index, role: role.clone() },
+                _ if message.has_tool_calls() => ContextError::UnsupportedContent {
+                    index,
+                    reason: "tool calls have no place in the chat fine-tuning format".to_string(),
+                },
+                role => {
+                    messages.push(serde_json::json!({
+                        "role": openai_role(role, OpenAIToolRole::default()),
+                        "content": message.content,
+                    }));
+                    continue;
+                }
+            };
+
+            match lossy {
+                LossyConversion::Reject => return Err(error),
+                LossyConversion::Coerce => warn!("Skipping message {} in fine-tuning export: {}", message.id, error),
+            }
+        }
+
+        let mut line = serde_json::to_string(&serde_json::json!({ "messages": messages }))?;
+        line.push('\n');
+        Ok(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::Message;
+
+    #[test]
+    fn test_to_markdown() {
+        let mut session = Session::with_name("debugging".to_string());
+        session.add_message(Message::system("Be precise\n\nAlways cite files".to_string()));
+        session.add_message(Message::user("Why does this panic?".to_string()));
+        session.add_message(Message::assistant("It unwraps `None`:\n```rust\nlet x = y.unwrap();\n```".to_string()));
+
+        let markdown = session.to_markdown();
+        assert!(markdown.starts_with("# debugging\n"));
+        assert!(markdown.contains("### System\n*"));
+        assert!(markdown.contains("> Be precise\n>\n> Always cite files\n"));
+        assert!(markdown.contains("### User\n"));
+        assert!(markdown.contains("```rust\nlet x = y.unwrap();\n```\n"));
+        assert!(markdown.contains(" UTC*\n"));
+    }
+
+    #[test]
+    fn test_to_transcript() {
+        let mut session = Session::with_name("legacy".to_string());
+        session.add_message(Message::user("Hi".to_string()));
+        session.add_message(Message::assistant("Hello!".to_string()));
+        session.add_message(Message::tool("ok".to_string()));
+
+        let opts = TranscriptOptions::default()
+            .with_label(MessageRole::User, "Human:")
+            .with_label(MessageRole::Assistant, "AI:");
+        assert_eq!(session.to_transcript(&opts), "Human: Hi\n\nAI: Hello!\n\nTool: ok");
+
+        let opts = opts.with_separator("\n").with_timestamps(true);
+        let transcript = session.to_transcript(&opts);
+        assert_eq!(transcript.lines().count(), 3);
+        assert!(transcript.lines().all(|line| line.starts_with('[')));
+        assert!(transcript.contains("] Human: Hi"));
+    }
+
+    #[test]
+    fn test_to_openai_jsonl() {
+        let mut session = Session::new();
+        session.add_message(Message::system("Be brief".to_string()));
+        session.add_message(Message::user("Line one\nline \"two\"".to_string()));
+        session.add_message(Message::tool("ignored".to_string()));
+        session.add_message(Message::assistant("Done".to_string()));
+
+        let jsonl = session.to_openai_jsonl().unwrap();
+        assert!(jsonl.ends_with('\n'));
+        assert_eq!(jsonl.lines().count(), 1);
+
+        let value: serde_json::Value = serde_json::from_str(jsonl.trim_end()).unwrap();
+        assert_eq!(value, serde_json::json!({
+            "messages": [
+                {"role": "system", "content": "Be brief"},
+                {"role": "user", "content": "Line one\nline \"two\""},
+                {"role": "assistant", "content": "Done"},
+            ]
+        }));
+    }
+
+    #[test]
+    fn test_to_openai_jsonl_skips_unknown_roles() {
+        let mut session = Session::new();
+        session.add_message(Message::user("Review this".to_string()));
+        session.add_message(Message::new(MessageRole::Unknown("critic".to_string()), "Too long".to_string()));
+        session.add_message(Message::assistant("Shortened".to_string()));
+
+        let value: serde_json::Value = serde_json::from_str(session.to_openai_jsonl().unwrap().trim_end()).unwrap();
+        assert_eq!(value, serde_json::json!({
+            "messages": [
+                {"role": "user", "content": "Review this"},
+                {"role": "assistant", "content": "Shortened"},
+            ]
+        }));
+
+        let error = session.to_openai_jsonl_with(LossyConversion::Reject).unwrap_err();
+        assert!(matches!(error, ContextError::UnsupportedRole { index: 1, ref role } if role == "critic"));
+    }
+
+    #[test]
+    fn test_to_openai_jsonl_skips_tool_call_turns() {
+        let mut session = Session::new();
+        session.add_message(Message::user("List files".to_string()));
+        session.add_message(
+            Message::assistant(String::new())
+                .with_metadata("tool_calls".to_string(), serde_json::json!([{ "id": "call_1" }])),
+        );
+        session.add_message(
+            Message::tool("a.txt".to_string()).with_metadata("tool_call_id".to_string(), serde_json::json!("call_1")),
+        );
+        session.add_message(Message::assistant("Just a.txt".to_string()));
+
+        let value: serde_json::Value = serde_json::from_str(session.to_openai_jsonl().unwrap().trim_end()).unwrap();
+        assert_eq!(value, serde_json::json!({
+            "messages": [
+                {"role": "user", "content": "List files"},
+                {"role": "assistant", "content": "Just a.txt"},
+            ]
+        }));
+
+        let error = session.to_openai_jsonl_with(LossyConversion::Reject).unwrap_err();
+        assert!(matches!(error, ContextError::UnsupportedContent { index: 1, .. }));
+    }
+}
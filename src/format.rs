@@ -1,9 +1,14 @@
 //! Message format abstraction for different LLM APIs
 
-use crate::session::{Session, Message};
-use crate::error::Result;
+use crate::session::{is_disallowed_control, Session, Message, MessageRole};
+use crate::error::{ContextError, Result};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use tracing::warn;
+use uuid::Uuid;
 
 /// Trait for converting between session format and LLM-specific message formats
+#[allow(clippy::wrong_self_convention)]
 pub trait MessageFormat<T> {
     /// Convert session messages to LLM-specific format
     fn from_session(&self, session: &Session) -> Result<Vec<T>>;
@@ -16,133 +21,698 @@ pub trait MessageFormat<T> {
     
     /// Get the maximum context window size for this format
     fn max_context_tokens(&self) -> usize;
+
+    /// Clean up message content for this API; `from_session` applies it to
+    /// every message it emits
+    ///
+    /// The default passes content through unchanged. The built-in formats
+    /// delegate to their configurable [`ContentSanitizer`].
+    fn sanitize_content<'a>(&self, content: &'a str) -> Cow<'a, str> {
+        Cow::Borrowed(content)
+    }
+
+    /// Ids of messages that alone exceed `max_context_tokens`
+    ///
+    /// A pre-flight check: such a message can never be sent, however the rest
+    /// of the session is compacted, so truncate or split it first.
+    fn oversized_messages(&self, session: &Session) -> Vec<Uuid> {
+        let limit = self.max_context_tokens();
+        session.messages().iter()
+            .filter(|m| m.estimate_tokens() > limit)
+            .map(|m| m.id)
+            .collect()
+    }
+
+    /// System prompt text, for APIs that take it outside the message list
+    ///
+    /// Pair this with [`SystemPlacement::SeparateField`]. All system messages
+    /// are joined with blank lines; `None` if the session has none.
+    fn system_prompt(&self, session: &Session) -> Option<String> {
+        let parts: Vec<&str> = session.messages().iter()
+            .filter(|m| m.role == MessageRole::System)
+            .map(|m| m.content.as_str())
+            .collect();
+
+        if parts.is_empty() {
+            None
+        } else {
+            Some(parts.join("\n\n"))
+        }
+    }
+}
+
+/// Where a format puts system messages in its output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SystemPlacement {
+    /// Emit system messages first, ahead of the conversation
+    #[default]
+    FirstMessage,
+    /// Prepend system content to the first user message, for models without a system role.
+    /// Sessions with no user message keep their system messages first.
+    MergeIntoFirstUser,
+    /// Leave system messages out of the list; read them via [`MessageFormat::system_prompt`]
+    SeparateField,
+}
+
+/// What a format does with messages it can't represent faithfully
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LossyConversion {
+    /// Map to the nearest equivalent, logging a warning for each message:
+    /// roles the API lacks become user messages, and metadata the message
+    /// type has no field for (like `tool_calls`) is dropped
+    #[default]
+    Coerce,
+    /// Fail with `ContextError::UnsupportedRole` or
+    /// `ContextError::UnsupportedContent` for the first such message
+    Reject,
+}
+
+/// What a format strips from message content before emitting it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContentSanitizer {
+    /// Pass content through verbatim
+    Raw,
+    /// Remove null bytes, which strict JSON APIs reject
+    #[default]
+    StripNul,
+    /// Remove every control character other than newlines and tabs, as
+    /// [`Message::sanitize`] does
+    StripControl,
+}
+
+impl ContentSanitizer {
+    /// `content` with the characters this sanitizer removes left out
+    pub fn apply(self, content: &str) -> Cow<'_, str> {
+        let strip = |c: char| match self {
+            ContentSanitizer::Raw => false,
+            ContentSanitizer::StripNul => c == '\0',
+            ContentSanitizer::StripControl => is_disallowed_control(c),
+        };
+        if content.chars().any(strip) {
+            Cow::Owned(content.chars().filter(|&c| !strip(c)).collect())
+        } else {
+            Cow::Borrowed(content)
+        }
+    }
+}
+
+/// Tool-calling metadata that flat-text formats have no place for
+const TOOL_CALL_METADATA: [&str; 2] = ["tool_calls", "tool_call_id"];
+
+/// Fail on the first message that would be coerced under `LossyConversion::Reject`,
+/// or warn about each one under `LossyConversion::Coerce`
+///
+/// `unsupported` picks messages, beyond those with `MessageRole::Unknown`,
+/// whose role the format would have to fold into another, and
+/// `unrepresentable` lists the metadata keys its output would drop.
+fn check_lossy(
+    session: &Session,
+    lossy: LossyConversion,
+    unsupported: impl Fn(&Message) -> bool,
+    unrepresentable: &[&str],
+) -> Result<()> {
+    for (index, message) in session.messages().iter().enumerate() {
+        let error = if matches!(message.role, MessageRole::Unknown(_)) || unsupported(message) {
+            ContextError::UnsupportedRole { index, role: message.role.as_str().to_string() }
+        } else if let Some(key) = unrepresentable.iter().find(|key| message.metadata.contains_key(**key)) {
+            ContextError::UnsupportedContent { index, reason: format!("metadata[{:?}] has no equivalent field", key) }
+        } else {
+            continue;
+        };
+
+        match lossy {
+            LossyConversion::Reject => return Err(error),
+            LossyConversion::Coerce => warn!("Coercing message {}: {}", message.id, error),
+        }
+    }
+    Ok(())
+}
+
+/// Arrange messages per `placement`, pairing each with the content to emit
+fn place_system_messages(messages: Vec<&Message>, placement: SystemPlacement) -> Vec<(&Message, String)> {
+    let (system, rest): (Vec<&Message>, Vec<&Message>) = messages.into_iter()
+        .partition(|m| m.role == MessageRole::System);
+
+    let first_user = rest.iter().position(|m| m.role == MessageRole::User);
+
+    match (placement, first_user) {
+        (SystemPlacement::SeparateField, _) => {
+            rest.into_iter().map(|m| (m, m.content.clone())).collect()
+        }
+        (SystemPlacement::MergeIntoFirstUser, Some(first_user)) if !system.is_empty() => {
+            let prompt: Vec<&str> = system.iter().map(|m| m.content.as_str()).collect();
+            let prompt = prompt.join("\n\n");
+
+            rest.into_iter()
+                .enumerate()
+                .map(|(i, m)| {
+                    if i == first_user {
+                        (m, format!("{}\n\n{}", prompt, m.content))
+                    } else {
+                        (m, m.content.clone())
+                    }
+                })
+                .collect()
+        }
+        _ => system.into_iter().chain(rest).map(|m| (m, m.content.clone())).collect(),
+    }
 }
 
 /// AWS Bedrock message format
+///
+/// Converts to and from Converse API requests. The Converse API takes the
+/// system prompt outside the message list, so `from_session` returns a single
+/// [`ConverseRequest`] holding both; [`BedrockFormat::to_converse`] returns it
+/// directly.
 #[derive(Debug, Clone)]
 pub struct BedrockFormat {
     pub max_tokens: usize,
+    /// Where system messages go. Converse has no system role in its message
+    /// list, so `FirstMessage` is treated like `SeparateField` (the default),
+    /// which emits `system` blocks.
+    pub system_placement: SystemPlacement,
+    /// Whether conversions may coerce; tool messages without a
+    /// `metadata["tool_call_id"]` count as lossy, since they are sent as user text
+    pub lossy: LossyConversion,
+    /// Applied to content by `from_session` and `to_converse`
+    pub sanitizer: ContentSanitizer,
 }
 
 impl Default for BedrockFormat {
     fn default() -> Self {
         Self {
             max_tokens: 8000, // Conservative default
+            system_placement: SystemPlacement::SeparateField,
+            lossy: LossyConversion::default(),
+            sanitizer: ContentSanitizer::default(),
         }
     }
 }
 
 impl BedrockFormat {
     pub fn new(max_tokens: usize) -> Self {
-        Self { max_tokens }
+        Self { max_tokens, ..Default::default() }
     }
-}
 
-/// Simplified Bedrock message representation for the format trait
-#[derive(Debug, Clone)]
-pub struct BedrockMessage {
-    pub role: String,
-    pub content: String,
+    /// Set where system messages go in the request
+    pub fn with_system_placement(mut self, placement: SystemPlacement) -> Self {
+        self.system_placement = placement;
+        self
+    }
+
+    /// Set whether conversions may coerce what they can't represent
+    pub fn with_lossy(mut self, lossy: LossyConversion) -> Self {
+        self.lossy = lossy;
+        self
+    }
+
+    /// Set what is stripped from content on the way out
+    pub fn with_sanitizer(mut self, sanitizer: ContentSanitizer) -> Self {
+        self.sanitizer = sanitizer;
+        self
+    }
 }
 
-impl MessageFormat<BedrockMessage> for BedrockFormat {
-    fn from_session(&self, session: &Session) -> Result<Vec<BedrockMessage>> {
-        let mut bedrock_messages = Vec::new();
-        
-        for message in &session.messages {
-            let role = match message.role {
-                crate::session::MessageRole::System => "system",
-                crate::session::MessageRole::User => "user", 
-                crate::session::MessageRole::Assistant => "assistant",
-                crate::session::MessageRole::Tool => "user", // Tool results as user messages
-            };
-            
-            bedrock_messages.push(BedrockMessage {
-                role: role.to_string(),
-                content: message.content.clone(),
-            });
-        }
-        
-        Ok(bedrock_messages)
+impl MessageFormat<ConverseRequest> for BedrockFormat {
+    /// The session as one Converse request; see [`BedrockFormat::to_converse`]
+    fn from_session(&self, session: &Session) -> Result<Vec<ConverseRequest>> {
+        Ok(vec![self.to_converse(session)?])
     }
-    
-    fn to_session(&self, messages: &[BedrockMessage], session_name: String) -> Result<Session> {
+
+    /// Every request's messages in order, in one session
+    fn to_session(&self, requests: &[ConverseRequest], session_name: String) -> Result<Session> {
         let mut session = Session::with_name(session_name);
-        
-        for bedrock_msg in messages {
-            let role = match bedrock_msg.role.as_str() {
-                "system" => crate::session::MessageRole::System,
-                "user" => crate::session::MessageRole::User,
-                "assistant" => crate::session::MessageRole::Assistant,
-                _ => crate::session::MessageRole::User, // Default fallback
-            };
-            
-            session.add_message(Message::new(role, bedrock_msg.content.clone()));
+        for request in requests {
+            self.append_converse(&mut session, request)?;
         }
-        
         Ok(session)
     }
-    
-    fn estimate_tokens(&self, message: &BedrockMessage) -> usize {
+
+    fn estimate_tokens(&self, request: &ConverseRequest) -> usize {
         // Simple estimation: ~4 characters per token
-        (message.content.len() + 3) / 4
+        let system = request.system.iter().map(|block| block.text.len().div_ceil(4));
+        let blocks = request.messages.iter().flat_map(|m| &m.content).map(|block| match block {
+            ContentBlock::Text(text) => text.len().div_ceil(4),
+            ContentBlock::ToolUse(tool_use) => (tool_use.name.len() + tool_use.input.to_string().len()).div_ceil(4),
+            ContentBlock::ToolResult(result) => result.content.iter()
+                .map(|content| match content {
+                    ToolResultContent::Text(text) => text.len().div_ceil(4),
+                    ToolResultContent::Json(value) => value.to_string().len().div_ceil(4),
+                })
+                .sum(),
+        });
+        system.chain(blocks).sum()
     }
-    
+
     fn max_context_tokens(&self) -> usize {
         self.max_tokens
     }
+
+    fn sanitize_content<'a>(&self, content: &'a str) -> Cow<'a, str> {
+        self.sanitizer.apply(content)
+    }
+}
+
+/// A Bedrock Converse request body: system prompt blocks plus messages
+///
+/// Serializes to the `system` and `messages` fields of the Converse API.
+/// Messages alternate between `user` and `assistant`, as Converse requires.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConverseRequest {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub system: Vec<SystemBlock>,
+    pub messages: Vec<ConverseMessage>,
+}
+
+/// One block of a Converse system prompt
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SystemBlock {
+    pub text: String,
+}
+
+/// A Converse message; `role` is `"user"` or `"assistant"`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConverseMessage {
+    pub role: String,
+    pub content: Vec<ContentBlock>,
+}
+
+/// A block of Converse message content
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ContentBlock {
+    Text(String),
+    ToolUse(ToolUseBlock),
+    ToolResult(ToolResultBlock),
+}
+
+/// A tool call requested by the assistant
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ToolUseBlock {
+    pub tool_use_id: String,
+    pub name: String,
+    pub input: serde_json::Value,
+}
+
+/// The result of a tool call, sent back in a user message
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ToolResultBlock {
+    pub tool_use_id: String,
+    pub content: Vec<ToolResultContent>,
+}
+
+/// Content of a tool result
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ToolResultContent {
+    Text(String),
+    Json(serde_json::Value),
+}
+
+impl BedrockFormat {
+    /// Convert a session to a Converse request
+    ///
+    /// System messages become `system` blocks, or are prepended to the first
+    /// user message under `SystemPlacement::MergeIntoFirstUser`. Tool messages
+    /// answering a call (`metadata["tool_call_id"]`) become `toolResult`
+    /// blocks, other tool messages plain text, both in user messages.
+    /// Assistant `tool_calls` (OpenAI shape) become `toolUse` blocks.
+    /// Consecutive messages with the same role are merged, and empty text is
+    /// left out.
+    pub fn to_converse(&self, session: &Session) -> Result<ConverseRequest> {
+        check_lossy(
+            session,
+            self.lossy,
+            |m| m.role == MessageRole::Tool && m.tool_call_id().is_none(),
+            &[],
+        )?;
+
+        let merge = self.system_placement == SystemPlacement::MergeIntoFirstUser
+            && session.messages().iter().any(|m| m.role == MessageRole::User);
+        let mut merged_prompt = if merge { self.system_prompt(session) } else { None };
+        let mut request = ConverseRequest { system: Vec::new(), messages: Vec::new() };
+
+        for message in session.messages() {
+            let content = self.sanitize_content(&message.content);
+            let (role, blocks) = match message.role {
+                MessageRole::System if merge => continue,
+                MessageRole::System => {
+                    request.system.push(SystemBlock { text: content.into_owned() });
+                    continue;
+                }
+                MessageRole::User => match merged_prompt.take() {
+                    Some(prompt) => {
+                        let merged = format!("{}\n\n{}", prompt, message.content);
+                        ("user", text_block(&self.sanitize_content(&merged)))
+                    }
+                    None => ("user", text_block(&content)),
+                },
+                MessageRole::Unknown(_) => ("user", text_block(&content)),
+                MessageRole::Assistant => {
+                    let mut blocks = text_block(&content);
+                    blocks.extend(tool_use_blocks(message)?);
+                    ("assistant", blocks)
+                }
+                MessageRole::Tool => match message.tool_call_id() {
+                    Some(id) => ("user", vec![ContentBlock::ToolResult(ToolResultBlock {
+                        tool_use_id: id.to_string(),
+                        content: vec![ToolResultContent::Text(content.into_owned())],
+                    })]),
+                    None => ("user", text_block(&content)),
+                },
+            };
+
+            if blocks.is_empty() {
+                continue;
+            }
+            match request.messages.last_mut() {
+                Some(last) if last.role == role => last.content.extend(blocks),
+                _ => request.messages.push(ConverseMessage { role: role.to_string(), content: blocks }),
+            }
+        }
+
+        Ok(request)
+    }
+
+    /// Convert a Converse request back to a session
+    ///
+    /// Every text block and tool result becomes its own message, and each
+    /// assistant message's `toolUse` blocks are recorded as OpenAI-shaped
+    /// `tool_calls` metadata.
+    pub fn from_converse(&self, request: &ConverseRequest, session_name: String) -> Result<Session> {
+        let mut session = Session::with_name(session_name);
+        self.append_converse(&mut session, request)?;
+        Ok(session)
+    }
+
+    fn append_converse(&self, session: &mut Session, request: &ConverseRequest) -> Result<()> {
+        for block in &request.system {
+            session.add_message(Message::system(block.text.clone()));
+        }
+
+        for (index, converse) in request.messages.iter().enumerate() {
+            let role = converse.role.as_str();
+            let assistant = role == "assistant";
+            if !assistant && role != "user" && self.lossy == LossyConversion::Reject {
+                return Err(ContextError::UnsupportedRole { index, role: role.to_string() });
+            }
+
+            let mut tool_calls = Vec::new();
+            for block in &converse.content {
+                match block {
+                    ContentBlock::Text(text) if assistant => session.add_message(Message::assistant(text.clone())),
+                    ContentBlock::Text(text) if role == "user" => session.add_message(Message::user(text.clone())),
+                    ContentBlock::Text(text) => session.add_message(unmapped_role_message(role, text.clone())),
+                    ContentBlock::ToolUse(tool_use) => tool_calls.push(serde_json::json!({
+                        "id": tool_use.tool_use_id,
+                        "type": "function",
+                        "function": {
+                            "name": tool_use.name,
+                            "arguments": tool_use.input.to_string(),
+                        },
+                    })),
+                    ContentBlock::ToolResult(result) => {
+                        let content: Vec<String> = result.content.iter()
+                            .map(|content| match content {
+                                ToolResultContent::Text(text) => text.clone(),
+                                ToolResultContent::Json(value) => value.to_string(),
+                            })
+                            .collect();
+                        session.add_message(
+                            Message::tool(content.join("\n"))
+                                .with_metadata("tool_call_id".to_string(), serde_json::json!(result.tool_use_id)),
+                        );
+                    }
+                }
+            }
+
+            if !tool_calls.is_empty() {
+                // Attach the calls to this turn's text, or to an empty message if it had none
+                let turn_has_text = converse.content.iter().any(|b| matches!(b, ContentBlock::Text(_)));
+                if !turn_has_text {
+                    session.add_message(Message::assistant(String::new()));
+                }
+                let last = session.messages().len() - 1;
+                session.messages_mut()[last].set_meta("tool_calls", tool_calls)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A text block for `content`, or nothing if it is empty
+fn text_block(content: &str) -> Vec<ContentBlock> {
+    if content.is_empty() {
+        Vec::new()
+    } else {
+        vec![ContentBlock::Text(content.to_string())]
+    }
+}
+
+/// `toolUse` blocks for the OpenAI-shaped `tool_calls` on an assistant message
+///
+/// Accepts both `{id, function: {name, arguments}}` and `{id, name, input}`.
+fn tool_use_blocks(message: &Message) -> Result<Vec<ContentBlock>> {
+    let Some(calls) = message.metadata.get("tool_calls").and_then(|calls| calls.as_array()) else {
+        return Ok(Vec::new());
+    };
+
+    calls.iter()
+        .map(|call| {
+            let field = |key: &str| call.get("function").and_then(|f| f.get(key)).or_else(|| call.get(key));
+            let id = call.get("id").and_then(|id| id.as_str());
+            let name = field("name").and_then(|name| name.as_str());
+            let (Some(id), Some(name)) = (id, name) else {
+                return Err(ContextError::InvalidSession(format!(
+                    "Tool call without id or name in message {}",
+                    message.id
+                )));
+            };
+
+            let input = match field("arguments").or_else(|| field("input")) {
+                Some(serde_json::Value::String(arguments)) => serde_json::from_str(arguments)?,
+                Some(input) => input.clone(),
+                None => serde_json::json!({}),
+            };
+
+            Ok(ContentBlock::ToolUse(ToolUseBlock { tool_use_id: id.to_string(), name: name.to_string(), input }))
+        })
+        .collect()
+}
+
+/// How to treat tool messages that don't follow an assistant tool call
+///
+/// See [`Session::validate_tool_pairing`] for what counts as dangling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DanglingToolPolicy {
+    /// Emit messages in session order, even if the API would reject them
+    #[default]
+    Keep,
+    /// Leave dangling tool messages out of the output
+    Drop,
+    /// Move dangling tool messages behind the assistant message whose tool
+    /// calls include their `tool_call_id`, dropping (with a warning) any
+    /// without one
+    Reorder,
+}
+
+/// Role name OpenAI output gives tool messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OpenAIToolRole {
+    /// `"tool"`, as the current Chat Completions API expects
+    #[default]
+    Tool,
+    /// `"function"`, the deprecated function-calling role
+    Function,
 }
 
 /// OpenAI message format
 #[derive(Debug, Clone)]
 pub struct OpenAIFormat {
     pub max_tokens: usize,
+    pub dangling_tools: DanglingToolPolicy,
+    pub system_placement: SystemPlacement,
+    /// Role for tool messages in `from_session`; `to_session` accepts either
+    pub tool_role: OpenAIToolRole,
+    /// Whether `from_session` and `to_session` may coerce
+    pub lossy: LossyConversion,
+    /// Applied to content by `from_session`
+    pub sanitizer: ContentSanitizer,
 }
 
 impl Default for OpenAIFormat {
     fn default() -> Self {
         Self {
             max_tokens: 4000, // GPT-3.5 default
+            dangling_tools: DanglingToolPolicy::default(),
+            system_placement: SystemPlacement::default(),
+            tool_role: OpenAIToolRole::default(),
+            lossy: LossyConversion::default(),
+            sanitizer: ContentSanitizer::default(),
         }
     }
 }
 
 impl OpenAIFormat {
     pub fn new(max_tokens: usize) -> Self {
-        Self { max_tokens }
+        Self { max_tokens, ..Default::default() }
     }
     
     pub fn gpt4() -> Self {
-        Self { max_tokens: 8000 }
+        Self::new(8000)
     }
     
     pub fn gpt4_turbo() -> Self {
-        Self { max_tokens: 128000 }
+        Self::new(128000)
+    }
+
+    /// Set how dangling tool messages are handled in `from_session`
+    pub fn with_dangling_tools(mut self, policy: DanglingToolPolicy) -> Self {
+        self.dangling_tools = policy;
+        self
+    }
+
+    /// Set where system messages go in `from_session` output
+    pub fn with_system_placement(mut self, placement: SystemPlacement) -> Self {
+        self.system_placement = placement;
+        self
+    }
+
+    /// Set the role name tool messages get in `from_session` output
+    pub fn with_tool_role(mut self, tool_role: OpenAIToolRole) -> Self {
+        self.tool_role = tool_role;
+        self
+    }
+
+    /// Set whether conversions may coerce what they can't represent
+    pub fn with_lossy(mut self, lossy: LossyConversion) -> Self {
+        self.lossy = lossy;
+        self
+    }
+
+    /// Set what is stripped from content on the way out
+    pub fn with_sanitizer(mut self, sanitizer: ContentSanitizer) -> Self {
+        self.sanitizer = sanitizer;
+        self
+    }
+
+    /// Session messages in the order they should be emitted, per `dangling_tools`
+    fn paired_messages<'a>(&self, session: &'a Session) -> Vec<&'a Message> {
+        let dangling = match session.validate_tool_pairing() {
+            Ok(()) => return session.messages().iter().collect(),
+            Err(ids) => ids,
+        };
+
+        let mut ordered: Vec<&Message> = session.messages().iter()
+            .filter(|m| self.dangling_tools == DanglingToolPolicy::Keep || !dangling.contains(&m.id))
+            .collect();
+
+        if self.dangling_tools != DanglingToolPolicy::Reorder {
+            return ordered;
+        }
+
+        for message in session.messages() {
+            if !dangling.contains(&message.id) {
+                continue;
+            }
+
+            // Only the assistant that issued this call id; guessing could pair
+            // a result with the wrong call
+            let anchor = message.tool_call_id()
+                .and_then(|call_id| ordered.iter().position(|m| m.requested_tool_call(call_id)));
+
+            let Some(anchor) = anchor else {
+                warn!(
+                    "Dropping dangling tool message {}: no assistant message requested call {:?}",
+                    message.id,
+                    message.tool_call_id()
+                );
+                continue;
+            };
+
+            let mut insert_at = anchor + 1;
+            while insert_at < ordered.len() && ordered[insert_at].role == MessageRole::Tool {
+                insert_at += 1;
+            }
+            ordered.insert(insert_at, message);
+        }
+
+        ordered
     }
 }
 
+/// OpenAI role name for a message role
+pub(crate) fn openai_role(role: &MessageRole, tool_role: OpenAIToolRole) -> &'static str {
+    match role {
+        MessageRole::System => "system",
+        MessageRole::User => "user",
+        MessageRole::Assistant => "assistant",
+        MessageRole::Tool => match tool_role {
+            OpenAIToolRole::Tool => "tool",
+            OpenAIToolRole::Function => "function",
+        },
+        MessageRole::Unknown(_) => "user",
+    }
+}
+
+/// Metadata key recording an API role no [`MessageRole`] maps to
+pub const ORIGINAL_ROLE_KEY: &str = "original_role";
+
+/// A user message standing in for one with an unmapped API `role`
+///
+/// The role is kept under [`ORIGINAL_ROLE_KEY`] so it isn't lost.
+fn unmapped_role_message(role: &str, content: String) -> Message {
+    warn!("Coercing unmapped role {:?} to user", role);
+    Message::user(content).with_metadata(ORIGINAL_ROLE_KEY.to_string(), serde_json::json!(role))
+}
+
 /// Simplified OpenAI message representation
-#[derive(Debug, Clone)]
+///
+/// Serializes to a Chat Completions message. Tool calling round-trips through
+/// `metadata["tool_calls"]` on assistant messages and `metadata["tool_call_id"]`
+/// on tool messages.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct OpenAIMessage {
     pub role: String,
     pub content: String,
+    /// The call a `tool` message answers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    /// Calls an `assistant` message requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<serde_json::Value>>,
+}
+
+impl OpenAIMessage {
+    /// A message with no tool-calling fields
+    pub fn new(role: impl Into<String>, content: impl Into<String>) -> Self {
+        Self { role: role.into(), content: content.into(), tool_call_id: None, tool_calls: None }
+    }
 }
 
 impl MessageFormat<OpenAIMessage> for OpenAIFormat {
     fn from_session(&self, session: &Session) -> Result<Vec<OpenAIMessage>> {
+        check_lossy(session, self.lossy, |_| false, &[])?;
         let mut openai_messages = Vec::new();
+        let messages = place_system_messages(self.paired_messages(session), self.system_placement);
         
-        for message in &session.messages {
-            let role = match message.role {
-                crate::session::MessageRole::System => "system",
-                crate::session::MessageRole::User => "user",
-                crate::session::MessageRole::Assistant => "assistant", 
-                crate::session::MessageRole::Tool => "function", // OpenAI has function role
-            };
-            
+        for (message, content) in messages {
+            let tool_calls = message.metadata.get("tool_calls")
+                .filter(|_| message.role == MessageRole::Assistant)
+                .and_then(|calls| calls.as_array())
+                .cloned();
+            let tool_call_id = message.tool_call_id()
+                .filter(|_| message.role == MessageRole::Tool)
+                .map(str::to_string);
+
             openai_messages.push(OpenAIMessage {
-                role: role.to_string(),
-                content: message.content.clone(),
+                role: openai_role(&message.role, self.tool_role).to_string(),
+                content: self.sanitize_content(&content).into_owned(),
+                tool_call_id,
+                tool_calls,
             });
         }
         
@@ -152,16 +722,29 @@ impl MessageFormat<OpenAIMessage> for OpenAIFormat {
     fn to_session(&self, messages: &[OpenAIMessage], session_name: String) -> Result<Session> {
         let mut session = Session::with_name(session_name);
         
-        for openai_msg in messages {
+        for (index, openai_msg) in messages.iter().enumerate() {
             let role = match openai_msg.role.as_str() {
                 "system" => crate::session::MessageRole::System,
                 "user" => crate::session::MessageRole::User,
                 "assistant" => crate::session::MessageRole::Assistant,
-                "function" => crate::session::MessageRole::Tool,
-                _ => crate::session::MessageRole::User, // Default fallback
+                "tool" | "function" => crate::session::MessageRole::Tool,
+                other if self.lossy == LossyConversion::Reject => {
+                    return Err(ContextError::UnsupportedRole { index, role: other.to_string() });
+                }
+                other => {
+                    session.add_message(unmapped_role_message(other, openai_msg.content.clone()));
+                    continue;
+                }
             };
             
-            session.add_message(Message::new(role, openai_msg.content.clone()));
+            let mut message = Message::new(role, openai_msg.content.clone());
+            if let Some(tool_call_id) = &openai_msg.tool_call_id {
+                message.set_meta("tool_call_id", tool_call_id)?;
+            }
+            if let Some(tool_calls) = &openai_msg.tool_calls {
+                message.set_meta("tool_calls", tool_calls)?;
+            }
+            session.add_message(message);
         }
         
         Ok(session)
@@ -169,12 +752,304 @@ impl MessageFormat<OpenAIMessage> for OpenAIFormat {
     
     fn estimate_tokens(&self, message: &OpenAIMessage) -> usize {
         // OpenAI's tokenization is roughly 4 characters per token
-        (message.content.len() + 3) / 4
+        message.content.len().div_ceil(4)
     }
     
     fn max_context_tokens(&self) -> usize {
         self.max_tokens
     }
+
+    fn sanitize_content<'a>(&self, content: &'a str) -> Cow<'a, str> {
+        self.sanitizer.apply(content)
+    }
+}
+
+/// Raw JSON message format
+///
+/// Emits each message as `{ "id", "timestamp", "role", "content", "metadata" }`
+/// for APIs whose body shape none of the typed formats cover. Reshape the values
+/// as needed; `to_session` accepts the same shape back, keeping `id` and
+/// `timestamp` (RFC 3339) when present and generating them otherwise.
+#[derive(Debug, Clone)]
+pub struct JsonFormat {
+    pub max_tokens: usize,
+    /// Applied to content by `from_session`; `Raw` by default, since this
+    /// format is also used for lossless round trips
+    pub sanitizer: ContentSanitizer,
+}
+
+impl Default for JsonFormat {
+    fn default() -> Self {
+        Self {
+            max_tokens: 8000, // Conservative default
+            sanitizer: ContentSanitizer::Raw,
+        }
+    }
+}
+
+impl JsonFormat {
+    pub fn new(max_tokens: usize) -> Self {
+        Self { max_tokens, ..Default::default() }
+    }
+
+    /// Set what is stripped from content on the way out
+    pub fn with_sanitizer(mut self, sanitizer: ContentSanitizer) -> Self {
+        self.sanitizer = sanitizer;
+        self
+    }
+}
+
+impl MessageFormat<serde_json::Value> for JsonFormat {
+    fn from_session(&self, session: &Session) -> Result<Vec<serde_json::Value>> {
+        session.messages().iter()
+            .map(|message| {
+                Ok(serde_json::json!({
+                    "id": message.id,
+                    "timestamp": message.timestamp,
+                    "role": message.role,
+                    "content": self.sanitize_content(&message.content),
+                    "metadata": message.metadata,
+                }))
+            })
+            .collect()
+    }
+
+    fn to_session(&self, messages: &[serde_json::Value], session_name: String) -> Result<Session> {
+        let mut session = Session::with_name(session_name);
+
+        for (index, value) in messages.iter().enumerate() {
+            let role: MessageRole = serde_json::from_value(value.get("role").cloned().unwrap_or_default())?;
+            let content = value.get("content")
+                .and_then(|c| c.as_str())
+                .ok_or_else(|| ContextError::InvalidSession(format!("Message {} has no string content", index)))?;
+
+            let mut message = Message::new(role, content.to_string());
+            if let Some(id) = value.get("id").filter(|v| !v.is_null()) {
+                message = message.with_id(serde_json::from_value(id.clone())?);
+            }
+            if let Some(timestamp) = value.get("timestamp").filter(|v| !v.is_null()) {
+                message = message.with_timestamp(serde_json::from_value(timestamp.clone())?);
+            }
+            if let Some(metadata) = value.get("metadata").filter(|m| !m.is_null()) {
+                message.metadata = serde_json::from_value(metadata.clone())?;
+            }
+
+            session.add_message(message);
+        }
+
+        Ok(session)
+    }
+
+    fn estimate_tokens(&self, message: &serde_json::Value) -> usize {
+        // Simple estimation: ~4 characters per token
+        message.get("content")
+            .and_then(|c| c.as_str())
+            .map_or(0, |c| c.len().div_ceil(4))
+    }
+
+    fn max_context_tokens(&self) -> usize {
+        self.max_tokens
+    }
+
+    fn sanitize_content<'a>(&self, content: &'a str) -> Cow<'a, str> {
+        self.sanitizer.apply(content)
+    }
+}
+
+/// Single-string prompt format for text-completion endpoints
+///
+/// Flattens a session into one prompt with role framing, legacy Anthropic
+/// style by default: `"\n\nHuman: Hi\n\nAssistant:"`. Each message is written
+/// as `separator`, its role's prefix, then its content; a leading message
+/// whose prefix is empty (a plain system preamble, by default) skips the
+/// separator. Unless the session already ends with an assistant message,
+/// which the model then continues, the prompt ends with `separator` and the
+/// assistant prefix (trailing whitespace trimmed) as the cue to answer.
+///
+/// `from_session` returns the prompt as the only element; [`PromptStringFormat::render`]
+/// returns it directly. `to_session` splits prompts back on the same
+/// framing, so roles sharing a prefix (tool results are written as `Human:`
+/// turns by default, since completion models have no tool role) come back as
+/// whichever role is checked first: user, assistant, tool, then system.
+#[derive(Debug, Clone)]
+pub struct PromptStringFormat {
+    pub max_tokens: usize,
+    pub system_prefix: String,
+    pub user_prefix: String,
+    pub assistant_prefix: String,
+    pub tool_prefix: String,
+    /// Written before every framed message, and before the trailing cue
+    pub separator: String,
+    /// Whether to end with the assistant cue
+    pub assistant_cue: bool,
+    /// Whether `from_session` may coerce; `render` always does
+    pub lossy: LossyConversion,
+    /// Applied to content by `render` and `from_session`
+    pub sanitizer: ContentSanitizer,
+}
+
+impl Default for PromptStringFormat {
+    fn default() -> Self {
+        Self {
+            max_tokens: 8000, // Conservative default
+            system_prefix: String::new(),
+            user_prefix: "Human: ".to_string(),
+            assistant_prefix: "Assistant: ".to_string(),
+            tool_prefix: "Human: ".to_string(),
+            separator: "\n\n".to_string(),
+            assistant_cue: true,
+            lossy: LossyConversion::default(),
+            sanitizer: ContentSanitizer::default(),
+        }
+    }
+}
+
+impl PromptStringFormat {
+    pub fn new(max_tokens: usize) -> Self {
+        Self { max_tokens, ..Default::default() }
+    }
+
+    /// Set the prefix written before messages of `role`
+    ///
+    /// `MessageRole::Unknown` messages use the user prefix and can't be set
+    /// separately.
+    pub fn with_role_prefix(mut self, role: MessageRole, prefix: impl Into<String>) -> Self {
+        let prefix = prefix.into();
+        match role {
+            MessageRole::System => self.system_prefix = prefix,
+            MessageRole::Assistant => self.assistant_prefix = prefix,
+            MessageRole::Tool => self.tool_prefix = prefix,
+            MessageRole::User | MessageRole::Unknown(_) => self.user_prefix = prefix,
+        }
+        self
+    }
+
+    /// Set the separator written before each framed message
+    pub fn with_separator(mut self, separator: impl Into<String>) -> Self {
+        self.separator = separator.into();
+        self
+    }
+
+    /// Set whether the prompt ends with the assistant cue
+    pub fn with_assistant_cue(mut self, assistant_cue: bool) -> Self {
+        self.assistant_cue = assistant_cue;
+        self
+    }
+
+    /// Set whether `from_session` may coerce what it can't represent
+    pub fn with_lossy(mut self, lossy: LossyConversion) -> Self {
+        self.lossy = lossy;
+        self
+    }
+
+    /// Set what is stripped from content on the way out
+    pub fn with_sanitizer(mut self, sanitizer: ContentSanitizer) -> Self {
+        self.sanitizer = sanitizer;
+        self
+    }
+
+    /// The whole session as one framed prompt
+    pub fn render(&self, session: &Session) -> String {
+        let mut prompt = String::new();
+        for message in session.messages() {
+            let prefix = self.prefix(&message.role);
+            if !(prompt.is_empty() && prefix.is_empty()) {
+                prompt.push_str(&self.separator);
+            }
+            prompt.push_str(prefix);
+            prompt.push_str(&self.sanitize_content(&message.content));
+        }
+
+        let ends_with_assistant = session.messages().last().is_some_and(|m| m.role == MessageRole::Assistant);
+        if self.assistant_cue && !ends_with_assistant {
+            prompt.push_str(&self.separator);
+            prompt.push_str(self.assistant_prefix.trim_end());
+        }
+        prompt
+    }
+
+    fn prefix(&self, role: &MessageRole) -> &str {
+        match role {
+            MessageRole::System => &self.system_prefix,
+            MessageRole::Assistant => &self.assistant_prefix,
+            MessageRole::Tool => &self.tool_prefix,
+            MessageRole::User | MessageRole::Unknown(_) => &self.user_prefix,
+        }
+    }
+
+    /// Messages framed in `prompt`, minus any trailing assistant cue
+    fn parse(&self, prompt: &str) -> Vec<Message> {
+        let cue = format!("{}{}", self.separator, self.assistant_prefix.trim_end());
+        let prompt = match prompt.strip_suffix(&cue) {
+            Some(stripped) if self.assistant_cue => stripped,
+            _ => prompt,
+        };
+
+        let markers: Vec<(MessageRole, String)> = [MessageRole::User, MessageRole::Assistant, MessageRole::Tool, MessageRole::System]
+            .into_iter()
+            .filter(|role| !self.prefix(role).is_empty())
+            .map(|role| {
+                let marker = format!("{}{}", self.separator, self.prefix(&role));
+                (role, marker)
+            })
+            .collect();
+        // Earliest marker wins, then the longest one starting there
+        let next_marker = |text: &str| {
+            markers.iter()
+                .filter_map(|(role, marker)| text.find(marker.as_str()).map(|at| (at, role, marker.len())))
+                .min_by_key(|&(at, _, len)| (at, std::cmp::Reverse(len)))
+        };
+
+        let mut messages = Vec::new();
+        // Text before the first marker is an unframed system preamble
+        let mut role: Option<&MessageRole> = None;
+        let mut rest = prompt;
+        loop {
+            let found = next_marker(rest);
+            let content = &rest[..found.map_or(rest.len(), |(at, _, _)| at)];
+            match role {
+                Some(role) => messages.push(Message::new(role.clone(), content.to_string())),
+                None if !content.trim().is_empty() => messages.push(Message::system(content.to_string())),
+                None => {}
+            }
+            match found {
+                Some((at, next_role, len)) => {
+                    role = Some(next_role);
+                    rest = &rest[at + len..];
+                }
+                None => break,
+            }
+        }
+        messages
+    }
+}
+
+impl MessageFormat<String> for PromptStringFormat {
+    fn from_session(&self, session: &Session) -> Result<Vec<String>> {
+        check_lossy(session, self.lossy, |_| false, &TOOL_CALL_METADATA)?;
+        Ok(vec![self.render(session)])
+    }
+
+    fn to_session(&self, messages: &[String], session_name: String) -> Result<Session> {
+        let mut session = Session::with_name(session_name);
+        for message in messages.iter().flat_map(|prompt| self.parse(prompt)) {
+            session.add_message(message);
+        }
+        Ok(session)
+    }
+
+    fn estimate_tokens(&self, message: &String) -> usize {
+        // Simple estimation: ~4 characters per token
+        message.len().div_ceil(4)
+    }
+
+    fn max_context_tokens(&self) -> usize {
+        self.max_tokens
+    }
+
+    fn sanitize_content<'a>(&self, content: &'a str) -> Cow<'a, str> {
+        self.sanitizer.apply(content)
+    }
 }
 
 #[cfg(test)]
@@ -182,6 +1057,20 @@ mod tests {
     use super::*;
     use crate::session::{Session, Message, MessageRole};
 
+    #[test]
+    fn test_oversized_messages() {
+        let mut session = Session::with_name("test".to_string());
+        session.add_message(Message::user("short".to_string()));
+        session.add_message(Message::tool("x".repeat(400)));
+        session.add_message(Message::assistant("counted".to_string()).with_token_count(101));
+
+        let format = OpenAIFormat::new(100);
+        assert_eq!(format.oversized_messages(&session), vec![session.messages()[2].id]);
+
+        let format = OpenAIFormat::new(99);
+        assert_eq!(format.oversized_messages(&session), vec![session.messages()[1].id, session.messages()[2].id]);
+    }
+
     #[test]
     fn test_bedrock_format_conversion() {
         let mut session = Session::with_name("test".to_string());
@@ -190,16 +1079,78 @@ mod tests {
         session.add_message(Message::new(MessageRole::Assistant, "Hi!".to_string()));
 
         let format = BedrockFormat::default();
-        let bedrock_messages = format.from_session(&session).unwrap();
+        let requests = format.from_session(&session).unwrap();
         
-        assert_eq!(bedrock_messages.len(), 3);
-        assert_eq!(bedrock_messages[0].role, "system");
-        assert_eq!(bedrock_messages[1].role, "user");
-        assert_eq!(bedrock_messages[2].role, "assistant");
+        // Converse rejects a system role in the message list
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].system, [SystemBlock { text: "You are helpful".to_string() }]);
+        assert_eq!(requests[0].messages.len(), 2);
+        assert_eq!(requests[0].messages[0].role, "user");
+        assert_eq!(requests[0].messages[1].role, "assistant");
+        assert_eq!(format.estimate_tokens(&requests[0]), 4 + 2 + 1);
         
         // Test round-trip conversion
-        let converted_session = format.to_session(&bedrock_messages, "converted".to_string()).unwrap();
-        assert_eq!(converted_session.messages.len(), 3);
+        let converted_session = format.to_session(&requests, "converted".to_string()).unwrap();
+        assert_eq!(converted_session.messages().len(), 3);
+        assert_eq!(converted_session.messages()[0].role, MessageRole::System);
+        assert_eq!(converted_session.messages()[0].content, "You are helpful");
+
+        let inline = format.with_system_placement(SystemPlacement::FirstMessage).from_session(&session).unwrap();
+        assert_eq!(inline, requests);
+    }
+
+    #[test]
+    fn test_bedrock_converse_round_trip() {
+        let mut session = Session::with_name("tools".to_string());
+        session.add_message(Message::system("You are helpful".to_string()));
+        session.add_message(Message::user("What's in /tmp?".to_string()));
+        session.add_message(
+            Message::assistant("Let me look.".to_string()).with_metadata(
+                "tool_calls".to_string(),
+                serde_json::json!([{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": { "name": "ls", "arguments": "{\"path\":\"/tmp\"}" },
+                }]),
+            ),
+        );
+        session.add_message(
+            Message::tool("a.txt".to_string())
+                .with_metadata("tool_call_id".to_string(), serde_json::json!("call_1")),
+        );
+        session.add_message(Message::user("Thanks".to_string()));
+        session.add_message(Message::assistant("You're welcome".to_string()));
+
+        let format = BedrockFormat::default();
+        let request = format.to_converse(&session).unwrap();
+        assert_eq!(format.from_session(&session).unwrap(), std::slice::from_ref(&request));
+        let expected = serde_json::json!({
+            "system": [{ "text": "You are helpful" }],
+            "messages": [
+                { "role": "user", "content": [{ "text": "What's in /tmp?" }] },
+                { "role": "assistant", "content": [
+                    { "text": "Let me look." },
+                    { "toolUse": { "toolUseId": "call_1", "name": "ls", "input": { "path": "/tmp" } } },
+                ] },
+                { "role": "user", "content": [
+                    { "toolResult": { "toolUseId": "call_1", "content": [{ "text": "a.txt" }] } },
+                    { "text": "Thanks" },
+                ] },
+                { "role": "assistant", "content": [{ "text": "You're welcome" }] },
+            ],
+        });
+        assert_eq!(serde_json::to_value(&request).unwrap(), expected);
+
+        let parsed: ConverseRequest = serde_json::from_value(expected).unwrap();
+        assert_eq!(parsed, request);
+
+        let restored = format.from_converse(&parsed, "restored".to_string()).unwrap();
+        let roles: Vec<&MessageRole> = restored.messages().iter().map(|m| &m.role).collect();
+        let original: Vec<&MessageRole> = session.messages().iter().map(|m| &m.role).collect();
+        assert_eq!(roles, original);
+        assert_eq!(restored.messages()[3].tool_call_id(), Some("call_1"));
+        assert!(restored.messages()[2].requested_tool_call("call_1"));
+        assert_eq!(format.to_converse(&restored).unwrap(), request);
     }
 
     #[test]
@@ -207,7 +1158,10 @@ mod tests {
         let mut session = Session::with_name("test".to_string());
         session.add_message(Message::new(MessageRole::System, "You are helpful".to_string()));
         session.add_message(Message::new(MessageRole::User, "Hello".to_string()));
-        session.add_message(Message::new(MessageRole::Tool, "result".to_string()));
+        session.add_message(
+            Message::new(MessageRole::Tool, "result".to_string())
+                .with_metadata("tool_call_id".to_string(), serde_json::json!("call_1")),
+        );
 
         let format = OpenAIFormat::default();
         let openai_messages = format.from_session(&session).unwrap();
@@ -215,6 +1169,284 @@ mod tests {
         assert_eq!(openai_messages.len(), 3);
         assert_eq!(openai_messages[0].role, "system");
         assert_eq!(openai_messages[1].role, "user");
-        assert_eq!(openai_messages[2].role, "function");
+        assert_eq!(openai_messages[2].role, "tool");
+        assert_eq!(openai_messages[2].tool_call_id.as_deref(), Some("call_1"));
+        assert_eq!(openai_messages[1].tool_call_id, None);
+
+        let legacy = format.clone().with_tool_role(OpenAIToolRole::Function).from_session(&session).unwrap();
+        assert_eq!(legacy[2].role, "function");
+
+        for messages in [openai_messages, legacy] {
+            let restored = format.to_session(&messages, "restored".to_string()).unwrap();
+            assert_eq!(restored.messages()[2].role, MessageRole::Tool);
+            assert_eq!(restored.messages()[2].tool_call_id(), Some("call_1"));
+        }
+    }
+
+    #[test]
+    fn test_unknown_roles_map_to_user() {
+        let mut session = Session::with_name("test".to_string());
+        session.add_message(Message::new(MessageRole::Unknown("critic".to_string()), "Looks wrong".to_string()));
+
+        let openai = OpenAIFormat::default();
+        assert_eq!(openai.from_session(&session).unwrap()[0].role, "user");
+        let bedrock = BedrockFormat::default().from_session(&session).unwrap();
+        assert_eq!(bedrock[0].messages[0].role, "user");
+
+        let external = [OpenAIMessage::new("developer", "Be terse")];
+        let imported = openai.to_session(&external, "imported".to_string()).unwrap();
+        assert_eq!(imported.messages()[0].role, MessageRole::User);
+        assert_eq!(imported.messages()[0].get_meta_str(ORIGINAL_ROLE_KEY), Some("developer"));
+
+        let values = JsonFormat::default().from_session(&session).unwrap();
+        assert_eq!(values[0]["role"], "critic");
+        let restored = JsonFormat::default().to_session(&values, "restored".to_string()).unwrap();
+        assert_eq!(restored.messages()[0].role, MessageRole::Unknown("critic".to_string()));
+    }
+
+    #[test]
+    fn test_reject_lossy_conversions() {
+        let mut session = Session::with_name("test".to_string());
+        session.add_message(Message::user("Run it".to_string()));
+        session.add_message(Message::tool("42".to_string()));
+        session.add_message(Message::new(MessageRole::Unknown("critic".to_string()), "Looks wrong".to_string()));
+
+        // Coercing, with a warning, is the default, so sessions built with
+        // Message::tool still convert
+        assert_eq!(OpenAIFormat::default().from_session(&session).unwrap()[2].role, "user");
+        assert!(BedrockFormat::default().from_session(&session).is_ok());
+        assert!(PromptStringFormat::default().from_session(&session).is_ok());
+
+        let openai = OpenAIFormat::default().with_lossy(LossyConversion::Reject);
+        let err = openai.from_session(&session).unwrap_err();
+        assert!(matches!(err, ContextError::UnsupportedRole { index: 2, ref role } if role == "critic"));
+        // Bedrock sends tool messages without a call id as user text, so those fail first
+        let bedrock = BedrockFormat::default().with_lossy(LossyConversion::Reject);
+        let err = bedrock.from_session(&session).unwrap_err();
+        assert!(matches!(err, ContextError::UnsupportedRole { index: 1, ref role } if role == "tool"));
+        let prompt = PromptStringFormat::default().with_lossy(LossyConversion::Reject);
+        assert!(prompt.from_session(&session).is_err());
+
+        let mut calls = Session::with_name("calls".to_string());
+        calls.add_message(
+            Message::assistant("Calling".to_string()).with_metadata(
+                "tool_calls".to_string(),
+                serde_json::json!([{ "id": "call_1", "function": { "name": "ls", "arguments": "{}" } }]),
+            ),
+        );
+        // OpenAI and Converse carry tool calls; a flat prompt can't
+        assert!(openai.from_session(&calls).is_ok());
+        assert!(bedrock.from_session(&calls).is_ok());
+        let err = prompt.from_session(&calls).unwrap_err();
+        assert!(matches!(err, ContextError::UnsupportedContent { index: 0, ref reason } if reason.contains("tool_calls")));
+
+        let external = [
+            OpenAIMessage::new("user", "Hi"),
+            OpenAIMessage::new("developer", "Be terse"),
+        ];
+        let err = openai.to_session(&external, "imported".to_string()).unwrap_err();
+        assert!(matches!(err, ContextError::UnsupportedRole { index: 1, ref role } if role == "developer"));
+        let faithful = Session::with_name("ok".to_string());
+        assert!(bedrock.from_session(&faithful).unwrap()[0].messages.is_empty());
+    }
+
+    #[test]
+    fn test_formats_sanitize_content() {
+        let mut session = Session::with_name("test".to_string());
+        session.add_message(Message::system("Rules\0".to_string()));
+        session.add_message(Message::user("pasted\0 bytes\u{1b}[0m\n".to_string()));
+
+        let openai = OpenAIFormat::default().from_session(&session).unwrap();
+        assert_eq!(openai[1].content, "pasted bytes\u{1b}[0m\n");
+        let strict = OpenAIFormat::default().with_sanitizer(ContentSanitizer::StripControl);
+        assert_eq!(strict.from_session(&session).unwrap()[1].content, "pasted bytes[0m\n");
+        let raw = OpenAIFormat::default().with_sanitizer(ContentSanitizer::Raw);
+        assert_eq!(raw.from_session(&session).unwrap()[1].content, session.messages()[1].content);
+
+        let converse = BedrockFormat::default().to_converse(&session).unwrap();
+        assert_eq!(converse.system[0].text, "Rules");
+        assert!(!PromptStringFormat::default().render(&session).contains('\0'));
+        // JSON stays lossless unless asked
+        assert_eq!(JsonFormat::default().from_session(&session).unwrap()[0]["content"], "Rules\0");
+        let cleaned = JsonFormat::default().with_sanitizer(ContentSanitizer::StripNul).from_session(&session).unwrap();
+        assert_eq!(cleaned[0]["content"], "Rules");
+        assert!(matches!(ContentSanitizer::StripNul.apply("clean"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_json_format_round_trip() {
+        let mut session = Session::with_name("test".to_string());
+        session.add_message(Message::system("You are helpful".to_string()));
+        session.add_message(
+            Message::tool("42".to_string())
+                .with_metadata("tool_call_id".to_string(), serde_json::json!("call_1")),
+        );
+
+        let format = JsonFormat::default();
+        let values = format.from_session(&session).unwrap();
+        assert_eq!(values[0]["role"], "system");
+        assert_eq!(values[1]["content"], "42");
+        assert_eq!(values[1]["metadata"]["tool_call_id"], "call_1");
+
+        let converted = format.to_session(&values, "converted".to_string()).unwrap();
+        assert_eq!(converted.messages()[1].role, MessageRole::Tool);
+        assert_eq!(converted.messages()[1].tool_call_id(), Some("call_1"));
+        assert_eq!(converted.messages()[1].id, session.messages()[1].id);
+        assert_eq!(converted.messages()[1].timestamp, session.messages()[1].timestamp);
+
+        let external = [serde_json::json!({
+            "id": "7d444840-9dc0-11d1-b245-5ffdce74fad2",
+            "timestamp": "2024-01-02T03:04:05Z",
+            "role": "user",
+            "content": "imported",
+        })];
+        let imported = format.to_session(&external, "imported".to_string()).unwrap();
+        assert_eq!(imported.messages()[0].id.to_string(), "7d444840-9dc0-11d1-b245-5ffdce74fad2");
+        assert_eq!(imported.messages()[0].timestamp.to_rfc3339(), "2024-01-02T03:04:05+00:00");
+
+        let bad = [serde_json::json!({ "role": "user" })];
+        assert!(format.to_session(&bad, "bad".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_system_placement() {
+        let mut session = Session::with_name("test".to_string());
+        session.add_message(Message::user("Hello".to_string()));
+        session.add_message(Message::system("Be brief".to_string()));
+        session.add_message(Message::assistant("Hi!".to_string()));
+
+        let first = OpenAIFormat::default().from_session(&session).unwrap();
+        assert_eq!(first[0].role, "system");
+        assert_eq!(first[1].content, "Hello");
+
+        let merged = BedrockFormat::default()
+            .with_system_placement(SystemPlacement::MergeIntoFirstUser)
+            .to_converse(&session)
+            .unwrap();
+        assert!(merged.system.is_empty());
+        assert_eq!(merged.messages.len(), 2);
+        assert_eq!(merged.messages[0].role, "user");
+        assert_eq!(merged.messages[0].content, [ContentBlock::Text("Be brief\n\nHello".to_string())]);
+
+        let format = OpenAIFormat::default().with_system_placement(SystemPlacement::SeparateField);
+        let separate = format.from_session(&session).unwrap();
+        assert_eq!(separate.len(), 2);
+        assert!(separate.iter().all(|m| m.role != "system"));
+        assert_eq!(format.system_prompt(&session).as_deref(), Some("Be brief"));
+    }
+
+    #[test]
+    fn test_openai_dangling_tool_messages() {
+        let mut session = Session::with_name("test".to_string());
+        session.add_message(Message::user("List files".to_string()));
+        session.add_message(Message::tool("orphan".to_string()));
+        session.add_message(
+            Message::assistant(String::new())
+                .with_metadata("tool_calls".to_string(), serde_json::json!([{ "id": "call_1" }])),
+        );
+        session.add_message(Message::user("Thanks".to_string()));
+        session.add_message(
+            Message::tool("a.txt b.txt".to_string())
+                .with_metadata("tool_call_id".to_string(), serde_json::json!("call_1")),
+        );
+
+        let dangling = session.validate_tool_pairing().unwrap_err();
+        assert_eq!(dangling, vec![session.messages()[1].id, session.messages()[4].id]);
+
+        let kept = OpenAIFormat::default().from_session(&session).unwrap();
+        assert_eq!(kept.len(), 5);
+
+        let dropped = OpenAIFormat::default()
+            .with_dangling_tools(DanglingToolPolicy::Drop)
+            .from_session(&session)
+            .unwrap();
+        let roles: Vec<&str> = dropped.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, ["user", "assistant", "user"]);
+
+        let reordered = OpenAIFormat::default()
+            .with_dangling_tools(DanglingToolPolicy::Reorder)
+            .from_session(&session)
+            .unwrap();
+        let contents: Vec<&str> = reordered.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["List files", "", "a.txt b.txt", "Thanks"]);
+
+        // The reordered tool result answers the call its assistant message requests
+        let body = serde_json::to_value(&reordered).unwrap();
+        assert_eq!(body[1]["role"], "assistant");
+        assert_eq!(body[1]["tool_calls"][0]["id"], "call_1");
+        assert_eq!(body[2]["role"], "tool");
+        assert_eq!(body[2]["tool_call_id"], body[1]["tool_calls"][0]["id"]);
+        assert!(body[0].get("tool_calls").is_none() && body[0].get("tool_call_id").is_none());
+    }
+
+    #[test]
+    fn test_openai_reorder_pairs_only_by_call_id() {
+        let mut session = Session::with_name("test".to_string());
+        session.add_message(Message::user("Read both".to_string()));
+        session.add_message(
+            Message::assistant(String::new())
+                .with_metadata("tool_calls".to_string(), serde_json::json!([{ "id": "call_1" }, { "id": "call_2" }])),
+        );
+        session.add_message(Message::user("Any luck?".to_string()));
+        session.add_message(Message::tool("unlabelled".to_string()));
+        session.add_message(
+            Message::tool("stale".to_string()).with_metadata("tool_call_id".to_string(), serde_json::json!("call_9")),
+        );
+        session.add_message(
+            Message::tool("second".to_string()).with_metadata("tool_call_id".to_string(), serde_json::json!("call_2")),
+        );
+        session.add_message(
+            Message::tool("first".to_string()).with_metadata("tool_call_id".to_string(), serde_json::json!("call_1")),
+        );
+
+        // Matched results move behind their call; a result without an id isn't
+        // paired with the nearest call, and one for an unknown call is dropped too
+        let reordered = OpenAIFormat::default()
+            .with_dangling_tools(DanglingToolPolicy::Reorder)
+            .from_session(&session)
+            .unwrap();
+        let contents: Vec<&str> = reordered.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["Read both", "", "second", "first", "Any luck?"]);
+        let ids: Vec<Option<&str>> = reordered.iter().map(|m| m.tool_call_id.as_deref()).collect();
+        assert_eq!(ids, [None, None, Some("call_2"), Some("call_1"), None]);
+    }
+
+    #[test]
+    fn test_prompt_string_format_frames_roles() {
+        let mut session = Session::with_name("test".to_string());
+        session.add_message(Message::system("You are helpful".to_string()));
+        session.add_message(Message::user("Hi".to_string()));
+        session.add_message(Message::assistant("Hello!".to_string()));
+        session.add_message(Message::user("Summarize\n\nthis".to_string()));
+
+        let format = PromptStringFormat::default();
+        let prompt = format.render(&session);
+        assert_eq!(prompt, "You are helpful\n\nHuman: Hi\n\nAssistant: Hello!\n\nHuman: Summarize\n\nthis\n\nAssistant:");
+        let rendered = format.from_session(&session).unwrap();
+        assert_eq!(rendered, [prompt]);
+
+        let parsed = format.to_session(&rendered, "parsed".to_string()).unwrap();
+        let roles: Vec<(MessageRole, &str)> = parsed.messages().iter().map(|m| (m.role.clone(), m.content.as_str())).collect();
+        assert_eq!(roles, [
+            (MessageRole::System, "You are helpful"),
+            (MessageRole::User, "Hi"),
+            (MessageRole::Assistant, "Hello!"),
+            (MessageRole::User, "Summarize\n\nthis"),
+        ]);
+
+        // A trailing assistant message is continued rather than cued
+        session.add_message(Message::assistant("Sure, in short:".to_string()));
+        assert!(format.render(&session).ends_with("\n\nAssistant: Sure, in short:"));
+
+        let chatml = PromptStringFormat::default()
+            .with_separator("\n")
+            .with_role_prefix(MessageRole::System, "<system> ")
+            .with_role_prefix(MessageRole::User, "<user> ")
+            .with_role_prefix(MessageRole::Assistant, "<bot> ")
+            .with_assistant_cue(false);
+        let mut short = Session::with_name("short".to_string());
+        short.add_message(Message::system("Be terse".to_string()));
+        short.add_message(Message::user("Hi".to_string()));
+        assert_eq!(chatml.render(&short), "\n<system> Be terse\n<user> Hi");
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,214 @@
+//! Importing conversations exported by other tools
+
+use crate::error::{ContextError, Result};
+use crate::session::{Message, MessageRole, Session};
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::collections::HashSet;
+use uuid::Uuid;
+
+impl Session {
+    /// Sessions from a ChatGPT data export (`conversations.json`)
+    ///
+    /// Accepts the whole export (an array of conversations) or a single
+    /// conversation. Each conversation is a tree of edits and regenerations;
+    /// only the branch ending at its `current_node`, the one shown in ChatGPT,
+    /// is kept. Titles become session names and original timestamps are
+    /// kept. Ids that are UUIDs are reused, so importing twice yields the same
+    /// sessions. Hidden and empty messages (ChatGPT's blank system prompt,
+    /// image-only turns) are skipped, and unrecognized author roles become
+    /// `MessageRole::Unknown`.
+    ///
+    /// Fails with `ContextError::InvalidSession` if a conversation lacks its
+    /// `mapping`, or its `current_node` path is broken or loops.
+    pub fn from_chatgpt_export(json: &Value) -> Result<Vec<Session>> {
+        match json {
+            Value::Array(conversations) => conversations.iter().map(chatgpt_conversation).collect(),
+            conversation => Ok(vec![chatgpt_conversation(conversation)?]),
+        }
+    }
+}
+
+fn chatgpt_conversation(conversation: &Value) -> Result<Session> {
+    let invalid = |reason: &str| ContextError::InvalidSession(format!("ChatGPT export: {}", reason));
+    let mapping = conversation
+        .get("mapping")
+        .and_then(Value::as_object)
+        .ok_or_else(|| invalid("conversation has no mapping"))?;
+
+    // Walk from the current node up to the root, then replay it forwards
+    let mut path = Vec::new();
+    let mut seen = HashSet::new();
+    let mut node_id = conversation.get("current_node").and_then(Value::as_str);
+    while let Some(id) = node_id {
+        if !seen.insert(id) {
+            return Err(invalid(&format!("node {} is its own ancestor", id)));
+        }
+        let node = mapping
+            .get(id)
+            .ok_or_else(|| invalid(&format!("node {} is missing from the mapping", id)))?;
+        path.push(node);
+        node_id = node.get("parent").and_then(Value::as_str);
+    }
+
+    let created_at = conversation.get("create_time").and_then(timestamp);
+    let title = conversation
+        .get("title")
+        .and_then(Value::as_str)
+        .filter(|title| !title.is_empty())
+        .unwrap_or("Imported conversation");
+    let mut session = Session::with_name(title.to_string());
+    if let Some(id) = conversation
+        .get("conversation_id")
+        .or_else(|| conversation.get("id"))
+        .and_then(uuid)
+    {
+        session.id = id;
+    }
+
+    for node in path.into_iter().rev() {
+        if let Some(message) = node.get("message").and_then(|message| chatgpt_message(message, created_at)) {
+            session.add_message(message);
+        }
+    }
+
+    if let Some(created_at) = created_at {
+        session.created_at = created_at;
+    }
+    session.updated_at = conversation
+        .get("update_time")
+        .and_then(timestamp)
+        .or_else(|| session.messages().last().map(|m| m.timestamp))
+        .unwrap_or(session.created_at);
+    Ok(session)
+}
+
+/// A message node's message, or `None` if it shows nothing
+fn chatgpt_message(message: &Value, fallback_time: Option<DateTime<Utc>>) -> Option<Message> {
+    let hidden = message
+        .pointer("/metadata/is_visually_hidden_from_conversation")
+        .and_then(Value::as_bool)
+        .unwrap_or(false);
+    if hidden {
+        return None;
+    }
+
+    let content = message.get("content")?;
+    let text = match content.get("parts").and_then(Value::as_array) {
+        Some(parts) => parts.iter().filter_map(Value::as_str).collect::<Vec<_>>().join("\n"),
+        None => content.get("text").and_then(Value::as_str).unwrap_or_default().to_string(),
+    };
+    if text.trim().is_empty() {
+        return None;
+    }
+
+    let role = message.pointer("/author/role").and_then(Value::as_str).unwrap_or("user");
+    let role = serde_json::from_value(Value::String(role.to_string()))
+        .unwrap_or_else(|_| MessageRole::Unknown(role.to_string()));
+    let mut imported = Message::new(role, text);
+    if let Some(id) = message.get("id").and_then(uuid) {
+        imported.id = id;
+    }
+    if let Some(time) = message.get("create_time").and_then(timestamp).or(fallback_time) {
+        imported = imported.with_timestamp(time);
+    }
+    Some(imported)
+}
+
+/// ChatGPT timestamps are fractional Unix seconds
+fn timestamp(value: &Value) -> Option<DateTime<Utc>> {
+    let seconds = value.as_f64()?;
+    DateTime::from_timestamp_millis((seconds * 1000.0).round() as i64)
+}
+
+fn uuid(value: &Value) -> Option<Uuid> {
+    value.as_str().and_then(|id| Uuid::parse_str(id).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_chatgpt_export_follows_current_branch() {
+        let conversation_id = Uuid::new_v4();
+        let answer_id = Uuid::new_v4();
+        let export = json!([{
+            "title": "Parser help",
+            "create_time": 1700000000.5,
+            "update_time": 1700000600.0,
+            "conversation_id": conversation_id.to_string(),
+            "current_node": "regenerated",
+            "mapping": {
+                "root": { "id": "root", "message": null, "parent": null, "children": ["system"] },
+                "system": {
+                    "id": "system",
+                    "message": {
+                        "author": { "role": "system" },
+                        "content": { "content_type": "text", "parts": [""] },
+                        "metadata": { "is_visually_hidden_from_conversation": true }
+                    },
+                    "parent": "root",
+                    "children": ["question"]
+                },
+                "question": {
+                    "id": "question",
+                    "message": {
+                        "author": { "role": "user" },
+                        "create_time": 1700000100.0,
+                        "content": { "content_type": "text", "parts": ["Why does", "this fail?"] }
+                    },
+                    "parent": "system",
+                    "children": ["first", "regenerated"]
+                },
+                "first": {
+                    "id": "first",
+                    "message": {
+                        "author": { "role": "assistant" },
+                        "content": { "content_type": "text", "parts": ["Abandoned answer"] }
+                    },
+                    "parent": "question",
+                    "children": []
+                },
+                "regenerated": {
+                    "id": "regenerated",
+                    "message": {
+                        "id": answer_id.to_string(),
+                        "author": { "role": "assistant" },
+                        "create_time": 1700000200.0,
+                        "content": { "content_type": "code", "text": "fn main() {}" }
+                    },
+                    "parent": "question",
+                    "children": []
+                }
+            }
+        }]);
+
+        let sessions = Session::from_chatgpt_export(&export).unwrap();
+        assert_eq!(sessions.len(), 1);
+        let session = &sessions[0];
+        assert_eq!((session.id, session.name.as_str()), (conversation_id, "Parser help"));
+        assert_eq!(session.created_at.timestamp_millis(), 1_700_000_000_500);
+        assert_eq!(session.updated_at.timestamp(), 1_700_000_600);
+
+        let messages: Vec<(MessageRole, &str)> = session.messages().iter()
+            .map(|m| (m.role.clone(), m.content.as_str()))
+            .collect();
+        assert_eq!(messages, [
+            (MessageRole::User, "Why does\nthis fail?"),
+            (MessageRole::Assistant, "fn main() {}"),
+        ]);
+        assert_eq!(session.messages()[0].timestamp.timestamp(), 1_700_000_100);
+        assert_eq!(session.messages()[1].id, answer_id);
+        assert_eq!(session.total_tokens(), session.messages().iter().map(|m| m.estimate_tokens()).sum::<usize>());
+
+        // A single conversation works too; a broken parent chain does not
+        assert_eq!(Session::from_chatgpt_export(&export[0]).unwrap()[0].id, conversation_id);
+        let mut broken = export[0].clone();
+        broken["mapping"]["question"]["parent"] = json!("missing");
+        let err = Session::from_chatgpt_export(&broken).unwrap_err();
+        assert!(matches!(err, ContextError::InvalidSession(ref reason) if reason.contains("missing")));
+        assert!(Session::from_chatgpt_export(&json!({ "title": "no mapping" })).is_err());
+    }
+}
@@ -15,7 +15,7 @@
 //!
 //! # fn example() -> Result<(), Box<dyn std::error::Error>> {
 //! // Create a session manager
-//! let mut manager = SessionManager::new()?;
+//! let manager = SessionManager::new()?;
 //!
 //! // Load the latest session (or create new if none exists)
 //! let mut session = manager.load_latest()?;
@@ -33,13 +33,40 @@ pub mod session;
 pub mod compaction;
 pub mod format;
 pub mod storage;
+pub mod log_storage;
+pub mod async_storage;
+pub mod cached_storage;
 pub mod error;
+pub mod export;
+pub mod metrics;
+pub mod events;
+pub mod clock;
+pub mod naming;
+pub mod tokens;
+pub mod search;
+pub mod import;
+pub mod compare;
+mod crypto;
+#[cfg(test)]
+mod test_support;
 
-pub use session::{Session, SessionManager, Message, MessageRole};
-pub use compaction::{CompactionStrategy, ContextCompactor};
+pub use session::{merge_timeline, AddMessageOutcome, ContentPolicy, Durability, SaveFailurePolicy, Session, SessionIdVersion, SessionManager, SessionStats, Message, MessageRole};
+pub use compaction::{compact_messages, CompactionPreview, CompactionStrategy, ContextCompactor, PinnedRange, RetentionOptions};
 pub use format::MessageFormat;
-pub use storage::SessionStorage;
+pub use storage::{migrate_storage, parse_session_id, LazySession, MigrationReport, SerializationFormat, SessionStorage, ShardLayout};
+#[cfg(feature = "watch")]
+pub use storage::StorageEvent;
+pub use log_storage::LogStorage;
+pub use async_storage::{AsyncSessionStorage, AsyncSessionManager};
+pub use cached_storage::CachedStorage;
 pub use error::{ContextError, Result};
+pub use metrics::{Metrics, NoopMetrics};
+pub use events::SessionEvent;
+pub use tokens::{Pricing, TokenDrift, TokenModel, Tokenizer};
+pub use search::SearchHit;
+pub use compare::ContentDiff;
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use naming::{SessionNamer, TimestampNamer};
 
 /// Default configuration for session management
 pub struct Config {
@@ -51,6 +78,38 @@ pub struct Config {
     pub storage_dir: Option<std::path::PathBuf>,
     /// Whether to auto-save sessions after each message
     pub auto_save: bool,
+    /// Whether auto-save writes new sessions before they have any messages;
+    /// when false, an empty session is first saved by `add_message`
+    pub persist_empty_sessions: bool,
+    /// Whether `list_sessions` and `load_latest` pass over sessions without
+    /// messages
+    pub skip_empty_sessions: bool,
+    /// How often auto-save writes; see [`Durability`] for what a crash can lose
+    pub durability: Durability,
+    /// Hard cap on messages per session, checked after compaction (`None` = unlimited)
+    pub max_messages: Option<usize>,
+    /// Hard cap on a session's serialized JSON size in bytes, checked after compaction (`None` = unlimited)
+    pub max_bytes: Option<usize>,
+    /// Hard cap on a message's serialized JSON metadata size in bytes, checked before it is added (`None` = unlimited)
+    pub max_metadata_bytes: Option<usize>,
+    /// What to do with control characters (other than newlines and tabs) in
+    /// added message content
+    pub content_policy: ContentPolicy,
+    /// What `add_message` does when its auto-save fails
+    pub save_failure_policy: SaveFailurePolicy,
+    /// Delete sessions not updated within this long when the manager is created (`None` = keep forever)
+    pub max_session_age: Option<std::time::Duration>,
+    /// Whether `load_latest` drops a trailing empty or incomplete assistant message
+    pub trim_incomplete_on_load: bool,
+    /// UUID version for ids of sessions the manager creates
+    pub session_id_version: SessionIdVersion,
+    /// Token estimation used for `max_tokens` and compaction budgets (`None` =
+    /// `Message::estimate_tokens`)
+    pub token_model: Option<TokenModel>,
+    /// Fraction of `max_tokens` to compact down to once the limit is hit, so
+    /// the next few messages don't immediately re-trigger compaction; must be
+    /// in (0, 1]
+    pub compaction_target_ratio: f32,
 }
 
 impl Default for Config {
@@ -60,9 +119,45 @@ impl Default for Config {
             compaction_strategy: CompactionStrategy::SystemAndRecent {
                 system_tokens: 1000,
                 recent_tokens: 6000,
+                options: RetentionOptions::default(),
             },
             storage_dir: None, // Will use default user config dir
             auto_save: true,
+            persist_empty_sessions: true,
+            skip_empty_sessions: false,
+            durability: Durability::EveryMessage,
+            max_messages: None,
+            max_bytes: None,
+            max_metadata_bytes: None,
+            content_policy: ContentPolicy::Allow,
+            save_failure_policy: SaveFailurePolicy::PropagateAndKeep,
+            max_session_age: None,
+            trim_incomplete_on_load: false,
+            session_id_version: SessionIdVersion::V4,
+            token_model: None,
+            compaction_target_ratio: 1.0,
         }
     }
-}
\ No newline at end of file
+}
+
+impl Config {
+    /// Token count compaction aims for: `max_tokens * compaction_target_ratio`
+    pub fn compaction_target(&self) -> usize {
+        (self.max_tokens as f32 * self.compaction_target_ratio) as usize
+    }
+
+    /// Check settings whose types allow values the managers can't use
+    ///
+    /// Fails with `ContextError::Config` if `compaction_target_ratio` is not
+    /// in (0, 1], including NaN, or if the compaction strategy's budgets
+    /// exceed [`Config::compaction_target`]; see [`CompactionStrategy::validate`].
+    pub fn validate(&self) -> Result<()> {
+        if !(self.compaction_target_ratio > 0.0 && self.compaction_target_ratio <= 1.0) {
+            return Err(ContextError::Config(format!(
+                "compaction_target_ratio must be in (0, 1], got {}",
+                self.compaction_target_ratio
+            )));
+        }
+        self.compaction_strategy.validate(self.compaction_target())
+    }
+}
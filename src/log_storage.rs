@@ -0,0 +1,789 @@
+//! Append-only log storage for write-heavy workloads
+//!
+//! Each session lives in `<id>.ndjson`, one JSON record per line. A save only
+//! appends the messages added since the previous save, or a record of the new
+//! `updated_at` and version if there are none; loading replays the log
+//! from its most recent snapshot. Once a log has grown by `snapshot_every`
+//! records it is rewritten as a single snapshot so replay stays cheap.
+
+use crate::clock::{Clock, SessionClock};
+use crate::error::{ContextError, Result};
+use crate::session::{Message, MessageRole, Session};
+use crate::storage::{
+    content_preview, set_mode, SessionInfo, SessionStorage, DEFAULT_FILE_MODE, DEFAULT_GROUP_KEY, DEFAULT_PREVIEW_CHARS,
+    SESSIONS_DIR_MODE, STARRED_KEY,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+/// Default number of appended records before the log is rewritten as a snapshot
+const DEFAULT_SNAPSHOT_EVERY: usize = 100;
+
+/// A single line in a session log
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum LogRecord {
+    /// The full session, replacing everything before it
+    Snapshot { session: Session },
+    /// One message appended to the session
+    Message {
+        message: Message,
+        updated_at: DateTime<Utc>,
+        #[serde(default)]
+        version: u64,
+    },
+    /// A save that added no messages, only bumping the session's times
+    Update { updated_at: DateTime<Utc>, version: u64 },
+}
+
+/// What is already on disk for a session, so saves know what to append
+#[derive(Debug, Clone)]
+struct LogState {
+    message_count: usize,
+    /// Chained hash of every logged message, so in-place edits are noticed
+    fingerprint: [u8; 32],
+    /// [`Session::history_mark`] of the session last saved, which can skip
+    /// rehashing the logged messages while it is unchanged
+    history: Option<u64>,
+    /// `seq` the next appended message gets
+    next_seq: u64,
+    name: String,
+    metadata: BTreeMap<String, serde_json::Value>,
+    group: Option<String>,
+    updated_at: DateTime<Utc>,
+    version: u64,
+    records_since_snapshot: usize,
+    /// The log ends in a torn record, so the next save must rewrite it
+    needs_snapshot: bool,
+}
+
+impl LogState {
+    fn of(session: &Session, records_since_snapshot: usize) -> Result<Self> {
+        Ok(Self {
+            message_count: session.messages().len(),
+            fingerprint: fingerprint(session.messages())?,
+            history: None,
+            next_seq: session.messages().last().map_or(0, |m| m.seq + 1),
+            name: session.name.clone(),
+            metadata: session.metadata.clone(),
+            group: session.group.clone(),
+            updated_at: session.updated_at,
+            version: session.version,
+            records_since_snapshot,
+            needs_snapshot: false,
+        })
+    }
+
+    /// The state once `session`'s messages past the logged ones are appended,
+    /// in `records` records
+    ///
+    /// Only the new messages are hashed, so a save costs what it appends.
+    fn appended(&self, session: &Session, records: usize) -> Result<Self> {
+        let new_messages = &session.messages()[self.message_count..];
+        Ok(Self {
+            message_count: session.messages().len(),
+            fingerprint: new_messages.iter().try_fold(self.fingerprint, chain_fingerprint)?,
+            history: Some(session.history_mark()),
+            next_seq: new_messages.last().map_or(self.next_seq, |m| m.seq + 1),
+            updated_at: session.updated_at,
+            version: session.version,
+            records_since_snapshot: self.records_since_snapshot + records,
+            ..self.clone()
+        })
+    }
+
+    /// Whether `session` only differs from the logged state by new trailing messages
+    fn is_prefix_of(&self, session: &Session) -> Result<bool> {
+        if self.needs_snapshot
+            || session.messages().len() < self.message_count
+            || session.name != self.name
+            || session.metadata != self.metadata
+            || session.group != self.group
+        {
+            return Ok(false);
+        }
+        if self.history == Some(session.history_mark()) {
+            return Ok(true);
+        }
+
+        Ok(fingerprint(&session.messages()[..self.message_count])? == self.fingerprint)
+    }
+}
+
+/// Chain `message`'s serialized form onto the fingerprint of the messages before it
+fn chain_fingerprint(chain: [u8; 32], message: &Message) -> Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    hasher.update(chain);
+    hasher.update(serde_json::to_vec(message)?);
+    Ok(hasher.finalize().into())
+}
+
+fn fingerprint(messages: &[Message]) -> Result<[u8; 32]> {
+    messages.iter().try_fold([0u8; 32], chain_fingerprint)
+}
+
+/// Append-only log session storage
+pub struct LogStorage {
+    sessions_dir: PathBuf,
+    latest_file: PathBuf,
+    snapshot_every: usize,
+    state: Mutex<HashMap<Uuid, LogState>>,
+    clock: Option<Arc<dyn Clock>>,
+}
+
+impl LogStorage {
+    /// Create a log storage instance in the given directory
+    ///
+    /// As with [`FileStorage`](crate::storage::FileStorage), a new directory
+    /// is made owner-only and logs are written with [`DEFAULT_FILE_MODE`].
+    pub fn with_directory<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let sessions_dir = dir.as_ref().to_path_buf();
+        let latest_file = sessions_dir.join("latest.txt");
+
+        if !sessions_dir.exists() {
+            fs::create_dir_all(&sessions_dir)
+                .map_err(|e| ContextError::Storage(format!("Failed to create sessions directory: {}", e)))?;
+            set_mode(&sessions_dir, SESSIONS_DIR_MODE)?;
+            info!("Created sessions directory: {}", sessions_dir.display());
+        }
+
+        Ok(Self {
+            sessions_dir,
+            latest_file,
+            snapshot_every: DEFAULT_SNAPSHOT_EVERY,
+            state: Mutex::new(HashMap::new()),
+            clock: None,
+        })
+    }
+
+    /// Set how many appended records trigger a snapshot rewrite
+    pub fn with_snapshot_every(mut self, records: usize) -> Self {
+        self.snapshot_every = records.max(1);
+        self
+    }
+
+    /// Stamp messages added with [`append_message`](SessionStorage::append_message) from `clock`
+    ///
+    /// The record's `updated_at` is read from it, and fresh messages are
+    /// stamped as [`Session::with_clock`] would.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Rewrite a session's log as a single snapshot
+    pub fn compact_log(&self, session_id: &Uuid) -> Result<()> {
+        let session = self.load_session(session_id)?;
+        self.write_snapshot(&session)
+    }
+
+    fn log_file_path(&self, session_id: &Uuid) -> PathBuf {
+        self.sessions_dir.join(format!("{}.ndjson", session_id))
+    }
+
+    fn lock_state(&self) -> std::sync::MutexGuard<'_, HashMap<Uuid, LogState>> {
+        // The map is only a cache of on-disk state, so a poisoned lock is still usable
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Replay a log file into the session and the on-disk state it was built from
+    fn replay(&self, file_path: &Path) -> Result<(Session, LogState)> {
+        let log_data = fs::read_to_string(file_path)
+            .map_err(|e| ContextError::Storage(format!("Failed to read session log: {}", e)))?;
+
+        let lines: Vec<&str> = log_data.lines().filter(|l| !l.trim().is_empty()).collect();
+        let mut session: Option<Session> = None;
+        let mut records_since_snapshot = 0;
+        let mut torn = false;
+
+        for (index, line) in lines.iter().enumerate() {
+            let record: LogRecord = match serde_json::from_str(line) {
+                Ok(record) => record,
+                // A torn final line means we crashed mid-append; everything before it is intact
+                Err(e) if index == lines.len() - 1 && session.is_some() => {
+                    warn!("Ignoring truncated last record in {}: {}", file_path.display(), e);
+                    torn = true;
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            match record {
+                LogRecord::Snapshot { session: snapshot } => {
+                    session = Some(snapshot);
+                    records_since_snapshot = 0;
+                }
+                LogRecord::Message { message, updated_at, version } => {
+                    let session = session.as_mut().ok_or_else(|| {
+                        ContextError::InvalidSession(format!("Log {} does not start with a snapshot", file_path.display()))
+                    })?;
+                    session.messages_mut().push(message);
+                    session.updated_at = updated_at;
+                    session.version = version;
+                    records_since_snapshot += 1;
+                }
+                LogRecord::Update { updated_at, version } => {
+                    let session = session.as_mut().ok_or_else(|| {
+                        ContextError::InvalidSession(format!("Log {} does not start with a snapshot", file_path.display()))
+                    })?;
+                    session.updated_at = updated_at;
+                    session.version = version;
+                    records_since_snapshot += 1;
+                }
+            }
+        }
+
+        let session = session
+            .ok_or_else(|| ContextError::InvalidSession(format!("Empty session log: {}", file_path.display())))?;
+        let mut state = LogState::of(&session, records_since_snapshot)?;
+        state.needs_snapshot = torn;
+        Ok((session, state))
+    }
+
+    /// The logged state for a session, replaying its log if it isn't cached
+    fn logged_state(&self, session_id: &Uuid) -> Result<Option<LogState>> {
+        if let Some(state) = self.lock_state().get(session_id) {
+            return Ok(Some(state.clone()));
+        }
+
+        let file_path = self.log_file_path(session_id);
+        if !file_path.exists() {
+            return Ok(None);
+        }
+
+        let (_, state) = self.replay(&file_path)?;
+        self.lock_state().insert(*session_id, state.clone());
+        Ok(Some(state))
+    }
+
+    fn write_snapshot(&self, session: &Session) -> Result<()> {
+        let file_path = self.log_file_path(&session.id);
+        let temp_path = file_path.with_extension("ndjson.tmp");
+
+        let mut line = serde_json::to_string(&LogRecord::Snapshot { session: session.clone() })?;
+        line.push('\n');
+
+        write_private(&temp_path, &line)
+            .map_err(|e| ContextError::Storage(format!("Failed to write session snapshot: {}", e)))?;
+        set_mode(&temp_path, DEFAULT_FILE_MODE)?;
+        fs::rename(&temp_path, &file_path)
+            .map_err(|e| ContextError::Storage(format!("Failed to replace session log: {}", e)))?;
+
+        let mut state = LogState::of(session, 0)?;
+        state.history = Some(session.history_mark());
+        self.lock_state().insert(session.id, state);
+        debug!("Wrote snapshot for session {}", session.id);
+        Ok(())
+    }
+
+    /// Append what `session` adds to the logged state: its new messages, or
+    /// an update record if it only moved `updated_at` or the version
+    fn append_messages(&self, session: &Session, state: &LogState) -> Result<()> {
+        let new_messages = &session.messages()[state.message_count..];
+        let mut lines = String::new();
+        for message in new_messages {
+            lines.push_str(&serde_json::to_string(&LogRecord::Message {
+                message: message.clone(),
+                updated_at: session.updated_at,
+                version: session.version,
+            })?);
+            lines.push('\n');
+        }
+        if new_messages.is_empty() && (session.updated_at, session.version) != (state.updated_at, state.version) {
+            lines.push_str(&serde_json::to_string(&LogRecord::Update {
+                updated_at: session.updated_at,
+                version: session.version,
+            })?);
+            lines.push('\n');
+        }
+
+        let records = lines.lines().count();
+        if records > 0 {
+            self.append_lines(&session.id, &lines)?;
+        }
+        // Also records the session's history mark, even if nothing was written
+        self.lock_state().insert(session.id, state.appended(session, records)?);
+        debug!("Appended {} records to session {}", records, session.id);
+        Ok(())
+    }
+
+    fn append_lines(&self, session_id: &Uuid, lines: &str) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(self.log_file_path(session_id))
+            .map_err(|e| ContextError::Storage(format!("Failed to open session log: {}", e)))?;
+        file.write_all(lines.as_bytes())
+            .map_err(|e| ContextError::Storage(format!("Failed to append to session log: {}", e)))
+    }
+
+    fn update_latest(&self, session_id: &Uuid) -> Result<()> {
+        write_private(&self.latest_file, &session_id.to_string())
+            .map_err(|e| ContextError::Storage(format!("Failed to update latest session: {}", e)))?;
+        set_mode(&self.latest_file, DEFAULT_FILE_MODE)
+    }
+
+    fn get_session_info(&self, file_path: &Path) -> Result<SessionInfo> {
+        let metadata = fs::metadata(file_path)
+            .map_err(|e| ContextError::Storage(format!("Failed to read file metadata: {}", e)))?;
+
+        let (session, _) = self.replay(file_path)?;
+
+        Ok(SessionInfo {
+            id: session.id,
+            name: session.name.clone(),
+            created_at: metadata.created().unwrap_or_else(|_| SystemTime::now()),
+            modified_at: metadata.modified().unwrap_or_else(|_| SystemTime::now()),
+            message_count: session.messages().len(),
+            total_tokens: session.total_tokens(),
+            file_path: file_path.to_path_buf(),
+            archived: false,
+            starred: session.get_meta_bool(STARRED_KEY) == Some(true),
+            preview: session.messages().iter()
+                .find(|m| m.role == MessageRole::User)
+                .or(session.messages().last())
+                .map(|m| content_preview(&m.content, DEFAULT_PREVIEW_CHARS)),
+            group: session.group.clone().or_else(|| session.get_meta_str(DEFAULT_GROUP_KEY).map(str::to_string)),
+        })
+    }
+}
+
+/// Create or truncate `path` with `data`, with [`DEFAULT_FILE_MODE`] if it is new
+fn write_private(path: &Path, data: &str) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(DEFAULT_FILE_MODE);
+    }
+
+    options.open(path)?.write_all(data.as_bytes())
+}
+
+impl SessionStorage for LogStorage {
+    /// Version conflicts are checked against this instance's view of the log,
+    /// so they catch writers sharing one `LogStorage` but not other processes.
+    fn save_session(&self, session: &Session) -> Result<()> {
+        let logged = self.logged_state(&session.id)?;
+        if let Some(state) = &logged {
+            session.check_version(state.version)?;
+        }
+
+        match logged {
+            Some(state) if state.is_prefix_of(session)? => {
+                self.append_messages(session, &state)?;
+
+                let logged = self.logged_state(&session.id)?;
+                if logged.is_some_and(|s| s.records_since_snapshot >= self.snapshot_every) {
+                    self.write_snapshot(session)?;
+                }
+            }
+            // New session, or history was rewritten (e.g. compaction): start a fresh snapshot
+            _ => self.write_snapshot(session)?,
+        }
+
+        self.update_latest(&session.id)
+    }
+
+    fn load_session(&self, session_id: &Uuid) -> Result<Session> {
+        let file_path = self.log_file_path(session_id);
+
+        if !file_path.exists() {
+            return Err(ContextError::SessionNotFound(session_id.to_string()));
+        }
+
+        let (session, state) = self.replay(&file_path)?;
+        self.lock_state().insert(*session_id, state);
+
+        debug!("Loaded session {} from {}", session_id, file_path.display());
+        Ok(session)
+    }
+
+    fn load_latest_session(&self) -> Result<Option<Session>> {
+        if !self.latest_file.exists() {
+            debug!("No latest session pointer found");
+            return Ok(None);
+        }
+
+        let latest = fs::read_to_string(&self.latest_file)
+            .map_err(|e| ContextError::Storage(format!("Failed to read latest session: {}", e)))?;
+        let session_id = Uuid::parse_str(latest.trim())
+            .map_err(|_| ContextError::Storage(format!("Invalid latest session id: {}", latest.trim())))?;
+
+        match self.load_session(&session_id) {
+            Ok(session) => Ok(Some(session)),
+            Err(ContextError::SessionNotFound(_)) => {
+                warn!("Latest session pointer refers to missing session {}", session_id);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn list_sessions(&self) -> Result<Vec<SessionInfo>> {
+        let mut sessions = Vec::new();
+
+        let entries = fs::read_dir(&self.sessions_dir)
+            .map_err(|e| ContextError::Storage(format!("Failed to read sessions directory: {}", e)))?;
+
+        for entry in entries {
+            let entry = entry
+                .map_err(|e| ContextError::Storage(format!("Failed to read directory entry: {}", e)))?;
+
+            let path = entry.path();
+            if path.extension().and_then(|s| s.to_str()) != Some("ndjson") {
+                continue;
+            }
+
+            match self.get_session_info(&path) {
+                Ok(info) => sessions.push(info),
+                Err(e) => warn!("Failed to get info for session log {}: {}", path.display(), e),
+            }
+        }
+
+        sessions.sort_by_key(|s| std::cmp::Reverse(s.modified_at));
+
+        debug!("Listed {} sessions", sessions.len());
+        Ok(sessions)
+    }
+
+    /// Writes a single record without replaying the log, unless this
+    /// instance hasn't seen the session yet
+    fn append_message(&self, session_id: &Uuid, message: &Message) -> Result<()> {
+        let mut state = self.logged_state(session_id)?
+            .ok_or_else(|| ContextError::SessionNotFound(session_id.to_string()))?;
+        if state.needs_snapshot {
+            // A torn log gets rewritten whole on the next save anyway
+            let mut session = self.load_session(session_id)?;
+            if let Some(clock) = &self.clock {
+                session.set_clock(Arc::clone(clock));
+            }
+            session.add_message(message.clone());
+            session.version += 1;
+            return self.save_session(&session);
+        }
+
+        let version = state.version + 1;
+        let mut sequenced = message.clone();
+        sequenced.seq = state.next_seq;
+        let clock = self.clock.clone().map(SessionClock::new).unwrap_or_default();
+        let updated_at = sequenced.stamp(&clock);
+        let fingerprint = chain_fingerprint(state.fingerprint, &sequenced)?;
+        let mut line = serde_json::to_string(&LogRecord::Message {
+            message: sequenced,
+            updated_at,
+            version,
+        })?;
+        line.push('\n');
+        self.append_lines(session_id, &line)?;
+
+        state.message_count += 1;
+        state.fingerprint = fingerprint;
+        // No in-memory session holds this message yet
+        state.history = None;
+        state.next_seq += 1;
+        state.updated_at = updated_at;
+        state.version = version;
+        state.records_since_snapshot += 1;
+        let snapshot_due = state.records_since_snapshot >= self.snapshot_every;
+        self.lock_state().insert(*session_id, state);
+        if snapshot_due {
+            self.write_snapshot(&self.load_session(session_id)?)?;
+        }
+
+        debug!("Appended message {} to session {}", message.id, session_id);
+        self.update_latest(session_id)
+    }
+
+    fn delete_session(&self, session_id: &Uuid) -> Result<()> {
+        let file_path = self.log_file_path(session_id);
+
+        if !file_path.exists() {
+            return Err(ContextError::SessionNotFound(session_id.to_string()));
+        }
+
+        fs::remove_file(&file_path)
+            .map_err(|e| ContextError::Storage(format!("Failed to delete session log: {}", e)))?;
+        self.lock_state().remove(session_id);
+
+        let latest = fs::read_to_string(&self.latest_file).unwrap_or_default();
+        if latest.trim() == session_id.to_string() {
+            fs::remove_file(&self.latest_file)
+                .map_err(|e| ContextError::Storage(format!("Failed to remove latest pointer: {}", e)))?;
+        }
+
+        info!("Deleted session {}", session_id);
+        Ok(())
+    }
+
+    fn cleanup_old_sessions(&self, keep_count: usize) -> Result<usize> {
+        let mut sessions = self.list_sessions()?;
+        sessions.retain(|info| !info.starred);
+
+        if sessions.len() <= keep_count {
+            debug!("No sessions to clean up (have {}, keeping {})", sessions.len(), keep_count);
+            return Ok(0);
+        }
+
+        let mut deleted_count = 0;
+        for session_info in &sessions[keep_count..] {
+            match self.delete_session(&session_info.id) {
+                Ok(()) => deleted_count += 1,
+                Err(e) => warn!("Failed to delete old session {}: {}", session_info.id, e),
+            }
+        }
+
+        info!("Cleaned up {} old sessions", deleted_count);
+        Ok(deleted_count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn log_lines(storage: &LogStorage, id: &Uuid) -> usize {
+        fs::read_to_string(storage.log_file_path(id)).unwrap().lines().count()
+    }
+
+    #[test]
+    fn test_log_storage_appends_and_replays() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = LogStorage::with_directory(temp_dir.path()).unwrap();
+
+        let mut session = Session::new();
+        session.add_message(Message::user("Hello".to_string()));
+        storage.save_session(&session).unwrap();
+        assert_eq!(log_lines(&storage, &session.id), 1);
+
+        session.add_message(Message::assistant("Hi there!".to_string()));
+        session.add_message(Message::user("How are you?".to_string()));
+        storage.save_session(&session).unwrap();
+        assert_eq!(log_lines(&storage, &session.id), 3);
+
+        // A fresh instance has no cached state and must replay from disk
+        let reopened = LogStorage::with_directory(temp_dir.path()).unwrap();
+        let loaded = reopened.load_latest_session().unwrap().unwrap();
+        assert_eq!(loaded.id, session.id);
+        assert_eq!(loaded.messages().len(), 3);
+        assert_eq!(loaded.messages()[2].content, "How are you?");
+
+        // Rewriting history falls back to a snapshot
+        session.messages_mut().remove(0);
+        reopened.save_session(&session).unwrap();
+        assert_eq!(log_lines(&reopened, &session.id), 1);
+        assert_eq!(reopened.load_session(&session.id).unwrap().messages().len(), 2);
+
+        reopened.delete_session(&session.id).unwrap();
+        assert!(reopened.list_sessions().unwrap().is_empty());
+        assert!(reopened.load_latest_session().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_log_storage_periodic_snapshot_and_torn_write() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = LogStorage::with_directory(temp_dir.path())
+            .unwrap()
+            .with_snapshot_every(3);
+
+        let mut session = Session::new();
+        storage.save_session(&session).unwrap();
+        for i in 0..3 {
+            session.add_message(Message::user(format!("Message {}", i)));
+            storage.save_session(&session).unwrap();
+        }
+        assert_eq!(log_lines(&storage, &session.id), 1);
+
+        session.add_message(Message::user("Message 3".to_string()));
+        storage.save_session(&session).unwrap();
+
+        // Simulate a crash halfway through writing a record
+        let mut file = OpenOptions::new().append(true).open(storage.log_file_path(&session.id)).unwrap();
+        file.write_all(b"{\"type\":\"message\",\"mess").unwrap();
+
+        let reopened = LogStorage::with_directory(temp_dir.path()).unwrap();
+        let mut loaded = reopened.load_session(&session.id).unwrap();
+        assert_eq!(loaded.messages().len(), 4);
+
+        // The next save rewrites the log rather than appending after the torn record
+        loaded.add_message(Message::user("Message 4".to_string()));
+        reopened.save_session(&loaded).unwrap();
+        assert_eq!(log_lines(&reopened, &session.id), 1);
+        assert_eq!(reopened.load_session(&session.id).unwrap().messages().len(), 5);
+    }
+
+    #[test]
+    fn test_append_message_writes_one_record() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = LogStorage::with_directory(temp_dir.path()).unwrap().with_snapshot_every(3);
+
+        let mut session = Session::new();
+        session.add_message(Message::user("Hello".to_string()));
+        session.version = 1;
+        storage.save_session(&session).unwrap();
+
+        storage.append_message(&session.id, &Message::assistant("Hi".to_string())).unwrap();
+        assert_eq!(log_lines(&storage, &session.id), 2);
+
+        // Other instances replay the appended record
+        let reopened = LogStorage::with_directory(temp_dir.path()).unwrap();
+        let loaded = reopened.load_session(&session.id).unwrap();
+        assert_eq!(loaded.messages().len(), 2);
+        assert_eq!(loaded.messages()[1].content, "Hi");
+        assert_eq!(loaded.version, 2);
+        assert!(matches!(storage.save_session(&session), Err(ContextError::Conflict(_))));
+
+        // Appends count towards the next snapshot
+        storage.append_message(&session.id, &Message::user("More".to_string())).unwrap();
+        storage.append_message(&session.id, &Message::assistant("Done".to_string())).unwrap();
+        assert_eq!(log_lines(&storage, &session.id), 1);
+        let loaded = storage.load_session(&session.id).unwrap();
+        assert_eq!(loaded.messages().iter().map(|m| m.seq).collect::<Vec<_>>(), [0, 1, 2, 3]);
+
+        let err = storage.append_message(&Uuid::new_v4(), &Message::user("lost".to_string())).unwrap_err();
+        assert!(matches!(err, ContextError::SessionNotFound(_)));
+    }
+
+    #[test]
+    fn test_append_message_uses_clock() {
+        let temp_dir = TempDir::new().unwrap();
+        let start = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        let clock = Arc::new(crate::ManualClock::new(start).with_step(chrono::Duration::seconds(1)));
+        let storage = LogStorage::with_directory(temp_dir.path()).unwrap().with_clock(clock.clone());
+
+        let session = Session::new();
+        storage.save_session(&session).unwrap();
+        storage.append_message(&session.id, &Message::user("Hello".to_string())).unwrap();
+        let imported = Message::user("imported".to_string()).with_timestamp(start - chrono::Duration::days(1));
+        storage.append_message(&session.id, &imported).unwrap();
+
+        let loaded = LogStorage::with_directory(temp_dir.path()).unwrap().load_session(&session.id).unwrap();
+        assert_eq!(loaded.messages()[0].timestamp, start);
+        assert_eq!(loaded.messages()[1].timestamp, start - chrono::Duration::days(1));
+        assert_eq!(loaded.updated_at, start + chrono::Duration::seconds(1));
+
+        // A torn log goes through a full save, on the same clock
+        let mut file = OpenOptions::new().append(true).open(storage.log_file_path(&session.id)).unwrap();
+        file.write_all(b"{\"type\":\"mess").unwrap();
+        let reopened = LogStorage::with_directory(temp_dir.path()).unwrap().with_clock(clock);
+        reopened.append_message(&session.id, &Message::user("After".to_string())).unwrap();
+        let loaded = reopened.load_session(&session.id).unwrap();
+        assert_eq!(loaded.messages()[2].timestamp, start + chrono::Duration::seconds(2));
+        assert_eq!(loaded.updated_at, start + chrono::Duration::seconds(2));
+    }
+
+    #[test]
+    fn test_edits_to_logged_messages_are_saved() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = LogStorage::with_directory(temp_dir.path()).unwrap();
+
+        let mut session = Session::new();
+        session.add_message(Message::user("Hello".to_string()));
+        storage.save_session(&session).unwrap();
+        session.add_message(Message::assistant("Hi".to_string()));
+        storage.save_session(&session).unwrap();
+        assert_eq!(log_lines(&storage, &session.id), 2);
+
+        session.messages_mut()[0].content = "Hello, edited".to_string();
+        storage.save_session(&session).unwrap();
+        assert_eq!(log_lines(&storage, &session.id), 1);
+
+        let reopened = LogStorage::with_directory(temp_dir.path()).unwrap();
+        let loaded = reopened.load_session(&session.id).unwrap();
+        assert_eq!(loaded.messages()[0].content, "Hello, edited");
+
+        // Appends after a reload still match the replayed prefix
+        let mut loaded = loaded;
+        loaded.add_message(Message::user("More".to_string()));
+        reopened.save_session(&loaded).unwrap();
+        assert_eq!(log_lines(&reopened, &session.id), 2);
+    }
+
+    #[test]
+    fn test_saves_only_hash_new_messages() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = LogStorage::with_directory(temp_dir.path()).unwrap();
+
+        let mut session = Session::new();
+        session.add_message(Message::user("Hello".to_string()));
+        storage.save_session(&session).unwrap();
+
+        // The logged messages aren't rehashed while the session only grows
+        storage.lock_state().get_mut(&session.id).unwrap().fingerprint = [0; 32];
+        session.add_message(Message::assistant("Hi".to_string()));
+        storage.save_session(&session).unwrap();
+        assert_eq!(log_lines(&storage, &session.id), 2);
+
+        // A copy is checked, and the wrong fingerprint forces a snapshot
+        let mut copy = session.clone();
+        copy.add_message(Message::user("More".to_string()));
+        storage.save_session(&copy).unwrap();
+        assert_eq!(log_lines(&storage, &session.id), 1);
+        copy.add_message(Message::assistant("Sure".to_string()));
+        storage.save_session(&copy).unwrap();
+        assert_eq!(log_lines(&storage, &session.id), 2);
+
+        // So is a session edited in place
+        copy.messages_mut()[0].content = "Hello, edited".to_string();
+        storage.save_session(&copy).unwrap();
+        assert_eq!(log_lines(&storage, &session.id), 1);
+    }
+
+    #[test]
+    fn test_saves_without_new_messages_are_logged() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = LogStorage::with_directory(temp_dir.path()).unwrap();
+
+        let mut session = Session::new();
+        session.add_message(Message::user("Hello".to_string()));
+        session.version = 1;
+        storage.save_session(&session).unwrap();
+
+        session.version = 2;
+        session.updated_at += chrono::Duration::minutes(5);
+        storage.save_session(&session).unwrap();
+        assert_eq!(log_lines(&storage, &session.id), 2);
+
+        let reopened = LogStorage::with_directory(temp_dir.path()).unwrap();
+        let loaded = reopened.load_session(&session.id).unwrap();
+        assert_eq!((loaded.updated_at, loaded.version), (session.updated_at, 2));
+
+        // The conflict check sees the new version
+        session.version = 2;
+        assert!(matches!(storage.save_session(&session), Err(ContextError::Conflict(_))));
+        assert!(matches!(reopened.save_session(&session), Err(ContextError::Conflict(_))));
+        session.version = 3;
+        storage.save_session(&session).unwrap();
+        assert_eq!(log_lines(&storage, &session.id), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_log_storage_file_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let sessions_dir = temp_dir.path().join("sessions");
+        let storage = LogStorage::with_directory(&sessions_dir).unwrap();
+
+        let mut session = Session::new();
+        session.add_message(Message::user("Secret prompt".to_string()));
+        storage.save_session(&session).unwrap();
+
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&sessions_dir), 0o700);
+        assert_eq!(mode(&storage.log_file_path(&session.id)), 0o600);
+        assert_eq!(mode(&sessions_dir.join("latest.txt")), 0o600);
+    }
+}
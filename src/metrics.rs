@@ -0,0 +1,44 @@
+//! Metrics hooks for session telemetry
+//!
+//! `SessionManager` reports saves, loads, and compactions through the
+//! [`Metrics`] trait so applications can forward them to Prometheus, StatsD,
+//! or whatever pipeline they run. Every method has a no-op default, so an
+//! implementation only needs to override what it exports.
+
+use crate::session::Session;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Receiver for session lifecycle metrics
+pub trait Metrics: Send + Sync {
+    /// A session was written to storage; use `session.total_tokens()` or
+    /// `session.messages().len()` to feed size gauges
+    fn record_save(&self, _session: &Session) {}
+
+    /// A session was read from storage
+    fn record_load(&self, _session: &Session) {}
+
+    /// Compaction ran on a session and removed `removed_tokens` estimated tokens
+    fn record_compaction(&self, _session_id: &Uuid, _removed_tokens: usize) {}
+}
+
+/// Lets callers keep a handle on the sink they hand to `SessionManager`
+impl<M: Metrics + ?Sized> Metrics for Arc<M> {
+    fn record_save(&self, session: &Session) {
+        (**self).record_save(session);
+    }
+
+    fn record_load(&self, session: &Session) {
+        (**self).record_load(session);
+    }
+
+    fn record_compaction(&self, session_id: &Uuid, removed_tokens: usize) {
+        (**self).record_compaction(session_id, removed_tokens);
+    }
+}
+
+/// Metrics sink that discards everything
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {}
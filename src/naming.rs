@@ -0,0 +1,48 @@
+//! Injectable naming schemes for sessions created without a name
+
+use crate::clock::{Clock, SystemClock};
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+
+/// Source of names for new sessions
+///
+/// Closures returning a `String` implement this, so an incrementing counter
+/// or a ticket lookup needs no wrapper type.
+pub trait SessionNamer: Send + Sync {
+    fn generate_name(&self) -> String;
+}
+
+impl<F: Fn() -> String + Send + Sync> SessionNamer for F {
+    fn generate_name(&self) -> String {
+        self()
+    }
+}
+
+/// The built-in scheme, `session-YYYYMMDD-HHMMSS` from the current time
+#[derive(Clone)]
+pub struct TimestampNamer {
+    clock: Arc<dyn Clock>,
+}
+
+impl TimestampNamer {
+    /// Name sessions after times read from `clock`
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self { clock }
+    }
+}
+
+impl Default for TimestampNamer {
+    fn default() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+}
+
+impl SessionNamer for TimestampNamer {
+    fn generate_name(&self) -> String {
+        timestamp_name(self.clock.now())
+    }
+}
+
+pub(crate) fn timestamp_name(now: DateTime<Utc>) -> String {
+    format!("session-{}", now.format("%Y%m%d-%H%M%S"))
+}
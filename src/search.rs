@@ -0,0 +1,149 @@
+//! Full-text search over session messages
+
+use crate::session::Session;
+use uuid::Uuid;
+
+/// Characters of context kept on each side of a match in [`SearchHit::snippet`]
+const SNIPPET_CONTEXT: usize = 40;
+
+/// One occurrence of a query in a message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchHit {
+    pub session_id: Uuid,
+    pub message_id: Uuid,
+    /// Zero-based position of the message within the session
+    pub message_index: usize,
+    /// Where the match starts in the message content, in characters
+    pub char_offset: usize,
+    /// Length of the match, in characters
+    pub match_len: usize,
+    /// The match with up to 40 characters of context either side, with `…`
+    /// marking cut-off content
+    pub snippet: String,
+}
+
+impl Session {
+    /// Every case-insensitive occurrence of `query` in this session's messages
+    ///
+    /// Hits are in message order, then by position within the message;
+    /// overlapping occurrences are reported once. An empty query matches
+    /// nothing.
+    pub fn search(&self, query: &str) -> Vec<SearchHit> {
+        let needle: Vec<char> = query.chars().flat_map(char::to_lowercase).collect();
+        if needle.is_empty() {
+            return Vec::new();
+        }
+
+        let mut hits = Vec::new();
+        for (message_index, message) in self.messages().iter().enumerate() {
+            let content: Vec<char> = message.content.chars().collect();
+            let mut start = 0;
+            while let Some(found) = find_from(&content, &needle, start) {
+                let (char_offset, match_len) = found;
+                hits.push(SearchHit {
+                    session_id: self.id,
+                    message_id: message.id,
+                    message_index,
+                    char_offset,
+                    match_len,
+                    snippet: snippet(&content, char_offset, match_len),
+                });
+                start = char_offset + match_len;
+            }
+        }
+        hits
+    }
+}
+
+/// First case-insensitive match of `needle` in `haystack` at or after `start`,
+/// as (offset, length) in haystack characters
+///
+/// Characters whose lowercase form is several characters (like `İ`) are
+/// compared by that whole form, so lengths can differ from the needle's.
+fn find_from(haystack: &[char], needle: &[char], start: usize) -> Option<(usize, usize)> {
+    (start..haystack.len()).find_map(|offset| {
+        let mut matched = 0;
+        for (consumed, c) in haystack[offset..].iter().enumerate() {
+            for lower in c.to_lowercase() {
+                if needle.get(matched) != Some(&lower) {
+                    return None;
+                }
+                matched += 1;
+            }
+            if matched == needle.len() {
+                return Some((offset, consumed + 1));
+            }
+        }
+        None
+    })
+}
+
+fn snippet(content: &[char], offset: usize, len: usize) -> String {
+    let from = offset.saturating_sub(SNIPPET_CONTEXT);
+    let to = (offset + len + SNIPPET_CONTEXT).min(content.len());
+
+    let mut snippet = String::new();
+    if from > 0 {
+        snippet.push('…');
+    }
+    snippet.extend(&content[from..to]);
+    if to < content.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::Message;
+
+    #[test]
+    fn test_search_reports_every_hit_with_position() {
+        let mut session = Session::new();
+        session.add_message(Message::system("You are helpful".to_string()));
+        session.add_message(Message::user("Ünïcode first: the Parser broke, then the parser broke again".to_string()));
+        session.add_message(Message::assistant(format!("{}parser at the end", "x".repeat(60))));
+
+        let hits = session.search("PARSER");
+        let positions: Vec<(usize, usize)> = hits.iter().map(|h| (h.message_index, h.char_offset)).collect();
+        assert_eq!(positions, [(1, 19), (1, 42), (2, 60)]);
+        assert_eq!(hits[0].message_id, session.messages()[1].id);
+        assert_eq!(hits[0].match_len, 6);
+
+        // Offsets count characters, not bytes
+        let content: String = session.messages()[1].content.chars().skip(19).take(6).collect();
+        assert_eq!(content, "Parser");
+
+        assert_eq!(hits[0].snippet, session.messages()[1].content);
+        assert_eq!(hits[2].snippet, format!("…{}parser at the end", "x".repeat(40)));
+
+        assert_eq!(session.search("aa").len(), 0);
+        assert!(session.search("").is_empty());
+        let mut repeated = Session::new();
+        repeated.add_message(Message::user("aaaa".to_string()));
+        assert_eq!(repeated.search("aa").len(), 2);
+    }
+
+    #[test]
+    fn test_storage_search_returns_hits_from_every_session() {
+        use crate::storage::{FileStorage, SessionStorage};
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let storage = FileStorage::with_directory(temp_dir.path()).unwrap();
+        let mut first = Session::new();
+        first.add_message(Message::user("deploy now".to_string()));
+        first.add_message(Message::assistant("Deploying; deploy done".to_string()));
+        let mut second = Session::new();
+        second.add_message(Message::user("unrelated".to_string()));
+        second.add_message(Message::user("one more deploy".to_string()));
+        storage.save_session(&first).unwrap();
+        storage.save_session(&second).unwrap();
+
+        let hits = storage.search("deploy").unwrap();
+        assert_eq!(hits.len(), 4);
+        assert_eq!(hits.iter().filter(|h| h.session_id == first.id).count(), 3);
+        let in_second: Vec<_> = hits.iter().filter(|h| h.session_id == second.id).collect();
+        assert_eq!((in_second[0].message_index, in_second[0].char_offset), (1, 9));
+    }
+}
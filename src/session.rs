@@ -2,21 +2,83 @@
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tracing::{debug, warn};
 use uuid::Uuid;
 
-use crate::error::Result;
+use crate::error::{ContextError, Result};
 use crate::storage::SessionStorage;
-use crate::compaction::CompactionStrategy;
+use crate::compaction::{self, CompactionPreview, CompactionStrategy, ContextCompactor, PinnedRange};
+use crate::events::{EventBus, SessionEvent};
+use crate::metrics::{Metrics, NoopMetrics};
+use crate::naming::SessionNamer;
+use crate::tokens::TokenModel;
+use crate::clock::{Clock, SessionClock};
+
+/// Metadata key [`Session::normalize_timestamps`] keeps a clamped message's
+/// original timestamp under
+pub const ORIGINAL_TIMESTAMP_KEY: &str = "original_timestamp";
 
 /// Role of a message in the conversation
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum MessageRole {
     System,
     User,
     Assistant,
     Tool,
+    /// A role this version doesn't know, e.g. from a session written by a
+    /// newer release; kept verbatim so saving doesn't lose it
+    #[serde(untagged)]
+    Unknown(String),
+}
+
+impl MessageRole {
+    /// The role's serialized name (`"system"`, `"user"`, ...)
+    pub fn as_str(&self) -> &str {
+        match self {
+            MessageRole::System => "system",
+            MessageRole::User => "user",
+            MessageRole::Assistant => "assistant",
+            MessageRole::Tool => "tool",
+            MessageRole::Unknown(role) => role,
+        }
+    }
+}
+
+/// Rules for [`Session::validate_protocol`]
+#[derive(Debug, Clone, Default)]
+pub struct ProtocolRules {
+    /// Roles the conversation must open with, in order
+    pub required_openers: Vec<MessageRole>,
+    /// For each role, the roles allowed immediately before it. Roles with no
+    /// entry may follow anything.
+    pub allowed_predecessors: HashMap<MessageRole, Vec<MessageRole>>,
+}
+
+impl ProtocolRules {
+    /// Strict agent protocol: system then user to open, and tool messages only
+    /// after an assistant message or another tool message
+    pub fn agent() -> Self {
+        Self::default()
+            .require_openers(vec![MessageRole::System, MessageRole::User])
+            .allow_after(MessageRole::Tool, vec![MessageRole::Assistant, MessageRole::Tool])
+    }
+
+    /// Require the conversation to open with these roles
+    pub fn require_openers(mut self, roles: Vec<MessageRole>) -> Self {
+        self.required_openers = roles;
+        self
+    }
+
+    /// Only allow `role` to follow one of `predecessors`
+    pub fn allow_after(mut self, role: MessageRole, predecessors: Vec<MessageRole>) -> Self {
+        self.allowed_predecessors.insert(role, predecessors);
+        self
+    }
 }
 
 /// A single message in a conversation
@@ -27,7 +89,21 @@ pub struct Message {
     pub content: String,
     pub timestamp: DateTime<Utc>,
     pub token_count: Option<usize>,
-    pub metadata: HashMap<String, serde_json::Value>,
+    pub metadata: BTreeMap<String, serde_json::Value>,
+    /// Externally computed importance, multiplying the priority
+    /// [`IntelligentCompactor`](crate::compaction::IntelligentCompactor)
+    /// gives this message (`None` = 1.0)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub importance: Option<f64>,
+    /// Position in the session's insertion order, assigned by
+    /// [`Session::add_message`]; breaks ties between equal timestamps
+    #[serde(default)]
+    pub seq: u64,
+    /// Whether an injected session clock should replace `timestamp` when the
+    /// message is added; cleared by `with_timestamp`, and on deserialized or
+    /// already-added messages
+    #[serde(skip)]
+    stamp_on_add: bool,
 }
 
 impl Message {
@@ -39,10 +115,19 @@ impl Message {
             content,
             timestamp: Utc::now(),
             token_count: None,
-            metadata: HashMap::new(),
+            metadata: BTreeMap::new(),
+            importance: None,
+            seq: 0,
+            stamp_on_add: true,
         }
     }
 
+    /// Create a message with a known id and timestamp, e.g. when importing
+    /// history from another system
+    pub fn with_id_and_time(role: MessageRole, content: String, id: Uuid, timestamp: DateTime<Utc>) -> Self {
+        Self::new(role, content).with_id(id).with_timestamp(timestamp)
+    }
+
     /// Create a new system message
     pub fn system(content: String) -> Self {
         Self::new(MessageRole::System, content)
@@ -63,29 +148,234 @@ impl Message {
         Self::new(MessageRole::Tool, content)
     }
 
+    /// Replace the generated id
+    pub fn with_id(mut self, id: Uuid) -> Self {
+        self.id = id;
+        self
+    }
+
+    /// Read `clock` for a message being added, stamping it if the clock is
+    /// injected and the message is fresh; returns the time read
+    pub(crate) fn stamp(&mut self, clock: &SessionClock) -> DateTime<Utc> {
+        let now = clock.now();
+        if clock.is_injected() && self.stamp_on_add {
+            self.timestamp = now;
+        }
+        self.stamp_on_add = false;
+        now
+    }
+
+    /// Replace the creation timestamp
+    ///
+    /// The timestamp is kept as given when the message is added, even to a
+    /// session on an injected clock.
+    pub fn with_timestamp(mut self, timestamp: DateTime<Utc>) -> Self {
+        self.timestamp = timestamp;
+        self.stamp_on_add = false;
+        self
+    }
+
     /// Set token count for this message
     pub fn with_token_count(mut self, count: usize) -> Self {
         self.token_count = Some(count);
         self
     }
 
+    /// Set the importance compaction weighs this message by
+    pub fn with_importance(mut self, importance: f64) -> Self {
+        self.importance = Some(importance);
+        self
+    }
+
+    /// Record the token count reported by the API for this message
+    ///
+    /// If the message belongs to a session, follow with
+    /// [`Session::recount_tokens`] or use [`Session::set_token_counts`].
+    /// Replacing an estimate that missed by more than
+    /// [`DEFAULT_DRIFT_TOLERANCE_PERCENT`](crate::tokens::DEFAULT_DRIFT_TOLERANCE_PERCENT)
+    /// logs a warning.
+    pub fn set_token_count(&mut self, count: usize) {
+        self.check_token_count(count, None);
+        self.token_count = Some(count);
+    }
+
     /// Add metadata to this message
     pub fn with_metadata(mut self, key: String, value: serde_json::Value) -> Self {
         self.metadata.insert(key, value);
         self
     }
 
+    /// String metadata value, if `key` is present and holds a string
+    pub fn get_meta_str(&self, key: &str) -> Option<&str> {
+        self.metadata.get(key).and_then(|v| v.as_str())
+    }
+
+    /// Integer metadata value, if `key` is present and holds an `i64`
+    pub fn get_meta_i64(&self, key: &str) -> Option<i64> {
+        self.metadata.get(key).and_then(|v| v.as_i64())
+    }
+
+    /// Boolean metadata value, if `key` is present and holds a bool
+    pub fn get_meta_bool(&self, key: &str) -> Option<bool> {
+        self.metadata.get(key).and_then(|v| v.as_bool())
+    }
+
+    /// Serialize `value` into metadata under `key`, replacing any previous value
+    pub fn set_meta<T: Serialize>(&mut self, key: impl Into<String>, value: T) -> Result<()> {
+        self.metadata.insert(key.into(), serde_json::to_value(value)?);
+        Ok(())
+    }
+
+    /// Whether this is an assistant message that requested tool calls
+    ///
+    /// Tool calls are recorded under `metadata["tool_calls"]`, using the same
+    /// shape as OpenAI's `tool_calls` array (objects with an `id` field).
+    pub fn has_tool_calls(&self) -> bool {
+        self.role == MessageRole::Assistant && self.metadata.contains_key("tool_calls")
+    }
+
+    /// Whether this assistant message requested the tool call with the given id
+    pub fn requested_tool_call(&self, call_id: &str) -> bool {
+        self.has_tool_calls()
+            && self.metadata["tool_calls"]
+                .as_array()
+                .is_some_and(|calls| {
+                    calls
+                        .iter()
+                        .any(|call| call.get("id").and_then(|id| id.as_str()) == Some(call_id))
+                })
+    }
+
+    /// The tool call this tool message answers, from `metadata["tool_call_id"]`
+    pub fn tool_call_id(&self) -> Option<&str> {
+        self.get_meta_str("tool_call_id")
+    }
+
+    /// Ids of earlier messages this one depends on, from `metadata["references"]`
+    ///
+    /// The value is an array of message id strings; entries that aren't valid
+    /// ids are skipped. Built-in compaction keeps referenced messages alive;
+    /// see [`Session::compact`].
+    pub fn references(&self) -> Vec<Uuid> {
+        self.metadata.get("references")
+            .and_then(|references| references.as_array())
+            .map(|references| {
+                references.iter()
+                    .filter_map(|id| id.as_str().and_then(|id| Uuid::parse_str(id).ok()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// SHA-256 over the role name, a zero byte, and the content
+    ///
+    /// Equal hashes mean byte-identical role and content; ids, timestamps, and
+    /// metadata are not included.
+    pub fn content_hash(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(self.role.as_str().as_bytes());
+        hasher.update([0]);
+        hasher.update(self.content.as_bytes());
+        hasher.finalize().into()
+    }
+
+    /// Remove control characters other than newline, carriage return, and tab
+    ///
+    /// Null bytes, escape sequences, and the like in pasted binary data can
+    /// break terminals and provider APIs. Returns how many characters were
+    /// removed; if any were, a provider-reported `token_count` is dropped.
+    pub fn sanitize(&mut self) -> usize {
+        let before = self.content.chars().count();
+        self.content.retain(|c| !is_disallowed_control(c));
+        let removed = before - self.content.chars().count();
+        if removed > 0 {
+            self.token_count = None;
+        }
+        removed
+    }
+
+    /// Character offset and value of the first control character `sanitize` would remove
+    pub fn first_disallowed_control(&self) -> Option<(usize, char)> {
+        self.content.chars().enumerate().find(|&(_, c)| is_disallowed_control(c))
+    }
+
     /// Estimate token count if not already set
     pub fn estimate_tokens(&self) -> usize {
         if let Some(count) = self.token_count {
             count
         } else {
-            // Simple estimation: ~4 characters per token
-            (self.content.len() + 3) / 4
+            estimate_content_tokens(&self.content)
         }
     }
 }
 
+/// Simple estimation: ~4 characters per token
+pub(crate) fn estimate_content_tokens(content: &str) -> usize {
+    content.len().div_ceil(4)
+}
+
+/// Running token total for a session's messages
+///
+/// Dropped by [`Session::messages_mut`], and only trusted while
+/// `message_count` matches the session's message count, so pushes or
+/// removals that bypass `Session` methods fall back to a full sum.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TokenCache {
+    total: usize,
+    message_count: usize,
+    /// The total under the last `TokenModel` asked for, kept up alongside
+    pub(crate) model: Option<(TokenModel, usize)>,
+    /// Serialized size of the messages, once something has asked for it
+    message_bytes: Option<usize>,
+}
+
+/// Identifies one in-memory run of a session's messages
+///
+/// Replaced whenever messages may have been edited, removed, or reordered
+/// rather than appended, and on clone or load, so an unchanged mark means
+/// the messages have only grown. Lets storage append new messages without
+/// re-reading the ones it already has.
+#[derive(Debug)]
+pub(crate) struct HistoryMark(u64);
+
+impl Default for HistoryMark {
+    fn default() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl Clone for HistoryMark {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+/// Aggregate figures for a session, from [`Session::stats`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionStats {
+    pub total_messages: usize,
+    pub messages_by_role: HashMap<MessageRole, usize>,
+    pub total_tokens: usize,
+    pub first_timestamp: Option<DateTime<Utc>>,
+    pub last_timestamp: Option<DateTime<Utc>>,
+    /// Mean message length in characters (0.0 for an empty session)
+    pub average_message_length: f64,
+}
+
+/// What [`SessionManager::add_message_detailed`] did with a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddMessageOutcome {
+    /// The session's token count once the message is in, after any compaction
+    pub total_tokens: usize,
+    /// Whether the message pushed the session over `max_tokens` and it was compacted
+    pub compacted: bool,
+    /// Messages compaction dropped or folded into a summary
+    pub messages_removed: usize,
+    /// Whether the session was written to storage
+    pub saved: bool,
+}
+
 /// A conversation session
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Session {
@@ -93,41 +383,201 @@ pub struct Session {
     pub name: String,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
-    pub messages: Vec<Message>,
-    pub metadata: HashMap<String, serde_json::Value>,
+    /// The conversation, oldest first; see [`Session::messages`]
+    messages: Vec<Message>,
+    pub metadata: BTreeMap<String, serde_json::Value>,
+    /// Project or folder the session belongs to (`None` = ungrouped)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Save counter for optimistic concurrency
+    ///
+    /// `SessionManager` bumps this before every save, and storage refuses to
+    /// overwrite a stored copy whose version is not older, returning
+    /// `ContextError::Conflict`. Sessions that stay at 0 are never checked.
+    #[serde(default)]
+    pub version: u64,
+    #[serde(skip)]
+    token_cache: Option<TokenCache>,
+    #[serde(skip)]
+    clock: SessionClock,
+    #[serde(skip)]
+    history: HistoryMark,
 }
 
 impl Session {
     /// Create a new session
     pub fn new() -> Self {
-        let now = Utc::now();
-        Self {
-            id: Uuid::new_v4(),
-            name: format!("session-{}", now.format("%Y%m%d-%H%M%S")),
-            created_at: now,
-            updated_at: now,
-            messages: Vec::new(),
-            metadata: HashMap::new(),
-        }
+        Self::starting(None, SessionClock::default())
     }
     
     /// Create a new session with a custom name
     pub fn with_name(name: String) -> Self {
-        let now = Utc::now();
+        Self::starting(Some(name), SessionClock::default())
+    }
+
+    /// Create a new session that takes all its timestamps from `clock`
+    ///
+    /// Besides creation and update times, messages are restamped with the
+    /// clock as they are added. The clock is not serialized; reattach it to
+    /// loaded sessions with [`Session::set_clock`].
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self::starting(None, SessionClock::new(clock))
+    }
+
+    /// Take timestamps from `clock` from now on, as [`Session::with_clock`]
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = SessionClock::new(clock);
+    }
+
+    fn starting(name: Option<String>, clock: SessionClock) -> Self {
+        let now = clock.now();
         Self {
             id: Uuid::new_v4(),
-            name,
+            name: name.unwrap_or_else(|| crate::naming::timestamp_name(now)),
             created_at: now,
             updated_at: now,
             messages: Vec::new(),
-            metadata: HashMap::new(),
+            metadata: BTreeMap::new(),
+            group: None,
+            version: 0,
+            token_cache: None,
+            clock,
+            history: HistoryMark::default(),
+        }
+    }
+
+    /// Hash chaining every message's `content_hash` in order
+    ///
+    /// Any change to a message's role or content, or to message order, changes
+    /// the result. This detects accidental or naive edits, not a determined
+    /// attacker who can recompute it.
+    pub fn integrity_hash(&self) -> [u8; 32] {
+        self.messages.iter().fold([0u8; 32], |chain, message| {
+            Sha256::digest([chain, message.content_hash()].concat()).into()
+        })
+    }
+
+    /// String metadata value, if `key` is present and holds a string
+    pub fn get_meta_str(&self, key: &str) -> Option<&str> {
+        self.metadata.get(key).and_then(|v| v.as_str())
+    }
+
+    /// Integer metadata value, if `key` is present and holds an `i64`
+    pub fn get_meta_i64(&self, key: &str) -> Option<i64> {
+        self.metadata.get(key).and_then(|v| v.as_i64())
+    }
+
+    /// Boolean metadata value, if `key` is present and holds a bool
+    pub fn get_meta_bool(&self, key: &str) -> Option<bool> {
+        self.metadata.get(key).and_then(|v| v.as_bool())
+    }
+
+    /// Serialize `value` into metadata under `key`, replacing any previous value
+    pub fn set_meta<T: Serialize>(&mut self, key: impl Into<String>, value: T) -> Result<()> {
+        self.metadata.insert(key.into(), serde_json::to_value(value)?);
+        Ok(())
+    }
+
+    /// Create a new, empty session with a caller-chosen id
+    pub fn with_id(id: Uuid) -> Self {
+        Self {
+            id,
+            ..Self::new()
         }
     }
 
+    /// Check whether a session may overwrite a stored copy at `stored_version`
+    pub(crate) fn check_version(&self, stored_version: u64) -> Result<()> {
+        if stored_version != 0 && stored_version >= self.version {
+            return Err(ContextError::Conflict(format!(
+                "session {} is at version {} in storage but {} in memory",
+                self.id, stored_version, self.version
+            )));
+        }
+        Ok(())
+    }
+
+    /// Copy this session under a fresh id
+    ///
+    /// Messages and metadata are cloned as-is; the copy gets new timestamps, a
+    /// `"<name> (copy)"` name, and `metadata["parent_session_id"]` pointing back here.
+    pub fn duplicate(&self) -> Session {
+        let mut copy = Session::starting(Some(format!("{} (copy)", self.name)), self.clock.clone());
+        copy.messages = self.messages.clone();
+        copy.metadata = self.metadata.clone();
+        copy.group = self.group.clone();
+        copy.metadata.insert(
+            "parent_session_id".to_string(),
+            serde_json::Value::String(self.id.to_string()),
+        );
+        copy.version = 0;
+        copy.recount_tokens();
+        copy
+    }
+
+    /// Split into the messages up to and including `message_id`, and the rest
+    ///
+    /// The prefix keeps this session's id, name, version, and metadata. The
+    /// suffix is a new session named `"<name> (continued)"` in the same group,
+    /// with a copy of the metadata and `metadata["parent_session_id"]`
+    /// pointing at the prefix, so the older half can be archived while the
+    /// conversation carries on in a lighter session. Splitting at the last
+    /// message leaves the suffix empty.
+    ///
+    /// Fails with `ContextError::InvalidSession` if no message has that id.
+    pub fn split_at(&self, message_id: &Uuid) -> Result<(Session, Session)> {
+        let split = self.messages.iter()
+            .position(|m| m.id == *message_id)
+            .ok_or_else(|| ContextError::InvalidSession(format!(
+                "session {} has no message {}",
+                self.id, message_id
+            )))?;
+
+        let mut prefix = self.clone();
+        let tail = prefix.edit_messages().split_off(split + 1);
+        prefix.recount_tokens();
+
+        let mut suffix = Session::starting(Some(format!("{} (continued)", self.name)), self.clock.clone());
+        suffix.messages = tail;
+        suffix.metadata = self.metadata.clone();
+        suffix.group = self.group.clone();
+        suffix.metadata.insert(
+            "parent_session_id".to_string(),
+            serde_json::Value::String(self.id.to_string()),
+        );
+        suffix.recount_tokens();
+        Ok((prefix, suffix))
+    }
+
     /// Add a message to the session
-    pub fn add_message(&mut self, message: Message) {
-        self.messages.push(message);
-        self.updated_at = Utc::now();
+    ///
+    /// The message's `seq` is set to one more than the last message's. On an
+    /// injected clock, a freshly created message is stamped with the clock's
+    /// time; one built with [`Message::with_timestamp`], loaded from storage,
+    /// or taken from another session keeps its own.
+    pub fn add_message(&mut self, mut message: Message) {
+        let message_count = self.messages.len();
+        message.seq = self.messages.last().map_or(0, |last| last.seq + 1);
+
+        self.updated_at = message.stamp(&self.clock);
+
+        match self.token_cache.as_mut().filter(|cache| cache.message_count == message_count) {
+            Some(cache) => {
+                cache.total += message.estimate_tokens();
+                if let Some((model, total)) = &mut cache.model {
+                    *total += model.estimate(&message);
+                }
+                if let Some(bytes) = cache.message_bytes {
+                    cache.message_bytes = serde_json::to_vec(&message).ok().map(|json| bytes + json.len());
+                }
+                cache.message_count += 1;
+                self.messages.push(message);
+            }
+            None => {
+                self.messages.push(message);
+                self.recount_tokens();
+            }
+        }
     }
 
     /// Add a user message
@@ -151,8 +601,237 @@ impl Session {
     }
 
     /// Get total estimated token count
+    ///
+    /// Kept as a running total by `add_message` and compaction, and
+    /// recomputed after any edit through [`Session::messages_mut`].
     pub fn total_tokens(&self) -> usize {
-        self.messages.iter().map(|m| m.estimate_tokens()).sum()
+        match self.fresh_token_cache() {
+            Some(cache) => cache.total,
+            None => self.messages.iter().map(|m| m.estimate_tokens()).sum(),
+        }
+    }
+
+    /// The conversation, oldest first
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
+    }
+
+    /// Take the messages, dropping the rest of the session
+    pub(crate) fn into_messages(self) -> Vec<Message> {
+        self.messages
+    }
+
+    /// The messages, for in-place edits; the running token total is
+    /// recomputed the next time it's needed
+    pub fn messages_mut(&mut self) -> &mut Vec<Message> {
+        self.token_cache = None;
+        self.edit_messages()
+    }
+
+    /// The messages, for edits that keep the running token total right
+    pub(crate) fn edit_messages(&mut self) -> &mut Vec<Message> {
+        self.history = HistoryMark::default();
+        &mut self.messages
+    }
+
+    /// See [`HistoryMark`]
+    pub(crate) fn history_mark(&self) -> u64 {
+        self.history.0
+    }
+
+    /// Recompute the running token total from scratch
+    pub fn recount_tokens(&mut self) {
+        let model = self.token_cache.take()
+            .and_then(|cache| cache.model)
+            .map(|(model, _)| {
+                let total = self.messages.iter().map(|m| model.estimate(m)).sum();
+                (model, total)
+            });
+        self.token_cache = Some(TokenCache {
+            total: self.messages.iter().map(|m| m.estimate_tokens()).sum(),
+            message_count: self.messages.len(),
+            model,
+            message_bytes: None,
+        });
+    }
+
+    /// Length of the session serialized as compact JSON, measuring only
+    /// messages added since the last call
+    pub(crate) fn serialized_size(&mut self) -> Result<usize> {
+        if self.fresh_token_cache().is_none() {
+            self.recount_tokens();
+        }
+        let message_bytes = match self.token_cache.as_ref().and_then(|cache| cache.message_bytes) {
+            Some(bytes) => bytes,
+            None => {
+                let mut bytes = 0;
+                for message in &self.messages {
+                    bytes += serde_json::to_vec(message)?.len();
+                }
+                if let Some(cache) = &mut self.token_cache {
+                    cache.message_bytes = Some(bytes);
+                }
+                bytes
+            }
+        };
+
+        // Everything but the messages, plus the commas between them
+        let messages = std::mem::take(&mut self.messages);
+        let header = serde_json::to_vec(self).map(|json| json.len());
+        self.messages = messages;
+        Ok(header? + message_bytes + self.messages.len().saturating_sub(1))
+    }
+
+    /// Add `message`, then run `fit` (compaction and limit checks); if `fit`
+    /// fails, the session is put back as it was before the append
+    ///
+    /// A copy is only taken when the message will push the session over
+    /// `max_tokens`, since compaction rewrites the session anyway.
+    pub(crate) fn add_message_within<T>(
+        &mut self,
+        message: Message,
+        model: Option<&TokenModel>,
+        max_tokens: usize,
+        fit: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        let incoming = model.map_or_else(|| message.estimate_tokens(), |model| model.estimate(&message));
+        let before = (self.track_tokens_with(model) + incoming > max_tokens).then(|| self.clone());
+        let updated_at = self.updated_at;
+        self.add_message(message);
+
+        let result = fit(self);
+        if result.is_err() {
+            match before {
+                Some(before) => *self = before,
+                None => {
+                    self.edit_messages().pop();
+                    self.updated_at = updated_at;
+                    self.recount_tokens();
+                }
+            }
+        }
+        result
+    }
+
+    /// Like [`Session::total_tokens_with`], keeping a running total for
+    /// `model` so later appends update it instead of re-summing
+    pub(crate) fn track_tokens_with(&mut self, model: Option<&TokenModel>) -> usize {
+        if self.fresh_token_cache().is_none() {
+            self.recount_tokens();
+        }
+        let Some(model) = model else {
+            return self.total_tokens();
+        };
+        if let Some(cache) = self.fresh_token_cache()
+            && let Some((cached, total)) = &cache.model
+            && cached == model
+        {
+            return *total;
+        }
+
+        let total = self.messages.iter().map(|m| model.estimate(m)).sum();
+        if let Some(cache) = &mut self.token_cache {
+            cache.model = Some((model.clone(), total));
+        }
+        total
+    }
+
+    /// The running token total, if nothing has invalidated it
+    pub(crate) fn fresh_token_cache(&self) -> Option<&TokenCache> {
+        self.token_cache.as_ref().filter(|cache| cache.message_count == self.messages.len())
+    }
+
+    /// Store authoritative token counts, e.g. from an API usage report
+    ///
+    /// Ids not in the session are ignored. The running total is recomputed,
+    /// so later estimates and compaction use the real counts. Estimates that
+    /// missed by more than the default model's tolerance are logged; use
+    /// [`Session::set_token_counts_with`] to judge them by another model.
+    pub fn set_token_counts(&mut self, counts: &[(Uuid, usize)]) {
+        self.set_token_counts_with(counts, None);
+    }
+
+    /// Merge each run of consecutive tool messages into one
+    ///
+    /// For agents that emit many small tool results in a row (one per file
+    /// read, say): fewer messages means less per-message overhead before
+    /// token-based compaction runs. Contents are joined with blank lines, and
+    /// a run is split wherever the next message would take the merged one over
+    /// `max_tokens`. The merged message keeps the first message's id and
+    /// timestamp. Metadata is combined: a key whose values differ becomes an
+    /// array of them in order, so `tool_call_id` lists every call answered,
+    /// and `references` become the union. References to merged-away messages
+    /// are pointed at the merged one. A token count is kept, summed, only if
+    /// every part had one. Returns how many messages were merged away.
+    pub fn coalesce_tool_messages(&mut self, max_tokens: usize) -> usize {
+        let original_len = self.messages.len();
+        let mut runs: Vec<Vec<Message>> = Vec::new();
+        let mut run_tokens = 0;
+        for message in std::mem::take(self.edit_messages()) {
+            let tokens = message.estimate_tokens();
+            if let Some(run) = runs.last_mut()
+                && run[0].role == MessageRole::Tool
+                && message.role == MessageRole::Tool
+                && run_tokens + tokens <= max_tokens
+            {
+                run_tokens += tokens;
+                run.push(message);
+                continue;
+            }
+            run_tokens = tokens;
+            runs.push(vec![message]);
+        }
+
+        let mut redirects: HashMap<String, String> = HashMap::new();
+        for run in &runs {
+            for merged_away in &run[1..] {
+                redirects.insert(merged_away.id.to_string(), run[0].id.to_string());
+            }
+        }
+        *self.edit_messages() = runs.into_iter().map(merge_run).collect();
+
+        if !redirects.is_empty() {
+            for message in &mut self.messages {
+                let own_id = message.id.to_string();
+                if let Some(serde_json::Value::Array(references)) = message.metadata.get_mut("references") {
+                    let mut seen = HashSet::new();
+                    references.retain_mut(|reference| {
+                        if let Some(target) = reference.as_str().and_then(|id| redirects.get(id)) {
+                            *reference = serde_json::Value::String(target.clone());
+                        }
+                        reference.as_str() != Some(own_id.as_str()) && seen.insert(reference.clone())
+                    });
+                }
+            }
+            self.recount_tokens();
+            self.updated_at = self.clock.now();
+        }
+        original_len - self.messages.len()
+    }
+
+    /// Summarize message counts, tokens, time span, and average length
+    pub fn stats(&self) -> SessionStats {
+        let mut messages_by_role = HashMap::new();
+        let mut total_chars = 0;
+        for message in &self.messages {
+            *messages_by_role.entry(message.role.clone()).or_insert(0) += 1;
+            total_chars += message.content.chars().count();
+        }
+
+        let average_message_length = if self.messages.is_empty() {
+            0.0
+        } else {
+            total_chars as f64 / self.messages.len() as f64
+        };
+
+        SessionStats {
+            total_messages: self.messages.len(),
+            messages_by_role,
+            total_tokens: self.total_tokens(),
+            first_timestamp: self.messages.iter().map(|m| m.timestamp).min(),
+            last_timestamp: self.messages.iter().map(|m| m.timestamp).max(),
+            average_message_length,
+        }
     }
 
     /// Get messages since a certain timestamp
@@ -173,169 +852,2340 @@ impl Session {
         }
     }
 
-    /// Apply compaction strategy to reduce token count
-    pub fn compact(&mut self, strategy: &CompactionStrategy, target_tokens: usize) -> Result<()> {
-        if self.total_tokens() <= target_tokens {
-            return Ok(());
-        }
+    /// Remove trailing assistant messages left unfinished by a crash mid-stream
+    ///
+    /// A trailing assistant message is incomplete when its content is blank and
+    /// it requested no tool calls, or its `metadata["incomplete"]` is `true`.
+    /// Returns how many were removed.
+    pub fn trim_incomplete(&mut self) -> usize {
+        let mut trimmed = 0;
 
-        match strategy {
-            CompactionStrategy::Sliding { max_tokens } => {
-                self.compact_sliding(*max_tokens)?;
-            }
-            CompactionStrategy::SystemAndRecent { system_tokens, recent_tokens } => {
-                self.compact_system_and_recent(*system_tokens, *recent_tokens)?;
-            }
-            CompactionStrategy::Intelligent { target_tokens } => {
-                self.compact_intelligent(*target_tokens)?;
+        while let Some(last) = self.messages.last() {
+            let incomplete = last.role == MessageRole::Assistant
+                && ((last.content.trim().is_empty() && !last.has_tool_calls())
+                    || last.get_meta_bool("incomplete") == Some(true));
+            if !incomplete {
+                break;
             }
+            self.edit_messages().pop();
+            trimmed += 1;
         }
 
-        self.updated_at = Utc::now();
-        Ok(())
+        if trimmed > 0 {
+            self.recount_tokens();
+        }
+        trimmed
     }
 
-    fn compact_sliding(&mut self, max_tokens: usize) -> Result<()> {
-        while self.total_tokens() > max_tokens && !self.messages.is_empty() {
-            self.messages.remove(0);
+    /// Clamp message timestamps so they never go backwards
+    ///
+    /// Message order is taken as the truth: a message stamped before the
+    /// session's `created_at` or before the message ahead of it, as when a
+    /// session moves between hosts with skewed clocks, gets that earlier
+    /// timestamp instead. Its original timestamp is kept under
+    /// [`ORIGINAL_TIMESTAMP_KEY`], unless an earlier normalization already
+    /// recorded one. `updated_at` is raised to the last timestamp if behind it.
+    /// Returns how many messages were clamped, logging a warning if any were.
+    pub fn normalize_timestamps(&mut self) -> usize {
+        let mut floor = self.created_at;
+        let mut clamped = 0;
+
+        for message in self.edit_messages() {
+            if message.timestamp < floor {
+                message.metadata
+                    .entry(ORIGINAL_TIMESTAMP_KEY.to_string())
+                    .or_insert_with(|| serde_json::json!(message.timestamp));
+                message.timestamp = floor;
+                clamped += 1;
+            }
+            floor = message.timestamp;
         }
-        Ok(())
+
+        self.updated_at = self.updated_at.max(floor);
+        if clamped > 0 {
+            warn!("Clamped {} out-of-order message timestamps in session {}", clamped, self.id);
+        }
+        clamped
     }
 
-    fn compact_system_and_recent(&mut self, system_tokens: usize, recent_tokens: usize) -> Result<()> {
-        // Keep system messages that fit in system_tokens budget
-        let mut system_messages = Vec::new();
-        let mut system_token_count = 0;
+    /// Check that every tool message follows an assistant tool call
+    ///
+    /// A tool message is paired when it comes after an assistant message with
+    /// `metadata["tool_calls"]`, with only other tool messages in between. This
+    /// is OpenAI's rule: a `tool` message must answer a `tool_calls` entry on the
+    /// preceding assistant message.
+    ///
+    /// Anthropic's Messages API is stricter. Every `tool_use` block in an
+    /// assistant turn must be answered by a matching `tool_result` block in the
+    /// very next user turn, and a `tool_result` may never appear without its
+    /// `tool_use`. A session that passes this check satisfies the ordering half
+    /// of that rule; matching up ids is left to the caller.
+    ///
+    /// Returns the ids of any dangling tool messages.
+    pub fn validate_tool_pairing(&self) -> std::result::Result<(), Vec<Uuid>> {
+        let mut dangling = Vec::new();
+        let mut in_tool_run = false;
 
         for message in &self.messages {
-            if message.role == MessageRole::System {
-                let tokens = message.estimate_tokens();
-                if system_token_count + tokens <= system_tokens {
-                    system_messages.push(message.clone());
-                    system_token_count += tokens;
+            match message.role {
+                MessageRole::Tool => {
+                    if !in_tool_run {
+                        dangling.push(message.id);
+                    }
                 }
+                _ => in_tool_run = message.has_tool_calls(),
             }
         }
 
-        // Keep recent messages that fit in recent_tokens budget
-        let mut recent_messages = Vec::new();
-        let mut recent_token_count = 0;
+        if dangling.is_empty() {
+            Ok(())
+        } else {
+            Err(dangling)
+        }
+    }
+
+    /// Check the message sequence against protocol rules
+    ///
+    /// Read-only. A session shorter than `required_openers` is only checked as
+    /// far as it goes, so a conversation in progress isn't flagged. Fails with
+    /// `ContextError::ProtocolViolation` naming the first offending message.
+    pub fn validate_protocol(&self, rules: &ProtocolRules) -> Result<()> {
+        for (index, message) in self.messages.iter().enumerate() {
+            if let Some(expected) = rules.required_openers.get(index)
+                && message.role != *expected
+            {
+                return Err(ContextError::ProtocolViolation {
+                    index,
+                    reason: format!("expected {:?} opener, found {:?}", expected, message.role),
+                });
+            }
 
-        for message in self.messages.iter().rev() {
-            if message.role != MessageRole::System {
-                let tokens = message.estimate_tokens();
-                if recent_token_count + tokens <= recent_tokens {
-                    recent_messages.insert(0, message.clone());
-                    recent_token_count += tokens;
-                } else {
-                    break;
-                }
+            let Some(allowed) = rules.allowed_predecessors.get(&message.role) else {
+                continue;
+            };
+            let previous = index.checked_sub(1).map(|i| &self.messages[i].role);
+            if !previous.is_some_and(|p| allowed.contains(p)) {
+                return Err(ContextError::ProtocolViolation {
+                    index,
+                    reason: match previous {
+                        Some(previous) => format!("{:?} message may not follow {:?}", message.role, previous),
+                        None => format!("{:?} message may not start the conversation", message.role),
+                    },
+                });
             }
         }
 
-        // Combine system and recent messages
-        self.messages = system_messages;
-        self.messages.extend(recent_messages);
-
         Ok(())
     }
 
-    fn compact_intelligent(&mut self, target_tokens: usize) -> Result<()> {
-        // For now, use system_and_recent strategy
-        // TODO: Implement more sophisticated compaction
-        let system_tokens = target_tokens / 4;
-        let recent_tokens = (target_tokens * 3) / 4;
-        self.compact_system_and_recent(system_tokens, recent_tokens)
+    /// Apply compaction strategy to reduce token count
+    ///
+    /// The strategy's own budgets are capped at `target_tokens`, so a target
+    /// below the strategy's limits compacts further than the strategy alone.
+    /// A [`CompactionStrategy::SystemAndRecent`] split over the target shrinks
+    /// to fit it, both budgets in proportion; use
+    /// [`CompactionStrategy::validate`] to reject such a split instead.
+    /// [`CompactionStrategy::RecentExchanges`] counts exchanges instead and
+    /// applies even when the session is already under the target.
+    ///
+    /// Messages referenced (see [`Message::references`]) by a kept message are
+    /// kept too, transitively, even if the strategy would evict them, so the
+    /// session can end up over the target. They return in their original
+    /// position and form. References to messages no longer in the session are
+    /// ignored, and reference cycles are followed once.
+    pub fn compact(&mut self, strategy: &CompactionStrategy, target_tokens: usize) -> Result<()> {
+        self.compact_with(strategy, target_tokens, None)
     }
-}
 
-/// Session manager for loading, saving, and managing sessions
-pub struct SessionManager {
-    storage: Box<dyn SessionStorage>,
-    compaction_strategy: CompactionStrategy,
-    max_tokens: usize,
-    auto_save: bool,
-}
+    /// Like [`Session::compact`], measuring budgets with `model` when given
+    pub fn compact_with(&mut self, strategy: &CompactionStrategy, target_tokens: usize, model: Option<&TokenModel>) -> Result<()> {
+        let count_based = matches!(strategy, CompactionStrategy::RecentExchanges { .. });
+        if !count_based && self.total_tokens_with(model) <= target_tokens {
+            return Ok(());
+        }
 
-impl SessionManager {
-    /// Create a new session manager with default storage
-    pub fn new() -> Result<Self> {
-        let storage = crate::storage::FileStorage::new()?;
-        Ok(Self {
-            storage: Box::new(storage),
-            compaction_strategy: CompactionStrategy::default(),
-            max_tokens: 8000,
-            auto_save: true,
-        })
+        compaction::apply(self.edit_messages(), strategy, target_tokens, model)?;
+        self.recount_tokens();
+        self.updated_at = self.clock.now();
+        Ok(())
     }
 
-    /// Create a new session manager with custom configuration
-    pub fn with_config(config: crate::Config) -> Result<Self> {
-        let storage = match config.storage_dir {
-            Some(dir) => crate::storage::FileStorage::with_directory(dir)?,
-            None => crate::storage::FileStorage::new()?,
-        };
-        Ok(Self {
-            storage: Box::new(storage),
-            compaction_strategy: config.compaction_strategy,
-            max_tokens: config.max_tokens,
-            auto_save: config.auto_save,
-        })
+    /// Like [`Session::compact`], leaving every message in `pinned` untouched
+    ///
+    /// For a section the user is actively working on, wherever it sits in
+    /// the conversation. The rest is compacted into whatever the target
+    /// leaves after the range's tokens. Fails with
+    /// `ContextError::CompactionFailed`, changing nothing, if either end of
+    /// the range isn't in the session or the range alone exceeds the target.
+    pub fn compact_pinned(&mut self, strategy: &CompactionStrategy, target_tokens: usize, pinned: &PinnedRange) -> Result<()> {
+        compaction::apply_pinned(self.edit_messages(), strategy, target_tokens, pinned, None)?;
+        self.recount_tokens();
+        self.updated_at = self.clock.now();
+        Ok(())
     }
 
-    /// Load the most recent session
-    pub fn load_latest(&mut self) -> Result<Session> {
-        match self.storage.load_latest_session()? {
-            Some(session) => Ok(session),
-            None => {
-                // Create a new session if none exists
-                let session = Session::new();
-                if self.auto_save {
-                    self.storage.save_session(&session)?;
+    /// Compact like [`Session::compact`], returning the removed messages in their original order
+    ///
+    /// Use this to archive history elsewhere while keeping the working context small.
+    pub fn compact_returning(&mut self, strategy: &CompactionStrategy, target_tokens: usize) -> Result<Vec<Message>> {
+        let before = self.messages.clone();
+        self.compact(strategy, target_tokens)?;
+        Ok(removed_messages(before, &self.messages))
+    }
+
+    /// Report what [`Session::compact`] would do, without changing the session
+    ///
+    /// Runs the strategy on a copy, so the result matches a real compaction.
+    pub fn compact_preview(&self, strategy: &CompactionStrategy, target_tokens: usize) -> Result<CompactionPreview> {
+        let mut compacted = self.clone();
+        compacted.compact(strategy, target_tokens)?;
+
+        let after: HashMap<Uuid, &Message> = compacted.messages.iter().map(|m| (m.id, m)).collect();
+        let mut preview = CompactionPreview {
+            projected_tokens: compacted.total_tokens(),
+            ..CompactionPreview::default()
+        };
+        for message in &self.messages {
+            match after.get(&message.id) {
+                Some(kept) => {
+                    preview.kept.push(message.id);
+                    if kept.content != message.content {
+                        preview.truncated.push(message.id);
+                    }
                 }
-                Ok(session)
+                None => preview.removed.push(message.id),
             }
         }
+        Ok(preview)
     }
+}
 
-    /// Load a specific session by ID
-    pub fn load_session(&mut self, session_id: &uuid::Uuid) -> Result<Session> {
-        self.storage.load_session(session_id)
+/// Lock a mutex, recovering from poisoning; guarded state here stays
+/// consistent even if a holder panicked
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Compact with `compactor` if set, otherwise with `strategy`
+pub(crate) fn compact(
+    session: &mut Session,
+    compactor: Option<&dyn ContextCompactor>,
+    strategy: &CompactionStrategy,
+    target_tokens: usize,
+    model: Option<&TokenModel>,
+) -> Result<()> {
+    match compactor {
+        Some(compactor) => {
+            compactor.compact(session, target_tokens)?;
+            // Custom compactors may edit messages without keeping the running total
+            session.recount_tokens();
+            session.updated_at = session.clock.now();
+            Ok(())
+        }
+        None => session.compact_with(strategy, target_tokens, model),
     }
+}
 
-    /// Save a session
-    pub fn save_session(&mut self, session: &Session) -> Result<()> {
-        self.storage.save_session(session)
+/// Messages in `before` whose ids no longer appear in `after`
+pub(crate) fn removed_messages(before: Vec<Message>, after: &[Message]) -> Vec<Message> {
+    let kept: std::collections::HashSet<Uuid> = after.iter().map(|m| m.id).collect();
+    before.into_iter().filter(|m| !kept.contains(&m.id)).collect()
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    /// Create a new session
-    pub fn new_session(&mut self) -> Result<Session> {
-        let session = Session::new();
-        if self.auto_save {
-            self.storage.save_session(&session)?;
+/// One message standing in for a run of tool messages; see
+/// [`Session::coalesce_tool_messages`]
+fn merge_run(run: Vec<Message>) -> Message {
+    let mut parts = run.into_iter();
+    let mut merged = parts.next().expect("runs are never empty");
+    let mut values: BTreeMap<String, Vec<serde_json::Value>> = std::mem::take(&mut merged.metadata)
+        .into_iter()
+        .map(|(key, value)| (key, vec![value]))
+        .collect();
+
+    for part in parts {
+        merged.content.push_str("\n\n");
+        merged.content.push_str(&part.content);
+        merged.token_count = merged.token_count.zip(part.token_count).map(|(a, b)| a + b);
+        for (key, value) in part.metadata {
+            let seen = values.entry(key).or_default();
+            if !seen.contains(&value) {
+                seen.push(value);
+            }
         }
-        Ok(session)
     }
 
-    /// List all available sessions
-    pub fn list_sessions(&self) -> Result<Vec<crate::storage::SessionInfo>> {
-        self.storage.list_sessions()
-    }
+    merged.metadata = values.into_iter()
+        .map(|(key, mut seen)| {
+            let value = if key == "references" {
+                serde_json::Value::Array(seen.into_iter().flat_map(|v| match v {
+                    serde_json::Value::Array(ids) => ids,
+                    other => vec![other],
+                }).collect())
+            } else if seen.len() == 1 {
+                seen.remove(0)
+            } else {
+                serde_json::Value::Array(seen)
+            };
+            (key, value)
+        })
+        .collect();
+    merged
+}
 
-    /// Add a message to a session with automatic compaction and saving
-    pub fn add_message(&mut self, session: &mut Session, message: Message) -> Result<()> {
-        session.add_message(message);
+/// Messages from several sessions in one chronological timeline
+///
+/// Each message is tagged with the id of its session. Messages with equal
+/// timestamps keep the order of `sessions`, and within a session are ordered
+/// by `seq`, then by position.
+pub fn merge_timeline<'a>(sessions: &[&'a Session]) -> Vec<(Uuid, &'a Message)> {
+    let mut timeline: Vec<(usize, Uuid, &'a Message)> = sessions.iter()
+        .enumerate()
+        .flat_map(|(order, session)| session.messages.iter().map(move |message| (order, session.id, message)))
+        .collect();
+    timeline.sort_by_key(|&(order, _, message)| (message.timestamp, order, message.seq));
+    timeline.into_iter().map(|(_, id, message)| (id, message)).collect()
+}
 
-        // Check if compaction is needed
-        if session.total_tokens() > self.max_tokens {
-            session.compact(&self.compaction_strategy, self.max_tokens)?;
-        }
+/// Which UUID version [`SessionManager`] gives the sessions it creates
+///
+/// Sessions with ids of any version load the same way; this only affects
+/// new ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SessionIdVersion {
+    /// Random ids
+    #[default]
+    V4,
+    /// Time-ordered ids: sorting them as strings (or sorting session file
+    /// names) puts sessions in creation order
+    V7,
+}
 
-        // Auto-save if enabled
-        if self.auto_save {
-            self.storage.save_session(session)?;
+impl SessionIdVersion {
+    /// A fresh id of this version
+    pub fn generate(self) -> Uuid {
+        match self {
+            SessionIdVersion::V4 => Uuid::new_v4(),
+            SessionIdVersion::V7 => Uuid::now_v7(),
         }
-
+    }
+}
+
+/// How often auto-save persists sessions as messages are added
+///
+/// Messages not yet persisted live only in the in-memory `Session` and are
+/// lost if the process crashes. Only applies while `auto_save` is on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Save after every message; a crash loses nothing already added
+    #[default]
+    EveryMessage,
+    /// Save once every N messages per session; a crash loses up to N - 1 messages
+    EveryN(usize),
+    /// Never save on add; a crash loses everything since the last
+    /// `SessionManager::flush` or `save_session`
+    OnDemand,
+}
+
+/// What `add_message` on either session manager does when its auto-save fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SaveFailurePolicy {
+    /// Return the error with the message still in the in-memory session
+    ///
+    /// Nothing typed is lost and the next save retries it, but until then
+    /// memory is ahead of storage and the caller must notice the error.
+    #[default]
+    PropagateAndKeep,
+    /// Return the error after restoring the session to how it was before the
+    /// call, undoing any compaction it triggered too
+    ///
+    /// Memory and storage stay in step, so the add can simply be retried,
+    /// but the session is cloned on every add and the caller must keep its
+    /// own copy of the message to retry with.
+    PropagateAndRollback,
+    /// Log the error with `tracing::warn!` and return `Ok`
+    ///
+    /// For loops that must not stall on a flaky disk. The message stays in
+    /// memory and a later save picks it up, but a crash before then loses it
+    /// silently.
+    LogAndContinue,
+}
+
+/// What `SessionManager` does with control characters in added messages
+///
+/// Newline, carriage return, and tab are always allowed; see
+/// [`Message::sanitize`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContentPolicy {
+    /// Store content as given
+    #[default]
+    Allow,
+    /// Strip disallowed control characters before adding
+    Sanitize,
+    /// Fail with `ContextError::InvalidContent`, leaving the session unchanged
+    Reject,
+}
+
+pub(crate) fn is_disallowed_control(c: char) -> bool {
+    c.is_control() && !matches!(c, '\n' | '\r' | '\t')
+}
+
+impl SaveFailurePolicy {
+    /// A copy of `session` to roll back to, if this policy needs one
+    pub(crate) fn snapshot(self, session: &Session) -> Option<Session> {
+        (self == Self::PropagateAndRollback).then(|| session.clone())
+    }
+
+    /// Settle a failed auto-save of `session`
+    ///
+    /// Returns `Ok` if the caller should carry on unsaved, or the error to
+    /// return, first restoring `session` from `snapshot` if rolling back.
+    pub(crate) fn recover(
+        self,
+        error: ContextError,
+        session: &mut Session,
+        snapshot: Option<Session>,
+        schedule: &mut SaveSchedule,
+    ) -> Result<()> {
+        match (self, snapshot) {
+            (Self::LogAndContinue, _) => {
+                warn!("Failed to save session {}, keeping it in memory: {}", session.id, error);
+                Ok(())
+            }
+            (Self::PropagateAndRollback, Some(snapshot)) => {
+                *session = snapshot;
+                schedule.message_removed(&session.id);
+                Err(error)
+            }
+            _ => Err(error),
+        }
+    }
+}
+
+/// Tracks unsaved messages per session to apply a [`Durability`] policy
+#[derive(Debug, Default)]
+pub(crate) struct SaveSchedule {
+    durability: Durability,
+    /// Messages added per session since it was last persisted
+    unsaved: HashMap<Uuid, usize>,
+}
+
+impl SaveSchedule {
+    pub(crate) fn new(durability: Durability) -> Self {
+        Self { durability, unsaved: HashMap::new() }
+    }
+
+    /// Count a newly added message and decide whether a save is due
+    pub(crate) fn message_added(&mut self, session_id: &Uuid) -> bool {
+        match self.durability {
+            Durability::EveryMessage => true,
+            Durability::EveryN(n) => {
+                let unsaved = self.unsaved.entry(*session_id).or_insert(0);
+                *unsaved += 1;
+                *unsaved >= n
+            }
+            Durability::OnDemand => false,
+        }
+    }
+
+    /// Record that a session was persisted
+    pub(crate) fn saved(&mut self, session_id: &Uuid) {
+        self.unsaved.remove(session_id);
+    }
+
+    /// Forget a counted message that was taken back out of its session
+    pub(crate) fn message_removed(&mut self, session_id: &Uuid) {
+        if let Some(unsaved) = self.unsaved.get_mut(session_id) {
+            *unsaved = unsaved.saturating_sub(1);
+        }
+    }
+}
+
+/// How a manager creates and reloads sessions: id version, naming, clock,
+/// and trimming on load
+///
+/// Shared by [`SessionManager`] and
+/// [`AsyncSessionManager`](crate::AsyncSessionManager) so both honor the
+/// same [`Config`](crate::Config) options.
+pub(crate) struct SessionFactory {
+    session_id_version: SessionIdVersion,
+    trim_incomplete_on_load: bool,
+    pub(crate) clock: Option<Arc<dyn Clock>>,
+    pub(crate) namer: Option<Box<dyn SessionNamer>>,
+}
+
+impl SessionFactory {
+    pub(crate) fn of(config: &crate::Config) -> Self {
+        Self {
+            session_id_version: config.session_id_version,
+            trim_incomplete_on_load: config.trim_incomplete_on_load,
+            clock: None,
+            namer: None,
+        }
+    }
+
+    /// A new, unsaved session on the clock, with an id of the configured
+    /// version and a name from the namer
+    pub(crate) fn fresh_session(&self) -> Session {
+        let mut session = match &self.clock {
+            Some(clock) => Session::with_clock(Arc::clone(clock)),
+            None => Session::new(),
+        };
+        session.id = self.new_id();
+        if let Some(namer) = &self.namer {
+            session.name = namer.generate_name();
+        }
+        session
+    }
+
+    /// A session id of the configured version
+    pub(crate) fn new_id(&self) -> Uuid {
+        self.session_id_version.generate()
+    }
+
+    /// Put a session read from storage on the clock
+    pub(crate) fn loaded(&self, session: &mut Session) {
+        if let Some(clock) = &self.clock {
+            session.set_clock(Arc::clone(clock));
+        }
+    }
+
+    /// Drop a trailing incomplete assistant message from the latest session
+    /// being resumed, if configured to
+    pub(crate) fn resumed(&self, session: &mut Session) {
+        if self.trim_incomplete_on_load {
+            let trimmed = session.trim_incomplete();
+            if trimmed > 0 {
+                warn!("Trimmed {} incomplete assistant messages from session {}", trimmed, session.id);
+            }
+        }
+    }
+}
+
+/// The per-message and per-session caps from [`Config`](crate::Config)
+#[derive(Debug, Clone)]
+pub(crate) struct SizeLimits {
+    max_messages: Option<usize>,
+    max_bytes: Option<usize>,
+    max_metadata_bytes: Option<usize>,
+    content_policy: ContentPolicy,
+}
+
+impl SizeLimits {
+    pub(crate) fn of(config: &crate::Config) -> Self {
+        Self {
+            max_messages: config.max_messages,
+            max_bytes: config.max_bytes,
+            max_metadata_bytes: config.max_metadata_bytes,
+            content_policy: config.content_policy,
+        }
+    }
+
+    /// Check a message about to be added, applying the content policy
+    pub(crate) fn check_message(&self, message: &mut Message) -> Result<()> {
+        self.check_metadata_size(message)?;
+        self.apply_content_policy(message)
+    }
+
+    fn check_metadata_size(&self, message: &Message) -> Result<()> {
+        if let Some(max_metadata_bytes) = self.max_metadata_bytes {
+            let bytes = serde_json::to_vec(&message.metadata)?.len();
+            if bytes > max_metadata_bytes {
+                return Err(ContextError::MetadataTooLarge(format!(
+                    "message {} has {} bytes of metadata (limit {})",
+                    message.id, bytes, max_metadata_bytes
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_content_policy(&self, message: &mut Message) -> Result<()> {
+        match self.content_policy {
+            ContentPolicy::Allow => {}
+            ContentPolicy::Sanitize => {
+                message.sanitize();
+            }
+            ContentPolicy::Reject => {
+                if let Some((offset, c)) = message.first_disallowed_control() {
+                    return Err(ContextError::InvalidContent(format!(
+                        "message {} contains control character U+{:04X} at offset {}",
+                        message.id, c as u32, offset
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_session(&self, session: &mut Session) -> Result<()> {
+        if let Some(max_messages) = self.max_messages
+            && session.messages.len() > max_messages
+        {
+            return Err(ContextError::SessionTooLarge(format!(
+                "session {} has {} messages (limit {})",
+                session.id,
+                session.messages.len(),
+                max_messages
+            )));
+        }
+
+        if let Some(max_bytes) = self.max_bytes {
+            let bytes = session.serialized_size()?;
+            if bytes > max_bytes {
+                return Err(ContextError::SessionTooLarge(format!(
+                    "session {} is {} bytes serialized (limit {})",
+                    session.id, bytes, max_bytes
+                )));
+            }
+        }
+
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Session manager for loading, saving, and managing sessions
+///
+/// `SessionManager` is `Send + Sync` and can be shared between threads or
+/// tasks behind an `Arc`. Loads, appends, and saves for the same session id
+/// are serialized, so each save reflects a complete `add_message`; different
+/// sessions proceed in parallel. Tasks holding their own copies of one
+/// session can all call `add_message`: each append is rebased onto whatever
+/// the others saved first.
+pub struct SessionManager {
+    storage: Box<dyn SessionStorage>,
+    compaction_strategy: CompactionStrategy,
+    compactor: Option<Box<dyn ContextCompactor>>,
+    max_tokens: usize,
+    compaction_target: usize,
+    token_model: Option<TokenModel>,
+    auto_save: bool,
+    persist_empty_sessions: bool,
+    skip_empty_sessions: bool,
+    save_schedule: Mutex<SaveSchedule>,
+    /// Per-session locks serializing appends and saves, dropped when idle
+    session_locks: Mutex<HashMap<Uuid, Arc<Mutex<()>>>>,
+    limits: SizeLimits,
+    save_failure_policy: SaveFailurePolicy,
+    factory: SessionFactory,
+    metrics: Box<dyn Metrics>,
+    events: EventBus,
+    /// Messages prepended by `prepared_messages`, never stored in sessions
+    prelude: Vec<Message>,
+}
+
+impl SessionManager {
+    /// Create a new session manager with default storage
+    pub fn new() -> Result<Self> {
+        Self::with_config(crate::Config::default())
+    }
+
+    /// Create a new session manager with custom configuration
+    pub fn with_config(mut config: crate::Config) -> Result<Self> {
+        let storage = match config.storage_dir.take() {
+            Some(dir) => crate::storage::FileStorage::with_directory(dir)?,
+            None => crate::storage::FileStorage::new()?,
+        };
+        Self::with_storage(Box::new(storage), config)
+    }
+
+    /// Create a session manager over any storage backend
+    ///
+    /// `config.storage_dir` is ignored; the backend decides where sessions live.
+    /// Fails with `ContextError::Config` if the config is invalid, e.g. the
+    /// compaction strategy's budgets exceed the compaction target; see
+    /// [`Config::validate`](crate::Config::validate).
+    pub fn with_storage(storage: Box<dyn SessionStorage>, config: crate::Config) -> Result<Self> {
+        config.validate()?;
+        if let Some(age) = config.max_session_age {
+            storage.cleanup_older_than(age)?;
+        }
+
+        Ok(Self {
+            storage,
+            factory: SessionFactory::of(&config),
+            compaction_target: config.compaction_target(),
+            limits: SizeLimits::of(&config),
+            compaction_strategy: config.compaction_strategy,
+            compactor: None,
+            max_tokens: config.max_tokens,
+            token_model: config.token_model,
+            auto_save: config.auto_save,
+            persist_empty_sessions: config.persist_empty_sessions,
+            skip_empty_sessions: config.skip_empty_sessions,
+            save_schedule: Mutex::new(SaveSchedule::new(config.durability)),
+            session_locks: Mutex::new(HashMap::new()),
+            save_failure_policy: config.save_failure_policy,
+            metrics: Box::new(NoopMetrics),
+            events: EventBus::default(),
+            prelude: Vec::new(),
+        })
+    }
+
+    /// Compact with a custom compactor instead of `config.compaction_strategy`
+    ///
+    /// The compactor is handed the compaction target and measures tokens its
+    /// own way; `config.token_model` only decides when compaction triggers.
+    pub fn with_compactor(mut self, compactor: Box<dyn ContextCompactor>) -> Self {
+        self.compactor = Some(compactor);
+        self
+    }
+
+    /// Report saves, loads, and compactions to a metrics sink
+    pub fn with_metrics(mut self, metrics: Box<dyn Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Subscribe to saves, loads, compactions, and deletes from now on
+    ///
+    /// Every call returns a new receiver that gets every later event, so
+    /// several consumers can watch the same manager. Events queue until
+    /// received; drop the receiver to unsubscribe. Async consumers can drain
+    /// it from a blocking task.
+    pub fn event_stream(&self) -> std::sync::mpsc::Receiver<SessionEvent> {
+        self.events.subscribe()
+    }
+
+    /// Share `prelude` (e.g. house-rules system prompts) across every session
+    ///
+    /// The prelude is only added by [`SessionManager::prepared_messages`]; it
+    /// is never written to session files and does not count toward
+    /// `max_tokens`, so leave headroom for it.
+    pub fn with_prelude(mut self, prelude: Vec<Message>) -> Self {
+        self.prelude = prelude;
+        self
+    }
+
+    /// The session's messages with the prelude in front, ready to send
+    ///
+    /// Prelude messages already present in the session as system messages
+    /// with the same content are not repeated.
+    pub fn prepared_messages(&self, session: &Session) -> Vec<Message> {
+        self.prelude.iter()
+            .filter(|prelude| {
+                !session.messages.iter().any(|m| m.role == MessageRole::System && m.content == prelude.content)
+            })
+            .chain(&session.messages)
+            .cloned()
+            .collect()
+    }
+
+    /// Stamp sessions this manager creates or loads with `clock`
+    ///
+    /// See [`Session::with_clock`] for what the clock controls.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.factory.clock = Some(clock);
+        self
+    }
+
+    /// Name sessions created without a name with `namer`
+    ///
+    /// Without one they are named by
+    /// [`TimestampNamer`](crate::naming::TimestampNamer) on the manager's clock.
+    pub fn with_namer(mut self, namer: Box<dyn SessionNamer>) -> Self {
+        self.factory.namer = Some(namer);
+        self
+    }
+
+    /// Bump the session version, save through storage, and report it
+    ///
+    /// The version bump is undone if the save fails.
+    fn persist(&self, session: &mut Session) -> Result<()> {
+        session.version += 1;
+        if let Err(e) = self.storage.save_session(session) {
+            session.version -= 1;
+            return Err(e);
+        }
+        lock(&self.save_schedule).saved(&session.id);
+        self.metrics.record_save(session);
+        self.events.emit(SessionEvent::Saved {
+            session_id: session.id,
+            version: session.version,
+            message_count: session.messages.len(),
+        });
+        Ok(())
+    }
+
+    /// Run `f` holding the lock for `session_id`
+    fn with_session_lock<R>(&self, session_id: Uuid, f: impl FnOnce() -> R) -> R {
+        let session_lock = Arc::clone(lock(&self.session_locks).entry(session_id).or_default());
+        let result = {
+            let _guard = lock(&session_lock);
+            f()
+        };
+
+        // Forget the lock once nobody else holds or waits on it
+        let mut locks = lock(&self.session_locks);
+        drop(session_lock);
+        if locks.get(&session_id).is_some_and(|l| Arc::strong_count(l) == 1) {
+            locks.remove(&session_id);
+        }
+        result
+    }
+
+    /// Report a session read from storage
+    fn loaded(&self, mut session: Session) -> Session {
+        self.factory.loaded(&mut session);
+        self.metrics.record_load(&session);
+        self.events.emit(SessionEvent::Loaded { session_id: session.id });
+        session
+    }
+
+    /// Load the most recent session, creating one if none exists
+    ///
+    /// With `skip_empty_sessions`, an empty latest session is passed over for
+    /// the most recently modified one with messages.
+    pub fn load_latest(&self) -> Result<Session> {
+        self.load_latest_or_created().map(|(session, _)| session)
+    }
+
+    /// Like [`SessionManager::load_latest`], also reporting whether the
+    /// session was newly created (`true`) rather than resumed
+    pub fn load_latest_or_created(&self) -> Result<(Session, bool)> {
+        let latest = match self.storage.load_latest_session()? {
+            Some(session) if self.skip_empty_sessions && session.messages.is_empty() => self.latest_non_empty()?,
+            latest => latest,
+        };
+        match latest {
+            Some(mut session) => {
+                self.factory.resumed(&mut session);
+                Ok((self.loaded(session), false))
+            }
+            None => Ok((self.new_session()?, true)),
+        }
+    }
+
+    /// The most recently modified stored session with messages
+    fn latest_non_empty(&self) -> Result<Option<Session>> {
+        self.list_sessions()?
+            .into_iter()
+            .max_by_key(|info| info.modified_at)
+            .map(|info| self.storage.load_session(&info.id))
+            .transpose()
+    }
+
+    /// Save a newly created session if auto-save is on, unless it is empty
+    /// and `persist_empty_sessions` is off
+    fn persist_new(&self, session: &mut Session) -> Result<()> {
+        if self.auto_save && (self.persist_empty_sessions || !session.messages.is_empty()) {
+            self.persist(session)?;
+        }
+        Ok(())
+    }
+
+    /// Load a specific session by ID
+    ///
+    /// Holds the session's lock, so the load never sees a save from this
+    /// manager half-written.
+    pub fn load_session(&self, session_id: &uuid::Uuid) -> Result<Session> {
+        let session = self.with_session_lock(*session_id, || self.storage.load_session(session_id))?;
+        Ok(self.loaded(session))
+    }
+
+    /// Load the session with this id, or create one with exactly this id
+    ///
+    /// Useful for keying sessions off external conversation ids; parse those
+    /// with [`crate::storage::parse_session_id`]. A created session is saved
+    /// immediately when auto-save is on.
+    pub fn load_or_create(&self, session_id: Uuid) -> Result<Session> {
+        match self.load_session(&session_id) {
+            Ok(session) => Ok(session),
+            Err(ContextError::SessionNotFound(_)) => {
+                let mut session = self.factory.fresh_session();
+                session.id = session_id;
+                self.persist_new(&mut session)?;
+                Ok(session)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Save a session
+    ///
+    /// Fails with `ContextError::Conflict` if another writer saved this session
+    /// since it was loaded; reload and reapply changes to resolve it.
+    pub fn save_session(&self, session: &mut Session) -> Result<()> {
+        self.with_session_lock(session.id, || self.persist(session))
+    }
+
+    /// Save a session now, including any messages buffered by `Durability`
+    ///
+    /// Required to persist anything under `Durability::OnDemand`, and before
+    /// shutdown under `Durability::EveryN`.
+    pub fn flush(&self, session: &mut Session) -> Result<()> {
+        self.with_session_lock(session.id, || self.persist(session))
+    }
+
+    /// Create a new session
+    pub fn new_session(&self) -> Result<Session> {
+        let mut session = self.factory.fresh_session();
+        self.persist_new(&mut session)?;
+        Ok(session)
+    }
+
+    /// Create a session with a name and initial messages, saving it once
+    ///
+    /// Seeds a session (a system prompt, say) without the extra save per
+    /// message that `new_session` followed by `add_message` costs. Without a
+    /// name the session is auto-named as `new_session` does. The messages get
+    /// the same metadata, compaction, and size checks as `add_message`, and
+    /// the session is saved if `auto_save` is on.
+    pub fn create_session(&self, name: Option<String>, mut initial: Vec<Message>) -> Result<Session> {
+        for message in &mut initial {
+            self.limits.check_message(message)?;
+        }
+
+        let mut session = self.factory.fresh_session();
+        if let Some(name) = name {
+            session.name = name;
+        }
+        for message in initial {
+            session.add_message(message);
+        }
+
+        self.fit_to_limits(&mut session)?;
+
+        self.persist_new(&mut session)?;
+        Ok(session)
+    }
+
+    /// Load a session, save a duplicate of it under a new id, and return the duplicate
+    pub fn duplicate_session(&self, session_id: &uuid::Uuid) -> Result<Session> {
+        let mut copy = self.load_session(session_id)?.duplicate();
+        copy.id = self.factory.new_id();
+        self.persist(&mut copy)?;
+        Ok(copy)
+    }
+
+    /// Split `session` after `message_id` and save both halves
+    ///
+    /// `session` becomes the prefix (see [`Session::split_at`]) and the new
+    /// suffix session is returned. The suffix is saved first, so a failure
+    /// never loses messages: if the prefix save then fails, `session` is left
+    /// unsplit and the suffix's messages are stored twice until it is retried.
+    pub fn split_session(&self, session: &mut Session, message_id: &Uuid) -> Result<Session> {
+        let (mut prefix, mut suffix) = session.split_at(message_id)?;
+        suffix.id = self.factory.new_id();
+        self.persist(&mut suffix)?;
+        self.with_session_lock(prefix.id, || self.persist(&mut prefix))?;
+        *session = prefix;
+        Ok(suffix)
+    }
+
+    /// List all available sessions
+    ///
+    /// Sessions without messages are left out when `skip_empty_sessions` is on.
+    pub fn list_sessions(&self) -> Result<Vec<crate::storage::SessionInfo>> {
+        let mut sessions = self.storage.list_sessions()?;
+        if self.skip_empty_sessions {
+            sessions.retain(|info| info.message_count > 0);
+        }
+        Ok(sessions)
+    }
+
+    /// Names of every group with a stored session, sorted
+    pub fn list_groups(&self) -> Result<Vec<String>> {
+        let groups: BTreeSet<String> = self.list_sessions()?.into_iter().filter_map(|info| info.group).collect();
+        Ok(groups.into_iter().collect())
+    }
+
+    /// Stored sessions in `group`, as [`SessionManager::list_sessions`] lists them
+    pub fn list_sessions_in_group(&self, group: &str) -> Result<Vec<crate::storage::SessionInfo>> {
+        let mut sessions = self.list_sessions()?;
+        sessions.retain(|info| info.group.as_deref() == Some(group));
+        Ok(sessions)
+    }
+
+    /// Load the most recently modified session in `group`, if it has any
+    ///
+    /// Unlike [`SessionManager::load_latest`], nothing is created when the
+    /// group is empty.
+    pub fn load_latest_in_group(&self, group: &str) -> Result<Option<Session>> {
+        self.list_sessions_in_group(group)?
+            .into_iter()
+            .max_by_key(|info| info.modified_at)
+            .map(|info| self.load_session(&info.id))
+            .transpose()
+    }
+
+    /// Delete a session from storage
+    pub fn delete_session(&self, session_id: &Uuid) -> Result<()> {
+        self.with_session_lock(*session_id, || self.storage.delete_session(session_id))?;
+        self.events.emit(SessionEvent::Deleted { session_id: *session_id });
+        Ok(())
+    }
+
+    /// Add a message to a session with automatic compaction and saving
+    ///
+    /// Fails with `ContextError::MetadataTooLarge`, before anything changes,
+    /// if the message's metadata exceeds `max_metadata_bytes`, and with
+    /// `ContextError::InvalidContent` if it breaks `content_policy`.
+    ///
+    /// Fails with `ContextError::SessionTooLarge` if the session still exceeds
+    /// `max_messages` or `max_bytes` after compaction. In that case nothing is
+    /// saved and `session` is left as it was before the call.
+    ///
+    /// If another copy of the session with the same id saved first through this
+    /// manager, the auto-save's version conflict is resolved under the session
+    /// lock: the stored session is reloaded and the messages it hasn't seen are
+    /// appended to it, so both writers' messages are kept. `session` then holds
+    /// the merged result.
+    ///
+    /// If the auto-save fails, `save_failure_policy` decides whether the error
+    /// is returned and whether the message stays; see [`SaveFailurePolicy`].
+    pub fn add_message(&self, session: &mut Session, message: Message) -> Result<()> {
+        self.add_message_detailed(session, message).map(|_| ())
+    }
+
+    /// Like [`SessionManager::add_message`], reporting whether compaction ran,
+    /// the resulting token count and whether the session was saved
+    ///
+    /// `saved` is false when auto-save is off, the save isn't due under
+    /// `durability`, or it failed under `SaveFailurePolicy::LogAndContinue`.
+    pub fn add_message_detailed(&self, session: &mut Session, message: Message) -> Result<AddMessageOutcome> {
+        self.with_session_lock(session.id, || self.add_message_locked(session, message))
+    }
+
+    fn add_message_locked(&self, session: &mut Session, mut message: Message) -> Result<AddMessageOutcome> {
+        self.limits.check_message(&mut message)?;
+        let snapshot = self.save_failure_policy.snapshot(session);
+        let compaction = session.add_message_within(message, self.token_model.as_ref(), self.max_tokens, |session| {
+            self.fit_to_limits(session)
+        })?;
+
+        // Auto-save if enabled and due
+        let mut saved = self.auto_save && lock(&self.save_schedule).message_added(&session.id);
+        if saved && let Err(e) = self.persist_rebasing(session) {
+            self.save_failure_policy.recover(e, session, snapshot, &mut lock(&self.save_schedule))?;
+            saved = false;
+        }
+
+        Ok(AddMessageOutcome {
+            total_tokens: session.total_tokens_with(self.token_model.as_ref()),
+            compacted: compaction.is_some(),
+            messages_removed: compaction.unwrap_or(0),
+            saved,
+        })
+    }
+
+    /// Save `session`, first rebasing it onto the stored copy if another
+    /// writer's save made it stale
+    fn persist_rebasing(&self, session: &mut Session) -> Result<()> {
+        match self.persist(session) {
+            Err(ContextError::Conflict(reason)) => {
+                debug!("Rebasing session {} onto its stored copy: {}", session.id, reason);
+                self.rebase_on_stored(session)?;
+                self.persist(session)
+            }
+            result => result,
+        }
+    }
+
+    /// Replace `session` with its stored copy plus the messages storage
+    /// hasn't seen, i.e. those after the last message both have
+    fn rebase_on_stored(&self, session: &mut Session) -> Result<()> {
+        let mut stored = self.storage.load_session(&session.id)?;
+        self.factory.loaded(&mut stored);
+        let known: HashSet<Uuid> = stored.messages.iter().map(|m| m.id).collect();
+        let unseen = session.messages.iter().rposition(|m| known.contains(&m.id)).map_or(0, |i| i + 1);
+        for message in session.messages.drain(unseen..) {
+            stored.add_message(message);
+        }
+        *session = stored;
+
+        self.fit_to_limits(session).map(|_| ())
+    }
+
+    /// Compact `session` if it's over `max_tokens`, then check the size
+    /// limits; returns how many messages were removed, if compaction ran
+    ///
+    /// The compaction metric and event are only recorded once the limits pass.
+    fn fit_to_limits(&self, session: &mut Session) -> Result<Option<usize>> {
+        let model = self.token_model.as_ref();
+        let tokens_before = session.track_tokens_with(model);
+        if tokens_before <= self.max_tokens {
+            self.limits.check_session(session)?;
+            return Ok(None);
+        }
+
+        let before: Vec<Uuid> = session.messages.iter().map(|m| m.id).collect();
+        compact(session, self.compactor.as_deref(), &self.compaction_strategy, self.compaction_target, model)?;
+        self.limits.check_session(session)?;
+        let removed_tokens = tokens_before.saturating_sub(session.total_tokens_with(model));
+        self.metrics.record_compaction(&session.id, removed_tokens);
+        let kept: HashSet<Uuid> = session.messages.iter().map(|m| m.id).collect();
+        let messages_removed = before.iter().filter(|id| !kept.contains(id)).count();
+        self.events.emit(SessionEvent::Compacted { session_id: session.id, removed_tokens, messages_removed });
+        Ok(Some(messages_removed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compaction::RetentionOptions;
+    use crate::storage::FileStorage;
+    use tempfile::TempDir;
+
+    fn manager_in(temp_dir: &TempDir, config: crate::Config) -> SessionManager {
+        let storage = FileStorage::with_directory(temp_dir.path()).unwrap();
+        SessionManager::with_storage(Box::new(storage), config).unwrap()
+    }
+
+    #[test]
+    fn test_duplicate_session() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = manager_in(&temp_dir, crate::Config::default());
+
+        let mut original = Session::with_name("draft".to_string());
+        original.add_user_message("Hello".to_string());
+        manager.save_session(&mut original).unwrap();
+
+        let copy = manager.duplicate_session(&original.id).unwrap();
+        assert_ne!(copy.id, original.id);
+        assert_eq!(copy.name, "draft (copy)");
+        assert_eq!(copy.messages.len(), 1);
+        assert_eq!(copy.metadata["parent_session_id"], original.id.to_string());
+
+        assert_eq!(manager.load_session(&copy.id).unwrap().messages.len(), 1);
+        assert!(!manager.load_session(&original.id).unwrap().metadata.contains_key("parent_session_id"));
+    }
+
+    #[test]
+    fn test_create_session_saves_once() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = manager_in(&temp_dir, crate::Config::default());
+
+        let initial = vec![Message::system("Be brief".to_string()), Message::user("Hello".to_string())];
+        let session = manager.create_session(Some("bootstrap".to_string()), initial).unwrap();
+        assert_eq!(session.version, 1);
+
+        let loaded = manager.load_session(&session.id).unwrap();
+        assert_eq!(loaded.name, "bootstrap");
+        assert_eq!(loaded.messages.len(), 2);
+        assert_eq!(loaded.messages[0].role, MessageRole::System);
+
+        let unnamed = manager.create_session(None, Vec::new()).unwrap();
+        assert!(!unnamed.name.is_empty());
+    }
+
+    #[test]
+    fn test_typed_metadata() {
+        let mut session = Session::new();
+        session.set_meta("project", "parser").unwrap();
+        session.set_meta("turns", 3).unwrap();
+        session.set_meta("archived", false).unwrap();
+        session.set_meta("tags", ["a", "b"]).unwrap();
+        assert_eq!(session.get_meta_str("project"), Some("parser"));
+        assert_eq!(session.get_meta_i64("turns"), Some(3));
+        assert_eq!(session.get_meta_bool("archived"), Some(false));
+        assert_eq!(session.get_meta_str("turns"), None);
+        assert_eq!(session.get_meta_i64("missing"), None);
+        assert_eq!(session.metadata["tags"], serde_json::json!(["a", "b"]));
+
+        let mut message = Message::assistant("partial".to_string());
+        message.set_meta("incomplete", true).unwrap();
+        assert_eq!(message.get_meta_bool("incomplete"), Some(true));
+        message.set_meta("tool_call_id", "call_1").unwrap();
+        assert_eq!(message.tool_call_id(), Some("call_1"));
+    }
+
+    #[test]
+    fn test_stats() {
+        let empty = Session::new().stats();
+        assert_eq!(empty.total_messages, 0);
+        assert_eq!(empty.first_timestamp, None);
+        assert_eq!(empty.average_message_length, 0.0);
+
+        let mut session = Session::new();
+        session.add_message(Message::system("ab".to_string()));
+        session.add_message(Message::user("abcd".to_string()));
+        session.add_message(Message::user("abcdef".to_string()));
+
+        let stats = session.stats();
+        assert_eq!(stats.total_messages, 3);
+        assert_eq!(stats.messages_by_role[&MessageRole::User], 2);
+        assert_eq!(stats.messages_by_role[&MessageRole::System], 1);
+        assert!(!stats.messages_by_role.contains_key(&MessageRole::Assistant));
+        assert_eq!(stats.total_tokens, session.total_tokens());
+        assert_eq!(stats.first_timestamp, Some(session.messages[0].timestamp));
+        assert_eq!(stats.last_timestamp, Some(session.messages[2].timestamp));
+        assert_eq!(stats.average_message_length, 4.0);
+    }
+
+    #[test]
+    fn test_trim_incomplete() {
+        let mut session = Session::new();
+        session.add_user_message("Explain lifetimes".to_string());
+        session.add_assistant_message("Lifetimes are".to_string());
+        assert_eq!(session.trim_incomplete(), 0);
+
+        session.messages[1].metadata.insert("incomplete".to_string(), serde_json::json!(true));
+        session.add_assistant_message("   ".to_string());
+        assert_eq!(session.trim_incomplete(), 2);
+        assert_eq!(session.messages.len(), 1);
+        assert_eq!(session.total_tokens(), session.messages[0].estimate_tokens());
+
+        // A tool-call turn has no text of its own but is complete
+        let mut tool_turn = session.clone();
+        tool_turn.add_message(
+            Message::assistant(String::new())
+                .with_metadata("tool_calls".to_string(), serde_json::json!([{ "id": "call_1" }])),
+        );
+        assert_eq!(tool_turn.trim_incomplete(), 0);
+        assert_eq!(tool_turn.messages.len(), 2);
+
+        let temp_dir = TempDir::new().unwrap();
+        let manager = manager_in(&temp_dir, crate::Config {
+            trim_incomplete_on_load: true,
+            ..Default::default()
+        });
+        manager.add_message(&mut session, Message::assistant(String::new())).unwrap();
+        assert_eq!(manager.load_latest().unwrap().messages.len(), 1);
+    }
+
+    #[test]
+    fn test_running_token_total() {
+        let mut session = Session::new();
+        session.add_user_message("abcd".repeat(10));
+        session.add_assistant_message("abcd".repeat(5));
+        assert_eq!(session.total_tokens(), 15);
+
+        // Edits through messages_mut are counted, however they change the messages
+        session.messages_mut().push(Message::user("abcd".to_string()));
+        assert_eq!(session.total_tokens(), 16);
+        session.add_user_message("abcd".to_string());
+        assert_eq!(session.total_tokens(), 17);
+        session.messages_mut().pop();
+        session.messages_mut().push(Message::user("abcd".repeat(3)));
+        assert_eq!(session.total_tokens(), 19);
+        session.messages_mut()[3].content = "abcd".to_string();
+        assert_eq!(session.total_tokens(), 17);
+        session.messages_mut()[0].set_token_count(1);
+        assert_eq!(session.total_tokens(), 8);
+
+        // Deserialized sessions start without a cache
+        let json = serde_json::to_string(&session).unwrap();
+        let mut loaded: Session = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.total_tokens(), 8);
+        loaded.add_user_message("abcd".to_string());
+        assert_eq!(loaded.total_tokens(), 9);
+
+        // A tracked model's total is kept up by appends too
+        let model = TokenModel::default().with_overhead(MessageRole::User, 3);
+        assert_eq!(loaded.track_tokens_with(Some(&model)), 9 + 3 * 4);
+        loaded.add_user_message("abcd".to_string());
+        assert_eq!(loaded.fresh_token_cache().unwrap().model.as_ref().unwrap().1, 10 + 3 * 5);
+        assert_eq!(loaded.total_tokens_with(Some(&model)), 10 + 3 * 5);
+        loaded.messages_mut()[0].token_count = None;
+        assert!(loaded.fresh_token_cache().is_none());
+        assert_eq!(loaded.total_tokens(), 19);
+        assert_eq!(loaded.total_tokens_with(Some(&model)), 19 + 3 * 5);
+    }
+
+    #[test]
+    fn test_merge_timeline() {
+        let start = Utc::now();
+        let at = |seconds| start + chrono::Duration::seconds(seconds);
+
+        let mut planner = Session::new();
+        planner.add_message(Message::user("plan".to_string()).with_timestamp(at(0)));
+        planner.add_message(Message::assistant("delegate".to_string()).with_timestamp(at(3)));
+        let mut worker = Session::new();
+        worker.add_message(Message::user("task".to_string()).with_timestamp(at(1)));
+        worker.add_message(Message::assistant("done".to_string()).with_timestamp(at(3)));
+
+        let timeline = merge_timeline(&[&planner, &worker]);
+        let contents: Vec<&str> = timeline.iter().map(|(_, m)| m.content.as_str()).collect();
+        assert_eq!(contents, ["plan", "task", "delegate", "done"]);
+        assert_eq!(timeline[1].0, worker.id);
+        assert_eq!(timeline[2].0, planner.id);
+        assert!(merge_timeline(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_seq_breaks_timestamp_ties() {
+        let at = Utc::now();
+        let mut imported = Session::new();
+        for content in ["first", "second", "third"] {
+            imported.add_message(Message::user(content.to_string()).with_timestamp(at));
+        }
+        assert_eq!(imported.messages.iter().map(|m| m.seq).collect::<Vec<_>>(), [0, 1, 2]);
+
+        // Insertion order wins over position among equal timestamps
+        imported.messages.swap(0, 2);
+        let restored: Session = serde_json::from_str(&serde_json::to_string(&imported).unwrap()).unwrap();
+        let mut other = Session::new();
+        other.add_message(Message::user("elsewhere".to_string()).with_timestamp(at));
+        let timeline = merge_timeline(&[&restored, &other]);
+        let contents: Vec<&str> = timeline.iter().map(|(_, m)| m.content.as_str()).collect();
+        assert_eq!(contents, ["first", "second", "third", "elsewhere"]);
+
+        // Messages saved before `seq` existed load as 0 and keep their position
+        let mut legacy = serde_json::to_value(&imported).unwrap();
+        for message in legacy["messages"].as_array_mut().unwrap() {
+            message.as_object_mut().unwrap().remove("seq");
+        }
+        let mut legacy: Session = serde_json::from_value(legacy).unwrap();
+        assert!(legacy.messages.iter().all(|m| m.seq == 0));
+        legacy.add_message(Message::user("new".to_string()));
+        assert_eq!(legacy.messages[3].seq, 1);
+    }
+
+    #[test]
+    fn test_normalize_timestamps_clamps_skew() {
+        let mut session = Session::new();
+        let created = session.created_at;
+        let at = |seconds| created + chrono::Duration::seconds(seconds);
+        session.add_message(Message::user("from a slow host".to_string()).with_timestamp(at(-60)));
+        session.add_message(Message::assistant("on time".to_string()).with_timestamp(at(10)));
+        session.add_message(Message::user("behind again".to_string()).with_timestamp(at(5)));
+        session.add_message(Message::assistant("later".to_string()).with_timestamp(at(20)));
+
+        assert_eq!(session.normalize_timestamps(), 2);
+        let stamps: Vec<_> = session.messages.iter().map(|m| m.timestamp).collect();
+        assert_eq!(stamps, [at(0), at(10), at(10), at(20)]);
+        let original = |message: &Message| -> DateTime<Utc> {
+            serde_json::from_value(message.metadata[ORIGINAL_TIMESTAMP_KEY].clone()).unwrap()
+        };
+        assert_eq!(original(&session.messages[2]), at(5));
+        assert!(session.messages[1].metadata.is_empty());
+        assert_eq!(session.messages_since(at(5)).len(), 3);
+        assert!(session.updated_at >= at(20));
+
+        // Already monotonic: nothing changes, originals stay put
+        assert_eq!(session.normalize_timestamps(), 0);
+        assert_eq!(original(&session.messages[0]), at(-60));
+    }
+
+    #[test]
+    fn test_unknown_role_round_trips() {
+        let mut message = serde_json::to_value(Message::user("Review this".to_string())).unwrap();
+        message["role"] = serde_json::json!("critic");
+
+        let parsed: Message = serde_json::from_value(message).unwrap();
+        assert_eq!(parsed.role, MessageRole::Unknown("critic".to_string()));
+        assert_eq!(parsed.role.as_str(), "critic");
+        assert_eq!(serde_json::to_value(&parsed).unwrap()["role"], "critic");
+        assert_eq!(serde_json::to_value(MessageRole::Tool).unwrap(), "tool");
+        assert_eq!(serde_json::from_value::<MessageRole>(serde_json::json!("tool")).unwrap(), MessageRole::Tool);
+    }
+
+    #[test]
+    fn test_set_token_counts() {
+        let mut session = Session::new();
+        session.add_user_message("abcd".repeat(10));
+        session.add_assistant_message("abcd".repeat(5));
+        session.add_tool_message("abcd".to_string());
+        assert_eq!(session.total_tokens(), 16);
+
+        let user = session.messages[0].id;
+        let assistant = session.messages[1].id;
+        session.set_token_counts(&[(user, 12), (assistant, 7), (Uuid::new_v4(), 100)]);
+        assert_eq!(session.messages[0].token_count, Some(12));
+        assert_eq!(session.messages[1].estimate_tokens(), 7);
+        assert_eq!(session.messages[2].token_count, None);
+        assert_eq!(session.total_tokens(), 20);
+    }
+
+    #[test]
+    fn test_validate_protocol() {
+        let rules = ProtocolRules::agent();
+
+        let mut session = Session::new();
+        session.add_system_message("You are an agent".to_string());
+        session.add_user_message("Run the tests".to_string());
+        session.add_assistant_message("Running".to_string());
+        session.add_tool_message("ok".to_string());
+        session.add_tool_message("ok".to_string());
+        assert!(session.validate_protocol(&rules).is_ok());
+
+        session.add_user_message("Again".to_string());
+        session.add_tool_message("stray".to_string());
+        match session.validate_protocol(&rules) {
+            Err(ContextError::ProtocolViolation { index, .. }) => assert_eq!(index, 6),
+            other => panic!("expected protocol violation, got {:?}", other),
+        }
+
+        let mut bad_opener = Session::new();
+        bad_opener.add_user_message("Hi".to_string());
+        match bad_opener.validate_protocol(&rules) {
+            Err(ContextError::ProtocolViolation { index, .. }) => assert_eq!(index, 0),
+            other => panic!("expected protocol violation, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_compaction_preserves_task_prompt() {
+        let mut session = Session::new();
+        session.add_system_message("You are an agent".to_string());
+        session.add_user_message("Refactor the parser module".to_string());
+        for i in 0..20 {
+            session.add_assistant_message(format!("Working on step {} of the refactor", i));
+            session.add_tool_message(format!("Output of step {}: {}", i, "x".repeat(200)));
+        }
+
+        // A single-prompt agent loop, so the trailing exchange can't be kept whole
+        let pin_prompt = RetentionOptions { preserve_first_user: true, keep_trailing_exchange: false, ..Default::default() };
+        let mut sliding = session.clone();
+        sliding.compact(&CompactionStrategy::Sliding { max_tokens: 100, options: pin_prompt }, 100).unwrap();
+        assert_eq!(sliding.messages[0].content, "Refactor the parser module");
+        assert!(sliding.total_tokens() <= 100);
+
+        let mut recent = session.clone();
+        let strategy = CompactionStrategy::SystemAndRecent {
+            system_tokens: 20,
+            recent_tokens: 80,
+            options: pin_prompt,
+        };
+        recent.compact(&strategy, 100).unwrap();
+        assert_eq!(recent.messages[0].role, MessageRole::System);
+        assert_eq!(recent.messages[1].content, "Refactor the parser module");
+        assert!(recent.total_tokens() <= 100);
+
+        let mut unpinned = session.clone();
+        let options = RetentionOptions { keep_trailing_exchange: false, ..Default::default() };
+        unpinned.compact(&CompactionStrategy::Sliding { max_tokens: 100, options }, 100).unwrap();
+        assert!(unpinned.messages.iter().all(|m| m.role != MessageRole::User));
+    }
+
+    #[test]
+    fn test_compaction_keeps_trailing_exchange() {
+        let mut session = Session::new();
+        session.add_system_message("You are helpful".to_string());
+        for i in 0..5 {
+            session.add_user_message(format!("Question {}", i));
+            session.add_assistant_message(format!("Answer {}", i));
+        }
+        session.add_user_message("Run the tests".to_string());
+        session.add_assistant_message(format!("Running: {}", "x".repeat(80)));
+        session.add_tool_message(format!("Output: {}", "y".repeat(80)));
+        let tail: Vec<Uuid> = session.messages[11..].iter().map(|m| m.id).collect();
+
+        // A budget far below the trailing exchange alone still keeps all of it
+        let strategies = [
+            CompactionStrategy::Sliding { max_tokens: 5, options: RetentionOptions { keep_trailing_exchange: true, ..Default::default() } },
+            CompactionStrategy::SystemAndRecent {
+                system_tokens: 5,
+                recent_tokens: 5,
+                options: RetentionOptions { keep_trailing_exchange: true, ..Default::default() },
+            },
+        ];
+        for strategy in &strategies {
+            let mut compacted = session.clone();
+            compacted.compact(strategy, 10).unwrap();
+            let conversation: Vec<Uuid> = compacted.messages.iter()
+                .filter(|m| m.role != MessageRole::System)
+                .map(|m| m.id)
+                .collect();
+            assert_eq!(conversation, tail);
+        }
+
+        // Without it the budget eats into the trailing exchange
+        let mut unprotected = session.clone();
+        let options = RetentionOptions { keep_trailing_exchange: false, ..Default::default() };
+        unprotected.compact(&CompactionStrategy::Sliding { max_tokens: 5, options }, 10).unwrap();
+        assert!(unprotected.messages.iter().all(|m| m.id != tail[0]));
+    }
+
+    #[test]
+    fn test_exchange_aware_compaction_keeps_pairs() {
+        let mut session = Session::new();
+        session.add_system_message("You are helpful".to_string());
+        for i in 0..10 {
+            session.add_user_message(format!("Question {}", i));
+            session.add_assistant_message(format!("Answer {}: {}", i, "x".repeat(40)));
+            session.add_tool_message(format!("Tool output {}", i));
+        }
+
+        // Every kept user message must bring all of its replies, and no reply
+        // may appear without its question
+        let assert_whole_exchanges = |session: &Session| {
+            let conversation: Vec<&Message> = session.messages.iter()
+                .filter(|m| m.role != MessageRole::System)
+                .collect();
+            assert!(!conversation.is_empty());
+            assert_eq!(conversation.len() % 3, 0);
+            for exchange in conversation.chunks(3) {
+                assert_eq!(exchange[0].role, MessageRole::User);
+                assert_eq!(exchange[1].role, MessageRole::Assistant);
+                assert_eq!(exchange[2].role, MessageRole::Tool);
+            }
+        };
+
+        for budget in [30, 45, 70, 100] {
+            let mut sliding = session.clone();
+            sliding.compact(&CompactionStrategy::Sliding { max_tokens: budget, options: RetentionOptions { exchange_aware: true, ..Default::default() } }, budget).unwrap();
+            assert_whole_exchanges(&sliding);
+            assert!(sliding.total_tokens() <= budget);
+
+            let mut recent = session.clone();
+            let strategy = CompactionStrategy::SystemAndRecent {
+                system_tokens: 10,
+                recent_tokens: budget,
+                options: RetentionOptions { preserve_first_user: true, exchange_aware: true, ..Default::default() },
+            };
+            recent.compact(&strategy, budget + 10).unwrap();
+            assert_eq!(recent.messages[0].role, MessageRole::System);
+            assert_eq!(recent.messages[1].content, "Question 0");
+            assert_whole_exchanges(&recent);
+        }
+
+        // Message-level sliding leaves an orphaned reply at this budget
+        let mut unaware = session.clone();
+        unaware.compact(&CompactionStrategy::Sliding { max_tokens: 45, options: RetentionOptions::default() }, 45).unwrap();
+        assert_ne!(unaware.messages[0].role, MessageRole::User);
+    }
+
+    #[derive(Default)]
+    struct CountingMetrics {
+        saves: std::sync::atomic::AtomicUsize,
+        loads: std::sync::atomic::AtomicUsize,
+        compacted_tokens: std::sync::atomic::AtomicUsize,
+    }
+
+    impl Metrics for CountingMetrics {
+        fn record_save(&self, _session: &Session) {
+            self.saves.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+
+        fn record_load(&self, _session: &Session) {
+            self.loads.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+
+        fn record_compaction(&self, _session_id: &Uuid, removed_tokens: usize) {
+            self.compacted_tokens.fetch_add(removed_tokens, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_manager_reports_metrics() {
+        use std::sync::atomic::Ordering;
+
+        let temp_dir = TempDir::new().unwrap();
+        let metrics = std::sync::Arc::new(CountingMetrics::default());
+        let manager = manager_in(&temp_dir, crate::Config {
+            max_tokens: 10,
+            compaction_strategy: CompactionStrategy::Sliding { max_tokens: 10, options: RetentionOptions::default() },
+            ..Default::default()
+        })
+        .with_metrics(Box::new(metrics.clone()));
+
+        let mut session = manager.new_session().unwrap();
+        manager.add_message(&mut session, Message::user("a".repeat(32))).unwrap();
+        manager.add_message(&mut session, Message::user("b".repeat(32))).unwrap();
+        manager.load_session(&session.id).unwrap();
+
+        assert_eq!(metrics.saves.load(Ordering::SeqCst), 3);
+        assert_eq!(metrics.loads.load(Ordering::SeqCst), 1);
+        assert_eq!(metrics.compacted_tokens.load(Ordering::SeqCst), 8);
+    }
+
+    #[test]
+    fn test_event_stream_fans_out_to_every_subscriber() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = manager_in(&temp_dir, crate::Config {
+            max_tokens: 10,
+            compaction_strategy: CompactionStrategy::Sliding { max_tokens: 10, options: RetentionOptions::default() },
+            ..Default::default()
+        });
+        let audit = manager.event_stream();
+        let dashboard = manager.event_stream();
+
+        let mut session = manager.new_session().unwrap();
+        let id = session.id;
+        manager.add_message(&mut session, Message::user("a".repeat(32))).unwrap();
+        manager.add_message(&mut session, Message::user("b".repeat(32))).unwrap();
+        drop(dashboard);
+        manager.load_session(&id).unwrap();
+        manager.delete_session(&id).unwrap();
+
+        let events: Vec<SessionEvent> = audit.try_iter().collect();
+        assert_eq!(events, [
+            SessionEvent::Saved { session_id: id, version: 1, message_count: 0 },
+            SessionEvent::Saved { session_id: id, version: 2, message_count: 1 },
+            SessionEvent::Compacted { session_id: id, removed_tokens: 8, messages_removed: 1 },
+            SessionEvent::Saved { session_id: id, version: 3, message_count: 1 },
+            SessionEvent::Loaded { session_id: id },
+            SessionEvent::Deleted { session_id: id },
+        ]);
+        // Subscribing later only sees later events
+        let late = manager.event_stream();
+        assert!(late.try_recv().is_err());
+        assert!(manager.delete_session(&id).is_err());
+
+        // Seeding over the limit compacts and reports it like add_message
+        let seeded = manager.create_session(None, vec![Message::user("a".repeat(32)), Message::user("b".repeat(32))]).unwrap();
+        assert_eq!(late.try_iter().collect::<Vec<_>>(), [
+            SessionEvent::Compacted { session_id: seeded.id, removed_tokens: 8, messages_removed: 1 },
+            SessionEvent::Saved { session_id: seeded.id, version: 1, message_count: 1 },
+        ]);
+    }
+
+    #[test]
+    fn test_load_or_create() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = manager_in(&temp_dir, crate::Config::default());
+        let external_id = Uuid::new_v4();
+
+        let mut created = manager.load_or_create(external_id).unwrap();
+        assert_eq!(created.id, external_id);
+        assert!(created.messages.is_empty());
+
+        manager.add_message(&mut created, Message::user("Hello".to_string())).unwrap();
+
+        let loaded = manager.load_or_create(external_id).unwrap();
+        assert_eq!(loaded.id, external_id);
+        assert_eq!(loaded.messages.len(), 1);
+    }
+
+    #[test]
+    fn test_split_session_links_halves() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = manager_in(&temp_dir, crate::Config::default());
+        let mut session = manager.create_session(Some("long".to_string()), vec![
+            Message::system("You are helpful".to_string()),
+            Message::user("First question".to_string()),
+            Message::assistant("First answer".to_string()),
+            Message::user("Second question".to_string()),
+        ]).unwrap();
+        let split_id = session.messages[2].id;
+
+        let suffix = manager.split_session(&mut session, &split_id).unwrap();
+        assert_eq!(session.messages.last().unwrap().id, split_id);
+        assert_eq!(session.total_tokens(), session.messages.iter().map(|m| m.estimate_tokens()).sum::<usize>());
+        assert_eq!(suffix.name, "long (continued)");
+        assert_eq!(suffix.messages.len(), 1);
+        assert_eq!(suffix.get_meta_str("parent_session_id"), Some(session.id.to_string().as_str()));
+
+        let stored_prefix = manager.load_session(&session.id).unwrap();
+        assert_eq!((stored_prefix.messages.len(), stored_prefix.version), (3, session.version));
+        assert_eq!(manager.load_session(&suffix.id).unwrap().messages[0].content, "Second question");
+
+        let err = session.split_at(&Uuid::new_v4()).unwrap_err();
+        assert!(matches!(err, ContextError::InvalidSession(_)));
+        let (whole, empty) = session.split_at(&split_id).unwrap();
+        assert_eq!((whole.messages.len(), empty.messages.len()), (3, 0));
+    }
+
+    #[test]
+    fn test_save_failure_policies() {
+        let temp_dir = TempDir::new().unwrap();
+        let sessions_dir = temp_dir.path().join("sessions");
+        let manager = |policy| SessionManager::with_config(crate::Config {
+            storage_dir: Some(sessions_dir.clone()),
+            save_failure_policy: policy,
+            ..Default::default()
+        }).unwrap();
+        let keep = manager(SaveFailurePolicy::PropagateAndKeep);
+        let rollback = manager(SaveFailurePolicy::PropagateAndRollback);
+        let carry_on = manager(SaveFailurePolicy::LogAndContinue);
+        let mut session = keep.create_session(None, vec![Message::user("saved".to_string())]).unwrap();
+
+        // Saves fail once the sessions directory is replaced by a file
+        std::fs::remove_dir_all(&sessions_dir).unwrap();
+        std::fs::write(&sessions_dir, "not a directory").unwrap();
+        let before = (session.messages.len(), session.version, session.total_tokens());
+
+        assert!(keep.add_message(&mut session, Message::user("kept".to_string())).is_err());
+        assert_eq!(session.messages.len(), 2);
+        session.messages.pop();
+        session.recount_tokens();
+
+        assert!(rollback.add_message(&mut session, Message::user("undone".to_string())).is_err());
+        assert_eq!((session.messages.len(), session.version, session.total_tokens()), before);
+
+        carry_on.add_message(&mut session, Message::user("unsaved".to_string())).unwrap();
+        assert_eq!(session.messages.len(), 2);
+        assert_eq!(session.version, before.1);
+    }
+
+    #[test]
+    fn test_concurrent_saves_conflict() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = manager_in(&temp_dir, crate::Config::default());
+
+        let session = manager.new_session().unwrap();
+        let mut tab_a = manager.load_session(&session.id).unwrap();
+        let mut tab_b = manager.load_session(&session.id).unwrap();
+
+        manager.add_message(&mut tab_a, Message::user("from a".to_string())).unwrap();
+        let mut stale = tab_b.clone();
+        stale.add_message(Message::user("edited directly".to_string()));
+        let err = manager.save_session(&mut stale).unwrap_err();
+        assert!(matches!(err, ContextError::Conflict(_)));
+        assert_eq!(stale.version, 1);
+
+        // add_message instead rebases onto the other writer's change
+        manager.add_message(&mut tab_b, Message::user("from b".to_string())).unwrap();
+        assert_eq!(tab_b.messages.iter().map(|m| m.content.as_str()).collect::<Vec<_>>(), ["from a", "from b"]);
+        let stored = manager.load_session(&session.id).unwrap();
+        assert_eq!(stored.messages.len(), 2);
+        assert_eq!(stored.version, 3);
+    }
+
+    #[test]
+    fn test_v7_session_ids_sort_by_creation() {
+        let temp_dir = TempDir::new().unwrap();
+        let legacy = Session::new();
+        FileStorage::with_directory(temp_dir.path()).unwrap().save_session(&legacy).unwrap();
+        let manager = manager_in(&temp_dir, crate::Config {
+            session_id_version: SessionIdVersion::V7,
+            ..Default::default()
+        });
+
+        let mut created = Vec::new();
+        for _ in 0..3 {
+            created.push(manager.new_session().unwrap().id);
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        created.push(manager.duplicate_session(&created[0]).unwrap().id);
+        assert!(created.iter().all(|id| id.get_version_num() == 7));
+
+        let mut sorted = created.clone();
+        sorted.sort_by_key(|id| id.to_string());
+        assert_eq!(sorted, created);
+
+        // Existing v4 sessions are unaffected
+        assert_eq!(manager.load_session(&legacy.id).unwrap().id.get_version_num(), 4);
+    }
+
+    #[test]
+    fn test_add_message_detailed_reports_outcome() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = manager_in(&temp_dir, crate::Config {
+            max_tokens: 10,
+            compaction_strategy: CompactionStrategy::Sliding {
+                max_tokens: 10,
+                options: RetentionOptions::default(),
+            },
+            durability: Durability::EveryN(2),
+            ..Default::default()
+        });
+
+        let mut session = manager.new_session().unwrap();
+        let first = manager.add_message_detailed(&mut session, Message::user("a".repeat(16)).with_token_count(4)).unwrap();
+        assert_eq!(first, AddMessageOutcome { total_tokens: 4, compacted: false, messages_removed: 0, saved: false });
+
+        let second = manager.add_message_detailed(&mut session, Message::user("b".repeat(16)).with_token_count(4)).unwrap();
+        assert_eq!(second, AddMessageOutcome { total_tokens: 8, compacted: false, messages_removed: 0, saved: true });
+
+        let third = manager.add_message_detailed(&mut session, Message::user("c".repeat(16)).with_token_count(4)).unwrap();
+        assert_eq!(third, AddMessageOutcome { total_tokens: 8, compacted: true, messages_removed: 1, saved: false });
+        assert_eq!(third.total_tokens, session.total_tokens());
+    }
+
+    #[test]
+    fn test_add_message_enforces_size_limits() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = manager_in(&temp_dir, crate::Config {
+            max_messages: Some(2),
+            ..Default::default()
+        });
+
+        let mut session = manager.new_session().unwrap();
+        manager.add_message(&mut session, Message::user("one".to_string())).unwrap();
+        manager.add_message(&mut session, Message::user("two".to_string())).unwrap();
+
+        let updated_at = session.updated_at;
+        let err = manager.add_message(&mut session, Message::user("three".to_string())).unwrap_err();
+        assert!(matches!(err, ContextError::SessionTooLarge(_)));
+        assert_eq!(manager.load_session(&session.id).unwrap().messages.len(), 2);
+        assert_eq!(session.messages.len(), 2);
+        assert_eq!(session.updated_at, updated_at);
+        assert_eq!(session.total_tokens(), 2);
+
+        let manager = manager_in(&temp_dir, crate::Config {
+            max_bytes: Some(1024),
+            ..Default::default()
+        });
+        let mut session = manager.new_session().unwrap();
+        manager.add_message(&mut session, Message::user("hello".to_string())).unwrap();
+        let err = manager.add_message(&mut session, Message::user("x".repeat(2048))).unwrap_err();
+        assert!(matches!(err, ContextError::SessionTooLarge(_)));
+        assert_eq!(session.messages.len(), 1);
+        for i in 0..3 {
+            manager.add_message(&mut session, Message::user(format!("Message {}", i))).unwrap();
+        }
+        assert_eq!(session.serialized_size().unwrap(), serde_json::to_vec(&session).unwrap().len());
+
+        // Compaction that can't get under max_messages is rolled back too
+        let manager = manager_in(&temp_dir, crate::Config {
+            max_tokens: 10,
+            max_messages: Some(1),
+            compaction_strategy: CompactionStrategy::RecentExchanges { count: 2 },
+            ..Default::default()
+        });
+        let mut session = manager.new_session().unwrap();
+        manager.add_message(&mut session, Message::user("a".repeat(24))).unwrap();
+        let err = manager.add_message(&mut session, Message::user("b".repeat(24))).unwrap_err();
+        assert!(matches!(err, ContextError::SessionTooLarge(_)));
+        assert_eq!(session.messages.len(), 1);
+        assert_eq!(session.messages[0].content, "a".repeat(24));
+
+        let manager = manager_in(&temp_dir, crate::Config {
+            max_metadata_bytes: Some(64),
+            ..Default::default()
+        });
+        let mut session = manager.new_session().unwrap();
+        let small = Message::user("ok".to_string()).with_metadata("source".to_string(), serde_json::json!("cli"));
+        manager.add_message(&mut session, small).unwrap();
+        let bloated = Message::user("ok".to_string()).with_metadata("dump".to_string(), serde_json::json!("x".repeat(100)));
+        let err = manager.add_message(&mut session, bloated).unwrap_err();
+        assert!(matches!(err, ContextError::MetadataTooLarge(_)));
+        assert_eq!(session.messages.len(), 1);
+    }
+
+    #[test]
+    fn test_content_policy_sanitizes_or_rejects_control_characters() {
+        let pasted = || Message::user("line one\r\n\tindented\0\u{1b}[31mred\u{7f}".to_string());
+
+        let mut message = pasted().with_token_count(50);
+        assert_eq!(message.first_disallowed_control(), Some((19, '\0')));
+        assert_eq!(message.sanitize(), 3);
+        assert_eq!(message.content, "line one\r\n\tindented[31mred");
+        assert_eq!(message.token_count, None);
+        assert_eq!(message.sanitize(), 0);
+
+        let temp_dir = TempDir::new().unwrap();
+        let allow = manager_in(&temp_dir, crate::Config::default());
+        let mut session = allow.new_session().unwrap();
+        allow.add_message(&mut session, pasted()).unwrap();
+        assert_eq!(session.messages[0].content, pasted().content);
+
+        let sanitize = manager_in(&temp_dir, crate::Config {
+            content_policy: ContentPolicy::Sanitize,
+            ..Default::default()
+        });
+        let session = sanitize.create_session(None, vec![pasted()]).unwrap();
+        assert_eq!(session.messages[0].content, "line one\r\n\tindented[31mred");
+
+        let reject = manager_in(&temp_dir, crate::Config {
+            content_policy: ContentPolicy::Reject,
+            ..Default::default()
+        });
+        let mut session = reject.new_session().unwrap();
+        let err = reject.add_message(&mut session, pasted()).unwrap_err();
+        assert!(matches!(err, ContextError::InvalidContent(ref reason) if reason.contains("U+0000 at offset 19")));
+        assert!(session.messages.is_empty());
+        reject.add_message(&mut session, Message::user("fine\n".to_string())).unwrap();
+    }
+
+    #[test]
+    fn test_compaction_target_ratio_leaves_headroom() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = manager_in(&temp_dir, crate::Config {
+            max_tokens: 100,
+            compaction_strategy: CompactionStrategy::Sliding { max_tokens: 70, options: RetentionOptions::default() },
+            compaction_target_ratio: 0.7,
+            ..Default::default()
+        });
+
+        // Each message estimates to 10 tokens
+        let mut session = manager.new_session().unwrap();
+        for _ in 0..11 {
+            manager.add_message(&mut session, Message::user("x".repeat(40))).unwrap();
+        }
+        assert_eq!(session.total_tokens(), 70);
+
+        manager.add_message(&mut session, Message::user("x".repeat(40))).unwrap();
+        assert_eq!(session.messages.len(), 8);
+
+        for ratio in [0.0, -0.5, 1.5, f32::NAN] {
+            let storage = FileStorage::with_directory(temp_dir.path()).unwrap();
+            let config = crate::Config { compaction_target_ratio: ratio, ..Default::default() };
+            let result = SessionManager::with_storage(Box::new(storage), config);
+            assert!(matches!(result, Err(ContextError::Config(_))), "ratio {} accepted", ratio);
+        }
+    }
+
+    #[test]
+    fn test_recent_exchanges_compaction() {
+        let mut session = Session::new();
+        session.add_assistant_message("Welcome back".to_string());
+        session.add_system_message("You are helpful".to_string());
+        for i in 0..5 {
+            session.add_user_message(format!("Question {}", i));
+            session.add_assistant_message("x".repeat(4000));
+            if i == 3 {
+                session.add_system_message("Mid-conversation note".to_string());
+            }
+        }
+
+        // Applies under the target, and keeps a huge exchange whole
+        let mut kept = session.clone();
+        kept.compact(&CompactionStrategy::RecentExchanges { count: 2 }, 10).unwrap();
+        let contents: Vec<&str> = kept.messages.iter()
+            .filter(|m| m.role != MessageRole::Assistant)
+            .map(|m| m.content.as_str())
+            .collect();
+        assert_eq!(contents, ["You are helpful", "Question 3", "Mid-conversation note", "Question 4"]);
+        assert_eq!(kept.messages.len(), 6);
+        assert!(kept.total_tokens() > 10);
+
+        let mut all = session.clone();
+        all.compact(&CompactionStrategy::RecentExchanges { count: 5 }, 10).unwrap();
+        assert_eq!(all.messages.len(), session.messages.len());
+
+        let mut none = session.clone();
+        none.compact(&CompactionStrategy::RecentExchanges { count: 0 }, 10).unwrap();
+        assert!(none.messages.iter().all(|m| m.role == MessageRole::System));
+        assert_eq!(none.messages.len(), 2);
+    }
+
+    #[test]
+    fn test_compaction_keeps_referenced_messages() {
+        let mut session = Session::new();
+        session.add_message(Message::user("Run the build".to_string()));
+        let mut result = Message::tool(format!("build log {}", "x".repeat(200)));
+        let result_id = result.id;
+        session.add_message(Message::assistant("Running".to_string()));
+        for i in 0..10 {
+            session.add_message(Message::user(format!("Filler {} {}", i, "y".repeat(40))));
+        }
+        let mut summary = Message::assistant("The build failed at step 3".to_string());
+        summary.set_meta("references", [result_id.to_string(), Uuid::new_v4().to_string(), "not-an-id".to_string()]).unwrap();
+        // A cycle back to the summary must not loop
+        result.set_meta("references", [summary.id.to_string()]).unwrap();
+        session.messages.insert(1, result);
+        session.add_message(summary);
+
+        let strategy = CompactionStrategy::Sliding { max_tokens: 30, options: RetentionOptions::default() };
+        session.compact(&strategy, 30).unwrap();
+
+        let ids: Vec<Uuid> = session.messages.iter().map(|m| m.id).collect();
+        assert_eq!(ids.len(), 3);
+        assert_eq!(ids[0], result_id);
+        assert!(session.messages[1].content.starts_with("Filler 9"));
+        assert_eq!(session.messages[2].content, "The build failed at step 3");
+        assert!(session.total_tokens() > 30);
+
+        // Without references the same compaction evicts the tool result
+        let mut plain = Session::new();
+        plain.add_message(Message::tool("x".repeat(200)));
+        plain.add_message(Message::assistant("done".to_string()));
+        plain.compact(&strategy, 30).unwrap();
+        assert_eq!(plain.messages.len(), 1);
+    }
+
+    #[test]
+    fn test_compact_preview_matches_compaction() {
+        let mut session = Session::new();
+        session.add_system_message("You are helpful".to_string());
+        for i in 0..20 {
+            session.add_user_message(format!("Question {} {}", i, "x".repeat(40)));
+        }
+        let updated_at = session.updated_at;
+
+        let strategy = CompactionStrategy::Sliding { max_tokens: 60, options: RetentionOptions::default() };
+        let preview = session.compact_preview(&strategy, 60).unwrap();
+        assert_eq!(session.messages.len(), 21);
+        assert_eq!(session.updated_at, updated_at);
+        assert_eq!(preview.kept.len() + preview.removed.len(), 21);
+        assert!(preview.truncated.is_empty());
+
+        let mut compacted = session.clone();
+        compacted.compact(&strategy, 60).unwrap();
+        assert_eq!(preview.kept, compacted.messages.iter().map(|m| m.id).collect::<Vec<_>>());
+        assert_eq!(preview.removed[0], session.messages[0].id);
+        assert_eq!(preview.projected_tokens, compacted.total_tokens());
+
+        let shrink = CompactionStrategy::CompressRole { role: MessageRole::User, max_tokens_per_message: 5 };
+        let preview = session.compact_preview(&shrink, 60).unwrap();
+        assert!(preview.removed.is_empty());
+        assert_eq!(preview.truncated.len(), 20);
+    }
+
+    #[test]
+    fn test_compress_role_only_touches_that_role() {
+        let mut session = Session::new();
+        session.add_user_message("List the repo".to_string());
+        session.add_assistant_message("Running ls".to_string());
+        for i in 0..4 {
+            session.add_tool_message(format!("{}{}", i, "é".repeat(2000)));
+        }
+        session.add_assistant_message("Here is the listing".to_string());
+        let originals: Vec<String> = session.messages.iter().map(|m| m.content.clone()).collect();
+
+        let strategy = CompactionStrategy::CompressRole { role: MessageRole::Tool, max_tokens_per_message: 50 };
+        let mut compressed = session.clone();
+        compressed.compact(&strategy, 250).unwrap();
+
+        for (message, original) in compressed.messages.iter().zip(&originals) {
+            if message.role == MessageRole::Tool {
+                assert!(message.estimate_tokens() <= 50);
+                assert!(message.content.ends_with("[truncated]"));
+                assert_eq!(message.content.chars().next(), original.chars().next());
+            } else {
+                assert_eq!(&message.content, original);
+            }
+        }
+        assert!(compressed.total_tokens() <= 250);
+
+        // Stops once under the target, oldest first
+        let mut partial = session.clone();
+        partial.compact(&strategy, 3500).unwrap();
+        assert!(partial.messages[2].content.ends_with("[truncated]"));
+        assert_eq!(partial.messages[5].content, originals[5]);
+
+        let mut dropped = session.clone();
+        dropped.compact(&CompactionStrategy::CompressRole { role: MessageRole::Tool, max_tokens_per_message: 0 }, 100).unwrap();
+        assert_eq!(dropped.messages.len(), 3);
+        assert!(dropped.messages.iter().all(|m| m.role != MessageRole::Tool));
+
+        // Caps below the marker's own size still hold, overhead included
+        for cap in [1, 2] {
+            let mut tiny = session.clone();
+            tiny.compact(&CompactionStrategy::CompressRole { role: MessageRole::Tool, max_tokens_per_message: cap }, 100).unwrap();
+            for message in tiny.messages.iter().filter(|m| m.role == MessageRole::Tool) {
+                assert!(message.estimate_tokens() <= cap);
+                assert!(!message.content.ends_with("[truncated]"));
+            }
+        }
+        let model = TokenModel::default().with_overhead(MessageRole::Tool, 2);
+        let mut framed = session.clone();
+        let strategy = CompactionStrategy::CompressRole { role: MessageRole::Tool, max_tokens_per_message: 5 };
+        framed.compact_with(&strategy, 100, Some(&model)).unwrap();
+        assert!(framed.messages.iter().filter(|m| m.role == MessageRole::Tool).all(|m| model.estimate(m) <= 5));
+        let mut overhead_only = session.clone();
+        let strategy = CompactionStrategy::CompressRole { role: MessageRole::Tool, max_tokens_per_message: 2 };
+        overhead_only.compact_with(&strategy, 100, Some(&model)).unwrap();
+        assert_eq!(overhead_only.messages.len(), 3);
+    }
+
+    #[test]
+    fn test_compaction_budgets_use_exact_counts() {
+        let mut session = Session::new();
+        session.add_user_message("x".repeat(400));
+        for _ in 0..3 {
+            session.add_message(Message::user("y".repeat(400)).with_token_count(10));
+        }
+        assert_eq!(session.total_tokens(), 130);
+
+        // Estimated at 100 tokens each these would not fit; their real 10 each do
+        let sliding = CompactionStrategy::Sliding {
+            max_tokens: 40,
+            options: RetentionOptions::default(),
+        };
+        let mut slid = session.clone();
+        slid.compact(&sliding, 40).unwrap();
+        assert_eq!(slid.messages.len(), 3);
+        assert_eq!(slid.total_tokens(), 30);
+
+        // A dense message (2 bytes per token) is cut to its real budget, not 4 bytes per token
+        let mut dense = Session::new();
+        dense.add_message(Message::tool("z".repeat(1000)).with_token_count(500));
+        let strategy = CompactionStrategy::CompressRole { role: MessageRole::Tool, max_tokens_per_message: 50 };
+        dense.compact(&strategy, 60).unwrap();
+        assert_eq!(dense.messages[0].content.len(), 100);
+        assert_eq!(dense.messages[0].token_count, Some(50));
+        assert_eq!(dense.total_tokens(), 50);
+    }
+
+    #[test]
+    fn test_coalesce_tool_messages() {
+        let mut session = Session::new();
+        session.add_user_message("Read the sources".to_string());
+        session.add_assistant_message("Reading".to_string());
+        let tool = |call: &str, content: &str, tokens: usize| {
+            Message::tool(content.to_string())
+                .with_token_count(tokens)
+                .with_metadata("tool_call_id".to_string(), serde_json::json!(call))
+                .with_metadata("tool".to_string(), serde_json::json!("read_file"))
+        };
+        session.add_message(tool("a", "fn a() {}", 4));
+        session.add_message(tool("b", "fn b() {}", 4));
+        session.add_message(tool("c", "fn c() {}", 10));
+        let first_id = session.messages[2].id;
+        let first_time = session.messages[2].timestamp;
+        let mut answer = Message::assistant("Both look fine".to_string());
+        answer.set_meta("references", [session.messages[3].id.to_string(), first_id.to_string()]).unwrap();
+        session.add_message(answer);
+
+        assert_eq!(session.coalesce_tool_messages(12), 1);
+        assert_eq!(session.messages.len(), 5);
+
+        let merged = &session.messages[2];
+        assert_eq!((merged.id, merged.timestamp), (first_id, first_time));
+        assert_eq!(merged.content, "fn a() {}\n\nfn b() {}");
+        assert_eq!(merged.token_count, Some(8));
+        assert_eq!(merged.metadata["tool_call_id"], serde_json::json!(["a", "b"]));
+        assert_eq!(merged.metadata["tool"], "read_file");
+        // c would have taken the run over the cap
+        assert_eq!(session.messages[3].content, "fn c() {}");
+        assert_eq!(session.messages[4].references(), [first_id]);
+        assert_eq!(session.total_tokens(), session.messages.iter().map(|m| m.estimate_tokens()).sum::<usize>());
+
+        assert_eq!(session.coalesce_tool_messages(12), 0);
+    }
+
+    #[test]
+    fn test_durability_modes() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = manager_in(&temp_dir, crate::Config {
+            durability: Durability::EveryN(3),
+            ..Default::default()
+        });
+        let mut session = manager.new_session().unwrap();
+        let stored_len = |manager: &SessionManager, id: &Uuid| manager.load_session(id).unwrap().messages.len();
+
+        for i in 1..=4 {
+            manager.add_message(&mut session, Message::user(format!("message {}", i))).unwrap();
+        }
+        assert_eq!(stored_len(&manager, &session.id), 3);
+        manager.flush(&mut session).unwrap();
+        assert_eq!(stored_len(&manager, &session.id), 4);
+
+        let manager = manager_in(&temp_dir, crate::Config {
+            durability: Durability::OnDemand,
+            ..Default::default()
+        });
+        for _ in 0..5 {
+            manager.add_message(&mut session, Message::user("draft".to_string())).unwrap();
+        }
+        assert_eq!(stored_len(&manager, &session.id), 4);
+        manager.flush(&mut session).unwrap();
+        assert_eq!(stored_len(&manager, &session.id), 9);
+    }
+
+    /// Keeps only the newest message, whatever the budget
+    struct KeepLast;
+
+    impl ContextCompactor for KeepLast {
+        fn compact(&self, session: &mut Session, _target_tokens: usize) -> Result<()> {
+            let keep_from = session.messages.len().saturating_sub(1);
+            session.messages.drain(..keep_from);
+            Ok(())
+        }
+
+        fn message_priority(&self, _message: &Message, _context: &Session) -> f64 {
+            0.0
+        }
+    }
+
+    #[test]
+    fn test_prelude_is_prepended_but_not_stored() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = manager_in(&temp_dir, crate::Config::default())
+            .with_prelude(vec![Message::system("House rules".to_string()), Message::system("Be brief".to_string())]);
+
+        let mut session = manager.new_session().unwrap();
+        manager.add_message(&mut session, Message::system("Be brief".to_string())).unwrap();
+        manager.add_message(&mut session, Message::user("Hi".to_string())).unwrap();
+
+        let prepared: Vec<String> = manager.prepared_messages(&session).into_iter().map(|m| m.content).collect();
+        assert_eq!(prepared, ["House rules", "Be brief", "Hi"]);
+
+        let stored = manager.load_session(&session.id).unwrap();
+        assert_eq!(stored.messages.len(), 2);
+        assert!(stored.messages.iter().all(|m| m.content != "House rules"));
+    }
+
+    #[test]
+    fn test_manager_clock_stamps_sessions() {
+        let start = Utc::now() - chrono::Duration::days(365);
+        let clock = Arc::new(crate::ManualClock::new(start).with_step(chrono::Duration::seconds(1)));
+        let temp_dir = TempDir::new().unwrap();
+        let manager = manager_in(&temp_dir, crate::Config::default()).with_clock(clock.clone());
+
+        let mut session = manager.new_session().unwrap();
+        assert_eq!(session.created_at, start);
+        manager.add_message(&mut session, Message::user("Hi".to_string())).unwrap();
+        assert_eq!(session.messages[0].timestamp, start + chrono::Duration::seconds(1));
+
+        // Loaded sessions pick the clock back up
+        let mut loaded = manager.load_session(&session.id).unwrap();
+        manager.add_message(&mut loaded, Message::user("Again".to_string())).unwrap();
+        assert_eq!(loaded.messages[1].timestamp, start + chrono::Duration::seconds(2));
+        assert_eq!(manager.load_or_create(Uuid::new_v4()).unwrap().created_at, start + chrono::Duration::seconds(3));
+
+        // Explicit timestamps, e.g. from an import, are left alone
+        let sent = DateTime::parse_from_rfc3339("2023-01-02T03:04:05Z").unwrap().with_timezone(&Utc);
+        manager.add_message(&mut loaded, Message::user("Imported".to_string()).with_timestamp(sent)).unwrap();
+        let id = Uuid::new_v4();
+        manager.add_message(&mut loaded, Message::with_id_and_time(MessageRole::Assistant, "Reply".to_string(), id, sent)).unwrap();
+        let reloaded = manager.load_session(&loaded.id).unwrap();
+        assert_eq!(reloaded.messages[2].timestamp, sent);
+        assert_eq!(reloaded.messages[3].timestamp, sent);
+        assert_eq!(reloaded.messages[0].timestamp, start + chrono::Duration::seconds(1));
+
+        // ...as are messages moved over from another session
+        let mut copy = manager.new_session().unwrap();
+        for message in reloaded.messages.clone() {
+            manager.add_message(&mut copy, message).unwrap();
+        }
+        assert!(copy.messages.iter().zip(&reloaded.messages).all(|(a, b)| a.timestamp == b.timestamp));
+    }
+
+    #[test]
+    fn test_namer_names_unnamed_sessions() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let temp_dir = TempDir::new().unwrap();
+        let counter = AtomicUsize::new(0);
+        let manager = manager_in(&temp_dir, crate::Config::default())
+            .with_namer(Box::new(move || format!("TICKET-{}", counter.fetch_add(1, Ordering::SeqCst) + 1)));
+
+        assert_eq!(manager.new_session().unwrap().name, "TICKET-1");
+        assert_eq!(manager.create_session(None, Vec::new()).unwrap().name, "TICKET-2");
+        assert_eq!(manager.create_session(Some("explicit".to_string()), Vec::new()).unwrap().name, "explicit");
+
+        // The default scheme, on an injected clock
+        let start = DateTime::parse_from_rfc3339("2024-03-05T06:07:08Z").unwrap().with_timezone(&Utc);
+        let namer = crate::TimestampNamer::with_clock(Arc::new(crate::ManualClock::new(start)));
+        let stamped = manager_in(&temp_dir, crate::Config::default()).with_namer(Box::new(namer));
+        assert_eq!(stamped.new_session().unwrap().name, "session-20240305-060708");
+    }
+
+    #[test]
+    fn test_empty_sessions_can_be_deferred_and_skipped() {
+        let temp_dir = TempDir::new().unwrap();
+        let lazy = manager_in(&temp_dir, crate::Config { persist_empty_sessions: false, ..Default::default() });
+        let mut session = lazy.new_session().unwrap();
+        lazy.create_session(Some("aborted".to_string()), Vec::new()).unwrap();
+        assert!(lazy.list_sessions().unwrap().is_empty());
+        lazy.add_message(&mut session, Message::user("Hi".to_string())).unwrap();
+        assert_eq!(lazy.list_sessions().unwrap().len(), 1);
+
+        // An eager manager leaves an empty session as the latest
+        let eager = manager_in(&temp_dir, crate::Config::default());
+        let empty = eager.new_session().unwrap();
+        assert_eq!(eager.load_latest().unwrap().id, empty.id);
+        assert_eq!(eager.list_sessions().unwrap().len(), 2);
+
+        let skipping = manager_in(&temp_dir, crate::Config { skip_empty_sessions: true, ..Default::default() });
+        let (latest, created) = skipping.load_latest_or_created().unwrap();
+        assert_eq!((latest.id, created), (session.id, false));
+        let listed: Vec<Uuid> = skipping.list_sessions().unwrap().iter().map(|info| info.id).collect();
+        assert_eq!(listed, [session.id]);
+
+        // With nothing but empty sessions, a new one is created
+        let only_empty = TempDir::new().unwrap();
+        manager_in(&only_empty, crate::Config::default()).new_session().unwrap();
+        let skipping = manager_in(&only_empty, crate::Config { skip_empty_sessions: true, ..Default::default() });
+        assert!(skipping.load_latest_or_created().unwrap().1);
+    }
+
+    #[test]
+    fn test_sessions_grouped_by_project() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = manager_in(&temp_dir, crate::Config::default());
+        let ids: Vec<Uuid> = [Some("billing"), Some("search"), Some("billing"), None]
+            .into_iter()
+            .map(|group| {
+                let mut session = manager.new_session().unwrap();
+                session.group = group.map(str::to_string);
+                manager.add_message(&mut session, Message::user("Hi".to_string())).unwrap();
+                session.id
+            })
+            .collect();
+        // Sessions grouped through metadata, from before the field, still count
+        let mut legacy = manager.new_session().unwrap();
+        legacy.set_meta("group", "archive").unwrap();
+        manager.save_session(&mut legacy).unwrap();
+
+        assert_eq!(manager.list_groups().unwrap(), ["archive", "billing", "search"]);
+        let mut in_billing: Vec<Uuid> = manager.list_sessions_in_group("billing").unwrap().iter().map(|i| i.id).collect();
+        in_billing.sort();
+        let mut expected = vec![ids[0], ids[2]];
+        expected.sort();
+        assert_eq!(in_billing, expected);
+        assert_eq!(manager.load_latest_in_group("search").unwrap().unwrap().id, ids[1]);
+        assert!(manager.load_latest_in_group("missing").unwrap().is_none());
+
+        let storage = crate::storage::FileStorage::with_directory(temp_dir.path()).unwrap();
+        assert_eq!(storage.load_latest_for("billing").unwrap().unwrap().id, ids[2]);
+        let loaded = manager.load_session(&ids[0]).unwrap();
+        assert_eq!(loaded.group.as_deref(), Some("billing"));
+        assert_eq!(loaded.split_at(&loaded.messages[0].id).unwrap().1.group.as_deref(), Some("billing"));
+    }
+
+    #[test]
+    fn test_load_latest_or_created() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = manager_in(&temp_dir, crate::Config::default());
+
+        let (created, was_created) = manager.load_latest_or_created().unwrap();
+        assert!(was_created);
+
+        let (resumed, was_created) = manager.load_latest_or_created().unwrap();
+        assert!(!was_created);
+        assert_eq!(resumed.id, created.id);
+        assert_eq!(manager.load_latest().unwrap().id, created.id);
+    }
+
+    #[test]
+    fn test_custom_compactor_takes_precedence() {
+        let temp_dir = TempDir::new().unwrap();
+        let config = || crate::Config {
+            max_tokens: 30,
+            compaction_strategy: CompactionStrategy::Sliding { max_tokens: 30, options: RetentionOptions::default() },
+            ..Default::default()
+        };
+
+        let builtin = manager_in(&temp_dir, config());
+        let custom = manager_in(&temp_dir, config()).with_compactor(Box::new(KeepLast));
+        let mut a = builtin.new_session().unwrap();
+        let mut b = custom.new_session().unwrap();
+        for _ in 0..4 {
+            builtin.add_message(&mut a, Message::user("x".repeat(40))).unwrap();
+            custom.add_message(&mut b, Message::user("x".repeat(40))).unwrap();
+        }
+
+        assert_eq!(a.messages.len(), 3);
+        assert_eq!(b.messages.len(), 1);
+        assert_eq!(b.total_tokens(), 10);
+    }
+
+    #[test]
+    fn test_shared_manager_serializes_appends() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SessionManager>();
+
+        let temp_dir = TempDir::new().unwrap();
+        let manager = Arc::new(manager_in(&temp_dir, crate::Config::default()));
+        let shared_id = manager.new_session().unwrap().id;
+
+        let handles: Vec<_> = (0..4)
+            .map(|worker| {
+                let manager = Arc::clone(&manager);
+                std::thread::spawn(move || {
+                    // One session per worker, appended in parallel with the others,
+                    // plus each worker's own copy of the shared session
+                    let mut own = manager.new_session().unwrap();
+                    let mut shared = manager.load_session(&shared_id).unwrap();
+                    for i in 0..10 {
+                        manager.add_message(&mut own, Message::user(format!("{} {}", worker, i))).unwrap();
+                        manager.add_message(&mut shared, Message::user(format!("{} {}", worker, i))).unwrap();
+                    }
+                    own.id
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            let id = handle.join().unwrap();
+            assert_eq!(manager.load_session(&id).unwrap().messages.len(), 10);
+        }
+
+        let stored = manager.load_session(&shared_id).unwrap();
+        let mut contents: Vec<&str> = stored.messages.iter().map(|m| m.content.as_str()).collect();
+        contents.sort();
+        let mut expected: Vec<String> = (0..4).flat_map(|w| (0..10).map(move |i| format!("{} {}", w, i))).collect();
+        expected.sort();
+        assert_eq!(contents, expected);
+        // Each worker's own messages stay in the order it added them
+        for worker in 0..4 {
+            let prefix = format!("{} ", worker);
+            let order: Vec<&str> = stored.messages.iter().map(|m| m.content.as_str()).filter(|c| c.starts_with(&prefix)).collect();
+            assert_eq!(order, (0..10).map(|i| format!("{} {}", worker, i)).collect::<Vec<_>>());
+        }
+        assert!(lock(&manager.session_locks).is_empty());
+    }
+}
@@ -1,15 +1,32 @@
+use crate::crypto;
 use crate::error::ContextError;
-use crate::session::Session;
+use crate::search::SearchHit;
+use crate::session::{estimate_content_tokens, Message, MessageRole, Session};
 use anyhow::Result;
 use std::fs;
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
 use tracing::{debug, info, warn};
 use uuid::Uuid;
 
+#[cfg(feature = "watch")]
+mod watch;
+#[cfg(feature = "watch")]
+pub use watch::StorageEvent;
+
 /// Trait for session storage backends
 pub trait SessionStorage: Send + Sync {
     /// Save a session to storage
+    ///
+    /// Implementations should reject the save with `ContextError::Conflict`
+    /// when the stored copy's `version` is nonzero and not older than
+    /// `session.version`.
     fn save_session(&self, session: &Session) -> Result<(), ContextError>;
     
     /// Load a session by ID
@@ -23,211 +40,1172 @@ pub trait SessionStorage: Send + Sync {
     
     /// Delete a session
     fn delete_session(&self, session_id: &Uuid) -> Result<(), ContextError>;
+
+    /// Add one message to the end of a stored session
+    ///
+    /// For hot paths that log messages without keeping the session in
+    /// memory. The stored `version` is bumped as a save would, so sessions
+    /// loaded earlier must be reloaded before they are saved again. Fails with
+    /// `ContextError::SessionNotFound` if the session isn't stored.
+    ///
+    /// The default loads the session, pushes the message and saves it whole;
+    /// backends that can append in place, like [`LogStorage`](crate::LogStorage),
+    /// override it.
+    fn append_message(&self, session_id: &Uuid, message: &Message) -> Result<(), ContextError> {
+        let mut session = self.load_session(session_id)?;
+        session.add_message(message.clone());
+        session.version += 1;
+        self.save_session(&session)
+    }
     
     /// Clean up old sessions (keep last N sessions)
+    ///
+    /// Starred sessions (see [`STARRED_KEY`]) are never removed and don't
+    /// count towards `keep_count`.
     fn cleanup_old_sessions(&self, keep_count: usize) -> Result<usize, ContextError>;
+
+    /// Delete sessions last updated more than `age` ago
+    ///
+    /// Age is judged by each session's own `updated_at`, not file timestamps,
+    /// so copying files around doesn't reset it. The latest session and
+    /// starred sessions are never deleted, however stale. Returns the number
+    /// of sessions removed.
+    fn cleanup_older_than(&self, age: Duration) -> Result<usize, ContextError> {
+        let age = chrono::Duration::from_std(age)
+            .map_err(|e| ContextError::Config(format!("Invalid session age: {}", e)))?;
+        let cutoff = Utc::now() - age;
+        let latest_id = self.load_latest_session()?.map(|s| s.id);
+
+        let mut deleted_count = 0;
+        for session_info in self.list_sessions()? {
+            if Some(session_info.id) == latest_id || session_info.starred {
+                continue;
+            }
+
+            let session = match self.load_session(&session_info.id) {
+                Ok(session) => session,
+                Err(e) => {
+                    warn!("Failed to load session {} for expiry check: {}", session_info.id, e);
+                    continue;
+                }
+            };
+
+            if session.updated_at < cutoff && session.get_meta_bool(STARRED_KEY) != Some(true) {
+                match self.delete_session(&session.id) {
+                    Ok(()) => deleted_count += 1,
+                    Err(e) => warn!("Failed to delete expired session {}: {}", session.id, e),
+                }
+            }
+        }
+
+        info!("Cleaned up {} expired sessions", deleted_count);
+        Ok(deleted_count)
+    }
+
+    /// Search every session for `query`, as [`Session::search`]
+    ///
+    /// Sessions are searched in `list_sessions` order, loading each in turn;
+    /// ones that fail to load are skipped with a warning. A session
+    /// contributes one hit per occurrence.
+    fn search(&self, query: &str) -> Result<Vec<SearchHit>, ContextError> {
+        let mut hits = Vec::new();
+        for session_info in self.list_sessions()? {
+            match self.load_session(&session_info.id) {
+                Ok(session) => hits.extend(session.search(query)),
+                Err(e) => warn!("Skipping session {} in search: {}", session_info.id, e),
+            }
+        }
+        Ok(hits)
+    }
+}
+
+/// An exclusive lock on one session, from [`FileStorage::lock_session`]
+///
+/// Released when dropped.
+pub struct SessionGuard<'a> {
+    storage: &'a FileStorage,
+    session_id: Uuid,
+    _lock: fs::File,
+}
+
+impl SessionGuard<'_> {
+    /// The locked session's id
+    pub fn session_id(&self) -> Uuid {
+        self.session_id
+    }
+
+    /// Load the locked session
+    pub fn load(&self) -> Result<Session, ContextError> {
+        self.storage.load_session(&self.session_id)
+    }
+
+    /// Save the locked session without giving up the lock
+    ///
+    /// Fails with `ContextError::InvalidSession` if `session` isn't the one
+    /// this guard locks.
+    pub fn save(&self, session: &Session) -> Result<(), ContextError> {
+        if session.id != self.session_id {
+            return Err(ContextError::InvalidSession(format!(
+                "guard locks session {}, not {}",
+                self.session_id, session.id
+            )));
+        }
+        self.storage.save_session_unlocked(session)
+    }
 }
 
 /// Information about a stored session
 #[derive(Debug, Clone)]
 pub struct SessionInfo {
     pub id: Uuid,
+    pub name: String,
     pub created_at: SystemTime,
     pub modified_at: SystemTime,
     pub message_count: usize,
+    /// Estimated tokens across all messages, as [`Session::total_tokens`]
+    pub total_tokens: usize,
     pub file_path: PathBuf,
+    /// Whether the session sits compressed in the archive; see
+    /// [`FileStorage::with_archive_on_cleanup`]
+    pub archived: bool,
+    /// Whether the session is flagged with [`STARRED_KEY`]
+    pub starred: bool,
+    /// The first user message, or the last message if there is no user
+    /// message, on one line and cut to length; see
+    /// [`FileStorage::with_preview_chars`]. `None` for an empty session.
+    pub preview: Option<String>,
+    /// [`Session::group`], or for sessions without one a string in their
+    /// group metadata key (see [`FileStorage::with_group_key`])
+    pub group: Option<String>,
+}
+
+/// Parse an externally supplied session id, such as one from a URL or CLI
+///
+/// Only the canonical hyphenated form (`67e55044-10b1-426f-9247-bb680e5fe0c8`,
+/// in either case) is accepted; the braced, URN and unhyphenated forms
+/// `Uuid::parse_str` also takes are rejected, as is anything else, with
+/// `ContextError::InvalidSession`. Parse ids this way before handing them to
+/// `SessionManager::load_or_create`.
+pub fn parse_session_id(id: &str) -> Result<Uuid, ContextError> {
+    Uuid::try_parse(id)
+        .ok()
+        .filter(|uuid| uuid.hyphenated().to_string().eq_ignore_ascii_case(id))
+        .ok_or_else(|| ContextError::InvalidSession(format!("Not a canonical session id: {:?}", id)))
+}
+
+/// Outcome of [`migrate_storage`]
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    /// Sessions saved to the destination
+    pub migrated: usize,
+    /// Sessions that failed to load or save, with the error
+    pub failed: Vec<(Uuid, ContextError)>,
+    /// The source's latest session, which the destination now also points at
+    pub latest: Option<Uuid>,
+}
+
+/// Copy every session from one backend to another, keeping ids and the latest pointer
+///
+/// Sessions are saved oldest first and the source's latest session last, so
+/// backends that track the latest save (like [`FileStorage`]) end up pointing
+/// at the same session. A session that fails to load or save is recorded in
+/// the report and the rest still migrate; only failing to list the source or
+/// read its latest session aborts. The source is left untouched. A session
+/// the destination already holds at the same or a newer version fails with
+/// `ContextError::Conflict`.
+pub fn migrate_storage(from: &dyn SessionStorage, to: &dyn SessionStorage) -> Result<MigrationReport, ContextError> {
+    let latest = from.load_latest_session()?;
+    let mut report = MigrationReport {
+        latest: latest.as_ref().map(|session| session.id),
+        ..MigrationReport::default()
+    };
+
+    let latest_id = report.latest;
+    let mut save = |session: Result<Session, ContextError>, id: Uuid| match session.and_then(|s| to.save_session(&s)) {
+        Ok(()) => report.migrated += 1,
+        Err(e) => {
+            warn!("Failed to migrate session {}: {}", id, e);
+            report.failed.push((id, e));
+        }
+    };
+
+    for info in from.list_sessions()?.iter().rev() {
+        if Some(info.id) != latest_id {
+            save(from.load_session(&info.id), info.id);
+        }
+    }
+    if let Some(latest) = latest {
+        let id = latest.id;
+        save(Ok(latest), id);
+    }
+
+    info!("Migrated {} sessions ({} failed)", report.migrated, report.failed.len());
+    Ok(report)
+}
+
+/// Default permissions for session files: owner read/write only
+///
+/// Only meaningful on Unix; on Windows file modes are ignored and files
+/// inherit the ACLs of the sessions directory.
+pub const DEFAULT_FILE_MODE: u32 = 0o600;
+
+/// Metadata key holding a session's integrity hash
+const INTEGRITY_HASH_KEY: &str = "integrity_hash";
+
+/// Metadata key naming a session's group for [`FileStorage::load_latest_for`]
+pub(crate) const DEFAULT_GROUP_KEY: &str = "group";
+
+/// Spaces per level in JSON session files, matching `serde_json`'s pretty printer
+const DEFAULT_JSON_INDENT: usize = 2;
+
+/// Characters of message content in [`SessionInfo::preview`]
+pub(crate) const DEFAULT_PREVIEW_CHARS: usize = 80;
+
+/// Metadata flag asking [`FileStorage`] to encrypt a session
+pub const ENCRYPTED_KEY: &str = "encrypted";
+
+/// Metadata flag exempting a session from every cleanup
+pub const STARRED_KEY: &str = "starred";
+
+/// Permissions for a newly created sessions directory: owner only
+pub(crate) const SESSIONS_DIR_MODE: u32 = 0o700;
+
+/// Subdirectory of the sessions directory holding archived sessions
+const ARCHIVE_DIR: &str = "archive";
+
+/// Subdirectory of the sessions directory holding per-session lock files
+const LOCKS_DIR: &str = ".locks";
+
+/// How [`FileStorage`] spreads session files across subdirectories
+///
+/// Large flat directories get slow to read, so sharding groups files into
+/// smaller directories. Sessions still in the flat layout keep loading and
+/// move to their shard the next time they are saved, so an existing
+/// directory can switch to sharding. Listing covers every subdirectory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShardLayout {
+    /// Every session directly in the sessions directory
+    #[default]
+    Flat,
+    /// `YYYY/MM/` by the session's `created_at` (UTC)
+    ByDate,
+    /// A directory named by the first `len` hex digits of the session id
+    ByIdPrefix { len: usize },
+}
+
+/// Encoding for session files written by [`FileStorage`]
+///
+/// Files are named `<id>.<extension>`, and loads pick the decoder from the
+/// extension, so a directory can hold a mix of formats while migrating.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SerializationFormat {
+    /// Pretty-printed JSON, easy to inspect by hand
+    #[default]
+    Json,
+    /// MessagePack: smaller and faster for large sessions
+    MessagePack,
+    /// CBOR (RFC 8949)
+    Cbor,
+}
+
+impl SerializationFormat {
+    const ALL: [Self; 3] = [Self::Json, Self::MessagePack, Self::Cbor];
+
+    /// File extension for this format, without the dot
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::MessagePack => "msgpack",
+            Self::Cbor => "cbor",
+        }
+    }
+
+    /// Format named by a path's extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?;
+        Self::ALL.into_iter().find(|format| format.extension() == extension)
+    }
+
+    /// Stream `value` to `writer` without an intermediate copy; JSON is
+    /// indented by `json_indent` spaces
+    fn encode_to<T: serde::Serialize>(&self, value: &T, json_indent: usize, writer: &mut dyn Write) -> Result<(), ContextError> {
+        let write_error = |e: &dyn std::fmt::Display| ContextError::Storage(format!("Failed to write session file: {}", e));
+        match self {
+            Self::Json => {
+                let indent = vec![b' '; json_indent];
+                let formatter = serde_json::ser::PrettyFormatter::with_indent(&indent);
+                Ok(value.serialize(&mut serde_json::Serializer::with_formatter(writer, formatter))?)
+            }
+            // Structs as maps, so fields skipped when empty don't shift the rest
+            Self::MessagePack => rmp_serde::encode::write_named(writer, value).map_err(|e| write_error(&e)),
+            Self::Cbor => ciborium::into_writer(value, writer).map_err(|e| write_error(&e)),
+        }
+    }
+
+    /// Read a `T` from `reader` as it streams in
+    fn decode_from<T: serde::de::DeserializeOwned>(&self, reader: impl Read) -> Result<T, ContextError> {
+        let malformed = |format: &str, e: &dyn std::fmt::Display| {
+            ContextError::InvalidSession(format!("Malformed {}: {}", format, e))
+        };
+        match self {
+            Self::Json => Ok(serde_json::from_reader(reader)?),
+            Self::MessagePack => rmp_serde::from_read(reader).map_err(|e| malformed("MessagePack", &e)),
+            Self::Cbor => ciborium::from_reader(reader).map_err(|e| malformed("CBOR", &e)),
+        }
+    }
 }
 
 /// File-based session storage implementation
 pub struct FileStorage {
     sessions_dir: PathBuf,
     latest_symlink: PathBuf,
+    file_mode: u32,
+    integrity_hash: bool,
+    format: SerializationFormat,
+    encryption: Option<crypto::Key>,
+    archive_on_cleanup: bool,
+    sharding: ShardLayout,
+    json_indent: usize,
+    group_key: String,
+    preview_chars: usize,
+    #[cfg(feature = "watch")]
+    watches: std::sync::Mutex<Vec<watch::ActiveWatch>>,
 }
 
 impl FileStorage {
     /// Create a new file storage instance
     pub fn new() -> Result<Self, ContextError> {
-        let sessions_dir = Self::default_sessions_dir()?;
-        let latest_symlink = sessions_dir.join("latest.json");
-        
-        // Create sessions directory if it doesn't exist
-        if !sessions_dir.exists() {
-            fs::create_dir_all(&sessions_dir)
-                .map_err(|e| ContextError::Storage(format!("Failed to create sessions directory: {}", e)))?;
-            info!("Created sessions directory: {}", sessions_dir.display());
-        }
-        
-        Ok(Self {
-            sessions_dir,
-            latest_symlink,
-        })
+        Self::with_directory(Self::default_sessions_dir()?)
     }
     
     /// Create a file storage instance with custom directory
+    ///
+    /// A directory created here is restricted to its owner (0700 on Unix).
+    /// Existing directories keep their permissions.
     pub fn with_directory<P: AsRef<Path>>(dir: P) -> Result<Self, ContextError> {
         let sessions_dir = dir.as_ref().to_path_buf();
         let latest_symlink = sessions_dir.join("latest.json");
         
+        // Create sessions directory if it doesn't exist
         if !sessions_dir.exists() {
             fs::create_dir_all(&sessions_dir)
                 .map_err(|e| ContextError::Storage(format!("Failed to create sessions directory: {}", e)))?;
+            set_mode(&sessions_dir, SESSIONS_DIR_MODE)?;
+            info!("Created sessions directory: {}", sessions_dir.display());
         }
         
         Ok(Self {
             sessions_dir,
             latest_symlink,
+            file_mode: DEFAULT_FILE_MODE,
+            integrity_hash: false,
+            format: SerializationFormat::default(),
+            encryption: None,
+            archive_on_cleanup: false,
+            sharding: ShardLayout::default(),
+            json_indent: DEFAULT_JSON_INDENT,
+            group_key: DEFAULT_GROUP_KEY.to_string(),
+            preview_chars: DEFAULT_PREVIEW_CHARS,
+            #[cfg(feature = "watch")]
+            watches: Default::default(),
+        })
+    }
+
+    /// Set the Unix permission bits for session files (default `0o600`)
+    ///
+    /// Applied every time a session is saved. This is a no-op on Windows.
+    pub fn with_mode(mut self, mode: u32) -> Self {
+        self.file_mode = mode;
+        self
+    }
+
+    /// Record `Session::integrity_hash` in `metadata["integrity_hash"]` on save
+    ///
+    /// Loads verify the hash whenever it is present, whether or not this is
+    /// enabled, and fail with `ContextError::InvalidSession` on a mismatch.
+    pub fn with_integrity_hash(mut self, enabled: bool) -> Self {
+        self.integrity_hash = enabled;
+        self
+    }
+
+    /// Encrypt sessions flagged with `metadata["encrypted"] = true` under `key`
+    ///
+    /// Only flagged sessions are encrypted; the rest stay plaintext. Loads
+    /// recognize encrypted files by their header and decrypt them, failing
+    /// with `ContextError::InvalidSession` if the key is wrong or the file was
+    /// altered. Without a key, saving a flagged session or loading an
+    /// encrypted one fails with `ContextError::Config`.
+    pub fn with_encryption_key(mut self, key: [u8; 32]) -> Self {
+        self.encryption = Some(crypto::Key::new(&key));
+        self
+    }
+
+    /// Write new and updated sessions in `format` (default JSON)
+    ///
+    /// Existing files in other formats still load. A session is rewritten in
+    /// the new format the next time it is saved, and its old file removed.
+    pub fn with_format(mut self, format: SerializationFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Indent JSON session files by `width` spaces per level (default 2)
+    ///
+    /// Metadata keys are always written in sorted order, so with a fixed
+    /// width an unchanged session serializes to identical bytes and diffs
+    /// stay small when sessions are committed to version control. Has no
+    /// effect on other formats.
+    pub fn with_json_indent(mut self, width: usize) -> Self {
+        self.json_indent = width;
+        self
+    }
+
+    /// Cut [`SessionInfo::preview`] at `chars` characters (default 80); 0
+    /// leaves previews out
+    pub fn with_preview_chars(mut self, chars: usize) -> Self {
+        self.preview_chars = chars;
+        self
+    }
+
+    /// Read the group of sessions without [`Session::group`] from
+    /// `metadata[key]` (default `"group"`)
+    ///
+    /// Saving a session with a group, from either place, also points
+    /// `latest-<group>.txt` at it; see [`FileStorage::load_latest_for`].
+    pub fn with_group_key(mut self, key: impl Into<String>) -> Self {
+        self.group_key = key.into();
+        self
+    }
+
+    /// Load the most recently saved session in `group`
+    ///
+    /// Each group (a project, say) has its own latest pointer next to the
+    /// global one, so several conversation streams can share a sessions
+    /// directory and each resume where it left off. `None` if no session in
+    /// the group has been saved, or the one last saved has since been
+    /// deleted.
+    pub fn load_latest_for(&self, group: &str) -> Result<Option<Session>, ContextError> {
+        let pointer = self.group_pointer_path(group);
+        let id = match fs::read_to_string(&pointer) {
+            Ok(id) => id,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(ContextError::Storage(format!("Failed to read {}: {}", pointer.display(), e))),
+        };
+        let id = Uuid::parse_str(id.trim())
+            .map_err(|e| ContextError::InvalidSession(format!("Bad session id in {}: {}", pointer.display(), e)))?;
+
+        match self.load_session(&id) {
+            Err(ContextError::SessionNotFound(_)) => {
+                warn!("Latest session for group {} no longer exists", group);
+                fs::remove_file(&pointer)
+                    .map_err(|e| ContextError::Storage(format!("Failed to remove {}: {}", pointer.display(), e)))?;
+                Ok(None)
+            }
+            result => result.map(Some),
+        }
+    }
+
+    /// `latest-<group>.txt`, with bytes outside `[A-Za-z0-9_-]` percent-escaped
+    /// so any group name stays a single file in the sessions directory
+    fn group_pointer_path(&self, group: &str) -> PathBuf {
+        let mut name = String::from("latest-");
+        for byte in group.bytes() {
+            if byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-' {
+                name.push(byte as char);
+            } else {
+                name.push_str(&format!("%{:02X}", byte));
+            }
+        }
+        name.push_str(".txt");
+        self.sessions_dir.join(name)
+    }
+
+    /// Point the session's group pointer at it, if it has a group
+    fn update_group_pointer(&self, session: &Session) -> Result<(), ContextError> {
+        let Some(group) = session.group.as_deref().or_else(|| session.get_meta_str(&self.group_key)) else {
+            return Ok(());
+        };
+        let staged = self.sessions_dir.join(format!(".latest-group.{}.tmp", Uuid::new_v4()));
+        self.write_private_file(&staged, session.id.to_string().as_bytes())?;
+        fs::rename(&staged, self.group_pointer_path(group)).map_err(|e| {
+            let _ = fs::remove_file(&staged);
+            ContextError::Storage(format!("Failed to update latest session for group {}: {}", group, e))
+        })
+    }
+
+    /// Archive sessions removed by `cleanup_old_sessions` instead of deleting them
+    ///
+    /// Archived sessions are gzip-compressed into an `archive/` subdirectory
+    /// as `<id>.<extension>.gz`, byte for byte (encrypted sessions stay
+    /// encrypted). They no longer load or show up in `list_sessions`; see
+    /// [`list_sessions_with_archived`](Self::list_sessions_with_archived) and
+    /// [`restore_archived`](Self::restore_archived).
+    pub fn with_archive_on_cleanup(mut self, enabled: bool) -> Self {
+        self.archive_on_cleanup = enabled;
+        self
+    }
+
+    /// Spread session files across subdirectories per `layout` (default flat)
+    ///
+    /// Lookups by id under [`ShardLayout::ByDate`] check each month directory,
+    /// so they cost a few more filesystem calls than the other layouts.
+    pub fn with_sharding(mut self, layout: ShardLayout) -> Self {
+        self.sharding = layout;
+        self
+    }
+
+    /// View of `session` as it is written, with the integrity hash if enabled
+    fn stored_session<'a>(&self, session: &'a Session) -> StoredSession<'a> {
+        StoredSession {
+            id: &session.id,
+            name: &session.name,
+            created_at: &session.created_at,
+            updated_at: &session.updated_at,
+            messages: session.messages(),
+            metadata: StoredMetadata {
+                metadata: &session.metadata,
+                integrity_hash: self.integrity_hash.then(|| hex::encode(session.integrity_hash())),
+            },
+            group: session.group.as_deref(),
+            version: session.version,
+        }
+    }
+
+    /// Format a session file is stored in, going by its extension
+    ///
+    /// Paths without a known extension (the Windows copy of `latest.json`) are
+    /// read in this storage's own format.
+    fn format_of(&self, file_path: &Path) -> SerializationFormat {
+        if file_path == self.latest_symlink {
+            self.format
+        } else {
+            SerializationFormat::from_path(file_path).unwrap_or(self.format)
+        }
+    }
+
+    /// Decode a session file in its own format
+    fn decode_file<T: serde::de::DeserializeOwned>(&self, file_path: &Path) -> Result<T, ContextError> {
+        decode_path(file_path, self.format_of(file_path), self.encryption.as_ref())
+    }
+
+    /// Read a session file and verify its integrity hash, if it has one
+    fn parse_session(&self, file_path: &Path) -> Result<Session, ContextError> {
+        read_session(file_path, self.format_of(file_path), self.encryption.as_ref())
+    }
+
+    /// Sessions whose estimated tokens fall within `min..=max`
+    ///
+    /// Useful for finding the heavyweight sessions worth pruning.
+    pub fn list_sessions_by_tokens(&self, min: usize, max: usize) -> Result<Vec<SessionInfo>, ContextError> {
+        let mut sessions = self.list_sessions()?;
+        sessions.retain(|info| (min..=max).contains(&info.total_tokens));
+        Ok(sessions)
+    }
+
+    /// Sessions whose name matches the glob `pattern`
+    ///
+    /// `*` matches any run of characters, `?` any single character, and
+    /// `[abc]`, `[a-z]` or `[!abc]` one character from (or not from) a set.
+    /// Matching is case-sensitive and covers the whole name, so
+    /// `proj-acme-*` finds `proj-acme-parser` but not `old-proj-acme-parser`.
+    /// An unclosed `[` matches itself.
+    pub fn list_sessions_matching(&self, pattern: &str) -> Result<Vec<SessionInfo>, ContextError> {
+        let pattern = GlobToken::parse(pattern);
+        let mut sessions = self.list_sessions()?;
+        sessions.retain(|info| glob_match(&pattern, &info.name.chars().collect::<Vec<_>>()));
+        Ok(sessions)
+    }
+
+    /// Lock a session exclusively, waiting until no one else holds it
+    ///
+    /// For multi-step read-modify-write sequences: load through the guard,
+    /// change the session as often as needed, then [`SessionGuard::save`] it.
+    /// The lock is an OS file lock on `.locks/<id>.lock` in the sessions
+    /// directory, so it excludes other processes as well as other threads,
+    /// and is released when the guard drops. `save_session` and
+    /// `delete_session` take the same lock for their duration, so they wait
+    /// for the guard, even on the thread holding it; save through the guard
+    /// instead. Loads don't lock. Lock files stay behind after use, since
+    /// removing one could let two processes lock different files for the same
+    /// session.
+    pub fn lock_session(&self, session_id: &Uuid) -> Result<SessionGuard<'_>, ContextError> {
+        Ok(SessionGuard {
+            storage: self,
+            session_id: *session_id,
+            _lock: self.acquire_lock(session_id)?,
+        })
+    }
+
+    /// Block until this process holds the session's lock file
+    fn acquire_lock(&self, session_id: &Uuid) -> Result<fs::File, ContextError> {
+        let dir = self.sessions_dir.join(LOCKS_DIR);
+        self.ensure_dir(&dir)?;
+        let path = dir.join(format!("{}.lock", session_id));
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(|e| ContextError::Storage(format!("Failed to open lock file {}: {}", path.display(), e)))?;
+        file.lock()
+            .map_err(|e| ContextError::Storage(format!("Failed to lock session {}: {}", session_id, e)))?;
+        Ok(file)
+    }
+
+    /// Open a session without reading its messages
+    ///
+    /// The returned handle carries the session's name, timestamps, and
+    /// metadata; messages are deserialized on the first call to
+    /// [`LazySession::messages`]. Suited to pickers that list many sessions.
+    pub fn open(&self, session_id: &Uuid) -> Result<LazySession, ContextError> {
+        let file_path = self.find_session_file(session_id)
+            .ok_or_else(|| ContextError::SessionNotFound(session_id.to_string()))?;
+        let format = self.format_of(&file_path);
+        let mut header: SessionHeader = decode_path(&file_path, format, self.encryption.as_ref())?;
+        // Checked against the messages once they load, by `read_session`
+        header.metadata.remove(INTEGRITY_HASH_KEY);
+
+        Ok(LazySession {
+            id: header.id,
+            name: header.name,
+            created_at: header.created_at,
+            updated_at: header.updated_at,
+            metadata: header.metadata,
+            group: header.group,
+            version: header.version,
+            message_count: header.messages.count,
+            file_path,
+            format,
+            encryption: self.encryption.clone(),
+            messages: None,
+        })
+    }
+
+    /// Write a session file, creating it with `file_mode` so it is never briefly world-readable
+    fn write_session_file(&self, file_path: &Path, session: &Session) -> Result<(), ContextError> {
+        let key = match (session.get_meta_bool(ENCRYPTED_KEY), &self.encryption) {
+            (Some(true), Some(key)) => Some(key),
+            (Some(true), None) => {
+                return Err(ContextError::Config(format!(
+                    "Session {} is flagged encrypted but no encryption key is configured",
+                    session.id
+                )));
+            }
+            _ => None,
+        };
+
+        // Stream into a file beside the target and rename it over, so a failed
+        // or interrupted write never leaves the session half written
+        let file_name = file_path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        let staged = file_path.with_file_name(format!(".{}.{}.tmp", file_name, Uuid::new_v4()));
+        let result = self.write_staged(&staged, session, key).and_then(|()| {
+            fs::rename(&staged, file_path)
+                .map_err(|e| ContextError::Storage(format!("Failed to replace session file: {}", e)))
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(&staged);
+        }
+        result
+    }
+
+    /// Write `session` to the new file `staged` and sync it to disk
+    fn write_staged(&self, staged: &Path, session: &Session, key: Option<&crypto::Key>) -> Result<(), ContextError> {
+        let write_error = |e: std::io::Error| ContextError::Storage(format!("Failed to write session file: {}", e));
+        let file = self.create_file(staged).map_err(write_error)?;
+        let mut writer = BufWriter::new(file);
+        match key {
+            Some(key) => {
+                let mut plaintext = Vec::new();
+                self.format.encode_to(&self.stored_session(session), self.json_indent, &mut plaintext)?;
+                writer.write_all(&crypto::seal(key, &plaintext)?).map_err(write_error)?;
+            }
+            None => self.format.encode_to(&self.stored_session(session), self.json_indent, &mut writer)?,
+        }
+        let file = writer.into_inner().map_err(|e| write_error(e.into_error()))?;
+        file.sync_all().map_err(write_error)?;
+
+        // Under a umask the mode given at creation may have been loosened
+        set_mode(staged, self.file_mode)
+    }
+
+    /// Create or truncate a file, with `file_mode` if it is new
+    fn create_file(&self, file_path: &Path) -> std::io::Result<fs::File> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(self.file_mode);
+        }
+
+        options.open(file_path)
+    }
+
+    /// Write `data` to a new file with `file_mode`
+    fn write_private_file(&self, file_path: &Path, data: &[u8]) -> Result<(), ContextError> {
+        self.create_file(file_path)
+            .and_then(|mut file| file.write_all(data))
+            .map_err(|e| ContextError::Storage(format!("Failed to write {}: {}", file_path.display(), e)))?;
+        set_mode(file_path, self.file_mode)
+    }
+
+    fn archive_dir(&self) -> PathBuf {
+        self.sessions_dir.join(ARCHIVE_DIR)
+    }
+
+    /// Find a session's archive, in any format
+    fn find_archive_file(&self, session_id: &Uuid) -> Option<PathBuf> {
+        SerializationFormat::ALL
+            .into_iter()
+            .map(|format| self.archive_dir().join(format!("{}.{}.gz", session_id, format.extension())))
+            .find(|path| path.exists())
+    }
+
+    /// Compress a session into the archive and remove it from the live sessions
+    ///
+    /// Returns the archive's path. An existing archive of the same session is
+    /// replaced.
+    pub fn archive_session(&self, session_id: &Uuid) -> Result<PathBuf, ContextError> {
+        let file_path = self.find_session_file(session_id)
+            .ok_or_else(|| ContextError::SessionNotFound(session_id.to_string()))?;
+        let data = fs::read(&file_path)
+            .map_err(|e| ContextError::Storage(format!("Failed to read session file: {}", e)))?;
+
+        let archive_dir = self.archive_dir();
+        self.ensure_dir(&archive_dir)?;
+
+        let file_name = file_path.file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| ContextError::Storage("Invalid session file name".to_string()))?;
+        let archive_path = archive_dir.join(format!("{}.gz", file_name));
+        self.write_private_file(&archive_path, &gzip(&data)?)?;
+
+        self.delete_session(session_id)?;
+        info!("Archived session {} to {}", session_id, archive_path.display());
+        Ok(archive_path)
+    }
+
+    /// Bring an archived session back into the live sessions
+    ///
+    /// It is restored in the format it was archived in, and its archive is
+    /// removed. Fails with `ContextError::Conflict` if a live session with the
+    /// same ID exists.
+    pub fn restore_archived(&self, session_id: &Uuid) -> Result<(), ContextError> {
+        let archive_path = self.find_archive_file(session_id)
+            .ok_or_else(|| ContextError::SessionNotFound(format!("{} (archived)", session_id)))?;
+        if self.find_session_file(session_id).is_some() {
+            return Err(ContextError::Conflict(format!(
+                "Session {} already exists; delete it before restoring its archive",
+                session_id
+            )));
+        }
+
+        let compressed = fs::read(&archive_path)
+            .map_err(|e| ContextError::Storage(format!("Failed to read archive: {}", e)))?;
+        let data = gunzip(&compressed)?;
+
+        // Back into the shard the current layout picks, keeping the archived format
+        let file_name = Path::new(archive_path.file_stem().unwrap_or_default());
+        let dir = if self.sharding == ShardLayout::ByDate {
+            let format = SerializationFormat::from_path(file_name).unwrap_or(self.format);
+            let header: SessionHeader = decode_bytes(&data, format, self.encryption.as_ref(), &archive_path)?;
+            self.shard_dir(session_id, &header.created_at)
+        } else {
+            self.shard_dir(session_id, &Utc::now())
+        };
+        self.ensure_dir(&dir)?;
+        let file_path = dir.join(file_name);
+        self.write_private_file(&file_path, &data)?;
+
+        fs::remove_file(&archive_path)
+            .map_err(|e| ContextError::Storage(format!("Failed to remove archive: {}", e)))?;
+        info!("Restored archived session {}", session_id);
+        Ok(())
+    }
+
+    /// All sessions, live and archived, newest first
+    ///
+    /// Archived entries have `archived` set and `file_path` pointing at the
+    /// compressed file.
+    pub fn list_sessions_with_archived(&self) -> Result<Vec<SessionInfo>, ContextError> {
+        let mut sessions = self.list_sessions()?;
+
+        let entries = match fs::read_dir(self.archive_dir()) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(sessions),
+            Err(e) => return Err(ContextError::Storage(format!("Failed to read archive directory: {}", e))),
+        };
+        for entry in entries {
+            let path = entry
+                .map_err(|e| ContextError::Storage(format!("Failed to read directory entry: {}", e)))?
+                .path();
+            if path.extension() != Some(std::ffi::OsStr::new("gz")) {
+                continue;
+            }
+            match self.get_archived_info(&path) {
+                Ok(info) => sessions.push(info),
+                Err(e) => warn!("Failed to get info for archived session {}: {}", path.display(), e),
+            }
+        }
+
+        sessions.sort_by_key(|s| std::cmp::Reverse(s.modified_at));
+        Ok(sessions)
+    }
+
+    /// Get session info from an archive, decompressing it in memory
+    fn get_archived_info(&self, archive_path: &Path) -> Result<SessionInfo, ContextError> {
+        let inner = Path::new(archive_path.file_stem().unwrap_or_default());
+        let format = SerializationFormat::from_path(inner)
+            .ok_or_else(|| ContextError::Storage(format!("Unknown format for archive {}", archive_path.display())))?;
+        let file_name = inner.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+        let session_id = Uuid::parse_str(file_name)
+            .map_err(|_| ContextError::Storage(format!("Invalid session ID in filename: {}", file_name)))?;
+
+        let metadata = fs::metadata(archive_path)
+            .map_err(|e| ContextError::Storage(format!("Failed to read file metadata: {}", e)))?;
+        let compressed = fs::read(archive_path)
+            .map_err(|e| ContextError::Storage(format!("Failed to read archive: {}", e)))?;
+        let header: SessionHeader =
+            decode_bytes(&gunzip(&compressed)?, format, self.encryption.as_ref(), archive_path)?;
+
+        Ok(SessionInfo {
+            id: session_id,
+            name: header.name.clone(),
+            created_at: metadata.created().unwrap_or_else(|_| SystemTime::now()),
+            modified_at: metadata.modified().unwrap_or_else(|_| SystemTime::now()),
+            message_count: header.messages.count,
+            total_tokens: header.messages.total_tokens,
+            file_path: archive_path.to_path_buf(),
+            archived: true,
+            starred: header.is_starred(),
+            preview: header.messages.preview(self.preview_chars),
+            group: header.group(&self.group_key),
         })
     }
     
     /// Get the default sessions directory
+    ///
+    /// Falls back to the system temp directory, with a warning, when no user
+    /// config directory can be determined (e.g. containers without a home).
     fn default_sessions_dir() -> Result<PathBuf, ContextError> {
-        let home_dir = home::home_dir()
-            .ok_or_else(|| ContextError::Storage("Could not determine home directory".to_string()))?;
-        
+        let config_dir = Self::user_config_dir(home::home_dir()).unwrap_or_else(|| {
+            let temp_dir = std::env::temp_dir();
+            warn!(
+                "Could not determine home directory; storing sessions under {}",
+                temp_dir.display()
+            );
+            temp_dir
+        });
+
+        Ok(config_dir.join("gamecode").join("sessions"))
+    }
+
+    /// Platform config directory, if the environment or home directory gives one
+    fn user_config_dir(home_dir: Option<PathBuf>) -> Option<PathBuf> {
         #[cfg(target_os = "macos")]
-        let config_dir = home_dir.join("Library").join("Application Support");
+        let config_dir = home_dir.map(|home| home.join("Library").join("Application Support"));
         
         #[cfg(target_os = "linux")]
         let config_dir = std::env::var("XDG_CONFIG_HOME")
             .map(PathBuf::from)
-            .unwrap_or_else(|_| home_dir.join(".config"));
+            .ok()
+            .or_else(|| home_dir.map(|home| home.join(".config")));
         
         #[cfg(target_os = "windows")]
         let config_dir = std::env::var("APPDATA")
             .map(PathBuf::from)
-            .unwrap_or_else(|_| home_dir.join("AppData").join("Roaming"));
+            .ok()
+            .or_else(|| home_dir.map(|home| home.join("AppData").join("Roaming")));
         
-        Ok(config_dir.join("gamecode").join("sessions"))
+        config_dir
     }
     
-    /// Get the file path for a session
-    fn session_file_path(&self, session_id: &Uuid) -> PathBuf {
-        self.sessions_dir.join(format!("{}.json", session_id))
+    /// Get the file path for a session in this storage's format and layout
+    fn session_file_path(&self, session: &Session) -> PathBuf {
+        self.shard_dir(&session.id, &session.created_at)
+            .join(format!("{}.{}", session.id, self.format.extension()))
     }
-    
-    /// Update the latest session symlink
-    fn update_latest_symlink(&self, session_id: &Uuid) -> Result<(), ContextError> {
-        let target_file = format!("{}.json", session_id);
-        
-        // Remove existing symlink if it exists
-        if self.latest_symlink.exists() {
-            fs::remove_file(&self.latest_symlink)
-                .map_err(|e| ContextError::Storage(format!("Failed to remove old symlink: {}", e)))?;
+
+    /// Directory a session belongs in under this storage's layout
+    fn shard_dir(&self, session_id: &Uuid, created_at: &DateTime<Utc>) -> PathBuf {
+        match self.sharding {
+            ShardLayout::Flat => self.sessions_dir.clone(),
+            ShardLayout::ByDate => self.sessions_dir
+                .join(created_at.format("%Y").to_string())
+                .join(created_at.format("%m").to_string()),
+            ShardLayout::ByIdPrefix { len } => {
+                let id = session_id.simple().to_string();
+                self.sessions_dir.join(&id[..len.clamp(1, id.len())])
+            }
+        }
+    }
+
+    /// Find a session's existing file, in any format
+    ///
+    /// Looks in the sessions directory itself and wherever the current
+    /// layout could have put it.
+    fn find_session_file(&self, session_id: &Uuid) -> Option<PathBuf> {
+        let mut dirs = vec![self.sessions_dir.clone()];
+        match self.sharding {
+            ShardLayout::Flat => {}
+            ShardLayout::ByIdPrefix { .. } => dirs.push(self.shard_dir(session_id, &Utc::now())),
+            ShardLayout::ByDate => dirs.extend(self.date_shard_dirs()),
+        }
+
+        dirs.iter()
+            .flat_map(|dir| {
+                std::iter::once(self.format)
+                    .chain(SerializationFormat::ALL)
+                    .map(move |format| dir.join(format!("{}.{}", session_id, format.extension())))
+            })
+            .find(|path| path.exists())
+    }
+
+    /// Existing `YYYY/MM` directories, newest first
+    fn date_shard_dirs(&self) -> Vec<PathBuf> {
+        let numbered_dirs = |dir: &Path, digits: usize| -> Vec<PathBuf> {
+            let mut dirs: Vec<PathBuf> = fs::read_dir(dir)
+                .into_iter()
+                .flatten()
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| {
+                    path.file_name()
+                        .and_then(|name| name.to_str())
+                        .is_some_and(|name| name.len() == digits && name.bytes().all(|b| b.is_ascii_digit()))
+                        && path.is_dir()
+                })
+                .collect();
+            dirs.sort_by(|a, b| b.cmp(a));
+            dirs
+        };
+
+        numbered_dirs(&self.sessions_dir, 4)
+            .iter()
+            .flat_map(|year| numbered_dirs(year, 2))
+            .collect()
+    }
+
+    /// Fail unless `path` resolves to somewhere inside the sessions directory
+    ///
+    /// Session ids are UUIDs, so this only trips on a shard directory
+    /// symlinked elsewhere; it backs up the id check in [`parse_session_id`].
+    /// The file itself need not exist, but its directory must.
+    fn check_within_sessions_dir(&self, path: &Path) -> Result<(), ContextError> {
+        let canonical = |path: &Path| fs::canonicalize(path)
+            .map_err(|e| ContextError::Storage(format!("Failed to resolve {}: {}", path.display(), e)));
+        let root = canonical(&self.sessions_dir)?;
+        let dir = canonical(path.parent().unwrap_or(path))?;
+        if !dir.starts_with(&root) {
+            return Err(ContextError::InvalidSession(format!(
+                "session path {} escapes the sessions directory {}",
+                path.display(),
+                root.display()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Create a directory for session files, owner only, if it is missing
+    fn ensure_dir(&self, dir: &Path) -> Result<(), ContextError> {
+        if !dir.exists() {
+            fs::create_dir_all(dir)
+                .map_err(|e| ContextError::Storage(format!("Failed to create {}: {}", dir.display(), e)))?;
+            set_mode(dir, SESSIONS_DIR_MODE)?;
         }
+        Ok(())
+    }
+    
+    /// Point the latest session symlink at a session file
+    fn update_latest_symlink(&self, session_file: &Path) -> Result<(), ContextError> {
+        // Relative to the sessions directory, so the directory can be moved
+        let target_file = session_file.strip_prefix(&self.sessions_dir)
+            .map_err(|_| ContextError::Storage("Session file outside the sessions directory".to_string()))?;
         
-        // Create new symlink (or copy on Windows)
+        // Create the new symlink under a unique name and rename it over the old
+        // one, so concurrent saves of different sessions never see it missing
         #[cfg(unix)]
         {
-            std::os::unix::fs::symlink(&target_file, &self.latest_symlink)
+            let staged = self.sessions_dir.join(format!(".latest.{}.tmp", Uuid::new_v4()));
+            std::os::unix::fs::symlink(target_file, &staged)
                 .map_err(|e| ContextError::Storage(format!("Failed to create symlink: {}", e)))?;
+            fs::rename(&staged, &self.latest_symlink).map_err(|e| {
+                let _ = fs::remove_file(&staged);
+                ContextError::Storage(format!("Failed to replace latest symlink: {}", e))
+            })?;
         }
         
         #[cfg(windows)]
         {
             // Windows doesn't always support symlinks, so we'll copy the file
-            let source = self.sessions_dir.join(&target_file);
-            fs::copy(&source, &self.latest_symlink)
+            // over the previous copy
+            fs::copy(session_file, &self.latest_symlink)
                 .map_err(|e| ContextError::Storage(format!("Failed to copy to latest: {}", e)))?;
         }
         
-        debug!("Updated latest session symlink to {}", session_id);
+        debug!("Updated latest session symlink to {}", session_file.display());
         Ok(())
     }
-    
-    /// Get session info from a file
-    fn get_session_info(&self, file_path: &Path) -> Result<SessionInfo, ContextError> {
-        let file_name = file_path.file_stem()
-            .and_then(|s| s.to_str())
-            .ok_or_else(|| ContextError::Storage("Invalid session file name".to_string()))?;
-        
-        let session_id = Uuid::parse_str(file_name)
-            .map_err(|_| ContextError::Storage(format!("Invalid session ID in filename: {}", file_name)))?;
-        
-        let metadata = fs::metadata(file_path)
-            .map_err(|e| ContextError::Storage(format!("Failed to read file metadata: {}", e)))?;
-        
-        let created_at = metadata.created().unwrap_or_else(|_| SystemTime::now());
-        let modified_at = metadata.modified().unwrap_or_else(|_| SystemTime::now());
-        
-        // Read session to get message count
-        let session_data = fs::read_to_string(file_path)
-            .map_err(|e| ContextError::Storage(format!("Failed to read session file: {}", e)))?;
-        
-        let session: Session = serde_json::from_str(&session_data)?;
-        
-        Ok(SessionInfo {
+
+    /// Whether the latest symlink exists, without following it
+    fn has_latest_symlink(&self) -> bool {
+        fs::symlink_metadata(&self.latest_symlink).is_ok()
+    }
+
+    fn remove_latest_symlink(&self) -> Result<(), ContextError> {
+        if self.has_latest_symlink() {
+            fs::remove_file(&self.latest_symlink)
+                .map_err(|e| ContextError::Storage(format!("Failed to remove latest symlink: {}", e)))?;
+        }
+        Ok(())
+    }
+
+    /// File the latest symlink points to (the copy itself on Windows)
+    fn latest_target(&self) -> Result<PathBuf, ContextError> {
+        #[cfg(unix)]
+        {
+            let target = fs::read_link(&self.latest_symlink)
+                .map_err(|e| ContextError::Storage(format!("Failed to read symlink: {}", e)))?;
+            
+            if target.is_relative() {
+                Ok(self.sessions_dir.join(target))
+            } else {
+                Ok(target)
+            }
+        }
+        
+        #[cfg(windows)]
+        Ok(self.latest_symlink.clone())
+    }
+
+    /// Re-point an orphaned latest symlink at the most recently modified
+    /// session that loads, or remove it if there is none
+    fn recover_latest(&self) -> Result<Option<Session>, ContextError> {
+        for session_info in self.list_sessions()? {
+            match self.parse_session(&session_info.file_path) {
+                Ok(session) => {
+                    self.update_latest_symlink(&session_info.file_path)?;
+                    info!("Recovered latest session symlink to {}", session.id);
+                    return Ok(Some(session));
+                }
+                Err(e) => warn!("Skipping unreadable session {} during recovery: {}", session_info.id, e),
+            }
+        }
+
+        self.remove_latest_symlink()?;
+        Ok(None)
+    }
+    
+    /// Read just the version of a stored session
+    fn stored_version(&self, file_path: &Path) -> Result<u64, ContextError> {
+        #[derive(serde::Deserialize)]
+        struct VersionProbe {
+            #[serde(default)]
+            version: u64,
+        }
+
+        let probe: VersionProbe = self.decode_file(file_path)?;
+        Ok(probe.version)
+    }
+    
+    /// Get session info from a file
+    fn get_session_info(&self, file_path: &Path) -> Result<SessionInfo, ContextError> {
+        let file_name = file_path.file_stem()
+            .and_then(|s| s.to_str())
+            .ok_or_else(|| ContextError::Storage("Invalid session file name".to_string()))?;
+        
+        let session_id = Uuid::parse_str(file_name)
+            .map_err(|_| ContextError::Storage(format!("Invalid session ID in filename: {}", file_name)))?;
+        
+        let metadata = fs::metadata(file_path)
+            .map_err(|e| ContextError::Storage(format!("Failed to read file metadata: {}", e)))?;
+        
+        let created_at = metadata.created().unwrap_or_else(|_| SystemTime::now());
+        let modified_at = metadata.modified().unwrap_or_else(|_| SystemTime::now());
+        
+        // Count messages and tokens without materializing them
+        let header: SessionHeader = self.decode_file(file_path)?;
+        
+        Ok(SessionInfo {
             id: session_id,
+            name: header.name.clone(),
             created_at,
             modified_at,
-            message_count: session.messages.len(),
+            message_count: header.messages.count,
+            total_tokens: header.messages.total_tokens,
             file_path: file_path.to_path_buf(),
+            archived: false,
+            starred: header.is_starred(),
+            preview: header.messages.preview(self.preview_chars),
+            group: header.group(&self.group_key),
         })
     }
-}
 
-impl SessionStorage for FileStorage {
-    fn save_session(&self, session: &Session) -> Result<(), ContextError> {
-        let file_path = self.session_file_path(&session.id);
-        
-        let session_json = serde_json::to_string_pretty(session)?;
+    /// Save under a lock the caller already holds
+    fn save_session_unlocked(&self, session: &Session) -> Result<(), ContextError> {
+        let file_path = self.session_file_path(session);
+        let existing = Some(file_path.clone())
+            .filter(|path| path.exists())
+            .or_else(|| self.find_session_file(&session.id));
+
+        if let Some(existing) = &existing {
+            session.check_version(self.stored_version(existing)?)?;
+        }
         
-        fs::write(&file_path, session_json)
-            .map_err(|e| ContextError::Storage(format!("Failed to write session file: {}", e)))?;
+        if let Some(dir) = file_path.parent() {
+            self.ensure_dir(dir)?;
+        }
+        self.check_within_sessions_dir(&file_path)?;
+        self.write_session_file(&file_path, session)?;
+
+        // Drop the copy in the old format or place once the new one is written
+        if let Some(existing) = existing.filter(|existing| *existing != file_path) {
+            fs::remove_file(&existing)
+                .map_err(|e| ContextError::Storage(format!("Failed to remove old session file: {}", e)))?;
+        }
         
-        // Update the latest symlink
-        self.update_latest_symlink(&session.id)?;
+        // Update the latest symlink, and the group's pointer
+        self.update_latest_symlink(&file_path)?;
+        self.update_group_pointer(session)?;
         
         debug!("Saved session {} to {}", session.id, file_path.display());
         Ok(())
     }
-    
+}
+
+impl SessionStorage for FileStorage {
+    fn save_session(&self, session: &Session) -> Result<(), ContextError> {
+        let _lock = self.acquire_lock(&session.id)?;
+        self.save_session_unlocked(session)
+    }
+
     fn load_session(&self, session_id: &Uuid) -> Result<Session, ContextError> {
-        let file_path = self.session_file_path(session_id);
-        
-        if !file_path.exists() {
-            return Err(ContextError::SessionNotFound(session_id.to_string()));
-        }
+        let file_path = self.find_session_file(session_id)
+            .ok_or_else(|| ContextError::SessionNotFound(session_id.to_string()))?;
+        self.check_within_sessions_dir(&file_path)?;
         
-        let session_data = fs::read_to_string(&file_path)
-            .map_err(|e| ContextError::Storage(format!("Failed to read session file: {}", e)))?;
-        
-        let session: Session = serde_json::from_str(&session_data)?;
+        let session = self.parse_session(&file_path)?;
         
         debug!("Loaded session {} from {}", session_id, file_path.display());
         Ok(session)
     }
     
     fn load_latest_session(&self) -> Result<Option<Session>, ContextError> {
-        if !self.latest_symlink.exists() {
+        if !self.has_latest_symlink() {
             debug!("No latest session symlink found");
             return Ok(None);
         }
         
         // Read the symlink target or the file content
-        #[cfg(unix)]
-        let target_path = {
-            let target = fs::read_link(&self.latest_symlink)
-                .map_err(|e| ContextError::Storage(format!("Failed to read symlink: {}", e)))?;
-            
-            if target.is_relative() {
-                self.sessions_dir.join(target)
-            } else {
-                target
-            }
-        };
-        
-        #[cfg(windows)]
-        let target_path = self.latest_symlink.clone();
+        let target_path = self.latest_target()?;
         
         if !target_path.exists() {
-            warn!("Latest session symlink points to non-existent file");
-            return Ok(None);
+            warn!("Latest session symlink points to non-existent file; recovering");
+            return self.recover_latest();
         }
         
-        let session_data = fs::read_to_string(&target_path)
-            .map_err(|e| ContextError::Storage(format!("Failed to read latest session: {}", e)))?;
-        
-        let session: Session = serde_json::from_str(&session_data)?;
+        let session = self.parse_session(&target_path)?;
         
         debug!("Loaded latest session: {}", session.id);
         Ok(Some(session))
@@ -236,24 +1214,7 @@ impl SessionStorage for FileStorage {
     fn list_sessions(&self) -> Result<Vec<SessionInfo>, ContextError> {
         let mut sessions = Vec::new();
         
-        let entries = fs::read_dir(&self.sessions_dir)
-            .map_err(|e| ContextError::Storage(format!("Failed to read sessions directory: {}", e)))?;
-        
-        for entry in entries {
-            let entry = entry
-                .map_err(|e| ContextError::Storage(format!("Failed to read directory entry: {}", e)))?;
-            
-            let path = entry.path();
-            
-            // Skip non-JSON files and the latest symlink
-            if path.extension().and_then(|s| s.to_str()) != Some("json") {
-                continue;
-            }
-            
-            if path.file_name() == Some(std::ffi::OsStr::new("latest.json")) {
-                continue;
-            }
-            
+        for path in session_files(&self.sessions_dir)? {
             match self.get_session_info(&path) {
                 Ok(info) => sessions.push(info),
                 Err(e) => warn!("Failed to get info for session file {}: {}", path.display(), e),
@@ -261,30 +1222,40 @@ impl SessionStorage for FileStorage {
         }
         
         // Sort by modification time (newest first)
-        sessions.sort_by(|a, b| b.modified_at.cmp(&a.modified_at));
+        sessions.sort_by_key(|s| std::cmp::Reverse(s.modified_at));
         
         debug!("Listed {} sessions", sessions.len());
         Ok(sessions)
     }
     
+    /// A locked read-modify-write, so concurrent appends aren't lost
+    fn append_message(&self, session_id: &Uuid, message: &Message) -> Result<(), ContextError> {
+        let _lock = self.acquire_lock(session_id)?;
+        let mut session = self.load_session(session_id)?;
+        session.add_message(message.clone());
+        session.version += 1;
+        self.save_session_unlocked(&session)
+    }
+
     fn delete_session(&self, session_id: &Uuid) -> Result<(), ContextError> {
-        let file_path = self.session_file_path(session_id);
+        let _lock = self.acquire_lock(session_id)?;
+        let file_path = self.find_session_file(session_id)
+            .ok_or_else(|| ContextError::SessionNotFound(session_id.to_string()))?;
+        self.check_within_sessions_dir(&file_path)?;
         
-        if !file_path.exists() {
-            return Err(ContextError::SessionNotFound(session_id.to_string()));
-        }
+        // Check before deleting, while the latest session can still be read
+        #[cfg(unix)]
+        let was_latest = self.has_latest_symlink() && self.latest_target().is_ok_and(|target| target == file_path);
+        
+        #[cfg(windows)]
+        let was_latest = matches!(self.load_latest_session(), Ok(Some(latest)) if latest.id == *session_id);
         
         fs::remove_file(&file_path)
             .map_err(|e| ContextError::Storage(format!("Failed to delete session file: {}", e)))?;
         
         // If this was the latest session, remove the symlink
-        if let Ok(Some(latest)) = self.load_latest_session() {
-            if latest.id == *session_id {
-                if self.latest_symlink.exists() {
-                    fs::remove_file(&self.latest_symlink)
-                        .map_err(|e| ContextError::Storage(format!("Failed to remove latest symlink: {}", e)))?;
-                }
-            }
+        if was_latest {
+            self.remove_latest_symlink()?;
         }
         
         info!("Deleted session {}", session_id);
@@ -292,7 +1263,8 @@ impl SessionStorage for FileStorage {
     }
     
     fn cleanup_old_sessions(&self, keep_count: usize) -> Result<usize, ContextError> {
-        let sessions = self.list_sessions()?;
+        let mut sessions = self.list_sessions()?;
+        sessions.retain(|info| !info.starred);
         
         if sessions.len() <= keep_count {
             debug!("No sessions to clean up (have {}, keeping {})", sessions.len(), keep_count);
@@ -303,12 +1275,17 @@ impl SessionStorage for FileStorage {
         let mut deleted_count = 0;
         
         for session_info in to_delete {
-            match self.delete_session(&session_info.id) {
+            let removed = if self.archive_on_cleanup {
+                self.archive_session(&session_info.id).map(|_| ())
+            } else {
+                self.delete_session(&session_info.id)
+            };
+            match removed {
                 Ok(()) => {
                     deleted_count += 1;
                     debug!("Cleaned up old session {}", session_info.id);
                 }
-                Err(e) => warn!("Failed to delete old session {}: {}", session_info.id, e),
+                Err(e) => warn!("Failed to clean up old session {}: {}", session_info.id, e),
             }
         }
         
@@ -317,6 +1294,462 @@ impl SessionStorage for FileStorage {
     }
 }
 
+/// A session as written to disk, serialized in place from borrowed fields
+///
+/// Field names and order match `Session`'s own serde layout.
+#[derive(serde::Serialize)]
+struct StoredSession<'a> {
+    id: &'a Uuid,
+    name: &'a str,
+    created_at: &'a DateTime<Utc>,
+    updated_at: &'a DateTime<Utc>,
+    messages: &'a [Message],
+    metadata: StoredMetadata<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group: Option<&'a str>,
+    version: u64,
+}
+
+/// Session metadata with any stale integrity hash dropped and the current
+/// one, when set, written in its place
+struct StoredMetadata<'a> {
+    metadata: &'a BTreeMap<String, serde_json::Value>,
+    integrity_hash: Option<String>,
+}
+
+impl serde::Serialize for StoredMetadata<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+
+        // Keep the hash in key order like every other entry
+        let entries = self.metadata.iter().filter(|(key, _)| key.as_str() != INTEGRITY_HASH_KEY);
+        let mut hash = self.integrity_hash.as_ref();
+        let mut map = serializer.serialize_map(Some(entries.clone().count() + usize::from(hash.is_some())))?;
+        for (key, value) in entries {
+            if key.as_str() > INTEGRITY_HASH_KEY
+                && let Some(hash) = hash.take()
+            {
+                map.serialize_entry(INTEGRITY_HASH_KEY, hash)?;
+            }
+            map.serialize_entry(key, value)?;
+        }
+        if let Some(hash) = hash {
+            map.serialize_entry(INTEGRITY_HASH_KEY, hash)?;
+        }
+        map.end()
+    }
+}
+
+/// Session fields other than messages, which are only summarized
+#[derive(serde::Deserialize)]
+struct SessionHeader {
+    id: Uuid,
+    name: String,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    metadata: BTreeMap<String, serde_json::Value>,
+    #[serde(default)]
+    group: Option<String>,
+    #[serde(default)]
+    version: u64,
+    #[serde(deserialize_with = "summarize_messages")]
+    messages: MessageSummary,
+}
+
+impl SessionHeader {
+    fn is_starred(&self) -> bool {
+        self.metadata.get(STARRED_KEY).and_then(|v| v.as_bool()) == Some(true)
+    }
+
+    fn group(&self, group_key: &str) -> Option<String> {
+        self.group.clone().or_else(|| self.metadata.get(group_key)?.as_str().map(str::to_string))
+    }
+}
+
+/// Message count and estimated tokens of a stored session
+struct MessageSummary {
+    count: usize,
+    total_tokens: usize,
+    /// Content of the first user message, else of the last message so far
+    preview_source: Option<String>,
+    found_user: bool,
+}
+
+impl MessageSummary {
+    fn preview(&self, max_chars: usize) -> Option<String> {
+        self.preview_source.as_deref()
+            .filter(|_| max_chars > 0)
+            .map(|content| content_preview(content, max_chars))
+    }
+}
+
+/// The message fields summaries need; everything else is skipped
+#[derive(serde::Deserialize)]
+struct MessageTokens {
+    role: MessageRole,
+    content: String,
+    #[serde(default)]
+    token_count: Option<usize>,
+}
+
+/// `content` with whitespace runs collapsed to single spaces, cut to
+/// `max_chars` characters with `…` marking the cut
+pub(crate) fn content_preview(content: &str, max_chars: usize) -> String {
+    let mut words = content.split_whitespace();
+    let mut preview = words.next().unwrap_or_default().to_string();
+    for word in words {
+        preview.push(' ');
+        preview.push_str(word);
+    }
+    match preview.char_indices().nth(max_chars) {
+        Some(_) => {
+            let cut = preview.char_indices().nth(max_chars.saturating_sub(1)).map_or(0, |(i, _)| i);
+            preview.truncate(cut);
+            preview.push('…');
+            preview
+        }
+        None => preview,
+    }
+}
+
+/// Deserialize a message sequence into a [`MessageSummary`]
+fn summarize_messages<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<MessageSummary, D::Error> {
+    struct Summarizer;
+
+    impl<'de> serde::de::Visitor<'de> for Summarizer {
+        type Value = MessageSummary;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str("a sequence")
+        }
+
+        fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<MessageSummary, A::Error> {
+            let mut summary = MessageSummary { count: 0, total_tokens: 0, preview_source: None, found_user: false };
+            while let Some(message) = seq.next_element::<MessageTokens>()? {
+                summary.count += 1;
+                summary.total_tokens += message.token_count
+                    .unwrap_or_else(|| estimate_content_tokens(&message.content));
+                if !summary.found_user {
+                    summary.found_user = message.role == MessageRole::User;
+                    summary.preview_source = Some(message.content);
+                }
+            }
+            Ok(summary)
+        }
+    }
+
+    deserializer.deserialize_seq(Summarizer)
+}
+
+/// Session files under `sessions_dir`, including shard subdirectories
+///
+/// The latest symlink and the archive are left out, as are files in no
+/// known format.
+fn session_files(sessions_dir: &Path) -> Result<Vec<PathBuf>, ContextError> {
+    let read_error = |e: std::io::Error| ContextError::Storage(format!("Failed to read sessions directory: {}", e));
+    let mut files = Vec::new();
+    let mut dirs = vec![sessions_dir.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        let top_level = dir == sessions_dir;
+        for entry in fs::read_dir(&dir).map_err(read_error)? {
+            let entry = entry.map_err(read_error)?;
+            let path = entry.path();
+            let name = entry.file_name();
+
+            if entry.file_type().map_err(read_error)?.is_dir() {
+                if !(top_level && (name == ARCHIVE_DIR || name == LOCKS_DIR)) {
+                    dirs.push(path);
+                }
+            } else if SerializationFormat::from_path(&path).is_some() && !(top_level && name == "latest.json") {
+                files.push(path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Gzip-compress a session file for the archive
+fn gzip(data: &[u8]) -> Result<Vec<u8>, ContextError> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)
+        .and_then(|_| encoder.finish())
+        .map_err(|e| ContextError::Storage(format!("Failed to compress archive: {}", e)))
+}
+
+/// Decompress an archived session file
+fn gunzip(data: &[u8]) -> Result<Vec<u8>, ContextError> {
+    let mut decompressed = Vec::new();
+    GzDecoder::new(data).read_to_end(&mut decompressed)
+        .map_err(|e| ContextError::InvalidSession(format!("Malformed gzip data: {}", e)))?;
+    Ok(decompressed)
+}
+
+/// Read and decode a file in `format`, decrypting it first if it is sealed
+fn decode_path<T: serde::de::DeserializeOwned>(
+    file_path: &Path,
+    format: SerializationFormat,
+    encryption: Option<&crypto::Key>,
+) -> Result<T, ContextError> {
+    let read_error = |e: std::io::Error| ContextError::Storage(format!("Failed to read session file: {}", e));
+    let mut reader = BufReader::new(fs::File::open(file_path).map_err(read_error)?);
+
+    let mut prefix = Vec::with_capacity(crypto::MAGIC.len());
+    (&mut reader).take(crypto::MAGIC.len() as u64).read_to_end(&mut prefix).map_err(read_error)?;
+    if !crypto::is_sealed(&prefix) {
+        return format.decode_from(prefix.as_slice().chain(reader)).map_err(|e| parse_error(file_path, e));
+    }
+
+    reader.read_to_end(&mut prefix).map_err(read_error)?;
+    decode_bytes(&prefix, format, encryption, file_path)
+}
+
+/// Decode an in-memory file read from `file_path`, decrypting it first if it is sealed
+fn decode_bytes<T: serde::de::DeserializeOwned>(
+    data: &[u8],
+    format: SerializationFormat,
+    encryption: Option<&crypto::Key>,
+    file_path: &Path,
+) -> Result<T, ContextError> {
+    if !crypto::is_sealed(data) {
+        return format.decode_from(data).map_err(|e| parse_error(file_path, e));
+    }
+
+    let key = encryption.ok_or_else(|| {
+        ContextError::Config(format!("{} is encrypted but no encryption key is configured", file_path.display()))
+    })?;
+    format.decode_from(crypto::open(key, data)?.as_slice()).map_err(|e| parse_error(file_path, e))
+}
+
+/// Attach the file, and for JSON the line and column, to a decoding error
+///
+/// Malformed content becomes `ContextError::InvalidSession`; I/O failures
+/// while streaming become `ContextError::Storage`.
+fn parse_error(file_path: &Path, error: ContextError) -> ContextError {
+    match error {
+        ContextError::Serialization(e) if e.is_io() => {
+            ContextError::Storage(format!("Failed to read {}: {}", file_path.display(), e))
+        }
+        ContextError::Serialization(e) if e.line() > 0 => {
+            // serde_json appends the position to its message; report it up front instead
+            let message = e.to_string();
+            let position = format!(" at line {} column {}", e.line(), e.column());
+            ContextError::InvalidSession(format!(
+                "Failed to parse {} at line {}, column {}: {}",
+                file_path.display(),
+                e.line(),
+                e.column(),
+                message.strip_suffix(&position).unwrap_or(&message)
+            ))
+        }
+        ContextError::Serialization(e) => {
+            ContextError::InvalidSession(format!("Failed to parse {}: {}", file_path.display(), e))
+        }
+        ContextError::InvalidSession(reason) => {
+            ContextError::InvalidSession(format!("Failed to parse {}: {}", file_path.display(), reason))
+        }
+        other => other,
+    }
+}
+
+/// Read a session file and verify its integrity hash, if it has one
+///
+/// The verified hash is removed from the session's metadata, since it goes
+/// stale as soon as the messages change.
+fn read_session(
+    file_path: &Path,
+    format: SerializationFormat,
+    encryption: Option<&crypto::Key>,
+) -> Result<Session, ContextError> {
+    let mut session: Session = decode_path(file_path, format, encryption)?;
+
+    if let Some(stored) = session.metadata.remove(INTEGRITY_HASH_KEY)
+        && stored.as_str() != Some(hex::encode(session.integrity_hash()).as_str())
+    {
+        return Err(ContextError::InvalidSession(format!(
+            "Integrity hash mismatch in {}",
+            file_path.display()
+        )));
+    }
+
+    Ok(session)
+}
+
+/// Handle to a stored session whose messages load on first access
+///
+/// Returned by [`FileStorage::open`]. The header fields are read up front;
+/// messages are deserialized, and the integrity hash checked, once, then
+/// cached. Later changes to the file are not picked up.
+#[derive(Debug, Clone)]
+pub struct LazySession {
+    pub id: Uuid,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub metadata: BTreeMap<String, serde_json::Value>,
+    pub group: Option<String>,
+    pub version: u64,
+    message_count: usize,
+    file_path: PathBuf,
+    format: SerializationFormat,
+    encryption: Option<crypto::Key>,
+    messages: Option<Vec<Message>>,
+}
+
+impl LazySession {
+    /// Number of messages, known without loading them
+    pub fn message_count(&self) -> usize {
+        self.messages.as_ref().map_or(self.message_count, |messages| messages.len())
+    }
+
+    /// Whether messages have been loaded yet
+    pub fn is_loaded(&self) -> bool {
+        self.messages.is_some()
+    }
+
+    /// The session's messages, read from disk on first call
+    pub fn messages(&mut self) -> Result<&[Message], ContextError> {
+        if self.messages.is_none() {
+            self.messages = Some(read_session(&self.file_path, self.format, self.encryption.as_ref())?.into_messages());
+        }
+        Ok(self.messages.as_deref().unwrap_or_default())
+    }
+
+    /// Load any remaining data and convert into a full `Session`
+    pub fn into_session(mut self) -> Result<Session, ContextError> {
+        self.messages()?;
+
+        let mut session = Session::with_id(self.id);
+        session.name = self.name;
+        session.created_at = self.created_at;
+        session.updated_at = self.updated_at;
+        session.metadata = self.metadata;
+        session.group = self.group;
+        session.version = self.version;
+        *session.messages_mut() = self.messages.unwrap_or_default();
+        session.recount_tokens();
+        Ok(session)
+    }
+}
+
+/// Set Unix permission bits on a path; a no-op elsewhere
+pub(crate) fn set_mode(path: &Path, mode: u32) -> Result<(), ContextError> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .map_err(|e| ContextError::Storage(format!("Failed to set permissions on {}: {}", path.display(), e)))?;
+    }
+
+    #[cfg(not(unix))]
+    let _ = (path, mode);
+
+    Ok(())
+}
+
+/// One element of a glob pattern for [`FileStorage::list_sessions_matching`]
+enum GlobToken {
+    Literal(char),
+    AnyChar,
+    AnyRun,
+    /// Inclusive character ranges; single characters are one-character ranges
+    Class { ranges: Vec<(char, char)>, negated: bool },
+}
+
+impl GlobToken {
+    fn parse(pattern: &str) -> Vec<GlobToken> {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            let token = match chars[i] {
+                '*' => GlobToken::AnyRun,
+                '?' => GlobToken::AnyChar,
+                '[' => match Self::parse_class(&chars[i + 1..]) {
+                    Some((class, consumed)) => {
+                        i += consumed;
+                        class
+                    }
+                    None => GlobToken::Literal('['),
+                },
+                c => GlobToken::Literal(c),
+            };
+            tokens.push(token);
+            i += 1;
+        }
+        tokens
+    }
+
+    /// A class from the characters after its `[`, with how many it used up
+    /// including the closing `]`; `None` if it is never closed
+    fn parse_class(chars: &[char]) -> Option<(GlobToken, usize)> {
+        let negated = chars.first() == Some(&'!');
+        let mut i = usize::from(negated);
+        let mut ranges = Vec::new();
+        // A `]` straight after the opening bracket is part of the set
+        let mut first = true;
+        while let Some(&c) = chars.get(i) {
+            if c == ']' && !first {
+                return Some((GlobToken::Class { ranges, negated }, i + 1));
+            }
+            first = false;
+            match (chars.get(i + 1), chars.get(i + 2)) {
+                (Some('-'), Some(&end)) if end != ']' => {
+                    ranges.push((c, end));
+                    i += 3;
+                }
+                _ => {
+                    ranges.push((c, c));
+                    i += 1;
+                }
+            }
+        }
+        None
+    }
+
+    fn matches(&self, c: char) -> bool {
+        match self {
+            GlobToken::Literal(literal) => *literal == c,
+            GlobToken::AnyChar => true,
+            GlobToken::AnyRun => false,
+            GlobToken::Class { ranges, negated } => {
+                ranges.iter().any(|(start, end)| (*start..=*end).contains(&c)) != *negated
+            }
+        }
+    }
+}
+
+/// Whether `pattern` matches all of `text`
+///
+/// Backtracks only to the most recent `*`, which is enough for globs and
+/// bounds matching at pattern length times text length.
+fn glob_match(pattern: &[GlobToken], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Position after the last `*`, and the text position it is matched up to
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(GlobToken::AnyRun) => {
+                backtrack = Some((p + 1, t));
+                p += 1;
+            }
+            Some(token) if token.matches(text[t]) => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_t)) => {
+                    backtrack = Some((star_p, star_t + 1));
+                    p = star_p;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|token| matches!(token, GlobToken::AnyRun))
+}
+
 impl Default for FileStorage {
     fn default() -> Self {
         Self::new().expect("Failed to create default file storage")
@@ -345,7 +1778,7 @@ mod tests {
         // Load session
         let loaded = storage.load_session(&session.id).unwrap();
         assert_eq!(loaded.id, session.id);
-        assert_eq!(loaded.messages.len(), 2);
+        assert_eq!(loaded.messages().len(), 2);
         
         // Load latest session
         let latest = storage.load_latest_session().unwrap();
@@ -387,4 +1820,679 @@ mod tests {
         let remaining = storage.list_sessions().unwrap();
         assert_eq!(remaining.len(), 2);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_sharded_layouts() {
+        let temp_dir = TempDir::new().unwrap();
+        let flat = FileStorage::with_directory(temp_dir.path()).unwrap();
+        let mut legacy = Session::new();
+        legacy.add_message(Message::user("From before sharding".to_string()));
+        flat.save_session(&legacy).unwrap();
+
+        let by_date = FileStorage::with_directory(temp_dir.path()).unwrap().with_sharding(ShardLayout::ByDate);
+        let mut dated = Session::new();
+        dated.created_at = DateTime::parse_from_rfc3339("2023-07-04T12:00:00Z").unwrap().with_timezone(&Utc);
+        by_date.save_session(&dated).unwrap();
+        let dated_path = temp_dir.path().join("2023").join("07").join(format!("{}.json", dated.id));
+        assert!(dated_path.exists());
+        assert_eq!(by_date.load_latest_session().unwrap().unwrap().id, dated.id);
+
+        // Flat files still load and list, and move into their shard on save
+        assert_eq!(by_date.list_sessions().unwrap().len(), 2);
+        let mut legacy = by_date.load_session(&legacy.id).unwrap();
+        legacy.version += 1;
+        by_date.save_session(&legacy).unwrap();
+        assert!(by_date.session_file_path(&legacy).starts_with(temp_dir.path().join(legacy.created_at.format("%Y").to_string())));
+        assert!(!temp_dir.path().join(format!("{}.json", legacy.id)).exists());
+        assert_eq!(by_date.list_sessions().unwrap().len(), 2);
+
+        by_date.delete_session(&dated.id).unwrap();
+        assert!(!dated_path.exists());
+        assert_eq!(by_date.list_sessions().unwrap().len(), 1);
+
+        let by_prefix = FileStorage::with_directory(temp_dir.path())
+            .unwrap()
+            .with_sharding(ShardLayout::ByIdPrefix { len: 2 });
+        let session = Session::new();
+        by_prefix.save_session(&session).unwrap();
+        let prefix = &session.id.simple().to_string()[..2];
+        assert!(temp_dir.path().join(prefix).join(format!("{}.json", session.id)).exists());
+        assert_eq!(by_prefix.load_session(&session.id).unwrap().id, session.id);
+        assert_eq!(by_prefix.load_latest_session().unwrap().unwrap().id, session.id);
+    }
+
+    #[test]
+    fn test_migrate_storage_between_backends() {
+        let source_dir = TempDir::new().unwrap();
+        let destination_dir = TempDir::new().unwrap();
+        let source = FileStorage::with_directory(source_dir.path()).unwrap();
+        let destination = crate::log_storage::LogStorage::with_directory(destination_dir.path()).unwrap();
+
+        let mut sessions = Vec::new();
+        for i in 0..3 {
+            let mut session = Session::with_name(format!("session {}", i));
+            session.add_message(Message::user(format!("Message {}", i)));
+            source.save_session(&session).unwrap();
+            sessions.push(session);
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        // Make the middle session the latest
+        source.save_session(&sessions[1]).unwrap();
+
+        // The destination already has a newer copy of one session
+        let mut newer = sessions[2].clone();
+        newer.version = 5;
+        destination.save_session(&newer).unwrap();
+
+        let report = migrate_storage(&source, &destination).unwrap();
+        assert_eq!(report.migrated, 2);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, sessions[2].id);
+        assert!(matches!(report.failed[0].1, ContextError::Conflict(_)));
+        assert_eq!(report.latest, Some(sessions[1].id));
+
+        let latest = destination.load_latest_session().unwrap().unwrap();
+        assert_eq!((latest.id, latest.name.as_str()), (sessions[1].id, "session 1"));
+        assert_eq!(destination.load_session(&sessions[0].id).unwrap().messages()[0].content, "Message 0");
+        assert_eq!(destination.list_sessions().unwrap().len(), 3);
+        assert_eq!(source.list_sessions().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_cleanup_archives_and_restores() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileStorage::with_directory(temp_dir.path()).unwrap().with_archive_on_cleanup(true);
+
+        let mut sessions = Vec::new();
+        for i in 0..3 {
+            let mut session = Session::new();
+            for j in 0..20 {
+                session.add_message(Message::user(format!("Message {} of session {}", j, i)));
+            }
+            storage.save_session(&session).unwrap();
+            sessions.push(session);
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let oldest = sessions[0].id;
+        let original_size = fs::metadata(storage.find_session_file(&oldest).unwrap()).unwrap().len();
+
+        assert_eq!(storage.cleanup_old_sessions(2).unwrap(), 1);
+        assert_eq!(storage.list_sessions().unwrap().len(), 2);
+        assert!(matches!(storage.load_session(&oldest), Err(ContextError::SessionNotFound(_))));
+
+        let all = storage.list_sessions_with_archived().unwrap();
+        let archived: Vec<_> = all.iter().filter(|info| info.archived).collect();
+        assert_eq!(all.len(), 3);
+        assert_eq!(archived.len(), 1);
+        assert_eq!((archived[0].id, archived[0].message_count), (oldest, 20));
+        assert!(fs::metadata(&archived[0].file_path).unwrap().len() < original_size);
+
+        storage.restore_archived(&oldest).unwrap();
+        assert_eq!(storage.load_session(&oldest).unwrap().messages().len(), 20);
+        assert!(storage.list_sessions_with_archived().unwrap().iter().all(|info| !info.archived));
+        assert!(storage.restore_archived(&oldest).is_err());
+
+        storage.archive_session(&oldest).unwrap();
+        storage.save_session(&Session::with_id(oldest)).unwrap();
+        assert!(matches!(storage.restore_archived(&oldest), Err(ContextError::Conflict(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_session_file_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let sessions_dir = temp_dir.path().join("sessions");
+        let storage = FileStorage::with_directory(&sessions_dir).unwrap();
+
+        let session = Session::new();
+        storage.save_session(&session).unwrap();
+
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&sessions_dir), 0o700);
+        assert_eq!(mode(&storage.session_file_path(&session)), 0o600);
+
+        let storage = storage.with_mode(0o640);
+        storage.save_session(&session).unwrap();
+        assert_eq!(mode(&storage.session_file_path(&session)), 0o640);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_saves_replace_the_file_whole() {
+        use std::io::Read;
+
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileStorage::with_directory(temp_dir.path()).unwrap();
+        let mut session = Session::new();
+        session.add_message(Message::user("first".to_string()));
+        storage.save_session(&session).unwrap();
+        let file_path = storage.session_file_path(&session);
+        let before = fs::read(&file_path).unwrap();
+
+        // The old file is never truncated in place, so a reader still holding
+        // it sees the whole previous save
+        let mut reader = fs::File::open(&file_path).unwrap();
+        session.add_message(Message::user("second".to_string()));
+        storage.save_session(&session).unwrap();
+        let mut held = Vec::new();
+        reader.read_to_end(&mut held).unwrap();
+        assert_eq!(held, before);
+        assert_eq!(storage.load_session(&session.id).unwrap().messages().len(), 2);
+
+        let leftovers: Vec<_> = fs::read_dir(temp_dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".tmp"))
+            .collect();
+        assert!(leftovers.is_empty(), "{:?}", leftovers);
+    }
+
+    #[test]
+    fn test_integrity_hash_detects_tampering() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileStorage::with_directory(temp_dir.path()).unwrap().with_integrity_hash(true);
+
+        let mut session = Session::new();
+        session.add_message(Message::user("Transfer $10".to_string()));
+        session.add_message(Message::user("Transfer $10".to_string()));
+        assert_eq!(session.messages()[0].content_hash(), session.messages()[1].content_hash());
+        storage.save_session(&session).unwrap();
+
+        let mut loaded = storage.load_session(&session.id).unwrap();
+        assert_eq!(loaded.integrity_hash(), session.integrity_hash());
+        assert!(!loaded.metadata.contains_key(INTEGRITY_HASH_KEY));
+
+        // Saving without hashing after an edit leaves no stale hash behind
+        let unhashed = FileStorage::with_directory(temp_dir.path()).unwrap();
+        loaded.add_message(Message::assistant("Done".to_string()));
+        unhashed.save_session(&loaded).unwrap();
+        assert_eq!(storage.load_session(&session.id).unwrap().messages().len(), 3);
+        storage.save_session(&session).unwrap();
+
+        let file_path = storage.session_file_path(&session);
+        let tampered = fs::read_to_string(&file_path).unwrap().replace("$10", "$1000");
+        fs::write(&file_path, tampered).unwrap();
+
+        let err = storage.load_session(&session.id).unwrap_err();
+        assert!(matches!(err, ContextError::InvalidSession(_)));
+    }
+
+    #[test]
+    fn test_stored_session_matches_session_layout() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileStorage::with_directory(temp_dir.path()).unwrap();
+
+        let mut session = Session::with_name("streamed".to_string());
+        session.metadata.insert("project".to_string(), serde_json::json!("parser"));
+        session.add_message(Message::user("Hello".to_string()).with_token_count(3));
+        session.version = 4;
+        assert_eq!(
+            serde_json::to_value(storage.stored_session(&session)).unwrap(),
+            serde_json::to_value(&session).unwrap()
+        );
+
+        // A stale hash is replaced rather than written twice
+        session.metadata.insert(INTEGRITY_HASH_KEY.to_string(), serde_json::json!("stale"));
+        let hashed = storage.with_integrity_hash(true);
+        let value = serde_json::to_value(hashed.stored_session(&session)).unwrap();
+        assert_eq!(value["metadata"][INTEGRITY_HASH_KEY], hex::encode(session.integrity_hash()));
+        assert_eq!(value["metadata"].as_object().unwrap().len(), 2);
+
+        hashed.save_session(&session).unwrap();
+        assert_eq!(hashed.load_session(&session.id).unwrap().messages()[0].token_count, Some(3));
+    }
+
+    #[test]
+    fn test_encrypts_only_flagged_sessions() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileStorage::with_directory(temp_dir.path()).unwrap().with_encryption_key([42; 32]);
+
+        let mut plain = Session::new();
+        plain.add_message(Message::user("debug me".to_string()));
+        let mut secret = Session::new();
+        secret.metadata.insert(ENCRYPTED_KEY.to_string(), serde_json::json!(true));
+        secret.add_message(Message::user("the launch codes".to_string()));
+        storage.save_session(&plain).unwrap();
+        storage.save_session(&secret).unwrap();
+
+        assert!(fs::read_to_string(storage.session_file_path(&plain)).unwrap().contains("debug me"));
+        let sealed = fs::read(storage.session_file_path(&secret)).unwrap();
+        assert!(crypto::is_sealed(&sealed));
+        assert!(!String::from_utf8_lossy(&sealed).contains("launch codes"));
+
+        assert_eq!(storage.load_session(&secret.id).unwrap().messages()[0].content, "the launch codes");
+        assert_eq!(storage.open(&secret.id).unwrap().messages().unwrap().len(), 1);
+        assert_eq!(storage.list_sessions().unwrap().len(), 2);
+
+        let keyless = FileStorage::with_directory(temp_dir.path()).unwrap();
+        assert_eq!(keyless.load_session(&plain.id).unwrap().messages().len(), 1);
+        assert!(matches!(keyless.load_session(&secret.id), Err(ContextError::Config(_))));
+        assert!(matches!(keyless.save_session(&secret), Err(ContextError::Config(_))));
+
+        let wrong_key = FileStorage::with_directory(temp_dir.path()).unwrap().with_encryption_key([7; 32]);
+        assert!(matches!(wrong_key.load_session(&secret.id), Err(ContextError::InvalidSession(_))));
+    }
+
+    #[test]
+    fn test_mixed_serialization_formats() {
+        let temp_dir = TempDir::new().unwrap();
+        let json = FileStorage::with_directory(temp_dir.path()).unwrap();
+        let msgpack = FileStorage::with_directory(temp_dir.path()).unwrap().with_format(SerializationFormat::MessagePack);
+        let cbor = FileStorage::with_directory(temp_dir.path()).unwrap().with_format(SerializationFormat::Cbor);
+
+        let mut legacy = Session::with_name("legacy".to_string());
+        legacy.add_message(Message::user("x".repeat(500)));
+        json.save_session(&legacy).unwrap();
+
+        let mut packed = Session::with_name("packed".to_string());
+        packed.add_message(Message::user("Hello".to_string()).with_token_count(3));
+        packed.metadata.insert("tags".to_string(), serde_json::json!({"labels": ["a", 1, null]}));
+        msgpack.save_session(&packed).unwrap();
+
+        let mut compact = Session::with_name("compact".to_string());
+        compact.add_message(Message::assistant("Hi".to_string()));
+        cbor.save_session(&compact).unwrap();
+        assert!(temp_dir.path().join(format!("{}.cbor", compact.id)).exists());
+
+        // Any storage reads every format, and the latest pointer follows the extension
+        assert_eq!(json.load_session(&packed.id).unwrap().messages()[0].token_count, Some(3));
+        assert_eq!(json.load_session(&packed.id).unwrap().metadata, packed.metadata);
+        assert_eq!(json.load_session(&compact.id).unwrap().name, "compact");
+        assert_eq!(msgpack.load_latest_session().unwrap().unwrap().id, compact.id);
+        assert_eq!(cbor.list_sessions().unwrap().len(), 3);
+
+        // Re-saving migrates the file and removes the old one
+        let json_path = temp_dir.path().join(format!("{}.json", legacy.id));
+        let msgpack_path = temp_dir.path().join(format!("{}.msgpack", legacy.id));
+        let json_size = fs::metadata(&json_path).unwrap().len();
+        msgpack.save_session(&legacy).unwrap();
+        assert!(!json_path.exists());
+        assert!(fs::metadata(&msgpack_path).unwrap().len() < json_size);
+        assert_eq!(json.load_session(&legacy.id).unwrap().messages()[0].content, "x".repeat(500));
+
+        msgpack.delete_session(&packed.id).unwrap();
+        assert!(matches!(json.load_session(&packed.id), Err(ContextError::SessionNotFound(_))));
+    }
+
+    #[test]
+    fn test_open_lazy_session() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileStorage::with_directory(temp_dir.path()).unwrap().with_format(SerializationFormat::MessagePack);
+
+        let mut session = Session::with_name("browsing".to_string());
+        session.metadata.insert("project".to_string(), serde_json::json!("parser"));
+        session.add_message(Message::user("Hello".to_string()));
+        session.add_message(Message::assistant("Hi".to_string()));
+        storage.save_session(&session).unwrap();
+        assert_eq!(storage.list_sessions().unwrap()[0].message_count, 2);
+
+        let mut lazy = storage.open(&session.id).unwrap();
+        assert_eq!(lazy.name, "browsing");
+        assert_eq!(lazy.metadata["project"], "parser");
+        assert_eq!(lazy.message_count(), 2);
+        assert!(!lazy.is_loaded());
+
+        assert_eq!(lazy.messages().unwrap()[1].content, "Hi");
+        assert!(lazy.is_loaded());
+
+        let full = lazy.into_session().unwrap();
+        assert_eq!(full.id, session.id);
+        assert_eq!(full.created_at, session.created_at);
+        assert_eq!(full.total_tokens(), session.total_tokens());
+        assert_eq!(full.group, None);
+
+        let mut grouped = Session::with_name("invoice fix".to_string());
+        grouped.group = Some("billing".to_string());
+        grouped.add_message(Message::user("Hello".to_string()));
+        storage.save_session(&grouped).unwrap();
+        let lazy = storage.open(&grouped.id).unwrap();
+        assert_eq!(lazy.group.as_deref(), Some("billing"));
+        assert_eq!(lazy.into_session().unwrap().group.as_deref(), Some("billing"));
+
+        assert!(matches!(storage.open(&Uuid::new_v4()), Err(ContextError::SessionNotFound(_))));
+    }
+
+    #[test]
+    fn test_list_sessions_by_tokens() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileStorage::with_directory(temp_dir.path()).unwrap();
+
+        let mut small = Session::new();
+        small.add_message(Message::user("abcd".repeat(5)));
+        let mut large = Session::new();
+        large.add_message(Message::user("abcd".repeat(100)));
+        large.add_message(Message::assistant("measured".to_string()).with_token_count(50));
+        storage.save_session(&small).unwrap();
+        storage.save_session(&large).unwrap();
+
+        let mut totals: Vec<usize> = storage.list_sessions().unwrap().iter().map(|info| info.total_tokens).collect();
+        totals.sort();
+        assert_eq!(totals, [5, 150]);
+
+        let heavy = storage.list_sessions_by_tokens(100, usize::MAX).unwrap();
+        assert_eq!(heavy.len(), 1);
+        assert_eq!(heavy[0].id, large.id);
+        assert_eq!(storage.list_sessions_by_tokens(5, 5).unwrap()[0].id, small.id);
+        assert!(storage.list_sessions_by_tokens(6, 149).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_session_ids_must_be_canonical() {
+        let id = Uuid::new_v4();
+        assert_eq!(parse_session_id(&id.to_string()).unwrap(), id);
+        assert_eq!(parse_session_id(&id.to_string().to_uppercase()).unwrap(), id);
+        for bad in [
+            id.simple().to_string(),
+            id.braced().to_string(),
+            id.urn().to_string(),
+            format!("../{}", id),
+            format!("{}/../../etc/passwd", id),
+            String::new(),
+        ] {
+            assert!(matches!(parse_session_id(&bad), Err(ContextError::InvalidSession(_))), "{}", bad);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_sessions_outside_directory_are_refused() {
+        let temp_dir = TempDir::new().unwrap();
+        let outside = TempDir::new().unwrap();
+        let storage = FileStorage::with_directory(temp_dir.path()).unwrap()
+            .with_sharding(ShardLayout::ByIdPrefix { len: 2 });
+
+        let session = Session::new();
+        let prefix = &session.id.simple().to_string()[..2];
+        std::os::unix::fs::symlink(outside.path(), temp_dir.path().join(prefix)).unwrap();
+
+        let err = storage.save_session(&session).unwrap_err();
+        assert!(matches!(err, ContextError::InvalidSession(_)));
+        assert_eq!(fs::read_dir(outside.path()).unwrap().count(), 0);
+
+        // A planted file is not read through the link either
+        fs::write(outside.path().join(format!("{}.json", session.id)), serde_json::to_vec(&session).unwrap()).unwrap();
+        assert!(matches!(storage.load_session(&session.id), Err(ContextError::InvalidSession(_))));
+    }
+
+    #[test]
+    fn test_session_guard_holds_off_other_writers() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileStorage::with_directory(temp_dir.path()).unwrap();
+        let session = Session::new();
+        storage.save_session(&session).unwrap();
+
+        let deleted = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            let guard = storage.lock_session(&session.id).unwrap();
+            scope.spawn(|| {
+                storage.delete_session(&session.id).unwrap();
+                deleted.store(true, Ordering::SeqCst);
+            });
+
+            // The delete waits while the guard is held
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            assert!(!deleted.load(Ordering::SeqCst));
+            let mut locked = guard.load().unwrap();
+            locked.add_message(Message::user("under the lock".to_string()));
+            guard.save(&locked).unwrap();
+            assert_eq!(storage.load_session(&session.id).unwrap().messages().len(), 1);
+
+            assert!(matches!(guard.save(&Session::new()), Err(ContextError::InvalidSession(_))));
+        });
+
+        assert!(deleted.load(Ordering::SeqCst));
+        assert!(storage.list_sessions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_session_info_preview() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileStorage::with_directory(temp_dir.path()).unwrap().with_preview_chars(20);
+
+        let mut chat = Session::new();
+        chat.add_message(Message::system("You are helpful".to_string()));
+        chat.add_message(Message::user("Why does  the\nparser fail on ünïcode input?".to_string()));
+        chat.add_message(Message::assistant("Because".to_string()));
+        let mut no_user = Session::new();
+        no_user.add_message(Message::system("Setup".to_string()));
+        no_user.add_message(Message::assistant("Ready".to_string()));
+        let empty = Session::new();
+        for session in [&chat, &no_user, &empty] {
+            storage.save_session(session).unwrap();
+        }
+
+        let preview = |session: &Session| {
+            storage.list_sessions().unwrap().into_iter().find(|info| info.id == session.id).unwrap().preview
+        };
+        assert_eq!(preview(&chat).as_deref(), Some("Why does the parser…"));
+        assert_eq!(preview(&no_user).as_deref(), Some("Ready"));
+        assert_eq!(preview(&empty), None);
+
+        let storage = storage.with_preview_chars(0);
+        assert!(storage.list_sessions().unwrap().iter().all(|info| info.preview.is_none()));
+    }
+
+    #[test]
+    fn test_append_message_to_stored_session() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileStorage::with_directory(temp_dir.path()).unwrap();
+        let mut session = Session::new();
+        session.version = 1;
+        storage.save_session(&session).unwrap();
+
+        std::thread::scope(|scope| {
+            for i in 0..4 {
+                let storage = &storage;
+                let id = session.id;
+                scope.spawn(move || storage.append_message(&id, &Message::user(format!("line {}", i))).unwrap());
+            }
+        });
+
+        // Concurrent appends each land, in some order
+        let stored = storage.load_session(&session.id).unwrap();
+        assert_eq!(stored.messages().len(), 4);
+        assert_eq!(stored.version, 5);
+        let err = storage.append_message(&Uuid::new_v4(), &Message::user("lost".to_string())).unwrap_err();
+        assert!(matches!(err, ContextError::SessionNotFound(_)));
+    }
+
+    #[test]
+    fn test_list_sessions_matching_glob() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileStorage::with_directory(temp_dir.path()).unwrap();
+        for name in ["proj-acme-parser", "proj-acme-ui", "old-proj-acme-parser", "proj-zeta-1", "[draft]"] {
+            storage.save_session(&Session::with_name(name.to_string())).unwrap();
+        }
+
+        let names = |pattern: &str| {
+            let mut names: Vec<String> = storage.list_sessions_matching(pattern).unwrap()
+                .into_iter().map(|info| info.name).collect();
+            names.sort();
+            names
+        };
+        assert_eq!(names("proj-acme-*"), ["proj-acme-parser", "proj-acme-ui"]);
+        assert_eq!(names("*parser"), ["old-proj-acme-parser", "proj-acme-parser"]);
+        assert_eq!(names("proj-????-?"), ["proj-zeta-1"]);
+        assert_eq!(names("proj-[a-m]*"), ["proj-acme-parser", "proj-acme-ui"]);
+        assert_eq!(names("proj-[!a]*"), ["proj-zeta-1"]);
+        assert_eq!(names("[[]draft]"), ["[draft]"]);
+        assert_eq!(names("*").len(), 5);
+        assert!(names("acme").is_empty());
+    }
+
+    #[test]
+    fn test_parse_errors_name_file_and_line() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileStorage::with_directory(temp_dir.path()).unwrap();
+
+        let id = Uuid::new_v4();
+        let path = temp_dir.path().join(format!("{}.json", id));
+        fs::write(&path, "{\n  \"name\": \"draft\",\n  \"messages\": oops\n}").unwrap();
+        let Err(ContextError::InvalidSession(message)) = storage.load_session(&id) else {
+            panic!("expected InvalidSession");
+        };
+        assert!(message.starts_with(&format!("Failed to parse {} at line 3, column", path.display())), "{}", message);
+        assert!(!message.ends_with("column 15"), "{}", message);
+
+        let id = Uuid::new_v4();
+        let path = temp_dir.path().join(format!("{}.msgpack", id));
+        fs::write(&path, [0xc1]).unwrap();
+        let Err(ContextError::InvalidSession(message)) = storage.load_session(&id) else {
+            panic!("expected InvalidSession");
+        };
+        assert!(message.starts_with(&format!("Failed to parse {}: Malformed", path.display())), "{}", message);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_orphaned_latest_symlink_recovers() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileStorage::with_directory(temp_dir.path()).unwrap();
+
+        let older = Session::with_name("older".to_string());
+        storage.save_session(&older).unwrap();
+        let newer = Session::with_name("newer".to_string());
+        storage.save_session(&newer).unwrap();
+
+        // Deleted out-of-band: the symlink now dangles
+        fs::remove_file(storage.session_file_path(&newer)).unwrap();
+        assert_eq!(storage.load_latest_session().unwrap().unwrap().id, older.id);
+        assert_eq!(storage.latest_target().unwrap(), storage.session_file_path(&older));
+
+        // With nothing left to point at, the dangling link is cleared and saves still work
+        fs::remove_file(storage.session_file_path(&older)).unwrap();
+        assert!(storage.load_latest_session().unwrap().is_none());
+        assert!(!storage.has_latest_symlink());
+        storage.save_session(&newer).unwrap();
+        assert_eq!(storage.load_latest_session().unwrap().unwrap().id, newer.id);
+
+        storage.delete_session(&newer.id).unwrap();
+        assert!(!storage.has_latest_symlink());
+    }
+
+    #[test]
+    fn test_cleanup_older_than() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileStorage::with_directory(temp_dir.path()).unwrap();
+
+        let mut stale = Session::new();
+        stale.updated_at = Utc::now() - chrono::Duration::days(45);
+        storage.save_session(&stale).unwrap();
+
+        let fresh = Session::new();
+        storage.save_session(&fresh).unwrap();
+
+        let mut stale_latest = Session::new();
+        stale_latest.updated_at = Utc::now() - chrono::Duration::days(90);
+        storage.save_session(&stale_latest).unwrap();
+
+        let deleted = storage.cleanup_older_than(Duration::from_secs(30 * 24 * 60 * 60)).unwrap();
+        assert_eq!(deleted, 1);
+
+        let remaining: Vec<Uuid> = storage.list_sessions().unwrap().iter().map(|s| s.id).collect();
+        assert!(remaining.contains(&fresh.id));
+        assert!(remaining.contains(&stale_latest.id));
+        assert!(!remaining.contains(&stale.id));
+    }
+
+    #[test]
+    fn test_cleanup_never_deletes_starred_sessions() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileStorage::with_directory(temp_dir.path()).unwrap();
+
+        let mut starred = Session::new();
+        starred.updated_at = Utc::now() - chrono::Duration::days(365);
+        starred.set_meta(STARRED_KEY, true).unwrap();
+        storage.save_session(&starred).unwrap();
+        let mut stale = Session::new();
+        stale.updated_at = Utc::now() - chrono::Duration::days(365);
+        storage.save_session(&stale).unwrap();
+        storage.save_session(&Session::new()).unwrap();
+
+        assert_eq!(storage.cleanup_older_than(Duration::from_secs(60)).unwrap(), 1);
+        let remaining: Vec<Uuid> = storage.list_sessions().unwrap().iter().map(|s| s.id).collect();
+        assert!(remaining.contains(&starred.id));
+        assert!(!remaining.contains(&stale.id));
+
+        // Starred sessions sit outside the keep budget, even when it is zero
+        assert_eq!(storage.cleanup_old_sessions(0).unwrap(), 1);
+        let remaining = storage.list_sessions().unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert!(remaining[0].starred);
+        assert_eq!(remaining[0].id, starred.id);
+    }
+
+    #[test]
+    fn test_json_output_is_stable_and_indented() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileStorage::with_directory(temp_dir.path()).unwrap()
+            .with_integrity_hash(true)
+            .with_json_indent(4);
+
+        let mut session = Session::new();
+        let keys = ["zeta", "alpha", "model", "branch", "user"];
+        for key in keys {
+            session.metadata.insert(key.to_string(), serde_json::json!(key));
+        }
+        session.add_message(Message::user("Hello".to_string()).with_metadata("b".to_string(), serde_json::json!(1)));
+        storage.save_session(&session).unwrap();
+        let first = fs::read_to_string(storage.session_file_path(&session)).unwrap();
+
+        // Same content inserted in the opposite order writes identical bytes
+        let mut reordered = session.clone();
+        reordered.metadata.clear();
+        for key in keys.iter().rev() {
+            reordered.metadata.insert(key.to_string(), serde_json::json!(key));
+        }
+        storage.save_session(&reordered).unwrap();
+        assert_eq!(fs::read_to_string(storage.session_file_path(&session)).unwrap(), first);
+
+        let metadata = &first[first.rfind("\"metadata\"").unwrap()..];
+        let positions: Vec<usize> = ["alpha", "branch", "integrity_hash", "model", "user", "zeta"]
+            .iter()
+            .map(|key| metadata.find(&format!("\"{}\":", key)).unwrap())
+            .collect();
+        assert!(positions.is_sorted());
+        assert!(first.lines().nth(1).unwrap().starts_with("    \""));
+        assert!(!first.lines().nth(1).unwrap().starts_with("     "));
+        assert_eq!(storage.load_session(&session.id).unwrap().metadata.len(), keys.len());
+    }
+
+    #[test]
+    fn test_latest_per_group() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileStorage::with_directory(temp_dir.path()).unwrap();
+        let in_group = |group: &str| {
+            let mut session = Session::new();
+            session.set_meta("group", group).unwrap();
+            session
+        };
+
+        let parser = in_group("parser");
+        let renderer = in_group("renderer/v2");
+        let ungrouped = Session::new();
+        for session in [&parser, &renderer, &ungrouped] {
+            storage.save_session(session).unwrap();
+        }
+
+        assert_eq!(storage.load_latest_session().unwrap().unwrap().id, ungrouped.id);
+        assert_eq!(storage.load_latest_for("parser").unwrap().unwrap().id, parser.id);
+        assert_eq!(storage.load_latest_for("renderer/v2").unwrap().unwrap().id, renderer.id);
+        assert!(temp_dir.path().join("latest-renderer%2Fv2.txt").exists());
+        assert!(storage.load_latest_for("unknown").unwrap().is_none());
+        assert_eq!(storage.list_sessions().unwrap().len(), 3);
+
+        // Deleting the group's latest session leaves nothing to resume
+        storage.delete_session(&parser.id).unwrap();
+        assert!(storage.load_latest_for("parser").unwrap().is_none());
+
+        let custom = FileStorage::with_directory(temp_dir.path()).unwrap().with_group_key("project");
+        let mut session = Session::new();
+        session.set_meta("project", "docs").unwrap();
+        custom.save_session(&session).unwrap();
+        assert_eq!(custom.load_latest_for("docs").unwrap().unwrap().id, session.id);
+    }
+}
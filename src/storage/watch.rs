@@ -0,0 +1,237 @@
+//! Watcher for session files changed by other processes, built on `notify`
+
+use super::{session_files, FileStorage, SerializationFormat, ARCHIVE_DIR, LOCKS_DIR};
+use crate::error::ContextError;
+use notify::{Event, EventKind, PollWatcher, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+/// A change to a stored session, seen by [`FileStorage::watch`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageEvent {
+    Created(Uuid),
+    Modified(Uuid),
+    Deleted(Uuid),
+}
+
+/// A running `notify` watcher, kept alive by the [`FileStorage`] that started it
+pub(super) struct ActiveWatch {
+    _watcher: Box<dyn Watcher + Send>,
+    /// Set once the receiver is gone, so the next `watch` call can drop this
+    closed: Arc<AtomicBool>,
+}
+
+impl FileStorage {
+    /// Watch the sessions directory for changes made by any process
+    ///
+    /// Uses the platform's file notification API (inotify, FSEvents,
+    /// ReadDirectoryChangesW), so changes arrive promptly without rescanning.
+    /// A single save may be reported as more than one `Modified`. A session
+    /// rewritten in another format, or moved to another shard, is reported as
+    /// modified.
+    ///
+    /// Watching lasts as long as this storage. Once the receiver is dropped,
+    /// the watch is released on the next change or `watch` call.
+    pub fn watch(&self) -> Result<Receiver<StorageEvent>, ContextError> {
+        self.start_watch(|handler| Ok(Box::new(notify::recommended_watcher(handler)?)))
+    }
+
+    /// Like [`watch`](Self::watch), but rescanning the directory every `interval`
+    ///
+    /// A fallback for filesystems that don't deliver change notifications,
+    /// such as network mounts. Every scan stats every session file, so it
+    /// costs more and reports changes up to `interval` late. Modification
+    /// times are compared to the second, so a rewrite within the same second
+    /// as the last change seen goes unreported until the file changes again.
+    pub fn watch_polling(&self, interval: Duration) -> Result<Receiver<StorageEvent>, ContextError> {
+        let config = notify::Config::default().with_poll_interval(interval);
+        self.start_watch(|handler| Ok(Box::new(PollWatcher::new(handler, config)?)))
+    }
+
+    fn start_watch(
+        &self,
+        make_watcher: impl FnOnce(Box<dyn FnMut(notify::Result<Event>) + Send>) -> notify::Result<Box<dyn Watcher + Send>>,
+    ) -> Result<Receiver<StorageEvent>, ContextError> {
+        let watch_error = |e: notify::Error| ContextError::Storage(format!("Failed to start watcher: {}", e));
+        // Event paths are canonical on some platforms, so scan with the same root
+        let sessions_dir = fs::canonicalize(&self.sessions_dir)
+            .map_err(|e| ContextError::Storage(format!("Failed to read sessions directory: {}", e)))?;
+
+        let (sender, receiver) = mpsc::channel();
+        let closed = Arc::new(AtomicBool::new(false));
+        let mut tracker = Tracker {
+            files: known_files(&sessions_dir)?,
+            sessions_dir: sessions_dir.clone(),
+            sender,
+            closed: Arc::clone(&closed),
+        };
+
+        let mut watcher = make_watcher(Box::new(move |result| tracker.handle(result))).map_err(watch_error)?;
+        watcher.watch(&sessions_dir, RecursiveMode::Recursive).map_err(watch_error)?;
+
+        let mut watches = self.watches.lock().unwrap_or_else(|e| e.into_inner());
+        watches.retain(|active| !active.closed.load(Ordering::Relaxed));
+        watches.push(ActiveWatch { _watcher: watcher, closed });
+        Ok(receiver)
+    }
+}
+
+/// Turns `notify` events into [`StorageEvent`]s
+///
+/// Events only say that a path changed, so whether a session was created,
+/// modified, or deleted comes from which of its files exist afterwards.
+struct Tracker {
+    sessions_dir: PathBuf,
+    /// Every file of each known session; more than one mid-migration
+    files: HashMap<Uuid, HashSet<PathBuf>>,
+    sender: Sender<StorageEvent>,
+    closed: Arc<AtomicBool>,
+}
+
+impl Tracker {
+    fn handle(&mut self, result: notify::Result<Event>) {
+        let event = match result {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("Watch error in {}: {}", self.sessions_dir.display(), e);
+                return;
+            }
+        };
+        if matches!(event.kind, EventKind::Access(_)) || self.closed.load(Ordering::Relaxed) {
+            return;
+        }
+
+        for path in event.paths {
+            let Some(id) = session_id(&self.sessions_dir, &path) else {
+                continue;
+            };
+            let Some(change) = self.update(id, path) else {
+                continue;
+            };
+            if self.sender.send(change).is_err() {
+                debug!("Stopped watching {}", self.sessions_dir.display());
+                self.closed.store(true, Ordering::Relaxed);
+                return;
+            }
+        }
+    }
+
+    /// Record whether `path` exists now, and what that means for session `id`
+    fn update(&mut self, id: Uuid, path: PathBuf) -> Option<StorageEvent> {
+        let paths = self.files.entry(id).or_default();
+        let was_known = !paths.is_empty();
+
+        if path.exists() {
+            paths.insert(path);
+            return Some(if was_known { StorageEvent::Modified(id) } else { StorageEvent::Created(id) });
+        }
+        if !paths.remove(&path) {
+            return None;
+        }
+        if paths.is_empty() {
+            self.files.remove(&id);
+            Some(StorageEvent::Deleted(id))
+        } else {
+            Some(StorageEvent::Modified(id))
+        }
+    }
+}
+
+/// Files of every session currently stored, keyed by session id
+fn known_files(sessions_dir: &Path) -> Result<HashMap<Uuid, HashSet<PathBuf>>, ContextError> {
+    let mut files: HashMap<Uuid, HashSet<PathBuf>> = HashMap::new();
+    for path in session_files(sessions_dir)? {
+        if let Some(id) = session_id(sessions_dir, &path) {
+            files.entry(id).or_default().insert(path);
+        }
+    }
+    Ok(files)
+}
+
+/// The session a path under `sessions_dir` stores, if it is a session file
+///
+/// Mirrors `session_files`: the latest symlink, the archive, and lock files
+/// don't count.
+fn session_id(sessions_dir: &Path, path: &Path) -> Option<Uuid> {
+    let relative = path.strip_prefix(sessions_dir).ok()?;
+    let top = relative.components().next()?.as_os_str();
+    if top == ARCHIVE_DIR || top == LOCKS_DIR || relative == Path::new("latest.json") {
+        return None;
+    }
+    SerializationFormat::from_path(path)?;
+    Uuid::parse_str(path.file_stem()?.to_str()?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::{Message, Session};
+    use crate::storage::SessionStorage;
+    use tempfile::TempDir;
+
+    /// `settle` is waited before rewriting a file, for watchers that can't see
+    /// changes closer together
+    fn assert_reports_external_changes(events: Receiver<StorageEvent>, dir: &Path, settle: Duration) {
+        let next = || events.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // Another process sharing the directory
+        let other = FileStorage::with_directory(dir).unwrap();
+        let mut session = Session::new();
+        other.save_session(&session).unwrap();
+        assert_eq!(next(), StorageEvent::Created(session.id));
+
+        std::thread::sleep(settle);
+        session.add_message(Message::user("changed elsewhere".to_string()));
+        other.save_session(&session).unwrap();
+        assert_eq!(next(), StorageEvent::Modified(session.id));
+
+        // One save can be several writes, so allow repeated modifications
+        other.delete_session(&session.id).unwrap();
+        let mut event = next();
+        while event == StorageEvent::Modified(session.id) {
+            event = next();
+        }
+        assert_eq!(event, StorageEvent::Deleted(session.id));
+    }
+
+    #[test]
+    fn test_watch_reports_external_changes() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileStorage::with_directory(temp_dir.path()).unwrap();
+        assert_reports_external_changes(storage.watch().unwrap(), temp_dir.path(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_polling_watch_reports_external_changes() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileStorage::with_directory(temp_dir.path()).unwrap();
+        let events = storage.watch_polling(Duration::from_millis(10)).unwrap();
+        assert_reports_external_changes(events, temp_dir.path(), Duration::from_millis(1100));
+    }
+
+    #[test]
+    fn test_dropped_watches_are_released() {
+        let temp_dir = TempDir::new().unwrap();
+        let storage = FileStorage::with_directory(temp_dir.path()).unwrap();
+        drop(storage.watch().unwrap());
+        let events = storage.watch().unwrap();
+
+        // The first watch notices its receiver is gone on this change
+        storage.save_session(&Session::new()).unwrap();
+        events.recv_timeout(Duration::from_secs(5)).unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !storage.watches.lock().unwrap()[0].closed.load(Ordering::Relaxed) {
+            assert!(std::time::Instant::now() < deadline, "dropped watch never closed");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        let _events = storage.watch().unwrap();
+        assert_eq!(storage.watches.lock().unwrap().len(), 2);
+    }
+}
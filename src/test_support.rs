@@ -0,0 +1,55 @@
+//! Fixtures shared by the storage and manager tests
+
+use crate::error::Result;
+use crate::session::Session;
+use crate::storage::{FileStorage, SessionInfo, SessionStorage};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// File storage that counts the saves and loads reaching it
+pub(crate) struct CountingStorage {
+    inner: FileStorage,
+    pub(crate) saves: Arc<AtomicUsize>,
+    pub(crate) loads: Arc<AtomicUsize>,
+}
+
+impl CountingStorage {
+    pub(crate) fn with_directory(dir: &Path) -> Self {
+        Self {
+            inner: FileStorage::with_directory(dir).unwrap(),
+            saves: Arc::new(AtomicUsize::new(0)),
+            loads: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+impl SessionStorage for CountingStorage {
+    fn save_session(&self, session: &Session) -> Result<()> {
+        self.saves.fetch_add(1, Ordering::SeqCst);
+        self.inner.save_session(session)
+    }
+
+    fn load_session(&self, session_id: &Uuid) -> Result<Session> {
+        self.loads.fetch_add(1, Ordering::SeqCst);
+        self.inner.load_session(session_id)
+    }
+
+    fn load_latest_session(&self) -> Result<Option<Session>> {
+        self.loads.fetch_add(1, Ordering::SeqCst);
+        self.inner.load_latest_session()
+    }
+
+    fn list_sessions(&self) -> Result<Vec<SessionInfo>> {
+        self.inner.list_sessions()
+    }
+
+    fn delete_session(&self, session_id: &Uuid) -> Result<()> {
+        self.inner.delete_session(session_id)
+    }
+
+    fn cleanup_old_sessions(&self, keep_count: usize) -> Result<usize> {
+        self.inner.cleanup_old_sessions(keep_count)
+    }
+}
@@ -0,0 +1,478 @@
+//! Tunable token estimation
+
+use crate::session::{Message, MessageRole, Session};
+use std::collections::HashMap;
+use tracing::warn;
+use uuid::Uuid;
+
+/// Symbol density at which [`TokenModel::with_structured_divisor`] treats
+/// content as JSON or code
+pub const DEFAULT_STRUCTURED_THRESHOLD: f64 = 0.2;
+
+/// Bytes per token the default [`TokenModel`] assumes for JSON and code
+pub const DEFAULT_STRUCTURED_DIVISOR: usize = 3;
+
+/// How far, as a percentage of the real count, an estimate may miss before
+/// setting the real count logs a warning
+pub const DEFAULT_DRIFT_TOLERANCE_PERCENT: f64 = 50.0;
+
+/// Parameters for estimating tokens closer to what a provider bills
+///
+/// The default model assumes about four bytes of content per token, like
+/// [`Message::estimate_tokens`], except for JSON and code, which it counts at
+/// [`DEFAULT_STRUCTURED_DIVISOR`]; it adds no per-message overhead. Set
+/// `structured_threshold` above 1 (or `structured_divisor` to `None`) to count
+/// everything at `base_divisor`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenModel {
+    /// Bytes of content per token; estimates round up
+    pub base_divisor: usize,
+    /// Fixed tokens added to every message of a role, for framing the
+    /// tokenizer adds around it (role markers, tool-result scaffolding)
+    pub per_role_overhead: HashMap<MessageRole, usize>,
+    /// Bytes per token for content at or above `structured_threshold`
+    /// (`None` = `base_divisor` for everything)
+    pub structured_divisor: Option<usize>,
+    /// [`symbol_density`] from which content counts as structured; densities
+    /// never exceed 1, so anything higher turns structured counting off
+    pub structured_threshold: f64,
+    /// Percentage an estimate may miss a real token count by before
+    /// [`Session::set_token_counts_with`] warns (`f64::INFINITY` = never)
+    pub drift_tolerance_percent: f64,
+}
+
+impl Default for TokenModel {
+    fn default() -> Self {
+        Self {
+            base_divisor: 4,
+            per_role_overhead: HashMap::new(),
+            structured_divisor: Some(DEFAULT_STRUCTURED_DIVISOR),
+            structured_threshold: DEFAULT_STRUCTURED_THRESHOLD,
+            drift_tolerance_percent: DEFAULT_DRIFT_TOLERANCE_PERCENT,
+        }
+    }
+}
+
+/// Fraction of non-whitespace characters that are neither letters nor digits
+///
+/// Around 0.05 for prose; JSON and code, whose braces, quotes and operators
+/// tokenizers split finely, typically score 0.2 or more. 0 for blank text.
+pub fn symbol_density(text: &str) -> f64 {
+    let (mut symbols, mut visible) = (0usize, 0usize);
+    for c in text.chars().filter(|c| !c.is_whitespace()) {
+        visible += 1;
+        if !c.is_alphanumeric() {
+            symbols += 1;
+        }
+    }
+    if visible == 0 { 0.0 } else { symbols as f64 / visible as f64 }
+}
+
+impl TokenModel {
+    pub fn new(base_divisor: usize) -> Self {
+        Self {
+            base_divisor,
+            ..Self::default()
+        }
+    }
+
+    /// Add `tokens` of fixed overhead to every message with `role`
+    pub fn with_overhead(mut self, role: MessageRole, tokens: usize) -> Self {
+        self.per_role_overhead.insert(role, tokens);
+        self
+    }
+
+    /// Count punctuation-dense content like JSON and code at `divisor` bytes
+    /// per token instead of `base_divisor`
+    pub fn with_structured_divisor(mut self, divisor: usize) -> Self {
+        self.structured_divisor = Some(divisor);
+        self
+    }
+
+    /// Set the [`symbol_density`] from which content counts as structured
+    pub fn with_structured_threshold(mut self, threshold: f64) -> Self {
+        self.structured_threshold = threshold;
+        self
+    }
+
+    /// Set how far an estimate may miss a real token count, in percent,
+    /// before setting the count warns
+    pub fn with_drift_tolerance(mut self, percent: f64) -> Self {
+        self.drift_tolerance_percent = percent;
+        self
+    }
+
+    /// Bytes per token this model assumes for `text`
+    pub fn divisor_for(&self, text: &str) -> usize {
+        let divisor = match self.structured_divisor {
+            Some(divisor) if symbol_density(text) >= self.structured_threshold => divisor,
+            _ => self.base_divisor,
+        };
+        divisor.max(1)
+    }
+
+    /// Fixed tokens this model adds to every message of `role`
+    pub fn overhead_for(&self, role: &MessageRole) -> usize {
+        self.per_role_overhead.get(role).copied().unwrap_or(0)
+    }
+
+    /// Estimated tokens for a message
+    ///
+    /// An explicit `token_count` replaces the content estimate, but the role
+    /// overhead is still added on top.
+    pub fn estimate(&self, message: &Message) -> usize {
+        let content = message.token_count.unwrap_or_else(|| self.count_text(&message.content));
+        content + self.overhead_for(&message.role)
+    }
+}
+
+/// Counts tokens the way a particular model does
+///
+/// Implement [`Tokenizer::count_text`] with a real tokenizer for exact
+/// budgets; [`TokenModel`] is the built-in approximation.
+pub trait Tokenizer: Send + Sync {
+    /// Tokens in `text`
+    fn count_text(&self, text: &str) -> usize;
+
+    /// Tokens for a whole message; an explicit `token_count` wins by default
+    fn count_message(&self, message: &Message) -> usize {
+        message.token_count.unwrap_or_else(|| self.count_text(&message.content))
+    }
+}
+
+impl Tokenizer for TokenModel {
+    fn count_text(&self, text: &str) -> usize {
+        text.len().div_ceil(self.divisor_for(text))
+    }
+
+    fn count_message(&self, message: &Message) -> usize {
+        self.estimate(message)
+    }
+}
+
+/// Per-token prices for [`Session::estimate_cost`], in dollars per 1000 tokens
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Pricing {
+    pub input_per_1k: f64,
+    pub output_per_1k: f64,
+}
+
+/// An estimate that missed the real token count, from [`Session::set_token_counts_with`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TokenDrift {
+    pub message_id: Uuid,
+    pub estimated: usize,
+    pub actual: usize,
+}
+
+impl TokenDrift {
+    /// How far off the estimate was, as a percentage of the actual count
+    ///
+    /// Infinite when the actual count is 0 but the estimate wasn't.
+    pub fn percent(&self) -> f64 {
+        let difference = self.estimated.abs_diff(self.actual) as f64;
+        match self.actual {
+            0 if self.estimated == 0 => 0.0,
+            0 => f64::INFINITY,
+            actual => difference * 100.0 / actual as f64,
+        }
+    }
+}
+
+impl Message {
+    /// Estimate token count using `model`
+    pub fn estimate_tokens_with(&self, model: &TokenModel) -> usize {
+        model.estimate(self)
+    }
+
+    /// Estimate with `model` if given, otherwise as [`Message::estimate_tokens`]
+    pub(crate) fn estimate_tokens_opt(&self, model: Option<&TokenModel>) -> usize {
+        model.map_or_else(|| self.estimate_tokens(), |model| model.estimate(self))
+    }
+
+    /// Compare the estimate `model` gives with a real count about to replace it
+    ///
+    /// `None` when the message already had a real count or the estimate was
+    /// within the model's `drift_tolerance_percent` (the default model's when
+    /// `model` is `None`); otherwise the miss, logged with `tracing::warn!`.
+    pub(crate) fn check_token_count(&self, actual: usize, model: Option<&TokenModel>) -> Option<TokenDrift> {
+        if self.token_count.is_some() {
+            return None;
+        }
+        let tolerance = model.map_or(DEFAULT_DRIFT_TOLERANCE_PERCENT, |model| model.drift_tolerance_percent);
+        let drift = TokenDrift { message_id: self.id, estimated: self.estimate_tokens_opt(model), actual };
+        if drift.percent() <= tolerance {
+            return None;
+        }
+        warn!(
+            "Token estimate for message {} was {} but the real count is {} ({:.0}% off)",
+            drift.message_id,
+            drift.estimated,
+            drift.actual,
+            drift.percent()
+        );
+        Some(drift)
+    }
+}
+
+impl Session {
+    /// The newest messages that fit in `max_tokens`, oldest first
+    ///
+    /// Walks back from the last message and stops at the first one that
+    /// would exceed the budget, so the result is always a contiguous tail.
+    /// Counts with `tokenizer` when given, otherwise
+    /// [`Message::estimate_tokens`].
+    pub fn recent_messages_by_tokens(&self, max_tokens: usize, tokenizer: Option<&dyn Tokenizer>) -> Vec<&Message> {
+        let mut remaining = max_tokens;
+        let start = self.messages().iter()
+            .rposition(|message| {
+                let tokens = tokenizer.map_or_else(|| message.estimate_tokens(), |t| t.count_message(message));
+                match remaining.checked_sub(tokens) {
+                    Some(left) => {
+                        remaining = left;
+                        false
+                    }
+                    None => true,
+                }
+            })
+            .map_or(0, |index| index + 1);
+        self.messages()[start..].iter().collect()
+    }
+
+    /// Each message with the estimated tokens from it through the last message
+    ///
+    /// In message order, so totals decrease down the list; the first entry
+    /// holds the whole session's total. The messages that fit in a window of
+    /// `n` tokens are those whose total is at most `n`. Counts with
+    /// [`Message::estimate_tokens`].
+    pub fn messages_with_cumulative_tokens(&self) -> Vec<(&Message, usize)> {
+        let mut running = 0;
+        let mut totals: Vec<(&Message, usize)> = self.messages().iter()
+            .rev()
+            .map(|message| {
+                running += message.estimate_tokens();
+                (message, running)
+            })
+            .collect();
+        totals.reverse();
+        totals
+    }
+
+    /// Tokens per role, counted with `tokenizer` when given, otherwise
+    /// [`Message::estimate_tokens`]
+    ///
+    /// Roles with no messages are absent.
+    pub fn token_breakdown(&self, tokenizer: Option<&dyn Tokenizer>) -> HashMap<MessageRole, usize> {
+        let mut breakdown = HashMap::new();
+        for message in self.messages() {
+            let tokens = tokenizer.map_or_else(|| message.estimate_tokens(), |t| t.count_message(message));
+            *breakdown.entry(message.role.clone()).or_insert(0) += tokens;
+        }
+        breakdown
+    }
+
+    /// Rough spend for the session in dollars under `pricing`
+    ///
+    /// Assistant tokens are billed as output and every other role as input,
+    /// each message counted once. Providers that resend the whole history
+    /// with each request bill more than this; treat it as a lower bound.
+    pub fn estimate_cost(&self, pricing: &Pricing, tokenizer: Option<&dyn Tokenizer>) -> f64 {
+        self.token_breakdown(tokenizer)
+            .into_iter()
+            .map(|(role, tokens)| {
+                let per_1k = match role {
+                    MessageRole::Assistant => pricing.output_per_1k,
+                    _ => pricing.input_per_1k,
+                };
+                tokens as f64 / 1000.0 * per_1k
+            })
+            .sum()
+    }
+
+    /// Like [`Session::set_token_counts`], judging estimates by `model`
+    ///
+    /// For each message that only had an estimate (no earlier `token_count`),
+    /// the estimate `model` gives (or [`Message::estimate_tokens`] when
+    /// `None`) is compared with the real count. Misses by more than the
+    /// model's `drift_tolerance_percent` are logged with `tracing::warn!` and
+    /// returned, so systematic bias (non-English text, code) shows up and the
+    /// model's `base_divisor` can be recalibrated.
+    pub fn set_token_counts_with(&mut self, counts: &[(Uuid, usize)], model: Option<&TokenModel>) -> Vec<TokenDrift> {
+        let by_id: HashMap<Uuid, usize> = counts.iter().copied().collect();
+        let mut drifts = Vec::new();
+        for message in self.edit_messages() {
+            if let Some(&actual) = by_id.get(&message.id) {
+                drifts.extend(message.check_token_count(actual, model));
+                message.token_count = Some(actual);
+            }
+        }
+        self.recount_tokens();
+        drifts
+    }
+
+    /// Total estimated tokens using `model`, or the running total when `None`
+    ///
+    /// O(1) for `None`, and for the model a `SessionManager` using it has
+    /// been keeping a running total for; otherwise a full sum.
+    pub fn total_tokens_with(&self, model: Option<&TokenModel>) -> usize {
+        let Some(model) = model else {
+            return self.total_tokens();
+        };
+        match self.fresh_token_cache().and_then(|cache| cache.model.as_ref()) {
+            Some((cached, total)) if cached == model => *total,
+            _ => self.messages().iter().map(|m| model.estimate(m)).sum(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_model_overhead() {
+        let mut session = Session::new();
+        session.add_message(Message::system("x".repeat(30)));
+        session.add_message(Message::tool("y".repeat(9)).with_token_count(2));
+
+        let default = TokenModel::default();
+        assert_eq!(session.total_tokens_with(Some(&default)), session.total_tokens());
+
+        let model = TokenModel::new(3)
+            .with_overhead(MessageRole::System, 4)
+            .with_overhead(MessageRole::Tool, 10);
+        assert_eq!(session.messages()[0].estimate_tokens_with(&model), 14);
+        assert_eq!(session.messages()[1].estimate_tokens_with(&model), 12);
+        assert_eq!(session.total_tokens_with(Some(&model)), 26);
+        assert_eq!(session.total_tokens_with(None), session.total_tokens());
+    }
+
+    #[test]
+    fn test_structured_content_uses_lower_divisor() {
+        let json = r#"{"files": [{"path": "src/a.rs", "size": 12}, {"path": "b", "size": 3}]}"#;
+        let prose = "The build finished without errors and every test passed on the first try.";
+        assert!(symbol_density(json) >= DEFAULT_STRUCTURED_THRESHOLD);
+        assert!(symbol_density(prose) < DEFAULT_STRUCTURED_THRESHOLD);
+        assert_eq!(symbol_density("  "), 0.0);
+
+        let model = TokenModel::default().with_structured_divisor(2);
+        let (json_message, prose_message) = (Message::tool(json.to_string()), Message::assistant(prose.to_string()));
+        assert_eq!(json_message.estimate_tokens_with(&model), json.len().div_ceil(2));
+        assert_eq!(prose_message.estimate_tokens_with(&model), prose_message.estimate_tokens());
+        assert_eq!(model.count_text(json), json.len().div_ceil(2));
+
+        // On by default, and the threshold is adjustable or can turn it off
+        let default = TokenModel::default();
+        assert_eq!(json_message.estimate_tokens_with(&default), json.len().div_ceil(DEFAULT_STRUCTURED_DIVISOR));
+        assert_eq!(prose_message.estimate_tokens_with(&default), prose_message.estimate_tokens());
+        let strict = model.with_structured_threshold(1.0);
+        assert_eq!(strict.divisor_for(json), 4);
+        let off = default.with_structured_threshold(f64::INFINITY);
+        assert_eq!(json_message.estimate_tokens_with(&off), json_message.estimate_tokens());
+    }
+
+    #[test]
+    fn test_compact_with_token_model() {
+        let mut session = Session::new();
+        for _ in 0..10 {
+            session.add_message(Message::tool("x".repeat(40)));
+        }
+        let strategy = crate::CompactionStrategy::Sliding { max_tokens: 100, options: crate::RetentionOptions::default() };
+
+        // 10 tokens each by default, so nothing needs removing
+        let mut plain = session.clone();
+        plain.compact_with(&strategy, 100, None).unwrap();
+        assert_eq!(plain.messages().len(), 10);
+
+        // With 15 tokens of framing per tool result, only four fit
+        let model = TokenModel::default().with_overhead(MessageRole::Tool, 15);
+        session.compact_with(&strategy, 100, Some(&model)).unwrap();
+        assert_eq!(session.messages().len(), 4);
+    }
+
+    #[test]
+    fn test_recent_messages_by_tokens() {
+        let mut session = Session::new();
+        session.add_message(Message::system("x".repeat(400)));
+        session.add_message(Message::user("x".repeat(40)));
+        session.add_message(Message::assistant("x".repeat(40)));
+        session.add_message(Message::user("x".repeat(20)));
+
+        let contents = |messages: Vec<&Message>| messages.iter().map(|m| m.content.len()).collect::<Vec<_>>();
+        assert_eq!(contents(session.recent_messages_by_tokens(25, None)), [40, 40, 20]);
+        assert_eq!(contents(session.recent_messages_by_tokens(24, None)), [40, 20]);
+        assert_eq!(contents(session.recent_messages_by_tokens(1000, None)), [400, 40, 40, 20]);
+        assert!(session.recent_messages_by_tokens(4, None).is_empty());
+
+        // Halving bytes per token doubles every count
+        let model = TokenModel::new(2);
+        assert_eq!(contents(session.recent_messages_by_tokens(25, Some(&model))), [20]);
+
+        let totals: Vec<usize> = session.messages_with_cumulative_tokens().iter().map(|&(_, total)| total).collect();
+        assert_eq!(totals, [125, 25, 15, 5]);
+        let fits = session.messages_with_cumulative_tokens().into_iter()
+            .filter(|&(_, total)| total <= 25)
+            .map(|(message, _)| message);
+        assert_eq!(contents(fits.collect()), contents(session.recent_messages_by_tokens(25, None)));
+        assert!(Session::new().messages_with_cumulative_tokens().is_empty());
+    }
+
+    #[test]
+    fn test_estimate_cost_splits_input_and_output() {
+        let mut session = Session::new();
+        session.add_message(Message::system("x".repeat(400)));
+        session.add_message(Message::user("x".repeat(1600)));
+        session.add_message(Message::tool("x".repeat(2000)).with_token_count(500));
+        session.add_message(Message::assistant("x".repeat(4000)));
+
+        let breakdown = session.token_breakdown(None);
+        assert_eq!(breakdown[&MessageRole::Assistant], 1000);
+        assert_eq!(breakdown[&MessageRole::Tool], 500);
+        assert_eq!(breakdown.len(), 4);
+
+        let pricing = Pricing { input_per_1k: 0.5, output_per_1k: 2.0 };
+        assert!((session.estimate_cost(&pricing, None) - (1.0 * 0.5 + 1.0 * 2.0)).abs() < 1e-9);
+
+        // Two bytes per token doubles content estimates; the explicit tool count stays
+        let model = TokenModel::new(2);
+        assert!((session.estimate_cost(&pricing, Some(&model)) - (1.5 * 0.5 + 2.0 * 2.0)).abs() < 1e-9);
+        assert_eq!(Session::new().estimate_cost(&pricing, None), 0.0);
+    }
+
+    #[test]
+    fn test_set_token_counts_reports_drift() {
+        let mut session = Session::new();
+        session.add_message(Message::user("x".repeat(40)));
+        session.add_message(Message::user("y".repeat(40)));
+        session.add_message(Message::user("z".repeat(40)).with_token_count(3));
+        let ids: Vec<Uuid> = session.messages().iter().map(|m| m.id).collect();
+
+        // Estimates are 10 each: 11 is within 20%, 25 is not, and the third
+        // message already had a real count
+        let strict = TokenModel::default().with_drift_tolerance(20.0);
+        let drifts = session.set_token_counts_with(&[(ids[0], 11), (ids[1], 25), (ids[2], 50)], Some(&strict));
+        assert_eq!(drifts, [TokenDrift { message_id: ids[1], estimated: 10, actual: 25 }]);
+        assert_eq!(drifts[0].percent(), 60.0);
+        assert_eq!(session.total_tokens(), 11 + 25 + 50);
+
+        // Judged against the caller's model; counts are now real, so no more reports
+        let mut fresh = Session::new();
+        fresh.add_message(Message::user("x".repeat(40)));
+        let id = fresh.messages()[0].id;
+        let halves = TokenModel::new(2).with_drift_tolerance(1.0);
+        assert!(fresh.set_token_counts_with(&[(id, 20)], Some(&halves)).is_empty());
+        assert!(fresh.set_token_counts_with(&[(id, 99)], Some(&strict)).is_empty());
+
+        // The plain setters go through the same check with the default tolerance
+        let mut plain = Session::new();
+        plain.add_message(Message::user("x".repeat(40)));
+        plain.add_message(Message::user("y".repeat(40)));
+        let ids: Vec<Uuid> = plain.messages().iter().map(|m| m.id).collect();
+        assert_eq!(plain.messages()[0].check_token_count(14, None), None);
+        assert_eq!(plain.messages()[0].check_token_count(30, None).map(|d| d.actual), Some(30));
+        plain.set_token_counts(&[(ids[0], 30)]);
+        assert_eq!(plain.messages()[0].check_token_count(90, None), None);
+        let unlimited = TokenModel::default().with_drift_tolerance(f64::INFINITY);
+        assert!(plain.set_token_counts_with(&[(ids[1], 1000)], Some(&unlimited)).is_empty());
+    }
+}